@@ -39,12 +39,12 @@ use serde::{Deserialize, Serialize};
 ///
 /// ```
 /// use std::str::FromStr;
-/// use conserve::apath::Apath;
+/// use conserve::Apath;
 ///
 /// let apath: Apath = "/something".parse().unwrap();
 /// assert_eq!(apath.to_string(), "/something");
 /// ```
-#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub struct Apath(String);
 
 impl Apath {
@@ -90,6 +90,52 @@ impl Apath {
             }
         }
     }
+
+    /// The parent directory of this apath, or None for the root.
+    ///
+    /// ```
+    /// use conserve::Apath;
+    ///
+    /// assert_eq!(Apath::from("/a/b").parent(), Some(Apath::from("/a")));
+    /// assert_eq!(Apath::from("/a").parent(), Some(Apath::from("/")));
+    /// assert_eq!(Apath::from("/").parent(), None);
+    /// ```
+    pub fn parent(&self) -> Option<Apath> {
+        if self.0 == "/" {
+            return None;
+        }
+        match self.0.rfind('/').expect("apaths start with a slash") {
+            0 => Some(Apath("/".to_owned())),
+            i => Some(Apath(self.0[..i].to_owned())),
+        }
+    }
+
+    /// True if this apath is ordered before all the contents of directory `dir`.
+    ///
+    /// The contents of a directory, including all its descendants, are contiguous
+    /// in apath order, although the directory itself is ordered among its siblings,
+    /// so this can be used to seek to the start of the contents.
+    ///
+    /// ```
+    /// use conserve::Apath;
+    ///
+    /// let dir = Apath::from("/b");
+    /// assert!(Apath::from("/b").is_before_contents_of(&dir));
+    /// assert!(Apath::from("/c").is_before_contents_of(&dir));
+    /// assert!(Apath::from("/a/z").is_before_contents_of(&dir));
+    /// assert!(!Apath::from("/b/a").is_before_contents_of(&dir));
+    /// assert!(!Apath::from("/b/a/z").is_before_contents_of(&dir));
+    /// assert!(!Apath::from("/c/a").is_before_contents_of(&dir));
+    /// ```
+    pub fn is_before_contents_of(&self, dir: &Apath) -> bool {
+        if dir.0 == "/" {
+            self.0 == "/"
+        } else {
+            // Compare to a child with an empty name, which would be ordered before
+            // any real child.
+            compare_apath_strs(&self.0, &format!("{}/", dir.0)) == Ordering::Less
+        }
+    }
 }
 
 impl FromStr for Apath {
@@ -189,38 +235,41 @@ impl AsRef<Path> for Apath {
 /// equal strings imply equal apaths.
 impl Ord for Apath {
     fn cmp(&self, b: &Apath) -> Ordering {
-        let &Apath(ref a) = self;
-        let &Apath(ref b) = b;
-        let mut ait = a.split('/');
-        let mut bit = b.split('/');
-        let mut oa = ait.next().expect("paths must not be empty");
-        let mut ob = bit.next().expect("paths must not be empty");
-        loop {
-            match (ait.next(), bit.next()) {
-                // Both paths end here: eg ".../aa" < ".../zz"
-                (None, None) => return oa.cmp(ob),
-
-                // If one is a direct child and the other is in a subdirectory,
-                // the direct child comes first.
-                // eg ".../zz" < ".../aa/bb"
-                (None, Some(_bc)) => return Ordering::Less,
-                (Some(_ac), None) => return Ordering::Greater,
-
-                // Both paths have children after this point
-                (Some(ac), Some(bc)) => match oa.cmp(ob) {
-                    Ordering::Equal => {
-                        // a/b/c/..., a/b/c/...
-                        // If parents are the same and both have children keep looking.
-                        oa = ac;
-                        ob = bc;
-                        continue;
-                    }
-                    // a/b/c/... < a/b/d/...
-                    // Both paths have children, but the path prefixes are
-                    // different.
-                    other => return other,
-                },
-            }
+        compare_apath_strs(&self.0, &b.0)
+    }
+}
+
+/// Compare two strings in apath order.
+fn compare_apath_strs(a: &str, b: &str) -> Ordering {
+    let mut ait = a.split('/');
+    let mut bit = b.split('/');
+    let mut oa = ait.next().expect("paths must not be empty");
+    let mut ob = bit.next().expect("paths must not be empty");
+    loop {
+        match (ait.next(), bit.next()) {
+            // Both paths end here: eg ".../aa" < ".../zz"
+            (None, None) => return oa.cmp(ob),
+
+            // If one is a direct child and the other is in a subdirectory,
+            // the direct child comes first.
+            // eg ".../zz" < ".../aa/bb"
+            (None, Some(_bc)) => return Ordering::Less,
+            (Some(_ac), None) => return Ordering::Greater,
+
+            // Both paths have children after this point
+            (Some(ac), Some(bc)) => match oa.cmp(ob) {
+                Ordering::Equal => {
+                    // a/b/c/..., a/b/c/...
+                    // If parents are the same and both have children keep looking.
+                    oa = ac;
+                    ob = bc;
+                    continue;
+                }
+                // a/b/c/... < a/b/d/...
+                // Both paths have children, but the path prefixes are
+                // different.
+                other => return other,
+            },
         }
     }
 }
@@ -246,6 +295,15 @@ impl CheckOrder {
         CheckOrder { last_apath: None }
     }
 
+    /// Make a CheckOrder that requires all apaths to be after `a`.
+    ///
+    /// This is used when continuing to write an index that already has some entries.
+    pub fn starting_after(a: &Apath) -> CheckOrder {
+        CheckOrder {
+            last_apath: Some(a.clone()),
+        }
+    }
+
     pub fn check(&mut self, a: &Apath) {
         if let Some(ref last_apath) = self.last_apath {
             assert!(
@@ -13,30 +13,38 @@
 
 //! Archives holding backup material.
 
-use std::collections::{BTreeSet, HashMap};
-use std::io::ErrorKind;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::io::{ErrorKind, Read};
 use std::path::Path;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
+use chrono::{DateTime, Local, Utc};
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 
 use crate::backup::BackupOptions;
 use crate::blockhash::BlockHash;
+use crate::compress::Compression;
+use crate::config::CONFIG_FILENAME;
 use crate::copy_tree::CopyOptions;
-use crate::errors::Error;
+use crate::errors::{Error, ErrorChain};
 use crate::jsonio::{read_json, write_json};
 use crate::kind::Kind;
 use crate::misc::remove_item;
 use crate::stats::{CopyStats, ValidateStats};
 use crate::stitch::IterStitchedIndexHunks;
+use crate::transport::append_only::AppendOnlyTransport;
 use crate::transport::local::LocalTransport;
-use crate::transport::{DirEntry, Transport};
+use crate::transport::mirror::MirrorTransport;
+use crate::transport::{DirEntry, Location, Transport};
 use crate::*;
 
-const HEADER_FILENAME: &str = "CONSERVE";
+pub(crate) const HEADER_FILENAME: &str = "CONSERVE";
 static BLOCK_DIR: &str = "d";
 
+/// Records when the mirror last had everything in this archive.
+pub(crate) const MIRROR_STATE_FILENAME: &str = "MIRROR";
+
 /// An archive holding backup material.
 #[derive(Clone, Debug)]
 pub struct Archive {
@@ -44,11 +52,38 @@ pub struct Archive {
     block_dir: BlockDir,
 
     transport: Box<dyn Transport>,
+
+    /// Optional format features used by this archive, from the header.
+    features: Vec<String>,
+
+    config: ArchiveConfig,
+
+    /// Compression for new index hunks.
+    index_compression: Compression,
+
+    /// True if the archive is configured append-only and was opened without
+    /// allowing deletion, so existing data can't be deleted or changed.
+    append_only: bool,
+}
+
+/// Contents of the `MIRROR` file.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+struct MirrorState {
+    /// The configured location of the mirror.
+    location: String,
+    /// The last band in this archive, which was complete, when the mirror
+    /// last had every band and block in this archive.
+    last_band: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct ArchiveHeader {
     conserve_archive_version: String,
+
+    /// Optional format features, from [ARCHIVE_FEATURES], used by this
+    /// archive.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    features: Vec<String>,
 }
 
 #[derive(Default, Debug)]
@@ -56,6 +91,43 @@ pub struct DeleteOptions {
     pub dry_run: bool,
     pub break_lock: bool,
     pub no_gc: bool,
+    /// Receives progress of the operation, if set.
+    pub progress: Option<Arc<dyn ProgressSink>>,
+}
+
+/// What [Archive::fix_incomplete_bands] does with each band that was never
+/// closed.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum IncompleteBandAction {
+    /// Only report them.
+    Report,
+    /// Close them, marked as partial, so they're kept but not mistaken for
+    /// complete backups.
+    Seal,
+    /// Delete them. Blocks referenced only by these bands remain until the
+    /// next gc.
+    Delete,
+}
+
+/// A band that was never closed, found by [Archive::fix_incomplete_bands].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct IncompleteBand {
+    pub band_id: BandId,
+    /// Number of entries written to the band's index before it was interrupted.
+    pub entry_count: usize,
+    /// The first apath in the band's index, if any.
+    pub first_apath: Option<Apath>,
+    /// The last apath in the band's index, if any.
+    pub last_apath: Option<Apath>,
+}
+
+#[derive(Default, Debug)]
+pub struct ValidateOptions {
+    /// Check the layout of the archive, bands, and blocks, and that every
+    /// referenced block is present, but don't read and hash every block.
+    pub quick: bool,
+    /// Receives progress of the operation, if set.
+    pub progress: Option<Arc<dyn ProgressSink>>,
 }
 
 impl Archive {
@@ -79,11 +151,18 @@ impl Archive {
             HEADER_FILENAME,
             &ArchiveHeader {
                 conserve_archive_version: String::from(ARCHIVE_VERSION),
+                features: Vec::new(),
             },
         )?;
+        let config = ArchiveConfig::default();
+        write_json(&transport, CONFIG_FILENAME, &config)?;
         Ok(Archive {
             block_dir,
             transport,
+            features: Vec::new(),
+            config,
+            index_compression: Compression::Snappy,
+            append_only: false,
         })
     }
 
@@ -95,6 +174,25 @@ impl Archive {
     }
 
     pub fn open(transport: Box<dyn Transport>) -> Result<Archive> {
+        Archive::open_with_allow_delete(transport, false)
+    }
+
+    /// Open an existing local archive, allowing existing data to be deleted
+    /// or changed even if the archive is configured append-only.
+    pub fn open_path_with_allow_delete(path: &Path, allow_delete: bool) -> Result<Archive> {
+        Archive::open_with_allow_delete(Box::new(LocalTransport::new(path)), allow_delete)
+    }
+
+    /// Open an existing archive.
+    ///
+    /// If the archive is configured append-only, and `allow_delete` is false,
+    /// all writes go through a transport that refuses to delete or overwrite
+    /// existing files, and operations that would do so fail with
+    /// `Error::ArchiveAppendOnly`.
+    pub fn open_with_allow_delete(
+        transport: Box<dyn Transport>,
+        allow_delete: bool,
+    ) -> Result<Archive> {
         let header: ArchiveHeader =
             read_json(&transport, HEADER_FILENAME).map_err(|err| match err {
                 Error::IOError { source } if source.kind() == ErrorKind::NotFound => {
@@ -108,45 +206,553 @@ impl Archive {
                 version: header.conserve_archive_version,
             });
         }
-        let block_dir = BlockDir::open(transport.sub_transport(BLOCK_DIR));
+        let unsupported_features: Vec<String> = header
+            .features
+            .iter()
+            .filter(|feature| !ARCHIVE_FEATURES.contains(&feature.as_str()))
+            .cloned()
+            .collect();
+        if !unsupported_features.is_empty() {
+            return Err(Error::UnsupportedArchiveFeatures {
+                features: unsupported_features,
+            });
+        }
+        let config = read_config(&transport)?;
+        let append_only = config.append_only && !allow_delete;
+        let transport: Box<dyn Transport> = if append_only {
+            Box::new(AppendOnlyTransport::new(transport))
+        } else {
+            transport
+        };
+        let zstd_blocks = header.features.iter().any(|f| f == ZSTD_BLOCKS_FEATURE);
+        let compression = match config.compression {
+            Some(Compression::Zstd) if !zstd_blocks => {
+                log::error!(
+                    "Archive config asks for zstd compression without the {:?} feature; using snappy",
+                    ZSTD_BLOCKS_FEATURE
+                );
+                Compression::Snappy
+            }
+            Some(compression) => compression,
+            None if zstd_blocks => Compression::Zstd,
+            None => Compression::Snappy,
+        };
+        let zstd_index = header.features.iter().any(|f| f == ZSTD_INDEX_FEATURE);
+        let index_compression = match config.index_compression {
+            Some(Compression::Zstd) if !zstd_index => {
+                log::error!(
+                    "Archive config asks for zstd index compression without the {:?} feature; using snappy",
+                    ZSTD_INDEX_FEATURE
+                );
+                Compression::Snappy
+            }
+            Some(compression) => compression,
+            None if zstd_index => Compression::Zstd,
+            None => Compression::Snappy,
+        };
+        let block_dir = BlockDir::open(transport.sub_transport(BLOCK_DIR))
+            .with_compression(compression)
+            .with_zstd_level(config.compression_level())
+            .with_block_size(config.block_size());
         Ok(Archive {
             block_dir,
             transport,
+            features: header.features,
+            config,
+            index_compression,
+            append_only,
         })
     }
 
+    /// Return the configuration stored in the archive.
+    pub fn config(&self) -> &ArchiveConfig {
+        &self.config
+    }
+
+    /// Replace the configuration stored in the archive.
+    ///
+    /// Configuring Zstandard compression enables the `zstd_blocks` feature,
+    /// Zstandard index compression enables the `zstd_index` feature, and
+    /// delta indexes enable the `delta_index` feature.
+    ///
+    /// This takes effect when the archive is next opened: it doesn't change
+    /// this object.
+    pub fn set_config(&self, config: &ArchiveConfig) -> Result<()> {
+        config.check()?;
+        self.check_append_only()?;
+        let _lock = WriteLock::acquire(self, "config")?;
+        let mut features = Vec::new();
+        if config.compression == Some(Compression::Zstd) {
+            features.push(ZSTD_BLOCKS_FEATURE);
+        }
+        if config.index_compression == Some(Compression::Zstd) {
+            features.push(ZSTD_INDEX_FEATURE);
+        }
+        if config.delta_index {
+            features.push(DELTA_INDEX_FEATURE);
+        }
+        self.enable_features(&features)?;
+        write_json(&self.transport, CONFIG_FILENAME, config)
+    }
+
+    /// Return how new index hunks are compressed.
+    pub(crate) fn index_compression(&self) -> Compression {
+        self.index_compression
+    }
+
+    /// True if new bands should have delta indexes.
+    pub(crate) fn writes_delta_index(&self) -> bool {
+        self.config.delta_index && self.has_feature(DELTA_INDEX_FEATURE)
+    }
+
+    /// True if existing data in this archive can't be deleted or changed,
+    /// because it's configured append-only and was opened without allowing
+    /// deletion.
+    pub fn is_append_only(&self) -> bool {
+        self.append_only
+    }
+
+    /// Return `Error::ArchiveAppendOnly` if existing data can't be changed.
+    pub(crate) fn check_append_only(&self) -> Result<()> {
+        if self.append_only {
+            Err(Error::ArchiveAppendOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Return the optional format features used by this archive.
+    pub fn features(&self) -> &[String] {
+        &self.features
+    }
+
+    /// True if this archive uses the named format feature.
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+
+    /// Record in the header that the archive uses all of `new_features`.
+    ///
+    /// This takes effect when the archive is next opened: it doesn't change
+    /// this object, so several features must be enabled in one call.
+    pub(crate) fn enable_features<S: AsRef<str>>(&self, new_features: &[S]) -> Result<()> {
+        let mut features = self.features.clone();
+        for feature in new_features {
+            if !features.iter().any(|f| f == feature.as_ref()) {
+                features.push(feature.as_ref().to_owned());
+            }
+        }
+        if features.len() == self.features.len() {
+            return Ok(());
+        }
+        write_json(
+            &self.transport,
+            HEADER_FILENAME,
+            &ArchiveHeader {
+                conserve_archive_version: String::from(ARCHIVE_VERSION),
+                features,
+            },
+        )
+    }
+
     /// Backup a source directory into a new band in the archive.
     ///
+    /// The pre-backup hook, if any, is run first; the post-backup hook is run
+    /// after the band is closed, or after the backup fails.
+    ///
     /// Returns statistics about what was copied.
     pub fn backup(&self, source_path: &Path, options: &BackupOptions) -> Result<CopyStats> {
-        let live_tree = LiveTree::open(source_path)?.with_excludes(options.excludes.clone());
-        let writer = BackupWriter::begin(self)?;
+        self.with_backup_hooks(&source_path.to_string_lossy(), options, || {
+            let live_tree = LiveTree::open(source_path)?
+                .with_excludes(options.excludes.clone())
+                .with_exclude_caches(options.exclude_caches)
+                .with_exclude_if_present(options.exclude_if_present.clone())
+                .with_one_file_system(options.one_file_system);
+            let mut stats = self.backup_tree(&live_tree, options)?;
+            stats.mount_points_skipped = live_tree.iter_stats().mount_points_skipped;
+            Ok(stats)
+        })
+    }
+
+    /// Backup several source directories into a single new band.
+    ///
+    /// Each directory is stored under its absolute path, so `/etc` and `/home`
+    /// appear as `/etc/...` and `/home/...` in the band. Restoring to `/` puts
+    /// them back where they came from; restoring anywhere else recreates those
+    /// paths under the destination. Excludes match the full apath.
+    ///
+    /// Hooks are run once for the whole backup, with `CONSERVE_SOURCE` set to
+    /// the directories separated by the platform's path separator.
+    pub fn backup_roots<P: AsRef<Path>>(
+        &self,
+        source_paths: &[P],
+        options: &BackupOptions,
+    ) -> Result<CopyStats> {
+        let source_str = std::env::join_paths(source_paths.iter().map(AsRef::as_ref))
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        self.with_backup_hooks(&source_str, options, || {
+            let tree = MultiRootTree::open(source_paths)?
+                .with_excludes(options.excludes.clone())
+                .with_exclude_caches(options.exclude_caches)
+                .with_exclude_if_present(options.exclude_if_present.clone())
+                .with_one_file_system(options.one_file_system);
+            let mut stats = self.backup_tree(&tree, options)?;
+            stats.mount_points_skipped = tree.iter_stats().mount_points_skipped;
+            Ok(stats)
+        })
+    }
+
+    /// Backup the contents of a tar file, which may be compressed with gzip or
+    /// Zstandard, into a new band.
+    ///
+    /// Entries are stored in apath order regardless of their order in the tar
+    /// file, and directories containing them are added if they're missing.
+    /// Hooks are run with `CONSERVE_SOURCE` set to the tar file.
+    pub fn import_tar(&self, tar_path: &Path, options: &BackupOptions) -> Result<CopyStats> {
+        self.with_backup_hooks(&tar_path.to_string_lossy(), options, || {
+            let tree = TarTree::open(tar_path)?.with_excludes(options.excludes.clone());
+            self.backup_tree(&tree, options)
+        })
+    }
+
+    /// Run the pre-backup hook, then `backup`, then the post-backup hook.
+    fn with_backup_hooks<F>(
+        &self,
+        source_str: &str,
+        options: &BackupOptions,
+        backup: F,
+    ) -> Result<CopyStats>
+    where
+        F: FnOnce() -> Result<CopyStats>,
+    {
+        if let Some(command) = &options.pre_backup_hook {
+            hooks::run_hook(
+                "pre-backup",
+                command,
+                &[("CONSERVE_SOURCE", source_str)],
+                options.hook_failure,
+            )?;
+        }
+        let result = backup();
+        if let Some(command) = &options.post_backup_hook {
+            let status = if result.is_ok() {
+                "succeeded"
+            } else {
+                "failed"
+            };
+            let hook_result = hooks::run_hook(
+                "post-backup",
+                command,
+                &[
+                    ("CONSERVE_SOURCE", source_str),
+                    ("CONSERVE_BACKUP_STATUS", status),
+                ],
+                options.hook_failure,
+            );
+            match (&result, hook_result) {
+                (Ok(_), Err(err)) => return Err(err),
+                // If the backup failed, that's the more interesting error.
+                (Err(_), Err(err)) => log::error!("{}", ErrorChain(&err)),
+                _ => (),
+            }
+        }
+        result
+    }
+
+    fn backup_tree<T: ReadTree>(&self, source: &T, options: &BackupOptions) -> Result<CopyStats> {
+        for tag in &options.tags {
+            crate::band::check_tag(tag)?;
+        }
+        if options.dry_run {
+            self.write_backup(source, options)
+        } else {
+            self.with_mirror(|archive| archive.write_backup(source, options))
+        }
+    }
+
+    /// Back up `source` into a new or resumed band.
+    fn write_backup<T: ReadTree>(&self, source: &T, options: &BackupOptions) -> Result<CopyStats> {
+        let writer = if options.dry_run {
+            BackupWriter::begin_dry_run(self)?
+        } else if options.resume {
+            BackupWriter::resume(self)?
+        } else {
+            BackupWriter::begin(self)?
+        }
+        .with_changed_file_retries(options.changed_file_retries)
+        .with_tags(options.tags.clone())
+        .with_threads(options.threads)?;
+        let after = writer.resume_after().cloned();
         copy_tree(
-            &live_tree,
+            source,
             writer,
             &CopyOptions {
                 print_filenames: options.print_filenames,
                 measure_first: false,
+                after,
+                dry_run: options.dry_run,
+                progress: options.progress.clone(),
                 ..CopyOptions::default()
             },
         )
     }
 
+    /// Store a stream, such as stdin, as a single file called `apath` in a new band.
+    ///
+    /// Returns statistics about what was copied.
+    pub fn backup_stream(&self, apath: &Apath, from: &mut dyn Read) -> Result<CopyStats> {
+        self.with_mirror(|archive| {
+            let mut writer = BackupWriter::begin(archive)?;
+            let mut stats = writer.copy_stream(apath, from)?;
+            stats += writer.finish()?;
+            Ok(stats)
+        })
+    }
+
+    /// Run `backup` on this archive, or if a mirror is configured, on a view
+    /// of it that writes to the mirror as well.
+    ///
+    /// The mirror is first caught up, by syncing, if it's missing bands from
+    /// this archive or the last backup here didn't finish, and it's caught up
+    /// again afterwards if any writes to it failed. If the mirror can't be
+    /// caught up, the problem is reported and the backup is written only to
+    /// this archive; the next backup tries again.
+    ///
+    /// The mirror is locked, as its own archive, while the backup is written
+    /// to it. If another process holds its write lock or garbage collection
+    /// lock, the backup is written only to this archive, and the mirror is
+    /// caught up by a later backup.
+    ///
+    /// Once the mirror is known to have everything, that's recorded in the
+    /// `MIRROR` file, so that the next backup needn't compare the mirror to
+    /// this archive.
+    fn with_mirror<F>(&self, backup: F) -> Result<CopyStats>
+    where
+        F: FnOnce(&Archive) -> Result<CopyStats>,
+    {
+        let location = match &self.config.mirror {
+            Some(location) => location,
+            None => return backup(self),
+        };
+        let mirror = location.parse::<Location>()?.open()?;
+        if !self.catch_up_mirror(location, mirror.as_ref()) {
+            return backup(self);
+        }
+        let _mirror_lock = match lock_mirror(location, mirror.as_ref()) {
+            Some(lock) => lock,
+            None => return backup(self),
+        };
+        let transport = MirrorTransport::new(self.transport.clone(), mirror.clone());
+        let mirrored = Archive {
+            block_dir: self
+                .block_dir
+                .with_transport(transport.sub_transport(BLOCK_DIR)),
+            transport: Box::new(transport.clone()),
+            ..self.clone()
+        };
+        let result = backup(&mirrored);
+        if transport.secondary_failed() {
+            self.catch_up_mirror(location, mirror.as_ref());
+        } else if result.is_ok() {
+            self.record_mirror_current(location);
+        }
+        result
+    }
+
+    /// Sync the mirror at `location` from this archive if it's behind.
+    ///
+    /// Returns true if the mirror is up to date.
+    fn catch_up_mirror(&self, location: &str, mirror: &dyn Transport) -> bool {
+        match self.mirror_is_current(location, mirror) {
+            Ok(true) => return true,
+            Ok(false) => log::info!("Catching up mirror {}", location),
+            Err(err) => log::error!("{}", ErrorChain(&err)),
+        }
+        match sync_archive(self, mirror.box_clone(), &SyncOptions::default()) {
+            Ok(_) => {
+                self.record_mirror_current(location);
+                true
+            }
+            Err(err) => {
+                log::error!("{}", ErrorChain(&err));
+                log::error!(
+                    "Failed to catch up mirror {}; it will be tried again by the next backup",
+                    location
+                );
+                false
+            }
+        }
+    }
+
+    /// True if the mirror has the same complete bands and every block as
+    /// this archive, and the last band here is complete.
+    ///
+    /// If the `MIRROR` file records that the mirror had everything when the
+    /// last band here was written, that's trusted. Otherwise, the bands and
+    /// blocks of this archive and the mirror are compared, and if they match,
+    /// that's recorded.
+    fn mirror_is_current(&self, location: &str, mirror: &dyn Transport) -> Result<bool> {
+        if !mirror.exists(HEADER_FILENAME)? {
+            return Ok(false);
+        }
+        let last_band_id = self.last_band_id()?;
+        if let Some(last_band_id) = &last_band_id {
+            if !self.band_is_closed(last_band_id)? {
+                return Ok(false);
+            }
+        }
+        match self.read_mirror_state() {
+            Ok(Some(state))
+                if state.location == location
+                    && state.last_band == last_band_id.as_ref().map(BandId::to_string) =>
+            {
+                return Ok(true)
+            }
+            Ok(_) => (),
+            Err(err) => log::warn!("{}", ErrorChain(&err)),
+        }
+        let mirror = Archive::open(mirror.box_clone())?;
+        let closed_bands = |archive: &Archive| -> Result<Vec<BandId>> {
+            let mut closed = Vec::new();
+            for band_id in archive.list_band_ids()? {
+                if archive.band_is_closed(&band_id)? {
+                    closed.push(band_id);
+                }
+            }
+            Ok(closed)
+        };
+        if closed_bands(self)? != closed_bands(&mirror)? {
+            return Ok(false);
+        }
+        // Blocks might be missing from the mirror if it was garbage collected
+        // separately, or blocks were deleted from it.
+        let mirror_blocks: HashSet<BlockHash> = mirror.block_dir().block_names()?.collect();
+        let current = self
+            .block_dir()
+            .block_names()?
+            .all(|hash| mirror_blocks.contains(&hash));
+        if current {
+            self.record_mirror_current(location);
+        }
+        Ok(current)
+    }
+
+    fn read_mirror_state(&self) -> Result<Option<MirrorState>> {
+        if !self.transport.exists(MIRROR_STATE_FILENAME)? {
+            return Ok(None);
+        }
+        read_json(&self.transport, MIRROR_STATE_FILENAME).map(Some)
+    }
+
+    /// Record in the `MIRROR` file that the mirror at `location` has every
+    /// band and block in this archive, if the last band here is complete.
+    ///
+    /// Failure to record it is only logged: the next backup compares the
+    /// mirror to this archive instead.
+    fn record_mirror_current(&self, location: &str) {
+        if let Err(err) = self.write_mirror_state(location) {
+            log::warn!("Failed to record the state of mirror {}", location);
+            log::warn!("{}", ErrorChain(&err));
+        }
+    }
+
+    fn write_mirror_state(&self, location: &str) -> Result<()> {
+        let last_band = match self.last_band_id()? {
+            Some(band_id) if !self.band_is_closed(&band_id)? => return Ok(()),
+            last_band_id => last_band_id.map(|band_id| band_id.to_string()),
+        };
+        let state = MirrorState {
+            location: location.to_owned(),
+            last_band,
+        };
+        if self.read_mirror_state().ok().flatten().as_ref() != Some(&state) {
+            write_json(&self.transport, MIRROR_STATE_FILENAME, &state)?;
+        }
+        Ok(())
+    }
+
     /// Restore a selected version, or by default the latest, to a destination directory.
     pub fn restore(&self, destination_path: &Path, options: &RestoreOptions) -> Result<CopyStats> {
         let st = self.open_stored_tree(options.band_selection.clone())?;
+        self.restore_stored_tree(st, destination_path, options)
+    }
+
+    /// Restore a specific band to a destination directory.
+    ///
+    /// `options.band_selection` is ignored. Returns `Error::BandIncomplete` if the
+    /// band was never finished, unless `options.allow_incomplete` is set.
+    pub fn restore_band(
+        &self,
+        band_id: &BandId,
+        destination_path: &Path,
+        options: &RestoreOptions,
+    ) -> Result<CopyStats> {
+        if !self.band_exists(band_id)? {
+            return Err(Error::BandNotFound {
+                band_id: band_id.clone(),
+            });
+        }
+        if !options.allow_incomplete && !Band::open(self, band_id)?.is_complete()? {
+            return Err(Error::BandIncomplete {
+                band_id: band_id.clone(),
+            });
+        }
+        let st = StoredTree::open(self, band_id)?;
+        self.restore_stored_tree(st, destination_path, options)
+    }
+
+    fn restore_stored_tree(
+        &self,
+        st: StoredTree,
+        destination_path: &Path,
+        options: &RestoreOptions,
+    ) -> Result<CopyStats> {
         let st = st.with_excludes(options.excludes.clone());
-        let rt = if options.overwrite {
-            RestoreTree::create_overwrite(destination_path)
+        let band_id = st.band().id().clone();
+        let (resume_after, overwrite) = if options.resume {
+            // The interrupted restore left files in the destination.
+            let overwrite = match options.overwrite {
+                OverwritePolicy::Refuse => OverwritePolicy::Force,
+                other => other,
+            };
+            (
+                restore::resume_after(destination_path, &band_id)?,
+                overwrite,
+            )
         } else {
+            (None, options.overwrite)
+        };
+        let rt = if options.dry_run {
+            RestoreTree::create_dry_run(destination_path, overwrite)
+        } else if overwrite == OverwritePolicy::Refuse {
             RestoreTree::create(destination_path)
-        }?;
+        } else {
+            RestoreTree::create_overwrite(destination_path).map(|rt| rt.with_overwrite(overwrite))
+        }?
+        .with_permissions(options.restore_permissions)
+        .with_ownership(options.ownership)
+        .with_threads(options.threads)?
+        .with_resume_after(resume_after)
+        .with_restore_state(&band_id)?;
+        // The destination is checked when the RestoreTree is created, before
+        // anything is deleted.
+        let deleted = if options.delete {
+            restore::delete_extraneous(&st, destination_path, options)?
+        } else {
+            0
+        };
         let opts = CopyOptions {
-            print_filenames: options.print_filenames,
+            // A dry run lists what it would do to each entry instead.
+            print_filenames: options.print_filenames && !options.dry_run,
             only_subtree: options.only_subtree.clone(),
+            progress: options.progress.clone(),
             ..CopyOptions::default()
         };
-        copy_tree(&st, rt, &opts)
+        Ok(CopyStats {
+            deleted,
+            ..copy_tree(&st, rt, &opts)?
+        })
     }
 
     pub fn block_dir(&self) -> &BlockDir {
@@ -199,6 +805,41 @@ impl Archive {
         StoredTree::open(self, &self.resolve_band_id(band_selection)?)
     }
 
+    /// Find a band by a name given by the user: either a band id, or a tag.
+    ///
+    /// If several bands have the tag, the latest is returned.
+    pub fn find_band(&self, name: &str) -> Result<BandId> {
+        if let Ok(band_id) = name.parse::<BandId>() {
+            return Ok(band_id);
+        }
+        for band_id in self.list_band_ids()?.into_iter().rev() {
+            if Band::open(self, &band_id)?
+                .tags()?
+                .iter()
+                .any(|t| t == name)
+            {
+                return Ok(band_id);
+            }
+        }
+        Err(Error::NoSuchBandOrTag {
+            name: name.to_owned(),
+        })
+    }
+
+    /// Add tags naming a closed band.
+    pub fn add_tags(&self, band_id: &BandId, tags: &[String]) -> Result<()> {
+        self.check_append_only()?;
+        let _lock = WriteLock::acquire(self, "tag")?;
+        Band::open(self, band_id)?.add_tags(tags)
+    }
+
+    /// Remove tags from a closed band.
+    pub fn remove_tags(&self, band_id: &BandId, tags: &[String]) -> Result<()> {
+        self.check_append_only()?;
+        let _lock = WriteLock::acquire(self, "tag")?;
+        Band::open(self, band_id)?.remove_tags(tags)
+    }
+
     /// Return an iterator of valid band ids in this archive, in arbitrary order.
     ///
     /// Errors reading the archive directory are logged and discarded.
@@ -225,30 +866,92 @@ impl Archive {
     pub fn last_complete_band(&self) -> Result<Option<Band>> {
         for id in self.list_band_ids()?.iter().rev() {
             let b = Band::open(self, &id)?;
-            if b.is_closed()? {
+            if b.is_complete()? {
                 return Ok(Some(b));
             }
         }
         Ok(None)
     }
 
-    /// Returns all blocks referenced by all bands.
+    /// Summarize the versions, blocks, and format of the archive.
     ///
-    /// Shows a progress bar as they're collected.
+    /// This reads every index and lists every block, so it may take a while
+    /// on a large archive. Progress is sent to `progress`, if it's given.
+    pub fn info(&self, progress: Option<&Arc<dyn ProgressSink>>) -> Result<ArchiveInfo> {
+        let mut complete_versions = 0;
+        let mut incomplete_versions = 0;
+        let mut oldest_backup: Option<DateTime<Utc>> = None;
+        let mut newest_backup: Option<DateTime<Utc>> = None;
+        let mut deduplicated_bytes = 0;
+        let mut seen_addrs = HashSet::new();
+        let mut progress_bar = ProgressBar::new(progress);
+        progress_bar.set_phase("Read indexes...".to_owned());
+        let band_ids = self.list_band_ids()?;
+        for (i, band_id) in band_ids.iter().enumerate() {
+            progress_bar.set_fraction(i, band_ids.len());
+            let band = Band::open(self, band_id)?;
+            let info = band.get_info()?;
+            if info.is_closed && !info.is_partial {
+                complete_versions += 1;
+            } else {
+                incomplete_versions += 1;
+            }
+            if oldest_backup.map_or(true, |t| info.start_time < t) {
+                oldest_backup = Some(info.start_time);
+            }
+            if newest_backup.map_or(true, |t| info.start_time > t) {
+                newest_backup = Some(info.start_time);
+            }
+            for addr in band.iter_entries()?.flat_map(|entry| entry.addrs) {
+                if seen_addrs.insert((addr.hash, addr.start, addr.len)) {
+                    deduplicated_bytes += addr.len;
+                }
+            }
+        }
+        drop(progress_bar);
+        let mut block_count = 0;
+        let mut compressed_bytes = 0;
+        for hash in self.iter_present_blocks(progress)? {
+            block_count += 1;
+            compressed_bytes += self.block_dir.compressed_size(&hash)?;
+        }
+        Ok(ArchiveInfo {
+            archive_version: ARCHIVE_VERSION.to_owned(),
+            features: self.features.clone(),
+            append_only: self.config.append_only,
+            complete_versions,
+            incomplete_versions,
+            oldest_backup,
+            newest_backup,
+            block_count,
+            compressed_bytes,
+            deduplicated_bytes,
+        })
+    }
+
+    /// Returns all blocks referenced by all bands.
     pub fn referenced_blocks(&self) -> Result<BTreeSet<BlockHash>> {
-        self.iter_referenced_blocks().map(Iterator::collect)
+        self.iter_referenced_blocks(&[], None)
+            .map(Iterator::collect)
     }
 
     /// Iterate all blocks referenced by all bands.
     ///
     /// The iterator returns repeatedly-referenced blocks repeatedly, without deduplicating.
     ///
-    /// This shows a progress bar as indexes are iterated.
-    fn iter_referenced_blocks(&self) -> Result<impl Iterator<Item = BlockHash>> {
+    /// Bands in `excluded_bands` are skipped, as if they were already deleted.
+    ///
+    /// Progress is sent to `progress` as indexes are iterated.
+    fn iter_referenced_blocks(
+        &self,
+        excluded_bands: &[BandId],
+        progress: Option<&Arc<dyn ProgressSink>>,
+    ) -> Result<impl Iterator<Item = BlockHash>> {
         let archive = self.clone();
-        let mut progress_bar = ProgressBar::new();
+        let mut progress_bar = ProgressBar::new(progress);
         progress_bar.set_phase("Find referenced blocks...".to_owned());
-        let band_ids = self.list_band_ids()?;
+        let mut band_ids = self.list_band_ids()?;
+        band_ids.retain(|band_id| !excluded_bands.contains(band_id));
         let num_bands = band_ids.len();
         Ok(band_ids
             .into_iter()
@@ -264,12 +967,15 @@ impl Archive {
     pub fn unreferenced_blocks(&self) -> Result<impl Iterator<Item = BlockHash>> {
         let referenced = self.referenced_blocks()?;
         Ok(self
-            .iter_present_blocks()?
+            .iter_present_blocks(None)?
             .filter(move |hash| !referenced.contains(hash)))
     }
 
-    fn iter_present_blocks(&self) -> Result<impl Iterator<Item = BlockHash>> {
-        let mut progress_bar = ProgressBar::new();
+    fn iter_present_blocks(
+        &self,
+        progress: Option<&Arc<dyn ProgressSink>>,
+    ) -> Result<impl Iterator<Item = BlockHash>> {
+        let mut progress_bar = ProgressBar::new(progress);
         progress_bar.set_phase("Find present blocks...".to_owned());
         Ok(self
             .block_dir()
@@ -277,8 +983,48 @@ impl Archive {
             .inspect(move |_| progress_bar.increment_work_done(1)))
     }
 
+    /// Returns true if the archive is locked by a writer, or by one that was
+    /// interrupted and left its lock behind.
+    pub fn is_locked(&self) -> Result<bool> {
+        WriteLock::is_locked(self)
+    }
+
+    /// Remove any lock on the archive, without taking a new one.
+    ///
+    /// Use this only if you're confident that the process owning the lock
+    /// has terminated and the lock is stale.
+    pub fn remove_lock(&self) -> Result<()> {
+        WriteLock::remove(self)
+    }
+
+    /// Take the lock that excludes other writers, breaking any existing lock
+    /// if `break_lock` is set.
+    fn write_lock(&self, operation: &str, break_lock: bool) -> Result<WriteLock> {
+        if break_lock {
+            WriteLock::break_lock(self, operation)
+        } else {
+            WriteLock::acquire(self, operation)
+        }
+    }
+
     /// Delete unreferenced blocks.
     pub fn delete_unreferenced(&self, options: &DeleteOptions) -> Result<DeleteStats> {
+        if !options.dry_run {
+            self.check_append_only()?;
+        }
+        let _lock = self.write_lock("gc", options.break_lock)?;
+        self.delete_unreferenced_locked(options, &[])
+    }
+
+    /// Delete unreferenced blocks, while the caller holds the write lock.
+    ///
+    /// Blocks referenced only by `excluded_bands` are treated as unreferenced:
+    /// this lets a dry run of deleting bands show what would be reclaimed.
+    fn delete_unreferenced_locked(
+        &self,
+        options: &DeleteOptions,
+        excluded_bands: &[BandId],
+    ) -> Result<DeleteStats> {
         let block_dir = self.block_dir();
         let mut stats = DeleteStats::default();
         let delete_guard = if options.break_lock {
@@ -287,8 +1033,10 @@ impl Archive {
             gc_lock::GarbageCollectionLock::new(self)?
         };
 
-        let mut blocks: BTreeSet<BlockHash> = self.iter_present_blocks()?.collect();
-        for block_hash in self.iter_referenced_blocks()? {
+        let mut blocks: BTreeSet<BlockHash> = self
+            .iter_present_blocks(options.progress.as_ref())?
+            .collect();
+        for block_hash in self.iter_referenced_blocks(excluded_bands, options.progress.as_ref())? {
             // NOTE: We could potentially notice here blocks that are missing: referenced but
             // not present. However, because the reference iter can contain duplicates,
             // it would require keeping another set. On the whole that seems better left
@@ -297,7 +1045,7 @@ impl Archive {
         }
         stats.unreferenced_block_count = blocks.len();
 
-        let mut progress_bar = ProgressBar::new();
+        let mut progress_bar = ProgressBar::new(options.progress.as_ref());
         progress_bar.set_phase("Measure unreferenced blocks".to_owned());
         progress_bar.set_total_work(blocks.len());
         let progress_bar_mutex = Mutex::new(progress_bar);
@@ -311,7 +1059,7 @@ impl Archive {
         delete_guard.check()?;
 
         if !blocks.is_empty() && !options.dry_run {
-            let mut progress_bar = ProgressBar::new();
+            let mut progress_bar = ProgressBar::new(options.progress.as_ref());
             progress_bar.set_phase("Deleting unreferenced blocks".to_owned());
             progress_bar.set_total_work(blocks.len());
             let progress_bar_mutex = Mutex::new(progress_bar);
@@ -327,12 +1075,26 @@ impl Archive {
         Ok(stats)
     }
 
-    /// Delete bands, and the blocks that they referenec.
+    /// Delete one band, and the blocks that only it referenced.
+    pub fn delete_band(&self, band_id: &BandId, options: &DeleteOptions) -> Result<DeleteStats> {
+        self.delete_bands(std::slice::from_ref(band_id), options)
+    }
+
+    /// Delete bands, and the blocks that only they referenced.
+    ///
+    /// With `options.dry_run`, nothing is deleted, but the stats count the
+    /// blocks that would become unreferenced. With `options.no_gc`, blocks
+    /// are left for a later gc.
     pub fn delete_bands(
         &self,
         band_ids: &[BandId],
         options: &DeleteOptions,
     ) -> Result<DeleteStats> {
+        if !options.dry_run {
+            self.check_append_only()?;
+        }
+        let _lock = self.write_lock("delete", options.break_lock)?;
+        self.check_delta_parents_kept(band_ids)?;
         let mut stats = DeleteStats::default();
         for band_id in band_ids {
             if !options.dry_run {
@@ -340,21 +1102,149 @@ impl Archive {
             }
         }
         if !options.no_gc {
-            stats += self.delete_unreferenced(options)?;
+            stats += self.delete_unreferenced_locked(options, band_ids)?;
         }
         Ok(stats)
     }
 
-    pub fn validate(&self) -> Result<ValidateStats> {
+    /// Check that no band that will remain after deleting `band_ids` has a
+    /// delta index from one of them.
+    fn check_delta_parents_kept(&self, band_ids: &[BandId]) -> Result<()> {
+        for child in self.list_band_ids()? {
+            if band_ids.contains(&child) {
+                continue;
+            }
+            // Bands that can't be read don't stop others being deleted.
+            let parent = Band::open(self, &child).and_then(|band| band.delta_parent());
+            if let Ok(Some(band_id)) = parent {
+                if band_ids.contains(&band_id) {
+                    return Err(Error::DeltaParentInUse { band_id, child });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Delete complete backups that aren't kept by `policy`, and the blocks
+    /// that only they referenced.
+    ///
+    /// Backups that are the delta parent of a kept or incomplete backup are
+    /// also kept.
+    ///
+    /// Incomplete and partial backups are neither counted nor deleted.
+    /// Returns the decision for each complete backup, and the deletion stats.
+    /// With `options.dry_run`, nothing is deleted.
+    pub fn prune(
+        &self,
+        policy: &RetentionPolicy,
+        options: &DeleteOptions,
+    ) -> Result<(Vec<BandRetention>, DeleteStats)> {
+        if policy.keeps_nothing() {
+            return Err(Error::RetentionPolicyKeepsNothing);
+        }
+        let mut bands = Vec::new();
+        for band_id in self.list_band_ids()? {
+            let band = Band::open(self, &band_id)?;
+            if band.is_complete()? {
+                let start_time = band.get_info()?.start_time;
+                bands.push((band_id, start_time.with_timezone(&Local).naive_local()));
+            }
+        }
+        let mut retention = prune::apply_retention_policy(&bands, policy);
+        // Newer bands come first, so that whole chains of deltas are kept.
+        for band_id in self.list_band_ids()?.iter().rev() {
+            if retention
+                .iter()
+                .any(|r| r.band_id == *band_id && !r.is_kept())
+            {
+                continue;
+            }
+            if let Some(parent) = Band::open(self, band_id)?.delta_parent()? {
+                if let Some(r) = retention
+                    .iter_mut()
+                    .find(|r| r.band_id == parent && !r.is_kept())
+                {
+                    r.keep_reasons.push("delta parent");
+                }
+            }
+        }
+        let removed: Vec<BandId> = retention
+            .iter()
+            .filter(|r| !r.is_kept())
+            .map(|r| r.band_id.clone())
+            .collect();
+        let stats = if removed.is_empty() {
+            DeleteStats::default()
+        } else {
+            self.delete_bands(&removed, options)?
+        };
+        Ok((retention, stats))
+    }
+
+    /// Find bands that were never closed, typically because a backup was
+    /// interrupted, and then seal or delete them according to `action`.
+    ///
+    /// Returns a description of each incomplete band, including the range of
+    /// apaths it covered.
+    ///
+    /// Sealing or deleting takes the write lock, so fails while a backup is
+    /// running.
+    pub fn fix_incomplete_bands(
+        &self,
+        action: IncompleteBandAction,
+    ) -> Result<Vec<IncompleteBand>> {
+        // A band that's still being written is incomplete, but the backup
+        // writing it holds the lock.
+        if action == IncompleteBandAction::Delete {
+            self.check_append_only()?;
+        }
+        let _lock = if action == IncompleteBandAction::Report {
+            None
+        } else if gc_lock::GarbageCollectionLock::is_locked(self)? {
+            return Err(Error::GarbageCollectionLockHeld);
+        } else {
+            Some(WriteLock::acquire(self, "incomplete")?)
+        };
+        let mut incomplete = Vec::new();
+        for band_id in self.list_band_ids()? {
+            let band = Band::open(self, &band_id)?;
+            if band.is_closed()? {
+                continue;
+            }
+            let mut found = IncompleteBand {
+                band_id: band_id.clone(),
+                entry_count: 0,
+                first_apath: None,
+                last_apath: None,
+            };
+            for entry in band.iter_entries()? {
+                if found.first_apath.is_none() {
+                    found.first_apath = Some(entry.apath.clone());
+                }
+                found.entry_count += 1;
+                found.last_apath = Some(entry.apath);
+            }
+            match action {
+                IncompleteBandAction::Report => (),
+                IncompleteBandAction::Seal => band.close_partial()?,
+                IncompleteBandAction::Delete => Band::delete(self, &band_id)?,
+            }
+            incomplete.push(found);
+        }
+        Ok(incomplete)
+    }
+
+    pub fn validate(&self, options: &ValidateOptions) -> Result<ValidateStats> {
         let mut stats = self.validate_archive_dir()?;
-        ui::println("Check blockdir...");
-        let block_lengths: HashMap<BlockHash, usize> = self.block_dir.validate(&mut stats)?;
+        log::info!("Check blockdir...");
+        let block_lengths: HashMap<BlockHash, Option<usize>> =
+            self.block_dir.validate(&mut stats, options)?;
 
-        ui::println("Check indexes...");
+        log::info!("Check indexes...");
         let band_ids = self.list_band_ids()?;
         let num_bands = band_ids.len();
 
-        let mut progress_bar = ProgressBar::new();
+        let mut progress_bar = ProgressBar::new(options.progress.as_ref());
         progress_bar.set_phase("Check index".to_owned());
         progress_bar.set_total_work(num_bands);
         let progress_bar_mutex = Mutex::new(progress_bar);
@@ -368,6 +1258,14 @@ impl Archive {
                     if b.validate(&mut stats).is_err() {
                         stats.band_metadata_problems += 1;
                     }
+                    match b.delta_parent() {
+                        Ok(Some(parent)) if !self.band_exists(&parent).unwrap_or(false) => {
+                            log::error!("Delta parent {} of band {} is missing", parent, band_id);
+                            stats.band_metadata_problems += 1;
+                        }
+                        Ok(_) => (),
+                        Err(_) => stats.band_metadata_problems += 1,
+                    }
                 } else {
                     stats.band_open_errors += 1;
                 }
@@ -393,7 +1291,7 @@ impl Archive {
     fn validate_archive_dir(&self) -> Result<ValidateStats> {
         // TODO: Tests for the problems detected here.
         let mut stats = ValidateStats::default();
-        ui::println("Check archive top-level directory...");
+        log::info!("Check archive top-level directory...");
 
         let mut files: Vec<String> = Vec::new();
         let mut dirs: Vec<String> = Vec::new();
@@ -407,26 +1305,32 @@ impl Archive {
                     Kind::Dir => dirs.push(name),
                     Kind::File => files.push(name),
                     other_kind => {
-                        ui::problem(&format!(
+                        log::error!(
                             "Unexpected file kind in archive directory: {:?} of kind {:?}",
-                            name, other_kind
-                        ));
+                            name,
+                            other_kind
+                        );
                         stats.unexpected_files += 1;
                     }
                 },
                 Err(source) => {
-                    ui::problem(&format!("Error listing archive directory: {:?}", source));
+                    log::error!("Error listing archive directory: {:?}", source);
                     stats.io_errors += 1;
                 }
             }
         }
         remove_item(&mut files, &HEADER_FILENAME);
+        remove_item(&mut files, &write_lock::LOCK_FILENAME);
+        remove_item(&mut files, &write_lock::BREAK_LOCK_FILENAME);
+        remove_item(&mut files, &CONFIG_FILENAME);
+        remove_item(&mut files, &MIRROR_STATE_FILENAME);
         if !files.is_empty() {
             stats.unexpected_files += 1;
-            ui::problem(&format!(
+            log::error!(
                 "Unexpected files in archive directory {:?}: {:?}",
-                self.transport, files
-            ));
+                self.transport,
+                files
+            );
         }
         remove_item(&mut dirs, &BLOCK_DIR);
         dirs.sort();
@@ -435,19 +1339,13 @@ impl Archive {
             if let Ok(b) = d.parse() {
                 if bs.contains(&b) {
                     stats.structure_problems += 1;
-                    ui::problem(&format!(
-                        "Duplicated band directory in {:?}: {:?}",
-                        self.transport, d
-                    ));
+                    log::error!("Duplicated band directory in {:?}: {:?}", self.transport, d);
                 } else {
                     bs.insert(b);
                 }
             } else {
                 stats.structure_problems += 1;
-                ui::problem(&format!(
-                    "Unexpected directory in {:?}: {:?}",
-                    self.transport, d
-                ));
+                log::error!("Unexpected directory in {:?}: {:?}", self.transport, d);
             }
         }
         Ok(stats)
@@ -465,6 +1363,47 @@ impl Archive {
     }
 }
 
+/// Read the archive's configuration, or the defaults if it has none.
+///
+/// If the configuration is invalid, this reports a problem and uses the
+/// defaults, so that the archive can still be read.
+fn read_config<TR: AsRef<dyn Transport>>(transport: &TR) -> Result<ArchiveConfig> {
+    if !transport.as_ref().exists(CONFIG_FILENAME)? {
+        return Ok(ArchiveConfig::default());
+    }
+    let config: ArchiveConfig = read_json(transport, CONFIG_FILENAME)?;
+    if let Err(err) = config.check() {
+        log::error!("{}", ErrorChain(&err));
+        return Ok(ArchiveConfig::default());
+    }
+    Ok(config)
+}
+
+/// Lock the mirror at `location` against other writers, while a backup is
+/// written to it.
+///
+/// Returns None, after logging why, if it's locked by another process or
+/// can't be locked.
+fn lock_mirror(location: &str, mirror: &dyn Transport) -> Option<WriteLock> {
+    let result = Archive::open(mirror.box_clone()).and_then(|mirror| {
+        if gc_lock::GarbageCollectionLock::is_locked(&mirror)? {
+            return Err(Error::GarbageCollectionLockHeld);
+        }
+        WriteLock::acquire(&mirror, "backup")
+    });
+    match result {
+        Ok(lock) => Some(lock),
+        Err(err) => {
+            log::warn!("{}", ErrorChain(&err));
+            log::warn!(
+                "Not writing to mirror {}; it will be caught up by the next backup",
+                location
+            );
+            None
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::fs;
@@ -507,7 +1446,7 @@ mod tests {
         temp.close().unwrap();
     }
 
-    /// A new archive contains just one header file.
+    /// A new archive contains just a header file and an empty config.
     /// The header is readable json containing only a version number.
     #[test]
     fn empty_archive() {
@@ -516,6 +1455,10 @@ mod tests {
         assert!(af.path().is_dir());
         assert!(af.path().join("CONSERVE").is_file());
         assert!(af.path().join("d").is_dir());
+        assert_eq!(
+            fs::read_to_string(af.path().join("CONFIG")).unwrap(),
+            "{}\n"
+        );
 
         let header_path = af.path().join("CONSERVE");
         let mut header_file = fs::File::open(&header_path).unwrap();
@@ -535,6 +1478,22 @@ mod tests {
         assert_eq!(af.block_dir.block_names().unwrap().count(), 0);
     }
 
+    #[test]
+    fn unsupported_features_are_refused() {
+        let af = ScratchArchive::new();
+        fs::write(
+            af.path().join("CONSERVE"),
+            "{\"conserve_archive_version\":\"0.6\",\"features\":[\"zstd_blocks\",\"teleport\"]}\n",
+        )
+        .unwrap();
+        match Archive::open_path(af.path()) {
+            Err(Error::UnsupportedArchiveFeatures { features }) => {
+                assert_eq!(features, ["teleport"])
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
     #[test]
     fn create_bands() {
         let af = ScratchArchive::new();
@@ -14,13 +14,32 @@
 //! Make a backup by walking a source directory and copying the contents
 //! into an archive.
 
+use std::cmp::Ordering;
+use std::collections::{HashMap, VecDeque};
+use std::io::Read;
+use std::iter::{Flatten, Peekable};
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::SystemTime;
+
 use globset::GlobSet;
 
-use crate::blockdir::StoreFiles;
-use crate::index::IndexEntryIter;
-use crate::stats::CopyStats;
+use crate::blockdir::{Address, HashingReader, StoreFiles};
+use crate::delta::DeltaFilter;
+use crate::errors::ErrorChain;
+use crate::stats::{CopyStats, IndexBuilderStats};
+use crate::stitch::IterStitchedIndexHunks;
+use crate::unix_time::UnixTime;
+use crate::workers::WorkerPool;
 use crate::*;
 
+/// By default, how many more times to read a file that changed while it was read.
+pub const DEFAULT_CHANGED_FILE_RETRIES: usize = 2;
+
+/// When delta indexes are configured, a full index is written after this many
+/// delta bands in a row, so that reading a band never merges too many indexes.
+pub const MAX_DELTA_CHAIN: usize = 20;
+
 /// Configuration of how to make a backup.
 #[derive(Debug)]
 pub struct BackupOptions {
@@ -29,6 +48,48 @@ pub struct BackupOptions {
 
     /// Exclude these globs from the backup.
     pub excludes: GlobSet,
+
+    /// Exclude directories marked as caches by a `CACHEDIR.TAG` file.
+    pub exclude_caches: bool,
+
+    /// Exclude directories that contain a file with any of these names.
+    pub exclude_if_present: Vec<String>,
+
+    /// Continue writing the last band, if it was left incomplete by an interrupted backup,
+    /// rather than starting a new band.
+    pub resume: bool,
+
+    /// Walk the source and report what would be written, without creating a band
+    /// or storing any blocks.
+    pub dry_run: bool,
+
+    /// Number of files to read and store concurrently.
+    ///
+    /// Index entries are still written in apath order. 1 stores each file in turn
+    /// on the calling thread.
+    pub threads: usize,
+
+    /// Don't descend into directories on a different filesystem from the source root.
+    pub one_file_system: bool,
+
+    /// A shell command to run before reading the source tree.
+    pub pre_backup_hook: Option<String>,
+
+    /// A shell command to run after the backup finishes or fails.
+    pub post_backup_hook: Option<String>,
+
+    /// What to do if a hook fails.
+    pub hook_failure: HookFailurePolicy,
+
+    /// How many more times to read a file that changed while it was being read,
+    /// before storing it and marking it as changed.
+    pub changed_file_retries: usize,
+
+    /// Tags naming the new band.
+    pub tags: Vec<String>,
+
+    /// Receives progress of the operation, if set.
+    pub progress: Option<Arc<dyn ProgressSink>>,
 }
 
 impl Default for BackupOptions {
@@ -36,19 +97,120 @@ impl Default for BackupOptions {
         BackupOptions {
             print_filenames: false,
             excludes: GlobSet::empty(),
+            exclude_caches: false,
+            exclude_if_present: Vec::new(),
+            resume: false,
+            dry_run: false,
+            threads: 1,
+            one_file_system: false,
+            pre_backup_hook: None,
+            post_backup_hook: None,
+            hook_failure: HookFailurePolicy::default(),
+            changed_file_retries: DEFAULT_CHANGED_FILE_RETRIES,
+            tags: Vec::new(),
+            progress: None,
+        }
+    }
+}
+
+impl BackupOptions {
+    /// Return options that exclude entries matching `excludes`.
+    ///
+    /// This replaces any previous exclusions.
+    pub fn with_excludes(self, excludes: GlobSet) -> BackupOptions {
+        BackupOptions { excludes, ..self }
+    }
+
+    /// Return options that, if `exclude_caches` is true, skip directories
+    /// marked as caches by a `CACHEDIR.TAG` file.
+    pub fn with_exclude_caches(self, exclude_caches: bool) -> BackupOptions {
+        BackupOptions {
+            exclude_caches,
+            ..self
+        }
+    }
+
+    /// Return options that, if `one_file_system` is true, don't descend into
+    /// directories on a different filesystem from the source root.
+    pub fn with_one_file_system(self, one_file_system: bool) -> BackupOptions {
+        BackupOptions {
+            one_file_system,
+            ..self
+        }
+    }
+
+    /// Return options that, if `dry_run` is true, only count what would be
+    /// stored, without writing anything to the archive.
+    pub fn with_dry_run(self, dry_run: bool) -> BackupOptions {
+        BackupOptions { dry_run, ..self }
+    }
+
+    /// Return options that store up to `threads` files concurrently.
+    pub fn with_threads(self, threads: usize) -> BackupOptions {
+        BackupOptions { threads, ..self }
+    }
+
+    /// Return options that, if `resume` is true, continue an interrupted backup
+    /// rather than starting a new band.
+    pub fn with_resume(self, resume: bool) -> BackupOptions {
+        BackupOptions { resume, ..self }
+    }
+
+    /// Return options that tag the new band with `tags`.
+    pub fn with_tags(self, tags: Vec<String>) -> BackupOptions {
+        BackupOptions { tags, ..self }
+    }
+
+    /// Return options that send progress to `progress`.
+    pub fn with_progress(self, progress: Arc<dyn ProgressSink>) -> BackupOptions {
+        BackupOptions {
+            progress: Some(progress),
+            ..self
         }
     }
 }
 
 /// Accepts files to write in the archive (in apath order.)
 pub struct BackupWriter {
-    band: Band,
-    index_builder: IndexBuilder,
+    /// The band being written, or None in a dry run.
+    band: Option<Band>,
+    /// Builds the index of `band`, or None in a dry run.
+    index_builder: Option<IndexBuilder>,
     store_files: StoreFiles,
 
-    /// The index for the last stored band, used as hints for whether newly
+    /// The tree of the last complete band, used as hints for whether newly
     /// stored files have changed.
-    basis_index: Option<IndexEntryIter>,
+    basis_index: Option<BasisIndex>,
+
+    /// Number of index hunks already present in a resumed band.
+    resumed_hunks: u32,
+
+    /// When resuming, the last apath already stored in the band.
+    resume_after: Option<Apath>,
+
+    /// Addresses of the content of the first file in each hardlink group, which
+    /// are reused by the other files in the group.
+    hardlink_addrs: HashMap<Apath, (Vec<Address>, Option<BlockHash>)>,
+
+    /// How many more times to read a file that changed while it was being read.
+    changed_file_retries: usize,
+
+    /// Stores files on worker threads, if more than one thread is used.
+    parallel: Option<ParallelStore>,
+
+    /// Tags to write in the band tail when it's closed.
+    tags: Vec<String>,
+
+    /// Total length of the files written to the index, including those
+    /// already in a resumed band.
+    tree_bytes: u64,
+
+    /// If the band has a delta index, reduces entries to the changes from its
+    /// parent band.
+    delta: Option<DeltaFilter>,
+
+    /// Excludes other writers until the backup is finished, or None in a dry run.
+    _lock: Option<WriteLock>,
 }
 
 impl BackupWriter {
@@ -59,38 +221,334 @@ impl BackupWriter {
         if gc_lock::GarbageCollectionLock::is_locked(archive)? {
             return Err(Error::GarbageCollectionLockHeld);
         }
-        let basis_index = archive
-            .last_complete_band()?
-            .map(|b| b.iter_entries())
-            .transpose()?;
+        let lock = WriteLock::acquire(archive, "backup")?;
+        let basis_band = archive.last_complete_band()?;
+        let basis_index = iter_basis(archive, basis_band.as_ref());
+        let delta_parent = match basis_band {
+            Some(basis_band) if archive.writes_delta_index() => {
+                if delta_chain_length(archive, basis_band.id())? < MAX_DELTA_CHAIN {
+                    Some(basis_band.id().clone())
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
         // Create the new band only after finding the basis band!
-        let band = Band::create(archive)?;
-        let index_builder = band.index_builder();
+        let band = match &delta_parent {
+            Some(parent) => Band::create_delta(archive, parent)?,
+            None => Band::create(archive)?,
+        };
+        let delta = delta_parent.map(|parent| DeltaFilter::new(archive, &parent, None));
+        let index_builder = band.index_builder().with_compression(
+            archive.index_compression(),
+            archive.config().compression_level(),
+        );
+        Ok(BackupWriter {
+            band: Some(band),
+            index_builder: Some(index_builder),
+            store_files: StoreFiles::new(archive.block_dir().clone()),
+            basis_index,
+            resumed_hunks: 0,
+            resume_after: None,
+            hardlink_addrs: HashMap::new(),
+            changed_file_retries: DEFAULT_CHANGED_FILE_RETRIES,
+            parallel: None,
+            tags: Vec::new(),
+            tree_bytes: 0,
+            delta,
+            _lock: Some(lock),
+        })
+    }
+
+    /// Create a BackupWriter that only measures what a backup would write.
+    ///
+    /// No band is created, and files should be passed to `measure_file` rather
+    /// than `copy_file`, which is what `copy_tree` does when `dry_run` is set.
+    pub fn begin_dry_run(archive: &Archive) -> Result<BackupWriter> {
+        let basis_index = iter_basis(archive, archive.last_complete_band()?.as_ref());
+        Ok(BackupWriter {
+            band: None,
+            index_builder: None,
+            store_files: StoreFiles::new(archive.block_dir().clone()),
+            basis_index,
+            resumed_hunks: 0,
+            resume_after: None,
+            hardlink_addrs: HashMap::new(),
+            changed_file_retries: DEFAULT_CHANGED_FILE_RETRIES,
+            parallel: None,
+            tags: Vec::new(),
+            tree_bytes: 0,
+            delta: None,
+            _lock: None,
+        })
+    }
+
+    /// Continue writing the last band in the archive, which must be incomplete.
+    ///
+    /// Entries already written to the band's index are kept, and new entries must
+    /// be ordered after the last of them: see `resume_after`. Blocks that were
+    /// already stored are reused through the usual deduplication.
+    pub fn resume(archive: &Archive) -> Result<BackupWriter> {
+        if gc_lock::GarbageCollectionLock::is_locked(archive)? {
+            return Err(Error::GarbageCollectionLockHeld);
+        }
+        let lock = WriteLock::acquire(archive, "backup")?;
+        let band_id = archive.last_band_id()?.ok_or(Error::ArchiveEmpty)?;
+        if archive.band_is_closed(&band_id)? {
+            return Err(Error::NothingToResume { band_id });
+        }
+        let basis_index = iter_basis(archive, archive.last_complete_band()?.as_ref());
+        let band = Band::open(archive, &band_id)?;
+        let index = band.index();
+        let resumed_hunks = index.count_hunks()?;
+        let resume_after = index.last_entry()?.map(|entry| entry.apath);
+        let index_builder = band
+            .resume_index_builder(resumed_hunks, resume_after.as_ref())
+            .with_compression(
+                archive.index_compression(),
+                archive.config().compression_level(),
+            );
+        let delta = band
+            .delta_parent()?
+            .map(|parent| DeltaFilter::new(archive, &parent, resume_after.as_ref()));
+        // The stitched index includes unchanged entries from a delta parent, and
+        // entries after those already written, from earlier bands.
+        let tree_bytes = match &resume_after {
+            Some(resume_after) => archive
+                .iter_stitched_index_hunks(&band_id)
+                .flatten()
+                .take_while(|entry| entry.apath <= *resume_after)
+                .filter(|entry| entry.kind() == Kind::File)
+                .filter_map(|entry| entry.size())
+                .sum(),
+            None => 0,
+        };
         Ok(BackupWriter {
-            band,
-            index_builder,
+            band: Some(band),
+            index_builder: Some(index_builder),
             store_files: StoreFiles::new(archive.block_dir().clone()),
             basis_index,
+            resumed_hunks,
+            resume_after,
+            hardlink_addrs: HashMap::new(),
+            changed_file_retries: DEFAULT_CHANGED_FILE_RETRIES,
+            parallel: None,
+            tags: Vec::new(),
+            tree_bytes,
+            delta,
+            _lock: Some(lock),
         })
     }
 
+    /// Return a BackupWriter that reads and stores up to `threads` files
+    /// concurrently.
+    ///
+    /// Entries are still written to the index in apath order: entries following a
+    /// file that's still being stored wait for it to finish.
+    ///
+    /// This has no effect on a dry run, where nothing is stored.
+    pub fn with_threads(self, threads: usize) -> Result<BackupWriter> {
+        let parallel = if threads > 1 && self.index_builder.is_some() {
+            Some(ParallelStore::new(
+                self.store_files.block_dir().clone(),
+                threads,
+            )?)
+        } else {
+            None
+        };
+        Ok(BackupWriter { parallel, ..self })
+    }
+
+    /// Return a BackupWriter that reads a file up to `retries` more times if it
+    /// changes while it's being read.
+    pub fn with_changed_file_retries(self, retries: usize) -> BackupWriter {
+        BackupWriter {
+            changed_file_retries: retries,
+            ..self
+        }
+    }
+
+    /// Return a BackupWriter that tags the band when it's closed.
+    pub fn with_tags(self, tags: Vec<String>) -> BackupWriter {
+        BackupWriter { tags, ..self }
+    }
+
+    /// The last apath already present in a resumed band: only entries after this
+    /// should be written.
+    ///
+    /// None for a new band, or if the resumed band's index is empty.
+    pub fn resume_after(&self) -> Option<&Apath> {
+        self.resume_after.as_ref()
+    }
+
     /// Push a new entry into the backup's IndexBuilder.
     ///
+    /// If files are still being stored on other threads, the entry is queued
+    /// behind them.
+    ///
     /// This is public only to facilitate testing.
     pub(crate) fn push_entry(&mut self, index_entry: IndexEntry) -> Result<()> {
+        if let Some(parallel) = self.parallel.as_mut() {
+            if !parallel.queue.is_empty() {
+                parallel.queue.push_back((None, index_entry));
+                return Ok(());
+            }
+        }
+        self.write_entry(index_entry)
+    }
+
+    /// Write an entry to the index, now.
+    fn write_entry(&mut self, index_entry: IndexEntry) -> Result<()> {
+        // If this is the first file in a hardlink group, remember its content so
+        // that later members can reuse it.
+        if let Some(group) = &index_entry.hardlink_group {
+            if *group == index_entry.apath {
+                self.hardlink_addrs.insert(
+                    group.clone(),
+                    (index_entry.addrs.clone(), index_entry.content_hash.clone()),
+                );
+            }
+        }
+        if index_entry.kind == Kind::File {
+            self.tree_bytes += index_entry.size().unwrap_or_default();
+        }
         // TODO: Return or accumulate index sizes.
-        self.index_builder.push_entry(index_entry)?;
+        if let Some(index_builder) = self.index_builder.as_mut() {
+            match self.delta.as_mut() {
+                Some(delta) => {
+                    for change in delta.filter(index_entry) {
+                        index_builder.push_entry(change)?;
+                    }
+                }
+                None => index_builder.push_entry(index_entry)?,
+            }
+        }
         Ok(())
     }
+
+    /// Write queued entries to the index, as the files ahead of them are stored.
+    ///
+    /// If `wait_for_all` is true, this waits until every file has been stored and
+    /// the queue is empty. Otherwise, it waits only while the maximum number of files
+    /// are in flight.
+    fn write_stored_entries(&mut self, wait_for_all: bool) -> Result<()> {
+        loop {
+            let parallel = match self.parallel.as_mut() {
+                Some(parallel) => parallel,
+                None => return Ok(()),
+            };
+            match parallel.next_ready_entry()? {
+                Some(index_entry) => self.write_entry(index_entry)?,
+                None if parallel.workers.in_flight() == 0 => return Ok(()),
+                None if wait_for_all || parallel.workers.is_full() => parallel.receive_one()?,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// Store a stream, such as stdin, as a single file at `apath`, preceded by
+    /// entries for the root and its other parent directories.
+    ///
+    /// The stream is stored in blocks like any other file, so it can be of any
+    /// length. The file and directories are given the current time as their mtime.
+    pub fn copy_stream(&mut self, apath: &Apath, from: &mut dyn Read) -> Result<CopyStats> {
+        if *apath == "/" {
+            return Err(Error::StreamNameIsRoot);
+        }
+        let mtime = UnixTime::from(SystemTime::now());
+        let mut stats = CopyStats::default();
+        let mut dir = String::from("/");
+        self.push_entry(stream_entry(Apath::from(dir.as_str()), Kind::Dir, mtime))?;
+        stats.directories += 1;
+        let names: Vec<&str> = apath[1..].split('/').collect();
+        for name in &names[..names.len() - 1] {
+            if dir.len() > 1 {
+                dir.push('/');
+            }
+            dir.push_str(name);
+            self.push_entry(stream_entry(Apath::from(dir.as_str()), Kind::Dir, mtime))?;
+            stats.directories += 1;
+        }
+        let mut from = HashingReader::new(from);
+        let (addrs, file_stats) = self.store_files.store_file_content(apath, &mut from, &[])?;
+        stats.files += 1;
+        stats.source_bytes += addrs.iter().map(|addr| addr.len).sum::<u64>();
+        stats.new_files += 1;
+        stats += file_stats;
+        self.push_entry(IndexEntry {
+            addrs,
+            content_hash: Some(from.finish()),
+            ..stream_entry(apath.clone(), Kind::File, mtime)
+        })?;
+        Ok(stats)
+    }
+
+    /// Look up `source_entry` in the basis index, counting whether it's new,
+    /// modified, or unmodified.
+    ///
+    /// Returns the basis entry if the file is unmodified, in which case its content
+    /// need not be read.
+    fn unchanged_basis_entry<E: Entry>(
+        &mut self,
+        source_entry: &E,
+        stats: &mut CopyStats,
+    ) -> Option<IndexEntry> {
+        let apath = source_entry.apath();
+        if let Some(basis_entry) = self
+            .basis_index
+            .as_mut()
+            .and_then(|bi| advance_basis_to(bi, apath))
+        {
+            // Files that changed while they were last read might not have been
+            // stored correctly, so are always read again.
+            if source_entry.is_unchanged_from(&basis_entry) && !basis_entry.changed_during_read {
+                // TODO: In verbose mode, say if the file is changed, unchanged,
+                // etc, but without duplicating the filenames.
+                //
+                // ui::println(&format!("unchanged file {}", apath));
+                stats.unmodified_files += 1;
+                return Some(basis_entry);
+            } else {
+                stats.modified_files += 1;
+            }
+        } else {
+            stats.new_files += 1;
+        }
+        None
+    }
 }
 
 impl tree::WriteTree for BackupWriter {
-    fn finish(self) -> Result<CopyStats> {
-        let index_builder_stats = self.index_builder.finish()?;
-        self.band.close(index_builder_stats.index_hunks)?;
+    fn finish(mut self) -> Result<CopyStats> {
+        self.write_stored_entries(true)?;
+        let stored_stats = self
+            .parallel
+            .take()
+            .map(|parallel| parallel.stats)
+            .unwrap_or_default();
+        let index_builder_stats = match self.index_builder {
+            Some(mut index_builder) => {
+                // Everything in the parent that wasn't seen again was deleted.
+                if let Some(delta) = self.delta.take() {
+                    for deletion in delta.finish() {
+                        index_builder.push_entry(deletion)?;
+                    }
+                }
+                index_builder.finish()?
+            }
+            None => IndexBuilderStats::default(),
+        };
+        if let Some(band) = self.band {
+            band.close_with_tags(
+                u64::from(self.resumed_hunks) + index_builder_stats.index_hunks,
+                &self.tags,
+                Some(self.tree_bytes),
+            )?;
+        }
         Ok(CopyStats {
             index_builder_stats,
-            ..CopyStats::default()
+            ..stored_stats
         })
     }
 
@@ -107,37 +565,64 @@ impl tree::WriteTree for BackupWriter {
     ) -> Result<CopyStats> {
         let mut stats = CopyStats::default();
         let apath = source_entry.apath();
-        if let Some(basis_entry) = self
-            .basis_index
-            .as_mut()
-            .map(|bi| bi.advance_to(&apath))
-            .flatten()
-        {
-            if source_entry.is_unchanged_from(&basis_entry) {
-                // TODO: In verbose mode, say if the file is changed, unchanged,
-                // etc, but without duplicating the filenames.
-                //
-                // ui::println(&format!("unchanged file {}", apath));
-
-                // We can reasonably assume that the existing archive complies
-                // with the archive invariants, which include that all the
-                // blocks referenced by the index, are actually present.
-                stats.unmodified_files += 1;
-                self.push_entry(basis_entry)?;
+        if let Some(group) = source_entry.hardlink_group() {
+            if group != apath && !self.hardlink_addrs.contains_key(group) {
+                // The first file in the group might still be being stored.
+                self.write_stored_entries(true)?;
+            }
+            if let Some((addrs, content_hash)) = self.hardlink_addrs.get(group) {
+                // Linked to a file that was already stored: no need to read it again.
+                stats.hardlinked_files += 1;
+                let addrs = addrs.clone();
+                let content_hash = content_hash.clone();
+                self.push_entry(IndexEntry {
+                    addrs,
+                    content_hash,
+                    ..IndexEntry::metadata_from(source_entry)
+                })?;
                 return Ok(stats);
-            } else {
-                stats.modified_files += 1;
             }
-        } else {
-            stats.new_files += 1;
         }
-        let content = &mut from_tree.file_contents(&source_entry)?;
-        // TODO: Don't read the whole file into memory, but especially don't do that and
-        // then downcast it to Read.
-        let (addrs, file_stats) = self.store_files.store_file_content(&apath, content)?;
+        if let Some(basis_entry) = self.unchanged_basis_entry(source_entry, &mut stats) {
+            // We can reasonably assume that the existing archive complies
+            // with the archive invariants, which include that all the
+            // blocks referenced by the index, are actually present.
+            //
+            // Other metadata, such as permissions, might still have changed, so
+            // take that from the source.
+            self.push_entry(IndexEntry {
+                addrs: basis_entry.addrs,
+                content_hash: basis_entry.content_hash,
+                ..IndexEntry::metadata_from(source_entry)
+            })?;
+            return Ok(stats);
+        }
+        let mut content = from_tree.file_contents(source_entry)?;
+        let holes = from_tree.file_holes(source_entry)?;
+        if let Some(parallel) = self.parallel.as_mut() {
+            // The stats from storing the content are returned by `finish`.
+            parallel.store(
+                IndexEntry::metadata_from(source_entry),
+                content,
+                holes,
+                self.changed_file_retries,
+            );
+            self.write_stored_entries(false)?;
+            return Ok(stats);
+        }
+        let (addrs, content_hash, file_stats) = store_content(
+            &mut self.store_files,
+            apath,
+            &mut content,
+            &holes,
+            self.changed_file_retries,
+        )?;
+        let changed_during_read = file_stats.changed_during_read > 0;
         stats += file_stats;
         self.push_entry(IndexEntry {
             addrs,
+            content_hash: Some(content_hash),
+            changed_during_read,
             ..IndexEntry::metadata_from(source_entry)
         })?;
         Ok(stats)
@@ -148,4 +633,323 @@ impl tree::WriteTree for BackupWriter {
         assert!(target.is_some());
         self.push_entry(IndexEntry::metadata_from(source_entry))
     }
+
+    fn copy_special<E: Entry>(&mut self, source_entry: &E) -> Result<()> {
+        self.push_entry(IndexEntry::metadata_from(source_entry))
+    }
+
+    /// Count what storing a file would do, by hashing its content and looking for
+    /// the blocks in the archive, without storing anything.
+    fn measure_file<R: ReadTree>(
+        &mut self,
+        source_entry: &R::Entry,
+        from_tree: &R,
+    ) -> Result<CopyStats> {
+        let mut stats = CopyStats::default();
+        if self
+            .unchanged_basis_entry(source_entry, &mut stats)
+            .is_some()
+        {
+            return Ok(stats);
+        }
+        let content = &mut from_tree.file_contents(&source_entry)?;
+        stats += self
+            .store_files
+            .measure_file_content(source_entry.apath(), content)?;
+        Ok(stats)
+    }
+}
+
+/// Store the content of a file, reading it again if it changes while it's being
+/// read.
+///
+/// Returns the addresses of the content, and the hash of the whole content as
+/// it was last read.
+///
+/// If it's still changing after `retries` more attempts, the last version read is
+/// stored with a warning, and the returned stats have `changed_during_read` set.
+fn store_content<R: ReadContent>(
+    store_files: &mut StoreFiles,
+    apath: &Apath,
+    content: &mut R,
+    holes: &[Range<u64>],
+    retries: usize,
+) -> Result<(Vec<Address>, BlockHash, CopyStats)> {
+    let read_err = |source| Error::StoreFile {
+        apath: apath.to_owned(),
+        source,
+    };
+    let mut stats = CopyStats::default();
+    let mut holes = holes;
+    let mut attempt = 0;
+    loop {
+        let before = content.mtime_and_size().map_err(read_err)?;
+        let mut hashing = HashingReader::new(&mut *content);
+        let (addrs, file_stats) = store_files.store_file_content(apath, &mut hashing, holes)?;
+        let content_hash = hashing.finish();
+        stats += file_stats;
+        if content.mtime_and_size().map_err(read_err)? == before {
+            return Ok((addrs, content_hash, stats));
+        } else if attempt == retries {
+            log::warn!("File {} changed while it was being read", apath);
+            stats.changed_during_read += 1;
+            return Ok((addrs, content_hash, stats));
+        }
+        attempt += 1;
+        content.rewind().map_err(read_err)?;
+        // Holes might have been filled in, so read everything this time.
+        holes = &[];
+    }
+}
+
+/// An index entry, with no metadata other than its mtime, for `copy_stream`.
+fn stream_entry(apath: Apath, kind: Kind, mtime: UnixTime) -> IndexEntry {
+    IndexEntry {
+        mtime: mtime.secs,
+        mtime_nanos: mtime.nanosecs,
+        ..IndexEntry::new(apath, kind)
+    }
+}
+
+/// Entries from the tree of the basis band, in apath order.
+type BasisIndex = Peekable<Flatten<IterStitchedIndexHunks>>;
+
+/// Iterate the whole tree of the basis band, including entries from its delta
+/// parents.
+fn iter_basis(archive: &Archive, basis_band: Option<&Band>) -> Option<BasisIndex> {
+    basis_band.map(|band| {
+        archive
+            .iter_stitched_index_hunks(band.id())
+            .flatten()
+            .peekable()
+    })
+}
+
+/// Skip forward in the basis to `apath`, and return its entry if it's present.
+fn advance_basis_to(basis: &mut BasisIndex, apath: &Apath) -> Option<IndexEntry> {
+    while let Some(entry) = basis.peek() {
+        match entry.apath.cmp(apath) {
+            Ordering::Less => {
+                basis.next();
+            }
+            Ordering::Equal => return basis.next(),
+            Ordering::Greater => return None,
+        }
+    }
+    None
+}
+
+/// Return how many bands with delta indexes lead back from `band_id`, including
+/// itself, to a band with a full index.
+fn delta_chain_length(archive: &Archive, band_id: &BandId) -> Result<usize> {
+    let mut length = 0;
+    let mut band = Band::open(archive, band_id)?;
+    while let Some(parent) = band.delta_parent()? {
+        length += 1;
+        band = Band::open(archive, &parent)?;
+    }
+    Ok(length)
+}
+
+/// The result of storing one file on a worker thread, tagged with its sequence number.
+type StoreResult = (u64, Result<(Vec<Address>, BlockHash, CopyStats)>);
+
+/// Reads and stores files on a pool of worker threads, and holds back index
+/// entries so they can be written in apath order.
+struct ParallelStore {
+    workers: WorkerPool<StoreResult>,
+    block_dir: BlockDir,
+
+    /// Entries waiting to be written to the index, in apath order. Entries with a
+    /// sequence number are waiting for that file's content to be stored.
+    queue: VecDeque<(Option<u64>, IndexEntry)>,
+
+    next_seq: u64,
+
+    /// Results received from workers, by sequence number, that aren't yet at the
+    /// head of the queue.
+    done: HashMap<u64, Result<(Vec<Address>, BlockHash, CopyStats)>>,
+
+    /// Stats from storing files, including errors.
+    stats: CopyStats,
+}
+
+impl ParallelStore {
+    fn new(block_dir: BlockDir, threads: usize) -> Result<ParallelStore> {
+        Ok(ParallelStore {
+            workers: WorkerPool::new(threads)?,
+            block_dir,
+            queue: VecDeque::new(),
+            next_seq: 0,
+            done: HashMap::new(),
+            stats: CopyStats::default(),
+        })
+    }
+
+    /// Start storing the content of a file on a worker thread, and queue its entry
+    /// to be written when that's done.
+    fn store<R: ReadContent>(
+        &mut self,
+        index_entry: IndexEntry,
+        mut content: R,
+        holes: Vec<Range<u64>>,
+        changed_file_retries: usize,
+    ) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let apath = index_entry.apath.clone();
+        let block_dir = self.block_dir.clone();
+        self.workers.spawn(move || {
+            let result = store_content(
+                &mut StoreFiles::new(block_dir),
+                &apath,
+                &mut content,
+                &holes,
+                changed_file_retries,
+            );
+            (seq, result)
+        });
+        self.queue.push_back((Some(seq), index_entry));
+    }
+
+    /// Wait for a worker to finish storing a file.
+    ///
+    /// A worker that fails without returning its file's result leaves the
+    /// queue stuck behind that file, so this is an error for the whole backup.
+    fn receive_one(&mut self) -> Result<()> {
+        let (seq, result) = self.workers.receive()?;
+        self.done.insert(seq, result);
+        Ok(())
+    }
+
+    /// Return the entry at the head of the queue, if it's ready to be written.
+    ///
+    /// Files that failed to be stored are reported and counted as errors, and
+    /// dropped from the index, as if they'd failed on the calling thread.
+    fn next_ready_entry(&mut self) -> Result<Option<IndexEntry>> {
+        while let Some(received) = self.workers.try_receive() {
+            let (seq, result) = received?;
+            self.done.insert(seq, result);
+        }
+        loop {
+            let seq = match self.queue.front() {
+                None => return Ok(None),
+                Some((None, _)) => return Ok(self.queue.pop_front().map(|(_, entry)| entry)),
+                Some((Some(seq), _)) => *seq,
+            };
+            let result = match self.done.remove(&seq) {
+                Some(result) => result,
+                None => return Ok(None),
+            };
+            let (_, index_entry) = self.queue.pop_front().unwrap();
+            match result {
+                Ok((addrs, content_hash, file_stats)) => {
+                    let changed_during_read = file_stats.changed_during_read > 0;
+                    self.stats += file_stats;
+                    return Ok(Some(IndexEntry {
+                        addrs,
+                        content_hash: Some(content_hash),
+                        changed_during_read,
+                        ..index_entry
+                    }));
+                }
+                Err(err) => {
+                    log::error!("{}", ErrorChain(&err));
+                    self.stats.errors += 1;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::io::{self, Cursor};
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::blockdir::hash_bytes;
+
+    /// Content whose mtime appears to change each time it's examined, until
+    /// it has changed `changes` times.
+    struct ChangingContent {
+        data: Cursor<Vec<u8>>,
+        changes: Cell<usize>,
+        version: Cell<i64>,
+    }
+
+    impl ChangingContent {
+        fn new(changes: usize) -> ChangingContent {
+            ChangingContent {
+                data: Cursor::new(b"some data".to_vec()),
+                changes: Cell::new(changes),
+                version: Cell::new(0),
+            }
+        }
+    }
+
+    impl Read for ChangingContent {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.data.read(buf)
+        }
+    }
+
+    impl ReadContent for ChangingContent {
+        fn mtime_and_size(&self) -> io::Result<Option<(UnixTime, u64)>> {
+            if self.changes.get() > 0 {
+                self.changes.set(self.changes.get() - 1);
+                self.version.set(self.version.get() + 1);
+            }
+            let mtime = UnixTime {
+                secs: self.version.get(),
+                nanosecs: 0,
+            };
+            Ok(Some((mtime, self.data.get_ref().len() as u64)))
+        }
+
+        fn rewind(&mut self) -> io::Result<()> {
+            self.data.set_position(0);
+            Ok(())
+        }
+    }
+
+    fn store(
+        content: &mut ChangingContent,
+        retries: usize,
+    ) -> (Vec<Address>, BlockHash, CopyStats) {
+        let testdir = TempDir::new().unwrap();
+        let block_dir = BlockDir::create_path(testdir.path()).unwrap();
+        store_content(
+            &mut StoreFiles::new(block_dir),
+            &Apath::from("/file"),
+            content,
+            &[],
+            retries,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn file_that_settles_is_read_again() {
+        // Changes between the first pair of checks, but not the second.
+        let (addrs, content_hash, stats) = store(&mut ChangingContent::new(2), 2);
+        assert_eq!(stats.changed_during_read, 0);
+        assert_eq!(addrs.len(), 1);
+        assert_eq!(addrs[0].len, 9);
+        // The hash is of the content read the last time, not all the attempts.
+        assert_eq!(content_hash, hash_bytes(b"some data").unwrap());
+    }
+
+    #[test]
+    fn file_that_keeps_changing_is_flagged() {
+        let (addrs, _content_hash, stats) = store(&mut ChangingContent::new(usize::MAX), 2);
+        assert_eq!(stats.changed_during_read, 1);
+        assert_eq!(addrs.len(), 1);
+        assert_eq!(addrs[0].len, 9);
+        // The first read wrote the block, and the retries found it again.
+        assert_eq!(stats.written_blocks, 1);
+        assert_eq!(stats.deduplicated_blocks, 2);
+    }
 }
@@ -35,6 +35,22 @@ static INDEX_DIR: &str = "i";
 /// read correctly by versions equal or later than the stated version.
 pub const BAND_FORMAT_VERSION: &str = "0.6.3";
 
+/// Check that a tag can name a band.
+///
+/// Tags can't be empty, contain whitespace or commas, or look like a band id.
+pub(crate) fn check_tag(tag: &str) -> Result<()> {
+    if tag.is_empty()
+        || tag.contains(|c: char| c.is_whitespace() || c == ',')
+        || tag.parse::<BandId>().is_ok()
+    {
+        Err(Error::InvalidTag {
+            tag: tag.to_owned(),
+        })
+    } else {
+        Ok(())
+    }
+}
+
 /// Describes how to select a band from an archive.
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum BandSelectionPolicy {
@@ -73,6 +89,11 @@ struct Head {
     /// Semver string for the minimum Conserve version to read this band
     /// correctly.
     band_format_version: Option<String>,
+
+    /// If set, the index holds only the entries that changed from this
+    /// earlier band, which must be kept while this band exists.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    delta_parent: Option<String>,
 }
 
 /// Format of the on-disk tail file.
@@ -85,6 +106,25 @@ struct Tail {
     ///
     /// Present from 0.6.4 onwards.
     index_hunk_count: Option<u64>,
+
+    /// True if the backup writing this band was interrupted, and the band was
+    /// later closed without all the source tree being written.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    partial: bool,
+
+    /// Names given to this band, at backup time or afterwards.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+
+    /// Total uncompressed length of the files in this band's tree, so that
+    /// `versions --sizes` can show it without reading the index.
+    ///
+    /// This is not the compressed size of the band's blocks, which are shared
+    /// with other bands.
+    ///
+    /// Present from 0.6.9 onwards, except for bands closed as partial.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tree_bytes: Option<u64>,
 }
 
 /// Readonly summary info about a band, from `Band::get_info`.
@@ -92,6 +132,10 @@ pub struct Info {
     pub id: BandId,
     pub is_closed: bool,
 
+    /// True if the band was closed after an interrupted backup, by
+    /// `Band::close_partial`.
+    pub is_partial: bool,
+
     /// Time Conserve started writing this band.
     pub start_time: DateTime<Utc>,
 
@@ -100,6 +144,13 @@ pub struct Info {
 
     /// Number of hunks present in the index, if that is known.
     pub index_hunk_count: Option<u64>,
+
+    /// Tags naming this band.
+    pub tags: Vec<String>,
+
+    /// Total uncompressed length of the files in the band's tree, if it was
+    /// recorded when the band was closed.
+    pub tree_bytes: Option<u64>,
 }
 
 // TODO: Maybe merge Band with StoredTree and/or with the Index classes? The distinction seems
@@ -109,6 +160,16 @@ impl Band {
     ///
     /// The Band gets the next id after those that already exist.
     pub fn create(archive: &Archive) -> Result<Band> {
+        Band::create_with_parent(archive, None)
+    }
+
+    /// Make a new band whose index will hold only the entries that changed
+    /// from `parent`, which must be a complete band.
+    pub fn create_delta(archive: &Archive, parent: &BandId) -> Result<Band> {
+        Band::create_with_parent(archive, Some(parent))
+    }
+
+    fn create_with_parent(archive: &Archive, delta_parent: Option<&BandId>) -> Result<Band> {
         let band_id = archive
             .last_band_id()?
             .map_or_else(BandId::zero, |b| b.next_sibling());
@@ -120,19 +181,63 @@ impl Band {
         let head = Head {
             start_time: Utc::now().timestamp(),
             band_format_version: Some(BAND_FORMAT_VERSION.to_owned()),
+            delta_parent: delta_parent.map(BandId::to_string),
         };
         write_json(&transport, BAND_HEAD_FILENAME, &head)?;
+        log::debug!("Created band {}", band_id);
         Ok(Band { band_id, transport })
     }
 
     /// Mark this band closed: no more blocks should be written after this.
     pub fn close(&self, index_hunk_count: u64) -> Result<()> {
+        self.close_with_tags(index_hunk_count, &[], None)
+    }
+
+    /// Mark this band closed, with some tags naming it, and optionally
+    /// recording the total length of its files.
+    pub fn close_with_tags(
+        &self,
+        index_hunk_count: u64,
+        tags: &[String],
+        tree_bytes: Option<u64>,
+    ) -> Result<()> {
+        for tag in tags {
+            check_tag(tag)?;
+        }
+        log::debug!(
+            "Closing band {} with {} index hunks",
+            self.band_id,
+            index_hunk_count
+        );
         write_json(
             &self.transport,
             BAND_TAIL_FILENAME,
             &Tail {
                 end_time: Utc::now().timestamp(),
                 index_hunk_count: Some(index_hunk_count),
+                partial: false,
+                tags: tags.to_vec(),
+                tree_bytes,
+            },
+        )
+    }
+
+    /// Close a band left incomplete by an interrupted backup, marking it as
+    /// partial.
+    ///
+    /// The band keeps the entries that were written to its index, but it's
+    /// not treated as a complete backup. No more entries can be added to it.
+    pub fn close_partial(&self) -> Result<()> {
+        let index_hunk_count = self.index().count_hunks()?;
+        write_json(
+            &self.transport,
+            BAND_TAIL_FILENAME,
+            &Tail {
+                end_time: Utc::now().timestamp(),
+                index_hunk_count: Some(index_hunk_count.into()),
+                partial: true,
+                tags: Vec::new(),
+                tree_bytes: None,
             },
         )
     }
@@ -162,6 +267,7 @@ impl Band {
     /// Delete a band.
     pub fn delete(archive: &Archive, band_id: &BandId) -> Result<()> {
         // TODO: Count how many files were deleted, and the total size?
+        log::debug!("Deleting band {}", band_id);
         archive
             .transport()
             .remove_dir_all(&band_id.to_string())
@@ -177,14 +283,70 @@ impl Band {
             .map_err(Error::from)
     }
 
+    /// True if the band is closed, and wasn't closed as partial.
+    pub fn is_complete(&self) -> Result<bool> {
+        Ok(self.read_tail()?.is_some_and(|tail| !tail.partial))
+    }
+
     pub fn id(&self) -> &BandId {
         &self.band_id
     }
 
+    /// Return the band whose index this band's index is a delta from, or
+    /// None if this band has a full index.
+    pub fn delta_parent(&self) -> Result<Option<BandId>> {
+        self.read_head()?
+            .delta_parent
+            .map(|parent| parent.parse())
+            .transpose()
+    }
+
+    /// Return the tags naming this band, which are empty if it's not closed.
+    pub fn tags(&self) -> Result<Vec<String>> {
+        Ok(self.read_tail()?.map(|tail| tail.tags).unwrap_or_default())
+    }
+
+    /// Add tags to a closed band, keeping its existing tags.
+    pub fn add_tags(&self, tags: &[String]) -> Result<()> {
+        for tag in tags {
+            check_tag(tag)?;
+        }
+        self.update_tags(|existing| {
+            for tag in tags {
+                if !existing.contains(tag) {
+                    existing.push(tag.clone());
+                }
+            }
+        })
+    }
+
+    /// Remove tags from a closed band, if they're present.
+    pub fn remove_tags(&self, tags: &[String]) -> Result<()> {
+        self.update_tags(|existing| existing.retain(|t| !tags.contains(t)))
+    }
+
+    /// Rewrite the tail with changed tags.
+    fn update_tags(&self, update: impl FnOnce(&mut Vec<String>)) -> Result<()> {
+        let mut tail = self.read_tail()?.ok_or_else(|| Error::BandIncomplete {
+            band_id: self.band_id.clone(),
+        })?;
+        update(&mut tail.tags);
+        write_json(&self.transport, BAND_TAIL_FILENAME, &tail)
+    }
+
     pub fn index_builder(&self) -> IndexBuilder {
         IndexBuilder::new(self.transport.sub_transport(INDEX_DIR))
     }
 
+    /// Make an IndexBuilder that continues writing the existing index of an incomplete band.
+    pub fn resume_index_builder(&self, next_hunk: u32, last_apath: Option<&Apath>) -> IndexBuilder {
+        IndexBuilder::resume(
+            self.transport.sub_transport(INDEX_DIR),
+            next_hunk,
+            last_apath,
+        )
+    }
+
     /// Get read-only access to the index of this band.
     pub fn index(&self) -> IndexRead {
         IndexRead::open(self.transport.sub_transport(INDEX_DIR))
@@ -219,11 +381,14 @@ impl Band {
         Ok(Info {
             id: self.band_id.clone(),
             is_closed,
+            is_partial: tail_option.as_ref().is_some_and(|tail| tail.partial),
             start_time: Utc.timestamp(head.start_time, 0),
             end_time: tail_option
                 .as_ref()
                 .map(|tail| Utc.timestamp(tail.end_time, 0)),
             index_hunk_count: tail_option.as_ref().and_then(|tail| tail.index_hunk_count),
+            tree_bytes: tail_option.as_ref().and_then(|tail| tail.tree_bytes),
+            tags: tail_option.map(|tail| tail.tags).unwrap_or_default(),
         })
     }
 
@@ -231,25 +396,27 @@ impl Band {
         let ListDirNames { mut files, dirs } =
             self.transport.list_dir_names("").map_err(Error::from)?;
         if !files.contains(&BAND_HEAD_FILENAME.to_string()) {
-            ui::problem(&format!("No band head file in {:?}", self.transport));
+            log::error!("No band head file in {:?}", self.transport);
             stats.missing_band_heads += 1;
         }
         remove_item(&mut files, &BAND_HEAD_FILENAME);
         remove_item(&mut files, &BAND_TAIL_FILENAME);
 
         if !files.is_empty() {
-            ui::problem(&format!(
+            log::error!(
                 "Unexpected files in band directory {:?}: {:?}",
-                self.transport, files
-            ));
+                self.transport,
+                files
+            );
             stats.unexpected_files += 1;
         }
 
         if dirs != [INDEX_DIR.to_string()] {
-            ui::problem(&format!(
+            log::error!(
                 "Incongruous directories in band directory {:?}: {:?}",
-                self.transport, dirs
-            ));
+                self.transport,
+                dirs
+            );
             stats.unexpected_files += 1;
         }
 
@@ -296,12 +463,63 @@ mod tests {
         assert_eq!(info.id.to_string(), "b0000");
         assert_eq!(info.is_closed, true);
         assert_eq!(info.index_hunk_count, Some(0));
+        assert_eq!(info.tree_bytes, None);
         let dur = info.end_time.expect("info has an end_time") - info.start_time;
         // Test should have taken (much) less than 5s between starting and finishing
         // the band.  (It might fail if you set a breakpoint right there.)
         assert!(dur < Duration::seconds(5));
     }
 
+    #[test]
+    fn tag_band() {
+        let af = ScratchArchive::new();
+        let band = Band::create(&af).unwrap();
+        match band.add_tags(&["early".to_owned()]) {
+            Err(Error::BandIncomplete { .. }) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+        band.close_with_tags(0, &["pre-upgrade".to_owned()], Some(1234))
+            .unwrap();
+        assert_eq!(band.tags().unwrap(), ["pre-upgrade"]);
+        assert_eq!(band.get_info().unwrap().tree_bytes, Some(1234));
+
+        band.add_tags(&["quarterly".to_owned(), "pre-upgrade".to_owned()])
+            .unwrap();
+        assert_eq!(band.get_info().unwrap().tags, ["pre-upgrade", "quarterly"]);
+        band.remove_tags(&["pre-upgrade".to_owned()]).unwrap();
+        assert_eq!(band.tags().unwrap(), ["quarterly"]);
+        assert!(band.is_complete().unwrap());
+    }
+
+    #[test]
+    fn invalid_tags() {
+        for tag in &["", "two words", "a,b", "b0001", "b0001-0002"] {
+            assert!(check_tag(tag).is_err(), "{:?}", tag);
+        }
+        check_tag("pre-upgrade").unwrap();
+        check_tag("b").unwrap();
+    }
+
+    #[test]
+    fn close_partial_band() {
+        let af = ScratchArchive::new();
+        let band = Band::create(&af).unwrap();
+        assert!(!band.is_complete().unwrap());
+
+        band.close_partial().unwrap();
+        assert!(band.is_closed().unwrap());
+        assert!(!band.is_complete().unwrap());
+        let info = band.get_info().unwrap();
+        assert!(info.is_closed);
+        assert!(info.is_partial);
+        assert_eq!(info.index_hunk_count, Some(0));
+
+        let tail: serde_json::Value =
+            serde_json::from_slice(&fs::read(af.path().join("b0000").join("BANDTAIL")).unwrap())
+                .unwrap();
+        assert_eq!(tail["partial"], true);
+    }
+
     #[test]
     fn delete_band() {
         let af = ScratchArchive::new();
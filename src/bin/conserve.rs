@@ -15,10 +15,11 @@
 
 use std::io::{BufWriter, Write};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
 use structopt::StructOpt;
 
-use conserve::backup::BackupOptions;
 use conserve::ReadTree;
 use conserve::RestoreOptions;
 use conserve::*;
@@ -27,20 +28,105 @@ use conserve::*;
 #[structopt(
     name = "conserve",
     about = "A robust backup tool <https://github.com/sourcefrog/conserve/>",
-    author
+    author,
+    after_help = "EXIT CODES:
+    0    Success.
+    1    Failed for some other reason.
+    2    Verification failed: the archive is damaged, or the source differs from the backup.
+    3    Completed with warnings: some entries couldn't be read, written, or deleted.
+    4    Transport error: reading or writing the archive, source, or destination failed.
+    5    Usage error: invalid arguments, names, or paths."
 )]
+struct Args {
+    /// How to show progress: "auto" draws a progress bar when stdout is a
+    /// terminal, "json" writes a json object describing progress to stderr
+    /// every second, and "none" shows nothing.
+    #[structopt(long, global = true, default_value = "auto")]
+    progress: ui::ProgressMode,
+
+    /// Print the names of files as they're copied, or with -vv also show
+    /// debug messages, or with -vvv also trace messages.
+    #[structopt(
+        long,
+        short,
+        global = true,
+        parse(from_occurrences),
+        conflicts_with = "quiet"
+    )]
+    verbose: u8,
+
+    /// Show only problems, not informational messages.
+    #[structopt(long, short, global = true)]
+    quiet: bool,
+
+    /// Append all messages, at debug level and above, to this file.
+    #[structopt(long, global = true)]
+    log_file: Option<PathBuf>,
+
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, StructOpt)]
 enum Command {
     /// Copy source directory into an archive.
     Backup {
         /// Path of an existing archive.
         archive: PathBuf,
         /// Source directory to copy from.
-        source: PathBuf,
-        /// Print copied file names.
-        #[structopt(long, short)]
-        verbose: bool,
+        ///
+        /// If several are given, each is stored under its absolute path, such as
+        /// `/etc/...` and `/home/...`. Restoring such a backup to `/` puts the
+        /// files back in their original locations.
+        #[structopt(required = true, min_values = 1)]
+        source: Vec<PathBuf>,
         #[structopt(long, short, number_of_values = 1)]
         exclude: Vec<String>,
+        /// Exclude directories containing a `CACHEDIR.TAG` file.
+        #[structopt(long)]
+        exclude_caches: bool,
+        /// Exclude directories containing a file with this name, such as `.nobackup`.
+        #[structopt(long, number_of_values = 1)]
+        exclude_if_present: Vec<String>,
+        /// Continue an interrupted backup, writing into the last, incomplete, band.
+        #[structopt(long)]
+        resume: bool,
+        /// Report what would be written, without changing the archive.
+        #[structopt(long, conflicts_with = "resume")]
+        dry_run: bool,
+        /// Number of files to read and store concurrently.
+        #[structopt(long, default_value = "1")]
+        threads: usize,
+        /// Don't back up the contents of directories on other filesystems.
+        #[structopt(long, short = "x")]
+        one_file_system: bool,
+        /// Shell command to run before reading the source.
+        #[structopt(long)]
+        pre_hook: Option<String>,
+        /// Shell command to run after the backup finishes or fails.
+        #[structopt(long)]
+        post_hook: Option<String>,
+        /// How many more times to read a file that changes while it's being read.
+        #[structopt(long, default_value = "2")]
+        changed_file_retries: usize,
+        /// What to do if a hook fails: abort the backup, or warn and continue.
+        #[structopt(long, default_value = "abort", possible_values = &["abort", "warn"])]
+        hook_failure: HookFailurePolicy,
+        /// Name the new backup with this tag, which can be used instead of its id.
+        #[structopt(long, number_of_values = 1)]
+        tag: Vec<String>,
+        /// Write the stats as json to this file, or to stdout if it's `-`.
+        #[structopt(long, value_name = "PATH")]
+        stats_json: Option<PathBuf>,
+    },
+
+    /// Store data read from stdin as a single file in a new backup.
+    BackupStdin {
+        /// Path of an existing archive.
+        archive: PathBuf,
+        /// Apath of the file to store, such as `/db/dump.sql`.
+        #[structopt(long)]
+        name: Apath,
     },
 
     Debug(Debug),
@@ -51,11 +137,11 @@ enum Command {
         archive: PathBuf,
         /// Backup to delete.
         #[structopt(long, short, multiple(true), required(true), number_of_values(1))]
-        backup: Vec<BandId>,
+        backup: Vec<String>,
         /// Don't actually delete, just check what could be deleted.
         #[structopt(long)]
         dry_run: bool,
-        /// Break a lock left behind by a previous interrupted gc operation, and then gc.
+        /// Break a lock left behind by a previous interrupted operation, and then gc.
         #[structopt(long)]
         break_lock: bool,
         /// Delete indexes but don't garbage-collect blocks.
@@ -63,22 +149,193 @@ enum Command {
         /// (Faster, but doesn't free up space. The archive should later be gc'd.)
         #[structopt(long)]
         no_gc: bool,
+        /// Allow deleting or changing existing data in an append-only archive.
+        #[structopt(long)]
+        allow_delete: bool,
+    },
+
+    /// Remove the lock that excludes other writers from an archive.
+    ///
+    /// Locks left by processes on this machine that are no longer running are
+    /// broken automatically. Use this only if you're confident that the
+    /// process holding the lock has stopped.
+    BreakLock {
+        archive: PathBuf,
+    },
+
+    /// Write the content of one stored file to stdout.
+    Cat {
+        archive: PathBuf,
+        /// Apath of the file within the stored tree, such as `/etc/hosts`.
+        apath: Apath,
+        #[structopt(long, short)]
+        backup: Option<String>,
+    },
+
+    /// Write a stored tree as a tar stream, to stdout or a file.
+    ///
+    /// Files, directories, symlinks, hard links, and special files are
+    /// included, with their permissions, ownership, and mtimes.
+    ExportTar {
+        archive: PathBuf,
+        #[structopt(long, short)]
+        backup: Option<String>,
+        /// Write the tar stream to this file, rather than stdout.
+        #[structopt(long, short)]
+        output: Option<PathBuf>,
+        /// Compress the stream with "none", "gzip", or "zstd".
+        #[structopt(long, default_value = "none")]
+        compression: TarCompression,
+        #[structopt(long, short, number_of_values = 1)]
+        exclude: Vec<String>,
+    },
+
+    /// Store the contents of a tar file as a new backup.
+    ///
+    /// The tar file may be compressed with gzip or Zstandard.
+    ImportTar {
+        archive: PathBuf,
+        /// Tar file to read.
+        file: PathBuf,
+        #[structopt(long, short, number_of_values = 1)]
+        exclude: Vec<String>,
+        /// Name the new backup with this tag, which can be used instead of its id.
+        #[structopt(long, number_of_values = 1)]
+        tag: Vec<String>,
     },
 
-    /// Compare a stored tree to a source directory.
+    /// Mount the archive as a read-only filesystem, with a directory for each
+    /// backup, until it's unmounted.
+    #[cfg(all(unix, feature = "fuse"))]
+    Mount {
+        archive: PathBuf,
+        mountpoint: PathBuf,
+    },
+
+    /// Copy backups and blocks missing from another archive, such as an
+    /// offsite copy.
+    ///
+    /// The destination archive is created if it doesn't exist. Running this
+    /// again copies only what's new.
+    Sync {
+        archive: PathBuf,
+        /// Path of the destination archive.
+        destination: String,
+        /// Count what would be copied, but don't change the destination.
+        #[structopt(long)]
+        dry_run: bool,
+    },
+
+    /// Upgrade an archive in place to the newest format supported by this
+    /// version, recompressing blocks with Zstandard.
+    ///
+    /// The archive remains readable and restorable while this runs, and it
+    /// can be interrupted and run again. Versions of Conserve older than this
+    /// one can't read the upgraded archive.
+    Migrate {
+        archive: PathBuf,
+        /// Count what would be changed, but don't change the archive.
+        #[structopt(long)]
+        dry_run: bool,
+        /// Allow deleting or changing existing data in an append-only archive.
+        #[structopt(long)]
+        allow_delete: bool,
+    },
+
+    /// Compare a stored tree to a source directory, or two stored trees.
+    ///
+    /// With a source directory, shows what the next backup would store,
+    /// comparing the latest or chosen backup to the source by kind, size, and
+    /// modification time. With `-b` given twice, shows the changes from the
+    /// first backup to the second.
     Diff {
+        archive: PathBuf,
+        source: Option<PathBuf>,
+        #[structopt(long, short, number_of_values = 1, max_values = 2)]
+        backup: Vec<String>,
+        #[structopt(long, short, number_of_values = 1)]
+        exclude: Vec<String>,
+        /// Show only counts of changed entries and sizes.
+        #[structopt(long)]
+        stat: bool,
+        /// Also list entries that are unchanged.
+        #[structopt(long, conflicts_with = "stat")]
+        include_unchanged: bool,
+    },
+
+    /// Check that a stored tree matches a source directory, without
+    /// restoring it.
+    ///
+    /// Exits with an error if anything was added, deleted, or changed.
+    Verify {
         archive: PathBuf,
         source: PathBuf,
         #[structopt(long, short)]
-        backup: Option<BandId>,
+        backup: Option<String>,
         #[structopt(long, short, number_of_values = 1)]
         exclude: Vec<String>,
+        /// Also read every source file and compare it to the stored content.
+        #[structopt(long)]
+        content: bool,
+    },
+
+    /// List backups that were never finished, and optionally seal or delete
+    /// them.
+    ///
+    /// CAUTION: Do not seal or delete while a backup is underway.
+    Incomplete {
+        archive: PathBuf,
+        /// Close incomplete backups, marked as partial, so that they're kept
+        /// but not used as the latest complete backup.
+        #[structopt(long, conflicts_with = "delete")]
+        seal: bool,
+        /// Delete incomplete backups. Blocks they reference remain until the
+        /// next gc.
+        #[structopt(long)]
+        delete: bool,
+        /// Allow deleting or changing existing data in an append-only archive.
+        #[structopt(long)]
+        allow_delete: bool,
     },
 
     /// Create a new archive.
     Init {
         /// Path for new archive.
         archive: PathBuf,
+        #[structopt(flatten)]
+        config: ConfigArgs,
+    },
+
+    /// Show or change the settings stored in an archive.
+    ///
+    /// Backups and restores from any machine use these settings, so they don't
+    /// need to be given on every command.
+    Config {
+        archive: PathBuf,
+        #[structopt(flatten)]
+        config: ConfigArgs,
+        /// Allow deleting or changing existing data in an append-only archive.
+        #[structopt(long)]
+        allow_delete: bool,
+    },
+
+    /// Add or remove tags naming a backup.
+    ///
+    /// Tags can be used instead of a backup id wherever one is accepted. If
+    /// several backups have the same tag, it names the latest of them.
+    Tag {
+        archive: PathBuf,
+        /// Backup id, or an existing tag.
+        backup: String,
+        /// Tags to add or remove.
+        #[structopt(required = true)]
+        tags: Vec<String>,
+        /// Remove these tags, rather than adding them.
+        #[structopt(long)]
+        remove: bool,
+        /// Allow deleting or changing existing data in an append-only archive.
+        #[structopt(long)]
+        allow_delete: bool,
     },
 
     /// Delete blocks unreferenced by any index.
@@ -90,15 +347,88 @@ enum Command {
         /// Don't actually delete, just check what could be deleted.
         #[structopt(long)]
         dry_run: bool,
-        /// Break a lock left behind by a previous interrupted gc operation, and then gc.
+        /// Break a lock left behind by a previous interrupted operation, and then gc.
         #[structopt(long)]
         break_lock: bool,
+        /// Allow deleting or changing existing data in an append-only archive.
+        #[structopt(long)]
+        allow_delete: bool,
+        /// Write the stats as json to this file, or to stdout if it's `-`.
+        #[structopt(long, value_name = "PATH")]
+        stats_json: Option<PathBuf>,
+    },
+
+    /// Summarize an archive's versions, blocks, and format.
+    Info {
+        archive: PathBuf,
+        /// Show the summary as json.
+        #[structopt(long)]
+        json: bool,
     },
 
     /// List files in a stored tree or source directory, with exclusions.
     Ls {
         #[structopt(flatten)]
         stos: StoredTreeOrSource,
+        /// List only this directory and its contents.
+        #[structopt(long)]
+        path: Option<Apath>,
+        /// List only entries whose apath matches one of these globs.
+        #[structopt(long, number_of_values = 1)]
+        include: Vec<String>,
+        /// Show permissions, size, modification time, and symlink targets.
+        #[structopt(long, short)]
+        long: bool,
+        /// Show each entry as a json object on its own line.
+        #[structopt(long, conflicts_with = "long")]
+        json: bool,
+        /// Terminate each apath by a zero byte rather than a newline.
+        #[structopt(long, short = "0", conflicts_with_all = &["long", "json"])]
+        null: bool,
+    },
+
+    /// Show how a file changed in each backup.
+    ///
+    /// For every backup, from first to last, shows whether the file is
+    /// present, and whether its content or metadata changed since the
+    /// previous complete backup, with its size and modification time.
+    Log {
+        archive: PathBuf,
+        /// Apath of the file, such as /home/user/notes.txt.
+        apath: Apath,
+        /// Show each version as a json object on its own line.
+        #[structopt(long)]
+        json: bool,
+    },
+
+    /// Delete backups that aren't kept by a retention policy.
+    ///
+    /// Each --keep option keeps the latest backup in each of the last N days,
+    /// weeks, or months that have any backups. A backup is kept if any option
+    /// keeps it. Incomplete backups are not affected.
+    Prune {
+        archive: PathBuf,
+        /// Keep this many of the most recent backups.
+        #[structopt(long, default_value = "0")]
+        keep_last: usize,
+        #[structopt(long, default_value = "0")]
+        keep_daily: usize,
+        #[structopt(long, default_value = "0")]
+        keep_weekly: usize,
+        #[structopt(long, default_value = "0")]
+        keep_monthly: usize,
+        /// Don't actually delete, just show what would be deleted.
+        #[structopt(long)]
+        dry_run: bool,
+        /// Break a lock left behind by a previous interrupted operation.
+        #[structopt(long)]
+        break_lock: bool,
+        /// Delete indexes but don't garbage-collect blocks.
+        #[structopt(long)]
+        no_gc: bool,
+        /// Allow deleting or changing existing data in an append-only archive.
+        #[structopt(long)]
+        allow_delete: bool,
     },
 
     /// Copy a stored tree to a restore directory.
@@ -106,15 +436,79 @@ enum Command {
         archive: PathBuf,
         destination: PathBuf,
         #[structopt(long, short)]
-        backup: Option<BandId>,
-        #[structopt(long, short)]
+        backup: Option<String>,
+        /// Allow restoring a band given by --backup that was never completed,
+        /// taking the rest of the tree from earlier bands.
+        #[structopt(long, requires = "backup")]
+        incomplete: bool,
+        /// Restore into a non-empty destination, replacing existing files.
+        #[structopt(long, short, visible_alias = "force")]
         force_overwrite: bool,
-        #[structopt(long, short)]
-        verbose: bool,
+        /// Restore into a non-empty destination, leaving existing files untouched.
+        #[structopt(long, conflicts_with_all = &["force-overwrite", "only-newer"])]
+        skip_existing: bool,
+        /// Restore into a non-empty destination, replacing existing files only
+        /// if the stored version is newer.
+        #[structopt(long, conflicts_with = "force-overwrite")]
+        only_newer: bool,
+        /// Restore into a non-empty destination, replacing only files whose
+        /// content differs from the stored version.
+        #[structopt(
+            long,
+            conflicts_with_all = &["force-overwrite", "skip-existing", "only-newer"]
+        )]
+        update: bool,
+        /// Delete files and directories in the destination that aren't in the
+        /// stored tree.
+        #[structopt(long)]
+        delete: bool,
+        /// List what would be created, overwritten, or skipped, without
+        /// changing the destination.
+        #[structopt(long)]
+        dry_run: bool,
         #[structopt(long, short, number_of_values = 1)]
         exclude: Vec<String>,
         #[structopt(long = "only", short = "i", number_of_values = 1)]
         only_subtree: Option<Apath>,
+        /// Don't set stored Unix permissions or Windows attributes on restored files.
+        #[structopt(long)]
+        no_permissions: bool,
+        /// Set the stored numeric user and group ids, rather than looking up the
+        /// stored names.
+        #[structopt(long)]
+        numeric_owner: bool,
+        /// Don't set the owner and group of restored files.
+        #[structopt(long, conflicts_with = "numeric-owner")]
+        no_owner: bool,
+        /// Number of files to write concurrently.
+        #[structopt(long, default_value = "1")]
+        threads: usize,
+        /// Continue an interrupted restore into the same destination.
+        #[structopt(long, conflicts_with = "dry-run")]
+        resume: bool,
+        /// Write the stats as json to this file, or to stdout if it's `-`.
+        #[structopt(long, value_name = "PATH")]
+        stats_json: Option<PathBuf>,
+    },
+
+    /// Show the size of each directory in a stored tree.
+    ///
+    /// The first column is the total length of files in the directory and
+    /// its subdirectories. The second is the size of the content stored for
+    /// them after deduplication, counting content shared with an earlier
+    /// directory only in that earlier directory.
+    Du {
+        archive: PathBuf,
+        #[structopt(long, short)]
+        backup: Option<String>,
+        #[structopt(long, short, number_of_values = 1)]
+        exclude: Vec<String>,
+        /// Show only directories this many levels below the root.
+        #[structopt(long, short = "d")]
+        max_depth: Option<usize>,
+        /// Show sizes in bytes rather than megabytes.
+        #[structopt(long)]
+        bytes: bool,
     },
 
     /// Show the total size of files in a stored tree or source directory, with exclusions.
@@ -127,17 +521,74 @@ enum Command {
     Validate {
         /// Path of the archive to check.
         archive: PathBuf,
+        /// Check the archive structure and that referenced blocks are present,
+        /// without reading every block.
+        #[structopt(long)]
+        quick: bool,
+        /// Show the validation stats as json.
+        #[structopt(long)]
+        json: bool,
+        /// Write the stats as json to this file, or to stdout if it's `-`.
+        #[structopt(long, value_name = "PATH", conflicts_with = "json")]
+        stats_json: Option<PathBuf>,
     },
 
     /// List backup versions in an archive.
     Versions {
         archive: PathBuf,
-        /// Show only version names.
-        #[structopt(long, short = "q")]
+        /// Show only version names; `-q` also does this.
+        #[structopt(long)]
         short: bool,
         /// Show size of stored trees.
         #[structopt(long, short = "z", conflicts_with = "short")]
         sizes: bool,
+        /// Show each version as a json object on its own line.
+        #[structopt(long, conflicts_with = "short")]
+        json: bool,
+    },
+
+    /// Back up a source directory now, and then again whenever it changes.
+    ///
+    /// Runs until it's interrupted, logging a summary of each backup.
+    Watch {
+        /// Path of an existing archive.
+        archive: PathBuf,
+        /// Source directory to copy from.
+        source: PathBuf,
+        /// How often to look for changes, such as `15m` or `1h`, or with
+        /// --quiet-period, the longest time between backups while the source
+        /// keeps changing.
+        #[structopt(long, default_value = "1h", parse(try_from_str = parse_duration))]
+        interval: Duration,
+        /// Back up once the source has stopped changing for this long.
+        #[structopt(long, parse(try_from_str = parse_duration))]
+        quiet_period: Option<Duration>,
+        #[structopt(long, short, number_of_values = 1)]
+        exclude: Vec<String>,
+        /// Exclude directories containing a `CACHEDIR.TAG` file.
+        #[structopt(long)]
+        exclude_caches: bool,
+        /// Exclude directories containing a file with this name, such as `.nobackup`.
+        #[structopt(long, number_of_values = 1)]
+        exclude_if_present: Vec<String>,
+        /// Number of files to read and store concurrently.
+        #[structopt(long, default_value = "1")]
+        threads: usize,
+        /// Don't back up the contents of directories on other filesystems.
+        #[structopt(long, short = "x")]
+        one_file_system: bool,
+        /// After each backup, prune to keep this many of the most recent backups.
+        #[structopt(long, default_value = "0")]
+        keep_last: usize,
+        #[structopt(long, default_value = "0")]
+        keep_daily: usize,
+        #[structopt(long, default_value = "0")]
+        keep_weekly: usize,
+        #[structopt(long, default_value = "0")]
+        keep_monthly: usize,
+        /// Stop after making this many backups.
+        #[structopt(long)]
+        max_backups: Option<usize>,
     },
 }
 
@@ -151,23 +602,145 @@ struct StoredTreeOrSource {
     source: Option<PathBuf>,
 
     #[structopt(long, short, conflicts_with = "source")]
-    backup: Option<BandId>,
+    backup: Option<String>,
+
+    #[structopt(long, short, number_of_values = 1)]
+    exclude: Vec<String>,
+}
+
+/// Settings stored in the archive's configuration.
+#[derive(Debug, StructOpt)]
+struct ConfigArgs {
+    /// Compress new blocks with "snappy" or "zstd".
+    #[structopt(long)]
+    compression: Option<Compression>,
+
+    /// Compress new index hunks with "snappy" or "zstd".
+    #[structopt(long)]
+    index_compression: Option<Compression>,
+
+    /// Zstandard compression level, from 1 to 22.
+    #[structopt(long)]
+    compression_level: Option<i32>,
+
+    /// Maximum size of new blocks, in bytes.
+    #[structopt(long)]
+    block_size: Option<usize>,
 
+    /// Exclude files matching this glob from every backup and restore.
     #[structopt(long, short, number_of_values = 1)]
     exclude: Vec<String>,
+
+    /// Remove all configured excludes, before adding any given by --exclude.
+    #[structopt(long)]
+    clear_excludes: bool,
+
+    /// Refuse to delete or change existing backups and blocks, unless
+    /// --allow-delete is given.
+    #[structopt(long, conflicts_with = "no-append-only")]
+    append_only: bool,
+
+    /// Allow existing data to be deleted again.
+    #[structopt(long)]
+    no_append_only: bool,
+
+    /// Store only the index entries that changed from the previous backup.
+    #[structopt(long, conflicts_with = "no-delta-index")]
+    delta_index: bool,
+
+    /// Store a full index in every new backup.
+    #[structopt(long)]
+    no_delta_index: bool,
+
+    /// Also write every backup to a second archive at this location, such as
+    /// a directory on another disk. The mirror is created if it doesn't
+    /// exist, and caught up if it missed any backups.
+    #[structopt(long, conflicts_with = "no-mirror")]
+    mirror: Option<String>,
+
+    /// Stop writing backups to a mirror.
+    #[structopt(long)]
+    no_mirror: bool,
+}
+
+impl ConfigArgs {
+    /// Update `config` from these arguments, and return true if anything was
+    /// given.
+    fn apply(&self, config: &mut ArchiveConfig) -> bool {
+        let mut changed = false;
+        if let Some(compression) = self.compression {
+            config.compression = Some(compression);
+            changed = true;
+        }
+        if let Some(compression) = self.index_compression {
+            config.index_compression = Some(compression);
+            changed = true;
+        }
+        if let Some(level) = self.compression_level {
+            config.compression_level = Some(level);
+            changed = true;
+        }
+        if let Some(block_size) = self.block_size {
+            config.block_size = Some(block_size);
+            changed = true;
+        }
+        if self.clear_excludes {
+            config.excludes.clear();
+            changed = true;
+        }
+        for exclude in &self.exclude {
+            if !config.excludes.contains(exclude) {
+                config.excludes.push(exclude.clone());
+            }
+            changed = true;
+        }
+        if self.append_only || self.no_append_only {
+            config.append_only = self.append_only;
+            changed = true;
+        }
+        if self.delta_index || self.no_delta_index {
+            config.delta_index = self.delta_index;
+            changed = true;
+        }
+        if self.mirror.is_some() || self.no_mirror {
+            // Backups might run from another directory, so a relative path
+            // is stored as absolute.
+            config.mirror = self.mirror.as_deref().map(absolute_location);
+            changed = true;
+        }
+        changed
+    }
+}
+
+/// Make a location that's a relative path absolute, from the current directory.
+fn absolute_location(location: &str) -> String {
+    match location.parse::<Location>() {
+        Ok(Location::Local(path)) if path.is_relative() => std::env::current_dir()
+            .map(|cwd| cwd.join(path).to_string_lossy().into_owned())
+            .unwrap_or_else(|_| location.to_owned()),
+        _ => location.to_owned(),
+    }
 }
 
 /// Show debugging information.
 #[derive(Debug, StructOpt)]
 enum Debug {
-    /// Dump the index as json.
+    /// Dump the index as json, with one entry per line.
     Index {
         /// Path of the archive to read.
         archive: PathBuf,
 
         /// Backup version number.
         #[structopt(long, short)]
-        backup: Option<BandId>,
+        backup: Option<String>,
+
+        /// Write one indented json array, rather than an entry per line.
+        #[structopt(long)]
+        pretty: bool,
+
+        /// Show only the entries in this hunk of the index, numbered from 0.
+        #[structopt(long)]
+        hunk: Option<u32>,
     },
 
     /// List all blocks.
@@ -180,29 +753,119 @@ enum Debug {
     Unreferenced { archive: PathBuf },
 }
 
+/// Process exit codes, described in the `--help` text.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 enum ExitCode {
     Ok = 0,
     Failed = 1,
-    PartialCorruption = 2,
+    VerificationFailed = 2,
+    CompletedWithWarnings = 3,
+    TransportError = 4,
+    UsageError = 5,
+}
+
+impl ExitCode {
+    /// The exit code for a command that failed with `error`.
+    fn from_error(error: &Error) -> ExitCode {
+        match error.category() {
+            ErrorCategory::Usage => ExitCode::UsageError,
+            ErrorCategory::Corruption => ExitCode::VerificationFailed,
+            ErrorCategory::Transport => ExitCode::TransportError,
+            ErrorCategory::Other => ExitCode::Failed,
+        }
+    }
+
+    /// The exit code for a command that finished, but counted `errors`
+    /// entries it couldn't handle.
+    fn from_error_count(errors: usize) -> ExitCode {
+        if errors > 0 {
+            ExitCode::CompletedWithWarnings
+        } else {
+            ExitCode::Ok
+        }
+    }
 }
 
 impl Command {
-    fn run(&self) -> Result<ExitCode> {
+    /// True if the command writes data, such as json stats or a tar stream,
+    /// to stdout, so messages should be kept out of the way.
+    fn stdout_is_data(&self) -> bool {
+        match self {
+            Command::Cat { .. } => true,
+            Command::ExportTar { output, .. } => output.is_none(),
+            Command::Backup { stats_json, .. }
+            | Command::Gc { stats_json, .. }
+            | Command::Restore { stats_json, .. } => is_stdout(stats_json),
+            Command::Validate {
+                json, stats_json, ..
+            } => *json || is_stdout(stats_json),
+            _ => false,
+        }
+    }
+
+    /// Run the command, printing the names of copied files if `print_filenames`
+    /// is set, and showing only problems if `quiet` is set.
+    fn run(&self, print_filenames: bool, quiet: bool) -> Result<ExitCode> {
         let mut stdout = std::io::stdout();
         match self {
             Command::Backup {
                 archive,
                 source,
-                verbose,
                 exclude,
+                exclude_caches,
+                exclude_if_present,
+                resume,
+                dry_run,
+                threads,
+                one_file_system,
+                pre_hook,
+                post_hook,
+                changed_file_retries,
+                hook_failure,
+                tag,
+                stats_json,
             } => {
+                let archive = Archive::open_path(archive)?;
                 let options = BackupOptions {
-                    print_filenames: *verbose,
-                    excludes: excludes::from_strings(exclude)?,
+                    print_filenames,
+                    excludes: archive.config().excludes_with(exclude)?,
+                    exclude_caches: *exclude_caches,
+                    exclude_if_present: exclude_if_present.clone(),
+                    resume: *resume,
+                    dry_run: *dry_run,
+                    threads: *threads,
+                    one_file_system: *one_file_system,
+                    pre_backup_hook: pre_hook.clone(),
+                    post_backup_hook: post_hook.clone(),
+                    hook_failure: *hook_failure,
+                    changed_file_retries: *changed_file_retries,
+                    tags: tag.clone(),
+                    progress: Some(terminal_progress()),
+                };
+                let copy_stats = if let [source] = source.as_slice() {
+                    archive.backup(source, &options)?
+                } else {
+                    archive.backup_roots(source, &options)?
                 };
-                let copy_stats = Archive::open_path(archive)?.backup(source, &options)?;
+                if !is_stdout(stats_json) {
+                    if *dry_run {
+                        ui::println("Backup dry run complete; nothing was written.");
+                    } else {
+                        ui::println("Backup complete.");
+                    }
+                    copy_stats.backup().summarize(&mut stdout)?;
+                }
+                if let Some(path) = stats_json {
+                    write_stats_json(&copy_stats.backup(), path)?;
+                }
+                return Ok(ExitCode::from_error_count(copy_stats.errors));
+            }
+            Command::BackupStdin { archive, name } => {
+                let copy_stats =
+                    Archive::open_path(archive)?.backup_stream(name, &mut std::io::stdin())?;
                 ui::println("Backup complete.");
-                copy_stats.summarize_backup(&mut stdout);
+                copy_stats.backup().summarize(&mut stdout)?;
+                return Ok(ExitCode::from_error_count(copy_stats.errors));
             }
             Command::Debug(Debug::Blocks { archive }) => {
                 let mut bw = BufWriter::new(stdout);
@@ -210,9 +873,19 @@ impl Command {
                     writeln!(bw, "{}", hash)?;
                 }
             }
-            Command::Debug(Debug::Index { archive, backup }) => {
+            Command::Debug(Debug::Index {
+                archive,
+                backup,
+                pretty,
+                hunk,
+            }) => {
                 let st = stored_tree_from_opt(archive, &backup, &Vec::new())?;
-                output::show_index_json(&st.band(), &mut stdout)?;
+                if let Some(hunk) = hunk {
+                    let entries = st.band().index().read_hunk(*hunk)?.unwrap_or_default();
+                    output::show_index_json(entries.into_iter(), *pretty, &mut stdout)?;
+                } else {
+                    output::show_index_json(st.band().iter_entries()?, *pretty, &mut stdout)?;
+                }
             }
             Command::Debug(Debug::Referenced { archive }) => {
                 let mut bw = BufWriter::new(stdout);
@@ -232,120 +905,553 @@ impl Command {
                 dry_run,
                 no_gc,
                 break_lock,
+                allow_delete,
             } => {
-                let stats = Archive::open_path(archive)?.delete_bands(
-                    &backup,
+                let archive = Archive::open_path_with_allow_delete(archive, *allow_delete)?;
+                let band_ids = backup
+                    .iter()
+                    .map(|name| archive.find_band(name))
+                    .collect::<Result<Vec<BandId>>>()?;
+                let stats = archive.delete_bands(
+                    &band_ids,
                     &DeleteOptions {
                         dry_run: *dry_run,
                         break_lock: *break_lock,
                         no_gc: *no_gc,
+                        progress: Some(terminal_progress()),
                     },
                 )?;
-                ui::println(&format!("{:#?}", stats));
+                stats.summarize(&mut stdout)?;
+                if *dry_run {
+                    ui::println("Dry run: nothing was deleted.");
+                }
+                return Ok(ExitCode::from_error_count(stats.deletion_errors));
+            }
+            Command::Sync {
+                archive,
+                destination,
+                dry_run,
+            } => {
+                let stats = sync_archive(
+                    &Archive::open_path(archive)?,
+                    destination.parse::<Location>()?.open()?,
+                    &SyncOptions {
+                        dry_run: *dry_run,
+                        progress: Some(terminal_progress()),
+                    },
+                )?;
+                stats.summarize(&mut stdout)?;
+                if *dry_run {
+                    ui::println("Dry run: nothing was copied.");
+                }
+            }
+            Command::Migrate {
+                archive,
+                dry_run,
+                allow_delete,
+            } => {
+                let stats = migrate_archive(
+                    &Archive::open_path_with_allow_delete(archive, *allow_delete)?,
+                    &MigrateOptions {
+                        dry_run: *dry_run,
+                        progress: Some(terminal_progress()),
+                    },
+                )?;
+                stats.summarize(&mut stdout)?;
+                if *dry_run {
+                    ui::println("Dry run: nothing was changed.");
+                }
             }
             Command::Diff {
                 archive,
                 source,
                 backup,
                 exclude,
+                stat,
+                include_unchanged,
+            } => {
+                let options = DiffOptions {
+                    include_unchanged: *include_unchanged,
+                };
+                let mut diff = match (source, backup.as_slice()) {
+                    (None, [old, new]) => {
+                        let archive = Archive::open_path(archive)?;
+                        let excludes = excludes::from_strings(exclude)?;
+                        let old = archive
+                            .open_stored_tree(BandSelectionPolicy::Specified(
+                                archive.find_band(old)?,
+                            ))?
+                            .with_excludes(excludes.clone());
+                        let new = archive
+                            .open_stored_tree(BandSelectionPolicy::Specified(
+                                archive.find_band(new)?,
+                            ))?
+                            .with_excludes(excludes);
+                        diff_stored_trees(&old, &new, &options)?
+                    }
+                    (Some(source), []) | (Some(source), [_]) => {
+                        let st = stored_tree_from_opt(archive, &backup.first().cloned(), exclude)?;
+                        let lt = live_tree_from_opt(source, exclude)?;
+                        diff_live_tree(&st, &lt, &options)?
+                    }
+                    _ => return Err(Error::InvalidDiffArguments),
+                };
+                if *stat {
+                    let mut stats = DiffStats::default();
+                    diff.for_each(|entry| entry.add_to_stats(&mut stats));
+                    stats.summarize(&mut stdout)?;
+                } else {
+                    output::show_diff(&mut diff, &mut stdout)?;
+                }
+            }
+            Command::Verify {
+                archive,
+                source,
+                backup,
+                exclude,
+                content,
             } => {
-                // TODO: Consider whether the actual files have changed.
-                // TODO: Summarize diff.
-                // TODO: Optionally include unchanged files.
-                let excludes = excludes::from_strings(exclude)?;
                 let st = stored_tree_from_opt(archive, backup, exclude)?;
-                let lt = LiveTree::open(source)?.with_excludes(excludes);
-                output::show_tree_diff(&mut conserve::iter_merged_entries(&st, &lt)?, &mut stdout)?;
+                let lt = live_tree_from_opt(source, exclude)?;
+                let mut bw = BufWriter::new(stdout.lock());
+                let stats = verify_tree(
+                    &st,
+                    &lt,
+                    &VerifyOptions { content: *content },
+                    &mut |apath, change| {
+                        writeln!(bw, "{:<8} {}", change, apath).expect("write change");
+                    },
+                )?;
+                bw.flush()?;
+                drop(bw);
+                stats.summarize(&mut stdout)?;
+                if stats.has_changes() || stats.errors > 0 {
+                    ui::problem("Source differs from the backup.");
+                    return Ok(ExitCode::VerificationFailed);
+                } else {
+                    ui::println("Source matches the backup.");
+                }
             }
             Command::Gc {
                 archive,
                 dry_run,
                 break_lock,
+                allow_delete,
+                stats_json,
             } => {
-                let archive = Archive::open_path(archive)?;
+                let archive = Archive::open_path_with_allow_delete(archive, *allow_delete)?;
                 let stats = archive.delete_unreferenced(&DeleteOptions {
                     dry_run: *dry_run,
                     break_lock: *break_lock,
                     no_gc: false,
+                    progress: Some(terminal_progress()),
                 })?;
-                ui::println(&format!("{:#?}", stats));
+                if !is_stdout(stats_json) {
+                    stats.summarize(&mut stdout)?;
+                    if *dry_run {
+                        ui::println("Dry run: nothing was deleted.");
+                    }
+                }
+                if let Some(path) = stats_json {
+                    write_stats_json(&stats, path)?;
+                }
+                return Ok(ExitCode::from_error_count(stats.deletion_errors));
             }
-            Command::Init { archive } => {
-                Archive::create_path(&archive)?;
+            Command::Incomplete {
+                archive,
+                seal,
+                delete,
+                allow_delete,
+            } => {
+                let action = if *seal {
+                    IncompleteBandAction::Seal
+                } else if *delete {
+                    IncompleteBandAction::Delete
+                } else {
+                    IncompleteBandAction::Report
+                };
+                let bands = Archive::open_path_with_allow_delete(archive, *allow_delete)?
+                    .fix_incomplete_bands(action)?;
+                output::show_incomplete_bands(&bands, &mut stdout)?;
+                if bands.is_empty() {
+                    ui::println("No incomplete backups.");
+                } else if action == IncompleteBandAction::Seal {
+                    ui::println(&format!("Sealed {} backups as partial.", bands.len()));
+                } else if action == IncompleteBandAction::Delete {
+                    ui::println(&format!("Deleted {} incomplete backups.", bands.len()));
+                }
+            }
+            Command::Init { archive, config } => {
+                let new_archive = Archive::create_path(&archive)?;
+                let mut archive_config = ArchiveConfig::default();
+                if config.apply(&mut archive_config) {
+                    new_archive.set_config(&archive_config)?;
+                }
                 ui::println(&format!("Created new archive in {:?}", &archive));
             }
-            Command::Ls { stos } => {
+            Command::Tag {
+                archive,
+                backup,
+                tags,
+                remove,
+                allow_delete,
+            } => {
+                let archive = Archive::open_path_with_allow_delete(archive, *allow_delete)?;
+                let band_id = archive.find_band(backup)?;
+                if *remove {
+                    archive.remove_tags(&band_id, tags)?;
+                } else {
+                    archive.add_tags(&band_id, tags)?;
+                }
+            }
+            Command::Config {
+                archive,
+                config,
+                allow_delete,
+            } => {
+                let mut opened = Archive::open_path_with_allow_delete(archive, *allow_delete)?;
+                let mut archive_config = opened.config().clone();
+                if config.apply(&mut archive_config) {
+                    opened.set_config(&archive_config)?;
+                    // Reopen to show the new settings and any features they enabled.
+                    opened = Archive::open_path(archive)?;
+                }
+                output::show_config(&opened, &mut stdout)?;
+            }
+            Command::BreakLock { archive } => {
+                let archive = Archive::open_path(archive)?;
+                if archive.is_locked()? {
+                    archive.remove_lock()?;
+                    ui::println("Lock removed.");
+                } else {
+                    ui::println("Archive is not locked.");
+                }
+            }
+            Command::Cat {
+                archive,
+                apath,
+                backup,
+            } => {
+                stored_tree_from_opt(archive, backup, &[])?
+                    .open_file(apath)?
+                    .write_to(&mut stdout.lock())?;
+            }
+            Command::ExportTar {
+                archive,
+                backup,
+                output,
+                compression,
+                exclude,
+            } => {
+                let st = stored_tree_from_opt(archive, backup, exclude)?;
+                let options = ExportTarOptions {
+                    compression: *compression,
+                };
+                if let Some(output) = output {
+                    let file = std::fs::File::create(output)?;
+                    export_tar(&st, BufWriter::new(file), &options)?.summarize(&mut stdout)?;
+                } else {
+                    export_tar(&st, BufWriter::new(stdout.lock()), &options)?;
+                }
+            }
+            Command::ImportTar {
+                archive,
+                file,
+                exclude,
+                tag,
+            } => {
+                let archive = Archive::open_path(archive)?;
+                let options = BackupOptions {
+                    print_filenames,
+                    excludes: archive.config().excludes_with(exclude)?,
+                    tags: tag.clone(),
+                    progress: Some(terminal_progress()),
+                    ..BackupOptions::default()
+                };
+                let copy_stats = archive.import_tar(file, &options)?;
+                ui::println("Import complete.");
+                copy_stats.backup().summarize(&mut stdout)?;
+                return Ok(ExitCode::from_error_count(copy_stats.errors));
+            }
+            #[cfg(all(unix, feature = "fuse"))]
+            Command::Mount {
+                archive,
+                mountpoint,
+            } => {
+                conserve::mount::mount(&Archive::open_path(archive)?, mountpoint)?;
+            }
+            Command::Info { archive, json } => {
+                let info = Archive::open_path(archive)?.info(Some(&terminal_progress()))?;
+                if *json {
+                    info.summarize_json(&mut stdout)?;
+                } else {
+                    info.summarize(&mut stdout)?;
+                }
+            }
+            Command::Log {
+                archive,
+                apath,
+                json,
+            } => {
+                let history = file_history(&Archive::open_path(archive)?, apath)?;
+                if *json {
+                    output::show_json_file_history(&history, &mut stdout)?;
+                } else {
+                    output::show_file_history(&history, &mut stdout)?;
+                }
+            }
+            Command::Ls {
+                stos,
+                path,
+                include,
+                long,
+                json,
+                null,
+            } => {
+                let format = if *long {
+                    ListFormat::Long
+                } else if *json {
+                    ListFormat::Json
+                } else if *null {
+                    ListFormat::Null
+                } else {
+                    ListFormat::Names
+                };
+                let path = path.clone().unwrap_or_else(|| "/".into());
+                let includes = excludes::from_strings(include)?;
                 if let Some(archive) = &stos.archive {
-                    output::show_tree_names(
-                        &stored_tree_from_opt(archive, &stos.backup, &stos.exclude)?,
+                    let st = stored_tree_from_opt(archive, &stos.backup, &stos.exclude)?;
+                    output::show_entry_list(
+                        &mut filter_entries(st.iter_subtree_entries(&path)?, includes),
+                        format,
                         &mut stdout,
                     )?;
                 } else {
-                    output::show_tree_names(
-                        &live_tree_from_opt(stos.source.as_ref().unwrap(), &stos.exclude)?,
+                    let lt = live_tree_from_opt(stos.source.as_ref().unwrap(), &stos.exclude)?;
+                    output::show_entry_list(
+                        &mut filter_entries(lt.iter_subtree_entries(&path)?, includes),
+                        format,
                         &mut stdout,
                     )?;
                 }
             }
+            Command::Prune {
+                archive,
+                keep_last,
+                keep_daily,
+                keep_weekly,
+                keep_monthly,
+                dry_run,
+                break_lock,
+                no_gc,
+                allow_delete,
+            } => {
+                let policy = RetentionPolicy {
+                    keep_last: *keep_last,
+                    keep_daily: *keep_daily,
+                    keep_weekly: *keep_weekly,
+                    keep_monthly: *keep_monthly,
+                };
+                let (retention, stats) =
+                    Archive::open_path_with_allow_delete(archive, *allow_delete)?.prune(
+                        &policy,
+                        &DeleteOptions {
+                            dry_run: *dry_run,
+                            break_lock: *break_lock,
+                            no_gc: *no_gc,
+                            progress: Some(terminal_progress()),
+                        },
+                    )?;
+                output::show_retention(&retention, &mut stdout)?;
+                stats.summarize(&mut stdout)?;
+                if *dry_run {
+                    ui::println("Dry run: nothing was deleted.");
+                }
+                return Ok(ExitCode::from_error_count(stats.deletion_errors));
+            }
             Command::Restore {
                 archive,
                 destination,
                 backup,
-                verbose,
+                incomplete,
                 force_overwrite,
+                skip_existing,
+                only_newer,
+                update,
+                delete,
+                dry_run,
                 exclude,
                 only_subtree,
+                no_permissions,
+                numeric_owner,
+                no_owner,
+                threads,
+                resume,
+                stats_json,
             } => {
-                let band_selection = band_selection_policy_from_opt(backup);
                 let archive = Archive::open_path(archive)?;
+                let band_selection = band_selection_policy_from_opt(&archive, backup)?;
 
                 let options = RestoreOptions {
-                    print_filenames: *verbose,
-                    excludes: excludes::from_strings(exclude)?,
+                    print_filenames,
+                    excludes: archive.config().excludes_with(exclude)?,
                     only_subtree: only_subtree.clone(),
                     band_selection,
-                    overwrite: *force_overwrite,
+                    dry_run: *dry_run,
+                    allow_incomplete: *incomplete,
+                    overwrite: if *skip_existing {
+                        OverwritePolicy::SkipExisting
+                    } else if *only_newer {
+                        OverwritePolicy::OnlyNewer
+                    } else if *update {
+                        OverwritePolicy::Update
+                    } else if *force_overwrite {
+                        OverwritePolicy::Force
+                    } else {
+                        OverwritePolicy::Refuse
+                    },
+                    delete: *delete,
+                    restore_permissions: !*no_permissions,
+                    ownership: if *no_owner {
+                        OwnershipPolicy::Skip
+                    } else if *numeric_owner {
+                        OwnershipPolicy::Numeric
+                    } else {
+                        OwnershipPolicy::ByName
+                    },
+                    threads: *threads,
+                    resume: *resume,
+                    progress: Some(terminal_progress()),
                 };
 
-                let copy_stats = archive.restore(&destination, &options)?;
-                ui::println("Restore complete.");
-                copy_stats.summarize_restore(&mut stdout)?;
+                let copy_stats =
+                    if let BandSelectionPolicy::Specified(band_id) = &options.band_selection {
+                        archive.restore_band(band_id, &destination, &options)?
+                    } else {
+                        archive.restore(&destination, &options)?
+                    };
+                if !is_stdout(stats_json) {
+                    if *dry_run {
+                        ui::println("Restore dry run complete; nothing was written.");
+                    } else {
+                        ui::println("Restore complete.");
+                    }
+                    copy_stats.restore().summarize(&mut stdout)?;
+                }
+                if let Some(path) = stats_json {
+                    write_stats_json(&copy_stats.restore(), path)?;
+                }
+                return Ok(ExitCode::from_error_count(copy_stats.errors));
+            }
+            Command::Du {
+                archive,
+                backup,
+                exclude,
+                max_depth,
+                bytes,
+            } => {
+                let st = stored_tree_from_opt(archive, backup, exclude)?;
+                output::show_dir_usage(&dir_usage(&st, *max_depth)?, *bytes, &mut stdout)?;
             }
             Command::Size { ref stos } => {
                 let size = if let Some(archive) = &stos.archive {
                     stored_tree_from_opt(archive, &stos.backup, &stos.exclude)?
-                        .size()?
+                        .size(Some(&terminal_progress()))?
                         .file_bytes
                 } else {
                     live_tree_from_opt(stos.source.as_ref().unwrap(), &stos.exclude)?
-                        .size()?
+                        .size(Some(&terminal_progress()))?
                         .file_bytes
                 };
                 ui::println(&conserve::bytes_to_human_mb(size));
             }
-            Command::Validate { archive } => {
-                let stats = Archive::open_path(archive)?.validate()?;
-                stats.summarize(&mut stdout)?;
-                if stats.has_problems() {
-                    ui::problem("Archive has some problems.");
-                    return Ok(ExitCode::PartialCorruption);
+            Command::Validate {
+                archive,
+                quick,
+                json,
+                stats_json,
+            } => {
+                let stats = Archive::open_path(archive)?.validate(&ValidateOptions {
+                    quick: *quick,
+                    progress: Some(terminal_progress()),
+                })?;
+                if let Some(path) = stats_json {
+                    write_stats_json(&stats, path)?;
+                }
+                if *json || is_stdout(stats_json) {
+                    if *json {
+                        stats.summarize_json(&mut stdout)?;
+                    }
+                    if stats.has_problems() {
+                        return Ok(ExitCode::VerificationFailed);
+                    }
                 } else {
-                    ui::println("Archive is OK.");
+                    stats.summarize(&mut stdout)?;
+                    if stats.has_problems() {
+                        ui::problem("Archive has some problems.");
+                        return Ok(ExitCode::VerificationFailed);
+                    } else {
+                        ui::println("Archive is OK.");
+                    }
                 }
             }
             Command::Versions {
                 archive,
                 short,
                 sizes,
+                json,
             } => {
                 ui::enable_progress(false);
                 let archive = Archive::open_path(archive)?;
-                if *short {
+                if *short || quiet {
                     output::show_brief_version_list(&archive, &mut stdout)?;
+                } else if *json {
+                    output::show_json_version_list(&archive, *sizes, &mut stdout)?;
                 } else {
                     output::show_verbose_version_list(&archive, *sizes, &mut stdout)?;
                 }
             }
+            Command::Watch {
+                archive,
+                source,
+                interval,
+                quiet_period,
+                exclude,
+                exclude_caches,
+                exclude_if_present,
+                threads,
+                one_file_system,
+                keep_last,
+                keep_daily,
+                keep_weekly,
+                keep_monthly,
+                max_backups,
+            } => {
+                let archive = Archive::open_path(archive)?;
+                let options = WatchOptions {
+                    backup: BackupOptions {
+                        excludes: archive.config().excludes_with(exclude)?,
+                        exclude_caches: *exclude_caches,
+                        exclude_if_present: exclude_if_present.clone(),
+                        threads: *threads,
+                        one_file_system: *one_file_system,
+                        progress: Some(terminal_progress()),
+                        ..BackupOptions::default()
+                    },
+                    interval: *interval,
+                    quiet_period: *quiet_period,
+                    retention: Some(RetentionPolicy {
+                        keep_last: *keep_last,
+                        keep_daily: *keep_daily,
+                        keep_weekly: *keep_weekly,
+                        keep_monthly: *keep_monthly,
+                    }),
+                    max_backups: *max_backups,
+                };
+                let stats = watch(&archive, source, &options)?;
+                stats.summarize(&mut stdout)?;
+                return Ok(ExitCode::from_error_count(stats.failed_backups));
+            }
         }
         Ok(ExitCode::Ok)
     }
@@ -353,21 +1459,57 @@ impl Command {
 
 fn stored_tree_from_opt(
     archive: &Path,
-    backup: &Option<BandId>,
+    backup: &Option<String>,
     exclude: &[String],
 ) -> Result<StoredTree> {
     let archive = Archive::open_path(archive)?;
-    let policy = band_selection_policy_from_opt(backup);
+    let policy = band_selection_policy_from_opt(&archive, backup)?;
     Ok(archive
         .open_stored_tree(policy)?
         .with_excludes(excludes::from_strings(exclude)?))
 }
 
-fn band_selection_policy_from_opt(backup: &Option<BandId>) -> BandSelectionPolicy {
-    if let Some(band_id) = backup {
-        BandSelectionPolicy::Specified(band_id.clone())
+/// Select the band named by a backup id or tag, or by default the latest.
+fn band_selection_policy_from_opt(
+    archive: &Archive,
+    backup: &Option<String>,
+) -> Result<BandSelectionPolicy> {
+    Ok(if let Some(name) = backup {
+        BandSelectionPolicy::Specified(archive.find_band(name)?)
     } else {
         BandSelectionPolicy::Latest
+    })
+}
+
+/// Keep only entries matching any of `includes`, or all entries if it's empty.
+fn filter_entries<E: Entry>(
+    entries: Box<dyn Iterator<Item = E>>,
+    includes: GlobSet,
+) -> impl Iterator<Item = E> {
+    entries.filter(move |entry| includes.is_empty() || includes.is_match(entry.apath()))
+}
+
+/// Draw progress on the terminal, as set by `--progress`.
+fn terminal_progress() -> Arc<dyn ProgressSink> {
+    Arc::new(ui::TerminalProgress)
+}
+
+/// True if `--stats-json` asks for the stats on stdout, in place of the
+/// usual summary.
+fn is_stdout(stats_json: &Option<PathBuf>) -> bool {
+    stats_json.as_deref() == Some(Path::new("-"))
+}
+
+/// Write `stats` as json to `path`, or to stdout if it's `-`.
+fn write_stats_json<S: Stats>(stats: &S, path: &Path) -> Result<()> {
+    if path == Path::new("-") {
+        stats.summarize_json(&mut std::io::stdout())
+    } else {
+        let mut file = std::fs::File::create(path).map_err(|source| Error::WriteStatsJson {
+            path: path.to_owned(),
+            source,
+        })?;
+        stats.summarize_json(&mut file)
     }
 }
 
@@ -376,8 +1518,29 @@ fn live_tree_from_opt(source: &Path, exclude: &[String]) -> Result<LiveTree> {
 }
 
 fn main() {
-    ui::enable_progress(true);
-    let result = Command::from_args().run();
+    let args = match Args::from_args_safe() {
+        Ok(args) => args,
+        Err(err) if err.use_stderr() => {
+            eprintln!("{}", err.message);
+            std::process::exit(ExitCode::UsageError as i32)
+        }
+        // Help and version requests.
+        Err(err) => err.exit(),
+    };
+    let stdout_is_data = args.command.stdout_is_data();
+    // While stdout is reserved for data, messages are still shown on stderr.
+    let log_level = match (args.quiet, args.verbose) {
+        (true, _) => log::LevelFilter::Warn,
+        (false, 0..=1) => log::LevelFilter::Info,
+        (false, 2) => log::LevelFilter::Debug,
+        (false, _) => log::LevelFilter::Trace,
+    };
+    ui::set_progress_mode(args.progress);
+    if stdout_is_data {
+        ui::reserve_stdout();
+    }
+    let result = ui::install_logger(log_level, args.log_file.as_deref())
+        .and_then(|()| args.command.run(args.verbose > 0, args.quiet));
     match result {
         Err(ref e) => {
             ui::show_error(e);
@@ -388,7 +1551,7 @@ fn main() {
             //     }
             // }
             // Avoid Rust redundantly printing the error.
-            std::process::exit(ExitCode::Failed as i32)
+            std::process::exit(ExitCode::from_error(e) as i32)
         }
         Ok(code) => std::process::exit(code as i32),
     }
@@ -21,29 +21,57 @@
 //!
 //! The structure is: archive > blockdir > subdir > file.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
 use std::io;
 use std::io::prelude::*;
+use std::ops::Range;
 use std::path::Path;
 use std::sync::Mutex;
+use std::time::Instant;
 
 use blake2_rfc::blake2b;
 use blake2_rfc::blake2b::Blake2b;
+use lazy_static::lazy_static;
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use thousands::Separable;
 
 use crate::blockhash::BlockHash;
 use crate::compress::snappy::{Compressor, Decompressor};
+use crate::compress::{zstandard, Compression};
+use crate::errors::ErrorChain;
 use crate::kind::Kind;
-use crate::stats::{CopyStats, Sizes, ValidateStats};
+use crate::stats::{CopyStats, PhaseTimes, Sizes, ValidateStats};
 use crate::transport::local::LocalTransport;
 use crate::transport::{DirEntry, ListDirNames, Transport};
 use crate::*;
 
 const BLOCKDIR_FILE_NAME_LEN: usize = crate::BLAKE_HASH_SIZE_BYTES * 2;
 
+lazy_static! {
+    /// Hash of a full-size block of zeros, which represents holes in sparse files.
+    static ref ZERO_BLOCK_HASH: BlockHash = hash_bytes(&vec![0; MAX_BLOCK_SIZE]).unwrap();
+}
+
+/// Return the ranges of a file, in order, stored as addresses of the block of
+/// zeros that represents holes.
+pub(crate) fn hole_ranges(addrs: &[Address]) -> Vec<Range<u64>> {
+    let mut holes: Vec<Range<u64>> = Vec::new();
+    let mut pos = 0;
+    for addr in addrs {
+        let end = pos + addr.len;
+        if addr.hash == *ZERO_BLOCK_HASH {
+            match holes.last_mut() {
+                Some(last) if last.end == pos => last.end = end,
+                _ => holes.push(pos..end),
+            }
+        }
+        pos = end;
+    }
+    holes
+}
+
 /// Take this many characters from the block hash to form the subdirectory name.
 const SUBDIR_NAME_CHARS: usize = 3;
 
@@ -69,6 +97,12 @@ pub struct Address {
 #[derive(Clone, Debug)]
 pub struct BlockDir {
     transport: Box<dyn Transport>,
+    /// How new blocks are compressed.
+    compression: Compression,
+    /// Zstandard compression level for new blocks.
+    zstd_level: i32,
+    /// New blocks hold at most this many uncompressed bytes.
+    block_size: usize,
 }
 
 /// Returns the transport-relative subdirectory name.
@@ -88,7 +122,21 @@ impl BlockDir {
     }
 
     pub fn open(transport: Box<dyn Transport>) -> BlockDir {
-        BlockDir { transport }
+        BlockDir {
+            transport,
+            compression: Compression::Snappy,
+            zstd_level: zstandard::DEFAULT_LEVEL,
+            block_size: MAX_BLOCK_SIZE,
+        }
+    }
+
+    /// Return a BlockDir with the same settings, accessing blocks through
+    /// `transport`.
+    pub(crate) fn with_transport(&self, transport: Box<dyn Transport>) -> BlockDir {
+        BlockDir {
+            transport,
+            ..self.clone()
+        }
     }
 
     /// Create a BlockDir directory and return an object accessing it.
@@ -100,15 +148,69 @@ impl BlockDir {
         transport
             .create_dir("")
             .map_err(|source| Error::CreateBlockDir { source })?;
-        Ok(BlockDir { transport })
+        Ok(BlockDir {
+            transport,
+            compression: Compression::Snappy,
+            zstd_level: zstandard::DEFAULT_LEVEL,
+            block_size: MAX_BLOCK_SIZE,
+        })
+    }
+
+    /// Compress blocks written from now on with `compression`.
+    pub(crate) fn with_compression(self, compression: Compression) -> BlockDir {
+        BlockDir {
+            compression,
+            ..self
+        }
+    }
+
+    /// Use this level for blocks written from now on with Zstandard.
+    pub(crate) fn with_zstd_level(self, zstd_level: i32) -> BlockDir {
+        BlockDir { zstd_level, ..self }
+    }
+
+    /// Break files into blocks of at most `block_size` bytes, which must be
+    /// no more than `MAX_BLOCK_SIZE`.
+    pub(crate) fn with_block_size(self, block_size: usize) -> BlockDir {
+        assert!(block_size > 0 && block_size <= MAX_BLOCK_SIZE);
+        BlockDir { block_size, ..self }
     }
 
     /// Returns the number of compressed bytes.
-    fn compress_and_store(&mut self, in_buf: &[u8], hash: &BlockHash) -> Result<u64> {
-        // TODO: Move this to a BlockWriter, which can hold a reusable buffer.
-        let mut compressor = Compressor::new();
-        let compressed = compressor.compress(&in_buf)?;
-        let comp_len: u64 = compressed.len().try_into().unwrap();
+    ///
+    /// Time spent compressing and writing is added to `times`.
+    fn compress_and_store(
+        &mut self,
+        in_buf: &[u8],
+        hash: &BlockHash,
+        times: &mut PhaseTimes,
+    ) -> Result<u64> {
+        let start = Instant::now();
+        let comp_len: u64 = match self.compression {
+            Compression::Snappy => {
+                // TODO: Move this to a BlockWriter, which can hold a reusable buffer.
+                let mut compressor = Compressor::new();
+                let compressed = compressor.compress(&in_buf)?;
+                let compressed_at = Instant::now();
+                times.compression += compressed_at - start;
+                self.write_compressed(hash, compressed)?;
+                times.block_writes += compressed_at.elapsed();
+                compressed.len().try_into().unwrap()
+            }
+            Compression::Zstd => {
+                let compressed = zstandard::compress(in_buf, self.zstd_level)?;
+                let compressed_at = Instant::now();
+                times.compression += compressed_at - start;
+                self.write_compressed(hash, &compressed)?;
+                times.block_writes += compressed_at.elapsed();
+                compressed.len().try_into().unwrap()
+            }
+        };
+        Ok(comp_len)
+    }
+
+    /// Write an already-compressed block.
+    fn write_compressed(&self, hash: &BlockHash, compressed: &[u8]) -> Result<()> {
         let hex_hash = hash.to_string();
         let relpath = block_relpath(hash);
         self.transport.create_dir(subdir_relpath(&hex_hash))?;
@@ -117,10 +219,7 @@ impl BlockDir {
             .or_else(|io_err| {
                 if io_err.kind() == io::ErrorKind::AlreadyExists {
                     // Perhaps it was simultaneously created by another thread or process.
-                    ui::problem(&format!(
-                        "Unexpected late detection of existing block {:?}",
-                        hex_hash
-                    ));
+                    log::error!("Unexpected late detection of existing block {:?}", hex_hash);
                     Ok(())
                 } else {
                     Err(Error::WriteBlock {
@@ -128,8 +227,63 @@ impl BlockDir {
                         source: io_err,
                     })
                 }
+            })
+    }
+
+    /// Copy a block into another block directory without recompressing it,
+    /// after checking that its content matches its hash.
+    ///
+    /// Returns the number of compressed bytes copied.
+    pub(crate) fn copy_block_to(&self, hash: &BlockHash, dest: &BlockDir) -> Result<u64> {
+        let compressed_bytes = self.read_compressed(hash)?;
+        check_block_hash(hash, &decompress_block(&compressed_bytes)?)?;
+        dest.write_compressed(hash, &compressed_bytes)?;
+        Ok(compressed_bytes.len() as u64)
+    }
+
+    /// True if the block is compressed with Zstandard.
+    pub(crate) fn is_zstd_compressed(&self, hash: &BlockHash) -> Result<bool> {
+        Ok(zstandard::is_zstd(&self.read_compressed(hash)?))
+    }
+
+    /// Rewrite a block compressed with Zstandard, unless it already is, after
+    /// checking that its content matches its hash.
+    ///
+    /// The block file is replaced atomically, so concurrent readers see
+    /// either the old or the new file, which have the same content.
+    ///
+    /// Returns the old and new compressed sizes, or None if the block was
+    /// already compressed with Zstandard.
+    pub(crate) fn recompress_block_zstd(&self, hash: &BlockHash) -> Result<Option<(u64, u64)>> {
+        let old_compressed = self.read_compressed(hash)?;
+        if zstandard::is_zstd(&old_compressed) {
+            return Ok(None);
+        }
+        let content = decompress_block(&old_compressed)?;
+        check_block_hash(hash, &content)?;
+        let new_compressed = zstandard::compress(&content, self.zstd_level)?;
+        self.transport
+            .write_file(&block_relpath(hash), &new_compressed)
+            .map_err(|source| Error::WriteBlock {
+                hash: hash.to_string(),
+                source,
             })?;
-        Ok(comp_len)
+        Ok(Some((
+            old_compressed.len() as u64,
+            new_compressed.len() as u64,
+        )))
+    }
+
+    /// Read the compressed content of a block, without checking it.
+    fn read_compressed(&self, hash: &BlockHash) -> Result<Vec<u8>> {
+        let mut compressed_bytes = Vec::new();
+        self.transport
+            .read_file(&block_relpath(hash), &mut compressed_bytes)
+            .map_err(|source| Error::ReadBlock {
+                source,
+                hash: hash.to_string(),
+            })?;
+        Ok(compressed_bytes)
     }
 
     /// True if the named block is present in this directory.
@@ -182,10 +336,7 @@ impl BlockDir {
             if dirname.len() == SUBDIR_NAME_CHARS {
                 true
             } else {
-                ui::problem(&format!(
-                    "Unexpected subdirectory in blockdir: {:?}",
-                    dirname
-                ));
+                log::error!("Unexpected subdirectory in blockdir: {:?}", dirname);
                 false
             }
         });
@@ -200,14 +351,14 @@ impl BlockDir {
             .map(move |subdir_name| transport.iter_dir_entries(&subdir_name))
             .filter_map(|iter_or| {
                 if let Err(ref err) = iter_or {
-                    ui::problem(&format!("Error listing block directory: {:?}", &err));
+                    log::error!("Error listing block directory: {:?}", &err);
                 }
                 iter_or.ok()
             })
             .flatten()
             .filter_map(|iter_or| {
                 if let Err(ref err) = iter_or {
-                    ui::problem(&format!("Error listing block subdirectory: {:?}", &err));
+                    log::error!("Error listing block subdirectory: {:?}", &err);
                 }
                 iter_or.ok()
             })
@@ -229,94 +380,190 @@ impl BlockDir {
     /// Check format invariants of the BlockDir.
     ///
     /// Return a dict describing which blocks are present, and the length of their uncompressed
-    /// data.
-    pub fn validate(&self, stats: &mut ValidateStats) -> Result<HashMap<BlockHash, usize>> {
-        // TODO: In the top-level directory, no files or directories other than prefix
-        // directories of the right length.
-        // TODO: Test having a block with the right compression but the wrong contents.
-        ui::println("Count blocks...");
-        let mut progress_bar = ProgressBar::new();
-        progress_bar.set_phase("Count blocks".to_owned());
-        let blocks: Vec<BlockHash> = self
-            .block_names()?
-            .enumerate()
-            .inspect(|(i, _hash)| {
-                if i % 100 == 0 {
-                    progress_bar.set_work_done(*i)
-                }
-            })
-            .map(|(_i, hash)| hash)
-            .collect();
-        crate::ui::println(&format!(
-            "Check {} blocks...",
-            blocks.len().separate_with_commas()
-        ));
-        progress_bar.set_total_work(blocks.len());
+    /// data. With `options.quick`, blocks are only listed, not read, and their lengths are None.
+    pub fn validate(
+        &self,
+        stats: &mut ValidateStats,
+        options: &ValidateOptions,
+    ) -> Result<HashMap<BlockHash, Option<usize>>> {
+        log::info!("Count blocks...");
+        let blocks = self.validate_block_names(stats, options)?;
+        if options.quick {
+            return Ok(blocks.into_iter().map(|hash| (hash, None)).collect());
+        }
+        log::info!("Check {} blocks...", blocks.len().separate_with_commas());
+        let mut progress_bar = ProgressBar::new(options.progress.as_ref());
         stats.block_read_count = blocks.len().try_into().unwrap();
         let block_count = blocks.len();
         progress_bar.set_phase("Check block hashes".to_owned());
         progress_bar.set_total_work(block_count);
         progress_bar.set_work_done(0);
         let progress_bar_mutex = Mutex::new(progress_bar);
-        // Make a vec of Some(usize) if the block could be read, or None if it
-        // failed, where the usize gives the uncompressed data size.
-        let mut results: Vec<Option<(BlockHash, usize)>> = Vec::new();
-        blocks
+        // Every block is read even if some fail, and the problems found on
+        // each thread are counted and then added together.
+        let (len_map, block_stats) = blocks
             .into_par_iter()
             .map(|hash| {
-                let r = self
-                    .get_block_content(&hash)
-                    .map(|(bytes, _sizes)| (hash, bytes.len()))
-                    .ok();
+                let mut block_stats = ValidateStats::default();
+                let r = self.validate_block(&hash, &mut block_stats);
                 progress_bar_mutex.lock().unwrap().increment_work_done(1);
-                r
+                let mut len_map = HashMap::new();
+                if let Some(len) = r {
+                    len_map.insert(hash, Some(len));
+                }
+                (len_map, block_stats)
             })
-            .collect_into_vec(&mut results);
-        stats.block_error_count += results.iter().filter(|o| o.is_none()).count();
-        let len_map: HashMap<BlockHash, usize> = results
-            .into_iter()
-            .filter_map(std::convert::identity) // keep only Some values
-            .collect();
+            .reduce(
+                || (HashMap::new(), ValidateStats::default()),
+                |(mut map_a, stats_a), (map_b, stats_b)| {
+                    map_a.extend(map_b);
+                    (map_a, stats_a + stats_b)
+                },
+            );
+        *stats += block_stats;
         Ok(len_map)
     }
 
+    /// Read one block and classify any problem with it into `stats`.
+    ///
+    /// Returns the uncompressed length if the block could be read.
+    fn validate_block(&self, hash: &BlockHash, stats: &mut ValidateStats) -> Option<usize> {
+        match self.get_block_content(hash) {
+            Ok((bytes, _sizes)) => {
+                if bytes.len() > MAX_BLOCK_SIZE {
+                    log::error!(
+                        "Block {} has {} bytes of content, more than the maximum {}",
+                        hash,
+                        bytes.len(),
+                        MAX_BLOCK_SIZE
+                    );
+                    stats.block_oversized_count += 1;
+                }
+                Some(bytes.len())
+            }
+            Err(err) => {
+                match err {
+                    // Already reported by get_block_content.
+                    Error::BlockCorrupt { .. } => stats.block_wrong_hash_count += 1,
+                    Error::SnapCompressionError { .. } | Error::ZstdCompressionError { .. } => {
+                        log::error!("Failed to decompress block {}", hash);
+                        stats.block_decompress_error_count += 1
+                    }
+                    _ => {
+                        log::error!("{}", ErrorChain(&err));
+                        stats.io_errors += 1
+                    }
+                }
+                stats.block_error_count += 1;
+                None
+            }
+        }
+    }
+
+    /// List the names of all blocks, by listing the directory.
+    ///
+    /// Anything that doesn't fit the layout of the block directory is reported
+    /// and counted: unexpected files or directories, and blocks in the wrong
+    /// subdirectory.
+    fn validate_block_names(
+        &self,
+        stats: &mut ValidateStats,
+        options: &ValidateOptions,
+    ) -> Result<Vec<BlockHash>> {
+        let mut progress_bar = ProgressBar::new(options.progress.as_ref());
+        progress_bar.set_phase("Count blocks".to_owned());
+        let ListDirNames { dirs, files } = self.transport.list_dir_names("")?;
+        for name in files {
+            log::error!("Unexpected file in blockdir: {:?}", name);
+            stats.unexpected_files += 1;
+        }
+        let mut blocks = Vec::new();
+        for subdir in dirs {
+            if subdir.len() != SUBDIR_NAME_CHARS {
+                log::error!("Unexpected subdirectory in blockdir: {:?}", subdir);
+                stats.structure_problems += 1;
+                continue;
+            }
+            let ListDirNames { dirs, files } = match self.transport.list_dir_names(&subdir) {
+                Ok(names) => names,
+                Err(err) => {
+                    log::error!("Error listing block subdirectory: {:?}", err);
+                    stats.io_errors += 1;
+                    continue;
+                }
+            };
+            for name in dirs {
+                log::error!("Unexpected directory in blockdir: {}/{}", subdir, name);
+                stats.structure_problems += 1;
+            }
+            for name in files {
+                if name.starts_with(TMP_PREFIX) {
+                    continue;
+                }
+                match name.parse::<BlockHash>() {
+                    Ok(hash) if subdir_relpath(&name) == subdir => blocks.push(hash),
+                    Ok(_) => {
+                        log::error!("Block {:?} is in the wrong subdirectory {:?}", name, subdir);
+                        stats.block_misplaced_count += 1;
+                    }
+                    Err(_) => {
+                        log::error!("Unexpected file in blockdir: {}/{}", subdir, name);
+                        stats.unexpected_files += 1;
+                    }
+                }
+            }
+            progress_bar.set_work_done(blocks.len());
+        }
+        Ok(blocks)
+    }
+
     /// Return the entire contents of the block.
     ///
     /// Checks that the hash is correct with the contents.
     pub fn get_block_content(&self, hash: &BlockHash) -> Result<(Vec<u8>, Sizes)> {
-        // TODO: Reuse decompressor buffer.
         // TODO: Reuse read buffer.
-        let mut decompressor = Decompressor::new();
-        let mut compressed_bytes = Vec::new();
-        let block_relpath = block_relpath(hash);
-        self.transport
-            .read_file(&block_relpath, &mut compressed_bytes)
-            .map_err(|source| Error::ReadBlock {
-                source,
-                hash: hash.to_string(),
-            })?;
-        let decompressed_bytes = decompressor.decompress(&compressed_bytes)?;
-        let actual_hash = BlockHash::from(blake2b::blake2b(
-            BLAKE_HASH_SIZE_BYTES,
-            &[],
-            &decompressed_bytes,
-        ));
-        if actual_hash != *hash {
-            ui::problem(&format!(
-                "Block file {:?} has actual decompressed hash {}",
-                &block_relpath, actual_hash
-            ));
-            return Err(Error::BlockCorrupt {
-                hash: hash.to_string(),
-                actual_hash: actual_hash.to_string(),
-            });
-        }
+        let compressed_bytes = self.read_compressed(hash)?;
+        let decompressed_bytes = decompress_block(&compressed_bytes)?;
+        check_block_hash(hash, &decompressed_bytes)?;
         let sizes = Sizes {
             uncompressed: decompressed_bytes.len() as u64,
             compressed: compressed_bytes.len() as u64,
         };
-        Ok((decompressor.take_buffer(), sizes))
+        Ok((decompressed_bytes, sizes))
+    }
+}
+
+/// Decompress a block, whichever format it was written in.
+fn decompress_block(compressed_bytes: &[u8]) -> Result<Vec<u8>> {
+    if zstandard::is_zstd(compressed_bytes) {
+        zstandard::decompress(compressed_bytes)
+    } else {
+        // TODO: Reuse decompressor buffer.
+        let mut decompressor = Decompressor::new();
+        decompressor.decompress(compressed_bytes)?;
+        Ok(decompressor.take_buffer())
+    }
+}
+
+/// Check that the decompressed content of a block matches its name.
+fn check_block_hash(hash: &BlockHash, decompressed_bytes: &[u8]) -> Result<()> {
+    let actual_hash = BlockHash::from(blake2b::blake2b(
+        BLAKE_HASH_SIZE_BYTES,
+        &[],
+        decompressed_bytes,
+    ));
+    if actual_hash != *hash {
+        log::error!(
+            "Block file {:?} has actual decompressed hash {}",
+            block_relpath(hash),
+            actual_hash
+        );
+        return Err(Error::BlockCorrupt {
+            hash: hash.to_string(),
+            actual_hash: actual_hash.to_string(),
+        });
     }
+    Ok(())
 }
 
 /// Manages storage into the BlockDir of any number of files.
@@ -330,23 +577,73 @@ pub(crate) struct StoreFiles {
     // separate from BackupWriter.
     block_dir: BlockDir,
     input_buf: Vec<u8>,
+    /// Blocks that `measure_file_content` found would be written, so that repeats
+    /// can be counted as deduplicated.
+    measured_blocks: HashSet<BlockHash>,
+    /// True once the block of zeros used to represent holes is known to be stored.
+    zero_block_present: bool,
 }
 
 impl StoreFiles {
     pub(crate) fn new(block_dir: BlockDir) -> StoreFiles {
         StoreFiles {
+            input_buf: vec![0; block_dir.block_size],
             block_dir,
-            input_buf: vec![0; MAX_BLOCK_SIZE],
+            measured_blocks: HashSet::new(),
+            zero_block_present: false,
         }
     }
 
+    pub(crate) fn block_dir(&self) -> &BlockDir {
+        &self.block_dir
+    }
+
+    /// Store the contents of a file, returning the addresses of its blocks.
+    ///
+    /// `holes` are ranges of the file, in order, known to be sparse: they're not
+    /// hashed or stored, but are represented by addresses of a block of zeros.
     pub(crate) fn store_file_content(
         &mut self,
         apath: &Apath,
         from_file: &mut dyn Read,
+        holes: &[Range<u64>],
     ) -> Result<(Vec<Address>, CopyStats)> {
         let mut addresses = Vec::<Address>::with_capacity(1);
         let mut stats = CopyStats::default();
+        let mut pos = 0;
+        for hole in holes {
+            let data_len = hole.start - pos;
+            self.store_data(
+                &mut (&mut *from_file).take(data_len),
+                apath,
+                &mut addresses,
+                &mut stats,
+            )?;
+            self.store_hole_content(
+                &mut (&mut *from_file).take(hole.end - hole.start),
+                apath,
+                &mut addresses,
+                &mut stats,
+            )?;
+            pos = hole.end;
+        }
+        self.store_data(from_file, apath, &mut addresses, &mut stats)?;
+        match addresses.len() {
+            0 => stats.empty_files += 1,
+            1 => stats.single_block_files += 1,
+            _ => stats.multi_block_files += 1,
+        }
+        Ok((addresses, stats))
+    }
+
+    /// Store blocks read from `from_file` until it ends.
+    fn store_data(
+        &mut self,
+        from_file: &mut dyn Read,
+        apath: &Apath,
+        addresses: &mut Vec<Address>,
+        stats: &mut CopyStats,
+    ) -> Result<()> {
         loop {
             // TODO: Possibly read repeatedly in case we get a short read and have room for more,
             // so that short reads don't lead to short blocks being stored.
@@ -360,41 +657,192 @@ impl StoreFiles {
                         source,
                     })?;
             if read_len == 0 {
-                break;
+                return Ok(());
             }
-            let block_data = &self.input_buf[..read_len];
-            let hash = hash_bytes(block_data)?;
-            if self.block_dir.contains(&hash)? {
-                // TODO: Separate counter for size of the already-present blocks?
-                stats.deduplicated_blocks += 1;
-                stats.deduplicated_bytes += read_len as u64;
+            self.store_block(read_len, addresses, stats)?;
+        }
+    }
+
+    /// Store content read from a hole in the source file.
+    ///
+    /// Reading holes is cheap, since the filesystem just returns zeros, but
+    /// there's no need to hash or store them. The file may have been written
+    /// since its holes were found, though, so any chunk that's not all zeros
+    /// is stored as data.
+    fn store_hole_content(
+        &mut self,
+        from_file: &mut dyn Read,
+        apath: &Apath,
+        addresses: &mut Vec<Address>,
+        stats: &mut CopyStats,
+    ) -> Result<()> {
+        let mut zeros_len = 0;
+        loop {
+            let read_len =
+                from_file
+                    .read(&mut self.input_buf)
+                    .map_err(|source| Error::StoreFile {
+                        apath: apath.to_owned(),
+                        source,
+                    })?;
+            if read_len == 0 {
+                return self.store_hole(zeros_len, addresses, stats);
+            }
+            if self.input_buf[..read_len].iter().all(|&b| b == 0) {
+                zeros_len += read_len as u64;
             } else {
-                let comp_len = self.block_dir.compress_and_store(block_data, &hash)?;
+                self.store_hole(zeros_len, addresses, stats)?;
+                zeros_len = 0;
+                self.store_block(read_len, addresses, stats)?;
+            }
+        }
+    }
+
+    /// Store the first `len` bytes of the input buffer as a block, unless it's
+    /// already present.
+    fn store_block(
+        &mut self,
+        len: usize,
+        addresses: &mut Vec<Address>,
+        stats: &mut CopyStats,
+    ) -> Result<()> {
+        let block_data = &self.input_buf[..len];
+        let hash_start = Instant::now();
+        let hash = hash_bytes(block_data)?;
+        stats.times.hashing += hash_start.elapsed();
+        if self.block_dir.contains(&hash)? {
+            // TODO: Separate counter for size of the already-present blocks?
+            stats.deduplicated_blocks += 1;
+            stats.deduplicated_bytes += len as u64;
+        } else {
+            let comp_len =
+                self.block_dir
+                    .compress_and_store(block_data, &hash, &mut stats.times)?;
+            stats.written_blocks += 1;
+            stats.uncompressed_bytes += len as u64;
+            stats.compressed_bytes += comp_len;
+        }
+        addresses.push(Address {
+            hash,
+            start: 0,
+            len: len as u64,
+        });
+        Ok(())
+    }
+
+    /// Represent a hole of `len` bytes by addresses of a block of zeros, storing
+    /// that block if it's not already present.
+    fn store_hole(
+        &mut self,
+        mut len: u64,
+        addresses: &mut Vec<Address>,
+        stats: &mut CopyStats,
+    ) -> Result<()> {
+        let zero_hash = &*ZERO_BLOCK_HASH;
+        if len > 0 && !self.zero_block_present {
+            if !self.block_dir.contains(zero_hash)? {
+                let zeros = vec![0; MAX_BLOCK_SIZE];
+                let comp_len =
+                    self.block_dir
+                        .compress_and_store(&zeros, zero_hash, &mut stats.times)?;
                 stats.written_blocks += 1;
-                stats.uncompressed_bytes += read_len as u64;
+                stats.uncompressed_bytes += MAX_BLOCK_SIZE as u64;
                 stats.compressed_bytes += comp_len;
             }
+            self.zero_block_present = true;
+        }
+        stats.sparse_bytes += len;
+        while len > 0 {
+            let chunk = len.min(MAX_BLOCK_SIZE as u64);
             addresses.push(Address {
-                hash,
+                hash: zero_hash.clone(),
                 start: 0,
-                len: read_len as u64,
+                len: chunk,
             });
+            len -= chunk;
         }
-        match addresses.len() {
+        Ok(())
+    }
+
+    /// Count the blocks that storing a file would write, without writing them.
+    ///
+    /// New blocks are counted in `written_blocks` and `uncompressed_bytes`, but since
+    /// they're not compressed, `compressed_bytes` is not updated.
+    pub(crate) fn measure_file_content(
+        &mut self,
+        apath: &Apath,
+        from_file: &mut dyn Read,
+    ) -> Result<CopyStats> {
+        let mut stats = CopyStats::default();
+        let mut n_blocks = 0;
+        loop {
+            let read_len =
+                from_file
+                    .read(&mut self.input_buf)
+                    .map_err(|source| Error::StoreFile {
+                        apath: apath.to_owned(),
+                        source,
+                    })?;
+            if read_len == 0 {
+                break;
+            }
+            n_blocks += 1;
+            let hash_start = Instant::now();
+            let hash = hash_bytes(&self.input_buf[..read_len])?;
+            stats.times.hashing += hash_start.elapsed();
+            if self.measured_blocks.contains(&hash) || self.block_dir.contains(&hash)? {
+                stats.deduplicated_blocks += 1;
+                stats.deduplicated_bytes += read_len as u64;
+            } else {
+                stats.written_blocks += 1;
+                stats.uncompressed_bytes += read_len as u64;
+                self.measured_blocks.insert(hash);
+            }
+        }
+        match n_blocks {
             0 => stats.empty_files += 1,
             1 => stats.single_block_files += 1,
             _ => stats.multi_block_files += 1,
         }
-        Ok((addresses, stats))
+        Ok(stats)
     }
 }
 
-fn hash_bytes(in_buf: &[u8]) -> Result<BlockHash> {
+pub(crate) fn hash_bytes(in_buf: &[u8]) -> Result<BlockHash> {
     let mut hasher = Blake2b::new(BLAKE_HASH_SIZE_BYTES);
     hasher.update(in_buf);
     Ok(BlockHash::from(hasher.finalize()))
 }
 
+/// Hashes everything read through it, to find the hash of a whole file's
+/// content while it's being stored, restored, or compared.
+pub(crate) struct HashingReader<R: Read> {
+    inner: R,
+    hasher: Blake2b,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub(crate) fn new(inner: R) -> HashingReader<R> {
+        HashingReader {
+            inner,
+            hasher: Blake2b::new(BLAKE_HASH_SIZE_BYTES),
+        }
+    }
+
+    /// Return the hash of everything read so far.
+    pub(crate) fn finish(self) -> BlockHash {
+        BlockHash::from(self.hasher.finalize())
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.hasher.update(&buf[..len]);
+        Ok(len)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::fs;
@@ -433,7 +881,7 @@ mod tests {
         let mut store = StoreFiles::new(block_dir.clone());
 
         let (addrs, stats) = store
-            .store_file_content(&Apath::from("/hello"), &mut example_file)
+            .store_file_content(&Apath::from("/hello"), &mut example_file, &[])
             .unwrap();
 
         // Should be in one block, with the expected hash.
@@ -476,10 +924,209 @@ mod tests {
         );
 
         let mut stats = ValidateStats::default();
-        block_dir.validate(&mut stats).unwrap();
+        let block_lengths = block_dir
+            .validate(&mut stats, &ValidateOptions::default())
+            .unwrap();
         assert_eq!(stats.io_errors, 0);
         assert_eq!(stats.block_error_count, 0);
         assert_eq!(stats.block_read_count, 1);
+        assert_eq!(block_lengths[&expected_hash], Some(EXAMPLE_TEXT.len()));
+    }
+
+    #[test]
+    fn recompress_block_with_zstd() {
+        let expected_hash: BlockHash = EXAMPLE_BLOCK_HASH.parse().unwrap();
+        let (testdir, block_dir) = setup();
+        let mut store = StoreFiles::new(block_dir.clone());
+        let (addrs, _stats) = store
+            .store_file_content(&Apath::from("/hello"), &mut make_example_file(), &[])
+            .unwrap();
+        let block_path = testdir.path().join("66a").join(EXAMPLE_BLOCK_HASH);
+        assert!(!zstandard::is_zstd(&fs::read(&block_path).unwrap()));
+
+        let (old_size, new_size) = block_dir
+            .recompress_block_zstd(&expected_hash)
+            .unwrap()
+            .unwrap();
+        assert_eq!(old_size, 8);
+        assert_eq!(new_size, fs::metadata(&block_path).unwrap().len());
+        assert!(zstandard::is_zstd(&fs::read(&block_path).unwrap()));
+        assert_eq!(block_dir.get(&addrs[0]).unwrap().0, EXAMPLE_TEXT);
+
+        // Already done.
+        assert_eq!(
+            block_dir.recompress_block_zstd(&expected_hash).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn store_with_zstd() {
+        let (testdir, block_dir) = setup();
+        let block_dir = block_dir.with_compression(Compression::Zstd);
+        let mut store = StoreFiles::new(block_dir.clone());
+        let (addrs, _stats) = store
+            .store_file_content(&Apath::from("/hello"), &mut make_example_file(), &[])
+            .unwrap();
+        let block_path = testdir.path().join("66a").join(EXAMPLE_BLOCK_HASH);
+        assert!(zstandard::is_zstd(&fs::read(&block_path).unwrap()));
+        assert_eq!(block_dir.get(&addrs[0]).unwrap().0, EXAMPLE_TEXT);
+    }
+
+    #[test]
+    fn quick_validate_lists_blocks_without_reading_them() {
+        let expected_hash: BlockHash = EXAMPLE_BLOCK_HASH.parse().unwrap();
+        let (testdir, block_dir) = setup();
+        StoreFiles::new(block_dir.clone())
+            .store_file_content(&Apath::from("/hello"), &mut make_example_file(), &[])
+            .unwrap();
+        // A block moved into the wrong subdirectory is a problem.
+        fs::create_dir(testdir.path().join("fff")).unwrap();
+        fs::copy(
+            testdir.path().join("66a").join(EXAMPLE_BLOCK_HASH),
+            testdir.path().join("fff").join(EXAMPLE_BLOCK_HASH),
+        )
+        .unwrap();
+
+        let mut stats = ValidateStats::default();
+        let block_lengths = block_dir
+            .validate(
+                &mut stats,
+                &ValidateOptions {
+                    quick: true,
+                    ..ValidateOptions::default()
+                },
+            )
+            .unwrap();
+        assert_eq!(stats.block_read_count, 0);
+        assert_eq!(stats.block_misplaced_count, 1);
+        assert!(stats.has_problems());
+        assert_eq!(block_lengths.len(), 1);
+        assert_eq!(block_lengths[&expected_hash], None);
+    }
+
+    #[test]
+    fn validate_counts_each_kind_of_damaged_block() {
+        let (testdir, block_dir) = setup();
+        let mut store_files = StoreFiles::new(block_dir.clone());
+        let mut hashes = Vec::new();
+        for content in &[&b"one"[..], b"two", b"three"] {
+            let mut file = NamedTempFile::new().unwrap();
+            file.write_all(content).unwrap();
+            file.seek(SeekFrom::Start(0)).unwrap();
+            let (addrs, _) = store_files
+                .store_file_content(&Apath::from("/f"), &mut file, &[])
+                .unwrap();
+            hashes.push(addrs[0].hash.clone());
+        }
+        // Valid compression of the wrong content.
+        let mut compressor = Compressor::new();
+        fs::write(
+            testdir.path().join(block_relpath(&hashes[0])),
+            compressor.compress(b"not one").unwrap(),
+        )
+        .unwrap();
+        // Not Snappy data at all.
+        fs::write(
+            testdir.path().join(block_relpath(&hashes[1])),
+            b"\xff\xff\xff\xff\xff",
+        )
+        .unwrap();
+
+        let mut stats = ValidateStats::default();
+        let block_lengths = block_dir
+            .validate(&mut stats, &ValidateOptions::default())
+            .unwrap();
+        assert_eq!(stats.block_read_count, 3);
+        assert_eq!(stats.block_error_count, 2);
+        assert_eq!(stats.block_wrong_hash_count, 1);
+        assert_eq!(stats.block_decompress_error_count, 1);
+        assert_eq!(stats.io_errors, 0);
+        assert!(stats.has_problems());
+        assert_eq!(block_lengths.len(), 1);
+        assert_eq!(block_lengths[&hashes[2]], Some(5));
+    }
+
+    #[test]
+    fn measure_file_content_writes_nothing() {
+        let (_testdir, block_dir) = setup();
+        let mut store_files = StoreFiles::new(block_dir.clone());
+        let stats = store_files
+            .measure_file_content(&"/hello".into(), &mut make_example_file())
+            .unwrap();
+        assert_eq!(stats.written_blocks, 1);
+        assert_eq!(stats.uncompressed_bytes, EXAMPLE_TEXT.len() as u64);
+        assert_eq!(stats.compressed_bytes, 0);
+        assert_eq!(block_dir.block_names().unwrap().count(), 0);
+
+        // The same content again would be deduplicated against the first.
+        let stats = store_files
+            .measure_file_content(&"/hello2".into(), &mut make_example_file())
+            .unwrap();
+        assert_eq!(stats.written_blocks, 0);
+        assert_eq!(stats.deduplicated_blocks, 1);
+        assert_eq!(stats.deduplicated_bytes, EXAMPLE_TEXT.len() as u64);
+    }
+
+    #[test]
+    fn store_file_with_holes() {
+        let (_testdir, block_dir) = setup();
+        let mut store_files = StoreFiles::new(block_dir.clone());
+        let mut content = vec![0u8; 3 * MAX_BLOCK_SIZE + 10];
+        content[..5].copy_from_slice(b"start");
+        let len = content.len();
+        content[len - 3..].copy_from_slice(b"end");
+        let hole = 5..(len as u64 - 3);
+        let (addrs, stats) = store_files
+            .store_file_content(
+                &"/sparse".into(),
+                &mut io::Cursor::new(&content),
+                &[hole.clone()],
+            )
+            .unwrap();
+        assert_eq!(stats.sparse_bytes, hole.end - hole.start);
+        // One data block at each end, and the hole of 3 * MAX_BLOCK_SIZE + 2
+        // bytes in the middle split into three full-size zero blocks and a
+        // last 2-byte piece of one.
+        assert_eq!(addrs.len(), 2 + 4);
+        assert_eq!(addrs[4].len, 2);
+        assert_eq!(addrs.iter().map(|a| a.len).sum::<u64>(), len as u64);
+        assert_eq!(addrs[1].hash, *ZERO_BLOCK_HASH);
+        assert_eq!(hole_ranges(&addrs), [hole]);
+        let mut back = Vec::new();
+        for addr in &addrs {
+            back.extend_from_slice(&block_dir.get(addr).unwrap().0);
+        }
+        assert_eq!(back, content);
+    }
+
+    #[test]
+    fn store_data_written_into_hole() {
+        let (_testdir, block_dir) = setup();
+        let mut store_files = StoreFiles::new(block_dir.clone());
+        // The whole file was a hole when it was checked, but has since had
+        // data written into its second block.
+        let mut content = vec![0u8; 3 * MAX_BLOCK_SIZE];
+        content[MAX_BLOCK_SIZE + 7..MAX_BLOCK_SIZE + 11].copy_from_slice(b"data");
+        let (addrs, stats) = store_files
+            .store_file_content(
+                &"/sparse".into(),
+                &mut io::Cursor::new(&content),
+                &[0..content.len() as u64],
+            )
+            .unwrap();
+        assert_eq!(stats.sparse_bytes, 2 * MAX_BLOCK_SIZE as u64);
+        assert_eq!(addrs.len(), 3);
+        assert_eq!(addrs[0].hash, *ZERO_BLOCK_HASH);
+        assert_ne!(addrs[1].hash, *ZERO_BLOCK_HASH);
+        assert_eq!(addrs[2].hash, *ZERO_BLOCK_HASH);
+        let block = MAX_BLOCK_SIZE as u64;
+        assert_eq!(hole_ranges(&addrs), [0..block, 2 * block..3 * block]);
+        let mut back = Vec::new();
+        for addr in &addrs {
+            back.extend_from_slice(&block_dir.get(addr).unwrap().0);
+        }
+        assert_eq!(back, content);
     }
 
     #[test]
@@ -487,7 +1134,11 @@ mod tests {
         let (_testdir, block_dir) = setup();
         let mut store_files = StoreFiles::new(block_dir.clone());
         let (addrs, _stats) = store_files
-            .store_file_content(&"/hello".into(), &mut io::Cursor::new(b"0123456789abcdef"))
+            .store_file_content(
+                &"/hello".into(),
+                &mut io::Cursor::new(b"0123456789abcdef"),
+                &[],
+            )
             .unwrap();
         assert_eq!(addrs.len(), 1);
         let hash = addrs[0].hash.clone();
@@ -514,7 +1165,11 @@ mod tests {
         let (_testdir, block_dir) = setup();
         let mut store_files = StoreFiles::new(block_dir.clone());
         let (addrs, _stats) = store_files
-            .store_file_content(&"/hello".into(), &mut io::Cursor::new(b"0123456789abcdef"))
+            .store_file_content(
+                &"/hello".into(),
+                &mut io::Cursor::new(b"0123456789abcdef"),
+                &[],
+            )
             .unwrap();
         assert_eq!(addrs.len(), 1);
 
@@ -559,7 +1214,7 @@ mod tests {
         let mut example_file = make_example_file();
         let mut store = StoreFiles::new(block_dir);
         let (addrs1, stats) = store
-            .store_file_content(&Apath::from("/ello"), &mut example_file)
+            .store_file_content(&Apath::from("/ello"), &mut example_file, &[])
             .unwrap();
         assert_eq!(stats.deduplicated_blocks, 0);
         assert_eq!(stats.written_blocks, 1);
@@ -568,7 +1223,7 @@ mod tests {
 
         let mut example_file = make_example_file();
         let (addrs2, stats2) = store
-            .store_file_content(&Apath::from("/ello2"), &mut example_file)
+            .store_file_content(&Apath::from("/ello2"), &mut example_file, &[])
             .unwrap();
         assert_eq!(stats2.deduplicated_blocks, 1);
         assert_eq!(stats2.written_blocks, 0);
@@ -598,7 +1253,7 @@ mod tests {
 
         let mut store = StoreFiles::new(block_dir.clone());
         let (addrs, stats) = store
-            .store_file_content(&Apath::from("/big"), &mut tf)
+            .store_file_content(&Apath::from("/big"), &mut tf, &[])
             .unwrap();
 
         // Only one block needs to get compressed. The others are deduplicated.
@@ -32,7 +32,7 @@ use crate::*;
 ///
 /// ```
 /// use std::str::FromStr;
-/// use conserve::blockhash::BlockHash;
+/// use conserve::BlockHash;
 ///
 /// let hex_hash = concat!(
 ///     "00000000000000000000000000000000",
@@ -12,3 +12,32 @@
 
 //! Data compression algorithms.
 pub mod snappy;
+pub mod zstandard;
+
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+/// How new data blocks are compressed.
+///
+/// Blocks in either format can always be read.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Snappy,
+    /// Zstandard, which can only be used in archives with the `zstd_blocks`
+    /// feature.
+    Zstd,
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "snappy" => Ok(Compression::Snappy),
+            "zstd" => Ok(Compression::Zstd),
+            other => Err(format!("Unknown compression {:?}", other)),
+        }
+    }
+}
@@ -0,0 +1,66 @@
+// Copyright 2020 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Zstandard compression, used for blocks in archives with the
+//! `zstd_blocks` feature.
+
+use crate::{Error, Result};
+
+/// Default compression level for blocks: a bit better than Snappy at similar
+/// speed.
+pub(crate) const DEFAULT_LEVEL: i32 = 3;
+
+/// Range of compression levels that can be configured.
+pub(crate) const LEVELS: std::ops::RangeInclusive<i32> = 1..=22;
+
+/// Magic number at the start of every Zstandard frame.
+///
+/// A raw Snappy stream can never start with these bytes, because they'd be
+/// read as a length followed by a copy, and a stream must start with a
+/// literal. So blocks of either format can be read without knowing in
+/// advance which it is.
+const MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// True if `compressed` is a Zstandard frame, rather than Snappy.
+pub(crate) fn is_zstd(compressed: &[u8]) -> bool {
+    compressed.starts_with(&MAGIC)
+}
+
+pub(crate) fn compress(input: &[u8], level: i32) -> Result<Vec<u8>> {
+    zstd::stream::encode_all(input, level).map_err(|source| Error::ZstdCompressionError { source })
+}
+
+pub(crate) fn decompress(input: &[u8]) -> Result<Vec<u8>> {
+    zstd::stream::decode_all(input).map_err(|source| Error::ZstdCompressionError { source })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compress::snappy::Compressor;
+
+    #[test]
+    fn round_trip() {
+        let input = b"hello world, hello world, hello world, hello world";
+        let compressed = compress(input, DEFAULT_LEVEL).unwrap();
+        assert!(is_zstd(&compressed));
+        assert_eq!(decompress(&compressed).unwrap(), &input[..]);
+    }
+
+    #[test]
+    fn snappy_is_not_mistaken_for_zstd() {
+        let mut compressor = Compressor::new();
+        assert!(!is_zstd(compressor.compress(b"").unwrap()));
+        assert!(!is_zstd(compressor.compress(&MAGIC).unwrap()));
+        assert!(!is_zstd(compressor.compress(&[0x28; 1000]).unwrap()));
+    }
+}
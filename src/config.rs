@@ -0,0 +1,182 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Per-archive configuration, stored in the archive so that every machine
+//! writing to it uses the same settings.
+//!
+//! The configuration is a `CONFIG` json file in the archive directory,
+//! written when the archive is created. Archives without one use the
+//! defaults.
+
+use globset::GlobSet;
+use serde::{Deserialize, Serialize};
+
+use crate::compress::{zstandard, Compression};
+use crate::*;
+
+pub(crate) const CONFIG_FILENAME: &str = "CONFIG";
+
+/// Smallest block size that can be configured, so that the archive doesn't
+/// fill up with tiny block files.
+const MIN_CONFIGURED_BLOCK_SIZE: usize = 4096;
+
+/// Settings stored in an archive, from [Archive::config].
+///
+/// Unset fields use the built-in defaults.
+#[derive(Debug, Default, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ArchiveConfig {
+    /// Compression for new blocks. By default, Zstandard if the archive has
+    /// the `zstd_blocks` feature, and otherwise Snappy.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compression: Option<Compression>,
+
+    /// Compression for new index hunks. By default, Zstandard if the archive
+    /// has the `zstd_index` feature, and otherwise Snappy.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub index_compression: Option<Compression>,
+
+    /// Zstandard compression level for new blocks and index hunks, from 1
+    /// to 22.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compression_level: Option<i32>,
+
+    /// New blocks hold at most this many uncompressed bytes, up to 1MiB.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub block_size: Option<usize>,
+
+    /// Globs excluded from every backup and restore, in addition to those
+    /// given for each operation.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub excludes: Vec<String>,
+
+    /// Refuse to delete or change existing bands and blocks, unless the
+    /// archive is opened allowing deletion. Backups may only add data.
+    #[serde(skip_serializing_if = "is_false")]
+    pub append_only: bool,
+
+    /// New bands store only the index entries that changed from the last
+    /// complete band. Requires the `delta_index` feature.
+    #[serde(skip_serializing_if = "is_false")]
+    pub delta_index: bool,
+
+    /// Location of a second archive that each backup is also written to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mirror: Option<String>,
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
+}
+
+impl ArchiveConfig {
+    /// Check that all the settings are valid.
+    pub fn check(&self) -> Result<()> {
+        let invalid = |reason: String| Err(Error::InvalidArchiveConfig { reason });
+        if let Some(level) = self.compression_level {
+            if !zstandard::LEVELS.contains(&level) {
+                return invalid(format!(
+                    "compression_level {} is not between {} and {}",
+                    level,
+                    zstandard::LEVELS.start(),
+                    zstandard::LEVELS.end()
+                ));
+            }
+        }
+        if let Some(block_size) = self.block_size {
+            if !(MIN_CONFIGURED_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size) {
+                return invalid(format!(
+                    "block_size {} is not between {} and {}",
+                    block_size, MIN_CONFIGURED_BLOCK_SIZE, MAX_BLOCK_SIZE
+                ));
+            }
+        }
+        excludes::from_strings(&self.excludes)?;
+        Ok(())
+    }
+
+    /// Return the configured block size, or the default.
+    pub fn block_size(&self) -> usize {
+        self.block_size.unwrap_or(MAX_BLOCK_SIZE)
+    }
+
+    /// Return the configured Zstandard compression level, or the default.
+    pub fn compression_level(&self) -> i32 {
+        self.compression_level.unwrap_or(zstandard::DEFAULT_LEVEL)
+    }
+
+    /// Build a GlobSet from the configured excludes together with `extra`
+    /// excludes for one operation.
+    pub fn excludes_with<S: AsRef<str>>(&self, extra: &[S]) -> Result<GlobSet> {
+        excludes::from_strings(
+            self.excludes
+                .iter()
+                .map(String::as_str)
+                .chain(extra.iter().map(AsRef::as_ref)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn default_config_is_empty_json() {
+        let config = ArchiveConfig::default();
+        assert_eq!(serde_json::to_string(&config).unwrap(), "{}");
+        assert_eq!(serde_json::from_str::<ArchiveConfig>("{}").unwrap(), config);
+        config.check().unwrap();
+        assert_eq!(config.block_size(), MAX_BLOCK_SIZE);
+    }
+
+    #[test]
+    fn parse_config() {
+        let config: ArchiveConfig = serde_json::from_str(
+            r#"{"compression":"zstd","compression_level":9,"block_size":65536,"excludes":["/**/target"],"append_only":true}"#,
+        )
+        .unwrap();
+        config.check().unwrap();
+        assert_eq!(config.compression, Some(Compression::Zstd));
+        assert_eq!(config.compression_level(), 9);
+        assert_eq!(config.block_size(), 65536);
+        assert!(config.append_only);
+        let excludes = config.excludes_with(&["/tmp"]).unwrap();
+        assert!(excludes.is_match("/src/target"));
+        assert!(excludes.is_match("/tmp"));
+        assert!(!excludes.is_match("/src"));
+    }
+
+    #[test]
+    fn invalid_settings() {
+        for config in &[
+            ArchiveConfig {
+                block_size: Some(MAX_BLOCK_SIZE + 1),
+                ..ArchiveConfig::default()
+            },
+            ArchiveConfig {
+                block_size: Some(0),
+                ..ArchiveConfig::default()
+            },
+            ArchiveConfig {
+                compression_level: Some(99),
+                ..ArchiveConfig::default()
+            },
+        ] {
+            match config.check() {
+                Err(Error::InvalidArchiveConfig { .. }) => (),
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+    }
+}
@@ -13,6 +13,10 @@
 
 //! Copy tree contents.
 
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::errors::ErrorChain;
 use crate::kind::Kind;
 use crate::stats::CopyStats;
 use crate::*;
@@ -23,6 +27,14 @@ pub struct CopyOptions {
     pub measure_first: bool,
     /// Copy only this subtree from the source.
     pub only_subtree: Option<Apath>,
+    /// Copy only entries ordered after this apath, for example when resuming
+    /// an interrupted backup.
+    pub after: Option<Apath>,
+    /// Only count what would be copied: files are passed to `WriteTree::measure_file`,
+    /// nothing else is sent to the destination, and it is not finished.
+    pub dry_run: bool,
+    /// Receives progress of the operation, if set.
+    pub progress: Option<Arc<dyn ProgressSink>>,
 }
 
 /// Copy files and other entries from one tree to another.
@@ -34,8 +46,9 @@ pub fn copy_tree<ST: ReadTree, DT: WriteTree>(
     mut dest: DT,
     options: &CopyOptions,
 ) -> Result<CopyStats> {
+    let start = Instant::now();
     let mut stats = CopyStats::default();
-    let mut progress_bar = ProgressBar::new();
+    let mut progress_bar = ProgressBar::new(options.progress.as_ref());
     // This causes us to walk the source tree twice, which is probably an acceptable option
     // since it's nice to see realistic overall progress. We could keep all the entries
     // in memory, and maybe we should, but it might get unreasonably big.
@@ -45,27 +58,56 @@ pub fn copy_tree<ST: ReadTree, DT: WriteTree>(
         // again a second time? But, that'll potentially use memory proportional to tree size, which
         // I'd like to avoid, and also perhaps make it more likely we grumble about files that were
         // deleted or changed while this is running.
-        progress_bar.set_bytes_total(source.size()?.file_bytes as u64);
+        progress_bar.set_bytes_total(source.size(options.progress.as_ref())?.file_bytes);
     }
 
     progress_bar.set_phase("Copying".to_owned());
-    let entry_iter: Box<dyn Iterator<Item = ST::Entry>> = match &options.only_subtree {
+    let mut entry_iter: Box<dyn Iterator<Item = ST::Entry>> = match &options.only_subtree {
         None => Box::new(source.iter_entries()?),
-        Some(subtree) => source.iter_subtree_entries(subtree)?,
+        // Also copy the directories containing the subtree, so that they're
+        // created with their stored metadata.
+        Some(subtree) => Box::new(
+            source
+                .parent_entries(subtree)?
+                .into_iter()
+                .chain(source.iter_subtree_entries(subtree)?),
+        ),
     };
-    for entry in entry_iter {
+    loop {
+        let walk_start = Instant::now();
+        let entry = match entry_iter.next() {
+            Some(entry) => entry,
+            None => break,
+        };
+        stats.times.source_walk += walk_start.elapsed();
+        if let Some(after) = &options.after {
+            if entry.apath() <= after {
+                continue;
+            }
+        }
         if options.print_filenames {
-            crate::ui::println(entry.apath());
+            log::info!("{}", entry.apath());
         }
         progress_bar.set_filename(entry.apath().to_string());
         if let Err(e) = match entry.kind() {
             Kind::Dir => {
                 stats.directories += 1;
-                dest.copy_dir(&entry)
+                if options.dry_run {
+                    Ok(())
+                } else {
+                    dest.copy_dir(&entry)
+                }
             }
             Kind::File => {
                 stats.files += 1;
-                let result = dest.copy_file(&entry, source).map(|s| stats += s);
+                stats.source_bytes += entry.size().unwrap_or_default();
+                progress_bar.increment_work_done(1);
+                let result = if options.dry_run {
+                    dest.measure_file(&entry, source)
+                } else {
+                    dest.copy_file(&entry, source)
+                }
+                .map(|s| stats += s);
                 if let Some(bytes) = entry.size() {
                     progress_bar.increment_bytes_done(bytes);
                 }
@@ -73,22 +115,34 @@ pub fn copy_tree<ST: ReadTree, DT: WriteTree>(
             }
             Kind::Symlink => {
                 stats.symlinks += 1;
-                dest.copy_symlink(&entry)
+                if options.dry_run {
+                    Ok(())
+                } else {
+                    dest.copy_symlink(&entry)
+                }
+            }
+            Kind::Fifo | Kind::Socket | Kind::CharDevice | Kind::BlockDevice => {
+                stats.special_files += 1;
+                if options.dry_run {
+                    Ok(())
+                } else {
+                    dest.copy_special(&entry)
+                }
             }
             Kind::Unknown => {
                 stats.unknown_kind += 1;
-                // TODO: Perhaps eventually we could backup and restore pipes,
-                // sockets, etc. Or at least count them. For now, silently skip.
-                // https://github.com/sourcefrog/conserve/issues/82
                 continue;
             }
         } {
-            ui::show_error(&e);
+            log::error!("{}", ErrorChain(&e));
             stats.errors += 1;
             continue;
         }
     }
-    stats += dest.finish()?;
+    if !options.dry_run {
+        stats += dest.finish()?;
+    }
+    stats.times.elapsed = start.elapsed();
     // TODO: Merge in stats from the tree iter and maybe the source tree?
     Ok(stats)
 }
@@ -0,0 +1,241 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Delta indexes, which hold only the entries that changed from a parent band.
+//!
+//! A band with a delta index names its parent in its head. Its index holds
+//! entries that are new or different from the parent's tree, and deletion
+//! entries for apaths that were in the parent's tree but are now gone.
+//! Everything else is unchanged from the parent.
+//!
+//! The parent might itself have a delta index, so reading a band merges its
+//! index with the full view of its parent, recursively.
+
+use std::cmp::Ordering;
+use std::iter::{Flatten, Peekable};
+
+use crate::index::{IndexHunkIter, MAX_ENTRIES_PER_HUNK};
+use crate::stitch::IterStitchedIndexHunks;
+use crate::*;
+
+/// Reconstruct the full tree of a band with a delta index, as hunks of entries
+/// in apath order.
+pub(crate) struct MergeDeltaHunks {
+    delta: Peekable<Flatten<IndexHunkIter>>,
+    parent: Peekable<Flatten<IterStitchedIndexHunks>>,
+}
+
+impl MergeDeltaHunks {
+    /// Merge the hunks of a delta index with the full tree of its parent.
+    ///
+    /// Both should already be advanced to the same point.
+    pub(crate) fn new(delta: IndexHunkIter, parent: IterStitchedIndexHunks) -> MergeDeltaHunks {
+        MergeDeltaHunks {
+            delta: delta.flatten().peekable(),
+            parent: parent.flatten().peekable(),
+        }
+    }
+}
+
+impl Iterator for MergeDeltaHunks {
+    type Item = Vec<IndexEntry>;
+
+    fn next(&mut self) -> Option<Vec<IndexEntry>> {
+        let mut hunk = Vec::new();
+        while hunk.len() < MAX_ENTRIES_PER_HUNK {
+            let order = match (self.delta.peek(), self.parent.peek()) {
+                (None, None) => break,
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some(delta), Some(parent)) => delta.apath.cmp(&parent.apath),
+            };
+            if order == Ordering::Greater {
+                hunk.push(self.parent.next().unwrap());
+                continue;
+            }
+            if order == Ordering::Equal {
+                // Replaced or deleted by the delta.
+                self.parent.next();
+            }
+            let entry = self.delta.next().unwrap();
+            if !entry.deleted {
+                hunk.push(entry);
+            }
+        }
+        if hunk.is_empty() {
+            None
+        } else {
+            Some(hunk)
+        }
+    }
+}
+
+/// Reduce the entries of a new tree, given in apath order, to the changes
+/// from a parent band, to be written into a delta index.
+pub(crate) struct DeltaFilter {
+    parent: Peekable<Flatten<IterStitchedIndexHunks>>,
+}
+
+impl DeltaFilter {
+    /// Compare entries to the full tree of `parent_id`.
+    ///
+    /// If `after` is set, entries up to and including that apath were already
+    /// written, as when resuming an interrupted backup.
+    pub(crate) fn new(archive: &Archive, parent_id: &BandId, after: Option<&Apath>) -> DeltaFilter {
+        let mut parent = archive.iter_stitched_index_hunks(parent_id);
+        if let Some(after) = after {
+            parent = parent.advance_to_after(after);
+        }
+        DeltaFilter {
+            parent: parent.flatten().peekable(),
+        }
+    }
+
+    /// Return the entries to write to the delta index for `entry`: deletions
+    /// for any parent entries ordered before it, and then `entry` itself,
+    /// unless it's the same as in the parent.
+    pub(crate) fn filter(&mut self, entry: IndexEntry) -> Vec<IndexEntry> {
+        let mut changes = Vec::new();
+        while let Some(parent_entry) = self.parent.peek() {
+            match parent_entry.apath.cmp(&entry.apath) {
+                Ordering::Less => {
+                    let gone = self.parent.next().unwrap();
+                    changes.push(IndexEntry::deletion(gone.apath, gone.kind));
+                }
+                Ordering::Equal => {
+                    if self.parent.next().unwrap() == entry {
+                        return changes;
+                    }
+                    break;
+                }
+                Ordering::Greater => break,
+            }
+        }
+        changes.push(entry);
+        changes
+    }
+
+    /// Return deletions for all the parent entries after the last entry given
+    /// to `filter`.
+    pub(crate) fn finish(self) -> impl Iterator<Item = IndexEntry> {
+        self.parent
+            .map(|gone| IndexEntry::deletion(gone.apath, gone.kind))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_fixtures::ScratchArchive;
+
+    fn symlink(name: &str, target: &str) -> IndexEntry {
+        IndexEntry {
+            target: Some(target.to_owned()),
+            ..IndexEntry::new(name.into(), Kind::Symlink)
+        }
+    }
+
+    fn simple_ls(archive: &Archive, band_id: &BandId) -> String {
+        let strs: Vec<String> = archive
+            .iter_stitched_index_hunks(band_id)
+            .flatten()
+            .map(|entry| format!("{}:{}", &entry.apath, entry.target.unwrap()))
+            .collect();
+        strs.join(" ")
+    }
+
+    /// Write a band with a delta index, holding the changes from `parent`
+    /// to `entries`.
+    fn write_delta_band(archive: &Archive, parent: &BandId, entries: &[IndexEntry]) -> Result<()> {
+        let band = Band::create_delta(archive, parent)?;
+        let mut filter = DeltaFilter::new(archive, parent, None);
+        let mut ib = band.index_builder();
+        for entry in entries {
+            for change in filter.filter(entry.clone()) {
+                ib.push_entry(change)?;
+            }
+        }
+        for change in filter.finish() {
+            ib.push_entry(change)?;
+        }
+        let stats = ib.finish()?;
+        band.close(stats.index_hunks)
+    }
+
+    #[test]
+    fn merge_delta_indexes() -> Result<()> {
+        let af = ScratchArchive::new();
+        let band = Band::create(&af)?;
+        let mut ib = band.index_builder();
+        for name in &["/0", "/1", "/2", "/3"] {
+            ib.push_entry(symlink(name, "b0"))?;
+        }
+        let stats = ib.finish()?;
+        band.close(stats.index_hunks)?;
+
+        // b1 changes /1, deletes /2 and /3, and adds /4.
+        write_delta_band(
+            &af,
+            &BandId::new(&[0]),
+            &[
+                symlink("/0", "b0"),
+                symlink("/1", "b1"),
+                symlink("/4", "b1"),
+            ],
+        )?;
+        let band = Band::open(&af, &BandId::new(&[1]))?;
+        assert_eq!(band.delta_parent()?, Some(BandId::new(&[0])));
+        let stored: Vec<(String, bool)> = band
+            .iter_entries()?
+            .map(|entry| (entry.apath.into(), entry.deleted))
+            .collect();
+        assert_eq!(
+            stored,
+            [
+                ("/1".to_owned(), false),
+                ("/2".to_owned(), true),
+                ("/3".to_owned(), true),
+                ("/4".to_owned(), false)
+            ]
+        );
+
+        // b2 is a delta from b1, and restores /3.
+        write_delta_band(
+            &af,
+            &BandId::new(&[1]),
+            &[
+                symlink("/0", "b0"),
+                symlink("/1", "b1"),
+                symlink("/3", "b2"),
+                symlink("/4", "b1"),
+            ],
+        )?;
+
+        assert_eq!(
+            simple_ls(&af, &BandId::new(&[0])),
+            "/0:b0 /1:b0 /2:b0 /3:b0"
+        );
+        assert_eq!(simple_ls(&af, &BandId::new(&[1])), "/0:b0 /1:b1 /4:b1");
+        assert_eq!(
+            simple_ls(&af, &BandId::new(&[2])),
+            "/0:b0 /1:b1 /3:b2 /4:b1"
+        );
+
+        // Seeking within a delta band finds entries from its parents.
+        let tree = StoredTree::open(&af, &BandId::new(&[2]))?;
+        assert_eq!(tree.get_entry(&"/0".into())?.unwrap().target.unwrap(), "b0");
+        assert_eq!(tree.get_entry(&"/3".into())?.unwrap().target.unwrap(), "b2");
+        assert!(tree.get_entry(&"/2".into())?.is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,195 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Show the differences between two trees.
+//!
+//! Both trees are walked together in apath order, so this needs memory only
+//! for the current entry from each side.
+
+use std::cmp::Ordering;
+use std::fmt;
+
+use crate::stats::DiffStats;
+use crate::*;
+
+/// How one entry differs between the old and the new tree.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum DiffKind {
+    /// Present in both trees, and the same.
+    Unchanged,
+    /// Present only in the new tree.
+    New,
+    /// Present only in the old tree.
+    Deleted,
+    /// Present in both trees, but different.
+    Changed,
+}
+
+impl fmt::Display for DiffKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            DiffKind::Unchanged => "unchanged",
+            DiffKind::New => "new",
+            DiffKind::Deleted => "deleted",
+            DiffKind::Changed => "changed",
+        })
+    }
+}
+
+/// One entry from a diff of two trees.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DiffEntry {
+    pub apath: Apath,
+    pub kind: DiffKind,
+    /// Size of the file in the old tree, if it's a file present there.
+    pub old_size: Option<u64>,
+    /// Size of the file in the new tree, if it's a file present there.
+    pub new_size: Option<u64>,
+}
+
+impl DiffEntry {
+    /// Count this entry into `stats`.
+    pub fn add_to_stats(&self, stats: &mut DiffStats) {
+        let old_size = self.old_size.unwrap_or_default();
+        let new_size = self.new_size.unwrap_or_default();
+        match self.kind {
+            DiffKind::Unchanged => stats.unchanged += 1,
+            DiffKind::New => {
+                stats.new += 1;
+                stats.new_bytes += new_size;
+            }
+            DiffKind::Deleted => {
+                stats.deleted += 1;
+                stats.deleted_bytes += old_size;
+            }
+            DiffKind::Changed => {
+                stats.changed += 1;
+                stats.changed_old_bytes += old_size;
+                stats.changed_new_bytes += new_size;
+            }
+        }
+    }
+}
+
+/// Options for [diff_stored_trees] and [diff_live_tree].
+#[derive(Debug, Default, Clone)]
+pub struct DiffOptions {
+    /// Also return entries that are the same in both trees.
+    pub include_unchanged: bool,
+}
+
+/// Compare two stored trees, such as two versions in one archive.
+///
+/// Entries are changed if they differ in kind, size, symlink target, or
+/// content. Content is compared by the whole-file hashes when both entries
+/// have one, and otherwise by the addresses of the blocks holding it.
+pub fn diff_stored_trees(
+    old: &StoredTree,
+    new: &StoredTree,
+    options: &DiffOptions,
+) -> Result<Box<dyn Iterator<Item = DiffEntry>>> {
+    diff_trees(old, new, options, same_content)
+}
+
+/// True if two stored entries have the same kind, size, symlink target, and
+/// content.
+pub(crate) fn same_content(a: &IndexEntry, b: &IndexEntry) -> bool {
+    a.kind() == b.kind()
+        && a.size() == b.size()
+        && a.symlink_target() == b.symlink_target()
+        && match (&a.content_hash, &b.content_hash) {
+            (Some(a_hash), Some(b_hash)) => a_hash == b_hash,
+            _ => a.addrs == b.addrs,
+        }
+}
+
+/// Compare a stored tree to a live tree, to show what the next backup would
+/// store, or whether a restored tree has changed.
+///
+/// Like a backup, this doesn't read file content: entries are changed if they
+/// differ in kind, size, modification time, or symlink target.
+pub fn diff_live_tree(
+    stored: &StoredTree,
+    live: &LiveTree,
+    options: &DiffOptions,
+) -> Result<Box<dyn Iterator<Item = DiffEntry>>> {
+    diff_trees(stored, live, options, |s: &IndexEntry, l: &LiveEntry| {
+        l.is_unchanged_from(s) && s.symlink_target() == l.symlink_target()
+    })
+}
+
+/// Walk two trees in order, using `same` to decide whether entries present in
+/// both are unchanged.
+fn diff_trees<AT, BT, F>(
+    old: &AT,
+    new: &BT,
+    options: &DiffOptions,
+    same: F,
+) -> Result<Box<dyn Iterator<Item = DiffEntry>>>
+where
+    AT: ReadTree,
+    BT: ReadTree,
+    AT::Entry: 'static,
+    BT::Entry: 'static,
+    F: Fn(&AT::Entry, &BT::Entry) -> bool + 'static,
+{
+    let include_unchanged = options.include_unchanged;
+    let mut old_entries = old.iter_entries()?.peekable();
+    let mut new_entries = new.iter_entries()?.peekable();
+    Ok(Box::new(std::iter::from_fn(move || loop {
+        let order = match (old_entries.peek(), new_entries.peek()) {
+            (None, None) => return None,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some(a), Some(b)) => a.apath().cmp(b.apath()),
+        };
+        match order {
+            Ordering::Less => {
+                let a = old_entries.next().unwrap();
+                return Some(DiffEntry {
+                    apath: a.apath().clone(),
+                    kind: DiffKind::Deleted,
+                    old_size: a.size(),
+                    new_size: None,
+                });
+            }
+            Ordering::Greater => {
+                let b = new_entries.next().unwrap();
+                return Some(DiffEntry {
+                    apath: b.apath().clone(),
+                    kind: DiffKind::New,
+                    old_size: None,
+                    new_size: b.size(),
+                });
+            }
+            Ordering::Equal => {
+                let a = old_entries.next().unwrap();
+                let b = new_entries.next().unwrap();
+                let kind = if same(&a, &b) {
+                    if !include_unchanged {
+                        continue;
+                    }
+                    DiffKind::Unchanged
+                } else {
+                    DiffKind::Changed
+                };
+                return Some(DiffEntry {
+                    apath: a.apath().clone(),
+                    kind,
+                    old_size: a.size(),
+                    new_size: b.size(),
+                });
+            }
+        }
+    })))
+}
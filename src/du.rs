@@ -0,0 +1,105 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Measure how much of a stored tree is in each directory, to find what's
+//! making backups large.
+
+use std::collections::{BTreeMap, HashSet};
+
+use crate::*;
+
+/// The size of the content under one directory of a stored tree.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DirUsage {
+    pub apath: Apath,
+    /// Number of files in this directory and its subdirectories.
+    pub files: u64,
+    /// Total length of those files.
+    pub apparent_bytes: u64,
+    /// Bytes of those files not stored by any earlier file in the tree.
+    ///
+    /// Each distinct piece of stored content is counted once, against the
+    /// first file in apath order that refers to it, so the value for the
+    /// root directory is the deduplicated size of the whole tree.
+    pub unique_bytes: u64,
+}
+
+impl DirUsage {
+    /// A directory with nothing counted in it yet.
+    fn new(apath: Apath) -> DirUsage {
+        DirUsage {
+            apath,
+            files: 0,
+            apparent_bytes: 0,
+            unique_bytes: 0,
+        }
+    }
+}
+
+/// Count the size of every directory in `tree`, down to `max_depth` levels
+/// below the root, returning them in apath order.
+///
+/// Sizes always include all the contents of each directory, even those
+/// deeper than `max_depth`.
+pub fn dir_usage(tree: &StoredTree, max_depth: Option<usize>) -> Result<Vec<DirUsage>> {
+    let mut dirs: BTreeMap<Apath, DirUsage> = BTreeMap::new();
+    let mut seen_addrs = HashSet::new();
+    for entry in tree.iter_entries()? {
+        if entry.kind() == Kind::Dir {
+            if max_depth.map_or(true, |max| depth(&entry.apath) <= max) {
+                dirs.insert(entry.apath.clone(), DirUsage::new(entry.apath.clone()));
+            }
+            continue;
+        } else if entry.kind() != Kind::File {
+            continue;
+        }
+        let apparent_bytes = entry.size().unwrap_or_default();
+        let mut unique_bytes = 0;
+        for addr in &entry.addrs {
+            if seen_addrs.insert((addr.hash.clone(), addr.start, addr.len)) {
+                unique_bytes += addr.len;
+            }
+        }
+        let mut parent = entry.apath.parent();
+        while let Some(dir) = parent {
+            if let Some(usage) = dirs.get_mut(&dir) {
+                usage.files += 1;
+                usage.apparent_bytes += apparent_bytes;
+                usage.unique_bytes += unique_bytes;
+            }
+            parent = dir.parent();
+        }
+    }
+    Ok(dirs.into_values().collect())
+}
+
+/// Number of directories between the root and `apath`.
+fn depth(apath: &Apath) -> usize {
+    if *apath == "/" {
+        0
+    } else {
+        apath.matches('/').count()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn apath_depth() {
+        assert_eq!(depth(&"/".into()), 0);
+        assert_eq!(depth(&"/a".into()), 1);
+        assert_eq!(depth(&"/a/b".into()), 2);
+    }
+}
@@ -27,11 +27,51 @@ pub trait Entry: Debug + Eq + PartialEq {
     fn size(&self) -> Option<u64>;
     fn symlink_target(&self) -> &Option<String>;
 
+    /// Unix permission bits, including setuid, setgid, and sticky bits, if known.
+    ///
+    /// None on platforms without Unix permissions, or for entries stored
+    /// before they were recorded.
+    fn unix_mode(&self) -> Option<u32>;
+
+    /// The user and group owning this entry, if known.
+    fn owner(&self) -> Owner;
+
+    /// Extended attributes, including ACLs, in name order.
+    ///
+    /// Empty unless Conserve was built with the `xattr` feature.
+    fn xattrs(&self) -> &[Xattr];
+
+    /// For files with several hard links within the tree, the apath of the
+    /// first of them, which identifies the group of linked files.
+    fn hardlink_group(&self) -> Option<&Apath>;
+
+    /// For device nodes, the device number.
+    fn rdev(&self) -> Option<u64>;
+
+    /// For stored files, the hash of the whole file content, if it was
+    /// recorded.
+    fn content_hash(&self) -> Option<&BlockHash> {
+        None
+    }
+
+    /// Windows file attributes, for entries read or stored on Windows.
+    fn windows_attributes(&self) -> Option<u32> {
+        None
+    }
+
+    /// Creation time, for entries read or stored on Windows.
+    fn creation_time(&self) -> Option<UnixTime> {
+        None
+    }
+
     /// True if the metadata supports an assumption the file contents have
     /// not changed.
+    ///
+    /// Sizes are only compared for files: stored directories and symlinks
+    /// have a size of zero, but live ones have none.
     fn is_unchanged_from<O: Entry>(&self, basis_entry: &O) -> bool {
         basis_entry.kind() == self.kind()
             && basis_entry.mtime() == self.mtime()
-            && basis_entry.size() == self.size()
+            && (self.kind() != Kind::File || basis_entry.size() == self.size())
     }
 }
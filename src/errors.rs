@@ -13,6 +13,7 @@
 
 //! Conserve error types.
 
+use std::fmt;
 use std::path::PathBuf;
 
 use thiserror::Error;
@@ -53,6 +54,16 @@ pub enum Error {
     )]
     UnsupportedArchiveVersion { version: String },
 
+    #[error(
+        "Archive uses features {:?} that are not supported by Conserve {}",
+        features,
+        crate::version()
+    )]
+    UnsupportedArchiveFeatures { features: Vec<String> },
+
+    #[error("Invalid archive configuration: {reason}")]
+    InvalidArchiveConfig { reason: String },
+
     #[error(
         "Band version {version:?} in {band_id} is not supported by Conserve {}",
         crate::version()
@@ -62,9 +73,21 @@ pub enum Error {
     #[error("Destination directory not empty: {:?}", path)]
     DestinationNotEmpty { path: PathBuf },
 
+    #[error("Can't use {:?} as one of several source directories", path)]
+    InvalidSourceRoot { path: PathBuf },
+
+    #[error("Source directories {:?} and {:?} overlap", a, b)]
+    OverlappingSourceRoots { a: PathBuf, b: PathBuf },
+
+    #[error("Diff needs either two backups, or a source directory and at most one backup")]
+    InvalidDiffArguments,
+
     #[error("Archive has no bands")]
     ArchiveEmpty,
 
+    #[error("Archive is append-only, so existing data can't be deleted or changed")]
+    ArchiveAppendOnly,
+
     #[error("Directory for new archive is not empty")]
     NewArchiveDirectoryNotEmpty,
 
@@ -83,6 +106,44 @@ pub enum Error {
     #[error("Band {} is incomplete", band_id)]
     BandIncomplete { band_id: BandId },
 
+    #[error("Band {} does not exist", band_id)]
+    BandNotFound { band_id: BandId },
+
+    #[error(
+        "Band {} can't be deleted because band {} has a delta index from it",
+        band_id,
+        child
+    )]
+    DeltaParentInUse { band_id: BandId, child: BandId },
+
+    #[error(
+        "Invalid tag {:?}: tags can't be empty, contain whitespace or commas, or look like a backup id",
+        tag
+    )]
+    InvalidTag { tag: String },
+
+    #[error(
+        "Invalid duration {:?}: expected a number of seconds, or a number followed by s, m, h, or d",
+        duration
+    )]
+    InvalidDuration { duration: String },
+
+    #[error("No backup has id or tag {:?}", name)]
+    NoSuchBandOrTag { name: String },
+
+    #[error("Can't resume because the last band ({}) is already complete", band_id)]
+    NothingToResume { band_id: BandId },
+
+    #[error("No interrupted restore to resume in {:?}", path)]
+    NoRestoreToResume { path: PathBuf },
+
+    #[error(
+        "Can't resume the restore into {:?}, because it was restoring band {}",
+        path,
+        band_id
+    )]
+    ResumeRestoreOfOtherBand { path: PathBuf, band_id: String },
+
     #[error(
         "Can't delete blocks because the last band ({}) is incomplete and may be in use",
         band_id
@@ -95,6 +156,24 @@ pub enum Error {
     #[error("Archive is locked for garbage collection")]
     GarbageCollectionLockHeld,
 
+    #[error("Archive is locked by {}", holder)]
+    ArchiveLocked { holder: String },
+
+    #[error(
+        "Band {} in the destination archive is different from the source",
+        band_id
+    )]
+    SyncBandMismatch { band_id: BandId },
+
+    #[error(
+        "Incomplete band {} in the destination archive wasn't copied from the source, so it won't be replaced",
+        band_id
+    )]
+    SyncWouldReplaceBand { band_id: BandId },
+
+    #[error("Retention policy would keep no backups")]
+    RetentionPolicyKeepsNothing,
+
     #[error(transparent)]
     ParseGlob {
         #[from]
@@ -110,6 +189,9 @@ pub enum Error {
     #[error("Failed to serialize index")]
     SerializeIndex { source: serde_json::Error },
 
+    #[error("Failed to serialize stats")]
+    SerializeStats { source: serde_json::Error },
+
     #[error("Failed to deserialize index hunk {:?}", path)]
     DeserializeIndex {
         path: String,
@@ -143,15 +225,60 @@ pub enum Error {
         source: std::io::Error,
     },
 
+    #[error("Failed to open log file {:?}", path)]
+    OpenLogFile { path: PathBuf, source: IOError },
+
+    #[error("Failed to write stats to {:?}", path)]
+    WriteStatsJson { path: PathBuf, source: IOError },
+
+    #[error("Failed to read tar file {:?}", path)]
+    ReadTar {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
     #[error("Failed to read source tree {:?}", path)]
     ListSourceTree { path: PathBuf, source: IOError },
 
     #[error("Failed to store file {:?}", apath)]
     StoreFile { apath: Apath, source: IOError },
 
+    #[error("A stream can't be stored as the root directory")]
+    StreamNameIsRoot,
+
+    #[error("Failed to run {hook} hook")]
+    RunHook { hook: String, source: IOError },
+
+    #[error("The {hook} hook failed: {status}")]
+    HookFailed {
+        hook: String,
+        status: std::process::ExitStatus,
+    },
+
+    #[error("Failed to start worker threads")]
+    StartThreads { source: rayon::ThreadPoolBuildError },
+
+    #[error("A worker thread stopped without finishing its work")]
+    WorkerFailed,
+
     #[error("Failed to restore {:?}", path)]
     Restore { path: PathBuf, source: IOError },
 
+    #[error(
+        "Restored content of {:?} doesn't match the hash stored in the index; the file was removed",
+        path
+    )]
+    RestoredContentMismatch { path: PathBuf },
+
+    #[error("{} is not present in band {}", apath, band_id)]
+    NotFoundInBand { apath: Apath, band_id: BandId },
+
+    #[error("{} is a {:?}, not a file", apath, kind)]
+    NotAFile { apath: Apath, kind: Kind },
+
+    #[error("Failed to mount archive on {:?}", path)]
+    Mount { path: PathBuf, source: IOError },
+
     #[error("Failed to delete band {}", band_id)]
     BandDeletion { band_id: BandId, source: IOError },
 
@@ -167,4 +294,151 @@ pub enum Error {
         #[from]
         source: snap::Error,
     },
+
+    #[error("Zstandard compression error")]
+    ZstdCompressionError { source: IOError },
+}
+
+/// The broad kind of an [Error], so that callers such as the command line can
+/// react to whole classes of failures.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ErrorCategory {
+    /// The request was invalid, such as a bad option, name, or path.
+    Usage,
+
+    /// The archive is damaged: its contents are missing, unreadable, or don't
+    /// match their hashes.
+    Corruption,
+
+    /// Reading or writing the archive, source, or destination failed.
+    Transport,
+
+    /// Any other failure.
+    Other,
+}
+
+impl Error {
+    /// Return the broad kind of this error.
+    pub fn category(&self) -> ErrorCategory {
+        use Error::*;
+        match self {
+            NotAnArchive {}
+            | InvalidArchiveConfig { .. }
+            | InvalidSourceRoot { .. }
+            | OverlappingSourceRoots { .. }
+            | InvalidDiffArguments
+            | InvalidVersion { .. }
+            | DestinationNotEmpty { .. }
+            | NewArchiveDirectoryNotEmpty
+            | BandIncomplete { .. }
+            | BandNotFound { .. }
+            | NothingToResume { .. }
+            | NoRestoreToResume { .. }
+            | ResumeRestoreOfOtherBand { .. }
+            | InvalidTag { .. }
+            | InvalidDuration { .. }
+            | NoSuchBandOrTag { .. }
+            | RetentionPolicyKeepsNothing
+            | ParseGlob { .. }
+            | StreamNameIsRoot
+            | NotFoundInBand { .. }
+            | NotAFile { .. } => ErrorCategory::Usage,
+
+            BlockCorrupt { .. }
+            | AddressTooLong { .. }
+            | DeserializeIndex { .. }
+            | DeserializeJson { .. }
+            | RestoredContentMismatch { .. }
+            | SnapCompressionError { .. }
+            | ZstdCompressionError { .. } => ErrorCategory::Corruption,
+
+            WriteBlock { .. }
+            | ReadBlock { .. }
+            | ListBlocks { .. }
+            | ReadArchiveHeader { .. }
+            | CreateBand { .. }
+            | CreateBlockDir { .. }
+            | CreateArchiveDirectory { .. }
+            | WriteIndex { .. }
+            | ReadIndex { .. }
+            | WriteMetadata { .. }
+            | ListBands { .. }
+            | ReadSourceFile { .. }
+            | OpenLogFile { .. }
+            | WriteStatsJson { .. }
+            | ReadTar { .. }
+            | ListSourceTree { .. }
+            | StoreFile { .. }
+            | Restore { .. }
+            | Mount { .. }
+            | BandDeletion { .. }
+            | IOError { .. } => ErrorCategory::Transport,
+
+            UnsupportedArchiveVersion { .. }
+            | UnsupportedArchiveFeatures { .. }
+            | UnsupportedBandVersion { .. }
+            | ArchiveEmpty
+            | ArchiveAppendOnly
+            | DeltaParentInUse { .. }
+            | DeleteWithIncompleteBackup { .. }
+            | DeleteWithConcurrentActivity
+            | GarbageCollectionLockHeld
+            | ArchiveLocked { .. }
+            | SyncBandMismatch { .. }
+            | SyncWouldReplaceBand { .. }
+            | SerializeIndex { .. }
+            | SerializeStats { .. }
+            | SerializeJson { .. }
+            | RunHook { .. }
+            | HookFailed { .. }
+            | StartThreads { .. }
+            | WorkerFailed => ErrorCategory::Other,
+        }
+    }
+}
+
+/// Displays an error followed by the errors that caused it, one per line.
+pub(crate) struct ErrorChain<'a>(pub &'a dyn std::error::Error);
+
+impl fmt::Display for ErrorChain<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)?;
+        let mut cause = self.0;
+        while let Some(c) = cause.source() {
+            write!(f, "\n  caused by: {}", c)?;
+            cause = c;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn categories() {
+        assert_eq!(
+            Error::InvalidTag { tag: "".to_owned() }.category(),
+            ErrorCategory::Usage
+        );
+        assert_eq!(
+            Error::BlockCorrupt {
+                hash: "00".to_owned(),
+                actual_hash: "11".to_owned()
+            }
+            .category(),
+            ErrorCategory::Corruption
+        );
+        let io_error = std::io::Error::new(std::io::ErrorKind::Other, "disconnected");
+        assert_eq!(Error::from(io_error).category(), ErrorCategory::Transport);
+        assert_eq!(Error::ArchiveAppendOnly.category(), ErrorCategory::Other);
+        assert_eq!(
+            Error::BandNotFound {
+                band_id: BandId::new(&[1])
+            }
+            .category(),
+            ErrorCategory::Usage
+        );
+    }
 }
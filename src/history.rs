@@ -0,0 +1,163 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! The history of one file across all the versions in an archive.
+//!
+//! Each band's index is searched for the apath, using the hunk manifest to
+//! read only the hunk that could hold it, and delta indexes are stitched to
+//! their parents as when reading the tree.
+
+use std::fmt;
+
+use chrono::{DateTime, Utc};
+
+use crate::diff::same_content;
+use crate::*;
+
+/// How an apath in one version compares to the previous complete version.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum HistoryChange {
+    /// Present in this version, but not in the previous one, or this is the
+    /// first version.
+    Added,
+    /// Absent from this version, but present in the previous one.
+    Deleted,
+    /// Present in both, with different content, kind, or symlink target.
+    Changed,
+    /// Present in both with the same content, but different metadata such as
+    /// the modification time, permissions, or ownership.
+    MetadataChanged,
+    /// Present in both, and the same.
+    Unchanged,
+    /// Absent from this version and the previous one, or from an incomplete
+    /// version, which might not have reached it.
+    Absent,
+}
+
+impl fmt::Display for HistoryChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            HistoryChange::Added => "added",
+            HistoryChange::Deleted => "deleted",
+            HistoryChange::Changed => "changed",
+            HistoryChange::MetadataChanged => "metadata",
+            HistoryChange::Unchanged => "unchanged",
+            HistoryChange::Absent => "absent",
+        })
+    }
+}
+
+/// An apath in one version of the archive.
+#[derive(Debug, Clone)]
+pub struct FileVersion {
+    pub band_id: BandId,
+    /// Time the backup started.
+    pub start_time: DateTime<Utc>,
+    /// True if the backup finished, rather than being interrupted.
+    pub is_complete: bool,
+    /// The entry for the apath in this version, if it's present.
+    pub entry: Option<IndexEntry>,
+    pub change: HistoryChange,
+}
+
+/// Find `apath` in every version in the archive, from first to last, and
+/// say how it changed in each.
+///
+/// Each version is compared to the last complete version before it.
+/// Incomplete versions aren't compared to by later versions, and an apath
+/// missing from them is `Absent` rather than `Deleted`.
+pub fn file_history(archive: &Archive, apath: &Apath) -> Result<Vec<FileVersion>> {
+    let mut history = Vec::new();
+    // The entry in the last complete version, if it was present there.
+    let mut previous: Option<IndexEntry> = None;
+    for band_id in archive.list_band_ids()? {
+        let info = Band::open(archive, &band_id)?.get_info()?;
+        let is_complete = info.is_closed && !info.is_partial;
+        let entry = archive
+            .open_stored_tree(BandSelectionPolicy::Specified(band_id.clone()))?
+            .get_entry(apath)?;
+        let change = match (&previous, &entry) {
+            (_, None) if !is_complete => HistoryChange::Absent,
+            (None, None) => HistoryChange::Absent,
+            (Some(_), None) => HistoryChange::Deleted,
+            (None, Some(_)) => HistoryChange::Added,
+            (Some(old), Some(new)) if !same_content(old, new) => HistoryChange::Changed,
+            (Some(old), Some(new)) if !same_metadata(old, new) => HistoryChange::MetadataChanged,
+            (Some(_), Some(_)) => HistoryChange::Unchanged,
+        };
+        if is_complete {
+            previous = entry.clone();
+        }
+        history.push(FileVersion {
+            band_id,
+            start_time: info.start_time,
+            is_complete,
+            entry,
+            change,
+        });
+    }
+    Ok(history)
+}
+
+/// True if two entries have the same metadata, apart from their content.
+fn same_metadata(a: &IndexEntry, b: &IndexEntry) -> bool {
+    a.mtime() == b.mtime()
+        && a.unix_mode == b.unix_mode
+        && a.owner() == b.owner()
+        && a.xattrs == b.xattrs
+        && a.windows_attributes == b.windows_attributes
+        && a.creation_time() == b.creation_time()
+        && a.hardlink_group == b.hardlink_group
+        && a.rdev == b.rdev
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_fixtures::{ScratchArchive, TreeFixture};
+
+    #[test]
+    fn history_of_changing_file() {
+        let af = ScratchArchive::new();
+        let tf = TreeFixture::new();
+        let backup = || af.backup(tf.path(), &BackupOptions::default()).unwrap();
+        tf.create_file("other");
+        backup();
+        tf.create_file_with_contents("hello", b"one");
+        backup();
+        backup();
+        tf.create_file_with_contents("hello", b"two");
+        backup();
+        std::fs::remove_file(tf.path().join("hello")).unwrap();
+        backup();
+        af.setup_incomplete_empty_band();
+
+        let history = file_history(&af, &Apath::from("/hello")).unwrap();
+        let changes: Vec<HistoryChange> = history.iter().map(|v| v.change).collect();
+        assert_eq!(
+            changes,
+            [
+                HistoryChange::Absent,
+                HistoryChange::Added,
+                HistoryChange::Unchanged,
+                HistoryChange::Changed,
+                HistoryChange::Deleted,
+                HistoryChange::Absent,
+            ]
+        );
+        assert_eq!(history[1].band_id, BandId::new(&[1]));
+        assert_eq!(history[1].entry.as_ref().unwrap().size(), Some(3));
+        assert!(history[4].entry.is_none());
+        assert!(!history[5].is_complete);
+    }
+}
@@ -0,0 +1,136 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Commands run before and after a backup, for example to make and release
+//! a filesystem snapshot.
+//!
+//! Hooks are run by the shell, with the source directory in `CONSERVE_SOURCE`.
+//! The post-backup hook also gets `CONSERVE_BACKUP_STATUS`, which is either
+//! `succeeded` or `failed`.
+
+use std::process::Command;
+use std::str::FromStr;
+
+use crate::errors::ErrorChain;
+use crate::*;
+
+/// What to do if a hook command fails.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum HookFailurePolicy {
+    /// Stop the backup, and return an error.
+    #[default]
+    Abort,
+    /// Report the failure as a problem and continue.
+    Warn,
+}
+
+impl FromStr for HookFailurePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "abort" => Ok(HookFailurePolicy::Abort),
+            "warn" => Ok(HookFailurePolicy::Warn),
+            other => Err(format!("Unknown hook failure policy {:?}", other)),
+        }
+    }
+}
+
+/// Run a hook command through the shell, with the given extra environment
+/// variables, and wait for it to finish.
+///
+/// `hook` names the hook in error messages, such as `"pre-backup"`.
+///
+/// If the command can't be started, or exits unsuccessfully, this returns an
+/// error or warns according to `policy`.
+pub(crate) fn run_hook(
+    hook: &str,
+    command: &str,
+    env: &[(&str, &str)],
+    policy: HookFailurePolicy,
+) -> Result<()> {
+    let result = match shell_command(command).envs(env.iter().cloned()).status() {
+        Err(source) => Err(Error::RunHook {
+            hook: hook.to_owned(),
+            source,
+        }),
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(Error::HookFailed {
+            hook: hook.to_owned(),
+            status,
+        }),
+    };
+    match (result, policy) {
+        (Err(err), HookFailurePolicy::Warn) => {
+            log::error!("{}", ErrorChain(&err));
+            Ok(())
+        }
+        (result, _) => result,
+    }
+}
+
+#[cfg(unix)]
+fn shell_command(command: &str) -> Command {
+    let mut c = Command::new("sh");
+    c.arg("-c").arg(command);
+    c
+}
+
+#[cfg(windows)]
+fn shell_command(command: &str) -> Command {
+    let mut c = Command::new("cmd");
+    c.arg("/C").arg(command);
+    c
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn successful_hook() {
+        run_hook("test", "true", &[], HookFailurePolicy::Abort).unwrap();
+    }
+
+    #[test]
+    fn hook_sees_environment() {
+        run_hook(
+            "test",
+            "test \"$CONSERVE_TEST\" = hello",
+            &[("CONSERVE_TEST", "hello")],
+            HookFailurePolicy::Abort,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn failed_hook_aborts_or_warns() {
+        match run_hook("test", "exit 3", &[], HookFailurePolicy::Abort) {
+            Err(Error::HookFailed { hook, status }) => {
+                assert_eq!(hook, "test");
+                assert_eq!(status.code(), Some(3));
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        run_hook("test", "exit 3", &[], HookFailurePolicy::Warn).unwrap();
+    }
+
+    #[test]
+    fn parse_policy() {
+        assert_eq!(
+            "warn".parse::<HookFailurePolicy>(),
+            Ok(HookFailurePolicy::Warn)
+        );
+        assert!("sometimes".parse::<HookFailurePolicy>().is_err());
+    }
+}
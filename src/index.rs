@@ -17,11 +17,14 @@ use std::cmp::Ordering;
 use std::io;
 use std::iter::Peekable;
 use std::path::Path;
+use std::time::Instant;
 use std::vec;
 
 use globset::GlobSet;
 
 use crate::compress::snappy::{Compressor, Decompressor};
+use crate::compress::{zstandard, Compression};
+use crate::jsonio::write_json;
 use crate::kind::Kind;
 use crate::stats::{IndexBuilderStats, IndexReadStats};
 use crate::transport::local::LocalTransport;
@@ -33,6 +36,34 @@ pub const MAX_ENTRIES_PER_HUNK: usize = 1000;
 
 pub const HUNKS_PER_SUBDIR: u32 = 10_000;
 
+/// Name of the file in the index directory listing the bounds of each hunk.
+const HUNK_MANIFEST_FILENAME: &str = "HUNKS";
+
+/// The range of apaths in one index hunk, as recorded in the hunk manifest.
+#[derive(Debug, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct HunkBounds {
+    /// The hunk number, from 0.
+    pub hunk: u32,
+    /// The apath of the first entry in the hunk.
+    pub first: Apath,
+    /// The apath of the last entry in the hunk.
+    pub last: Apath,
+    /// The number of entries in the hunk.
+    pub entry_count: u32,
+}
+
+impl HunkBounds {
+    /// Describe the bounds of a hunk, or None if it's empty.
+    fn of(hunk: u32, entries: &[IndexEntry]) -> Option<HunkBounds> {
+        Some(HunkBounds {
+            hunk,
+            first: entries.first()?.apath.clone(),
+            last: entries.last()?.apath.clone(),
+            entry_count: entries.len() as u32,
+        })
+    }
+}
+
 /// Description of one archived file.
 ///
 /// This struct is directly encoded/decoded to the json index file, and also can be constructed by
@@ -72,6 +103,85 @@ pub struct IndexEntry {
     #[serde(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub target: Option<String>,
+
+    /// Unix permission bits, if known.
+    ///
+    /// Absent in indexes written before 0.6.9, and for entries stored on
+    /// platforms without Unix permissions.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unix_mode: Option<u32>,
+
+    /// Numeric id of the user owning this entry, if known.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u32>,
+
+    /// Numeric id of the group owning this entry, if known.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gid: Option<u32>,
+
+    /// Name of the user owning this entry, if known.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+
+    /// Name of the group owning this entry, if known.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+
+    /// Extended attributes, including ACLs, in name order.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub xattrs: Vec<Xattr>,
+
+    /// Windows file attributes (read-only, hidden, system, and archive), for
+    /// entries stored on Windows.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub windows_attributes: Option<u32>,
+
+    /// Creation time, in whole seconds past the Unix epoch, for entries
+    /// stored on Windows.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created: Option<i64>,
+
+    /// Fractional nanoseconds for the creation time.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "crate::misc::zero_u32")]
+    pub created_nanos: u32,
+
+    /// For files hard-linked to others in the tree, the apath of the first
+    /// file in the group. This is set on every member, including the first.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hardlink_group: Option<Apath>,
+
+    /// For character and block devices, the device number.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rdev: Option<u64>,
+
+    /// True if the file kept changing while it was being read, so the stored
+    /// content might be inconsistent.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub changed_during_read: bool,
+
+    /// For files, the BLAKE2b hash of the whole file content, if it was
+    /// recorded. Absent in indexes written before 0.6.9.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_hash: Option<BlockHash>,
+
+    /// In a delta index, true if this apath was deleted since the parent band.
+    /// Deleted entries are never returned when reading a tree.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub deleted: bool,
 }
 // GRCOV_EXCLUDE_STOP
 
@@ -104,6 +214,46 @@ impl Entry for IndexEntry {
     fn symlink_target(&self) -> &Option<String> {
         &self.target
     }
+
+    fn unix_mode(&self) -> Option<u32> {
+        self.unix_mode
+    }
+
+    fn owner(&self) -> Owner {
+        Owner {
+            uid: self.uid,
+            gid: self.gid,
+            user: self.user.clone(),
+            group: self.group.clone(),
+        }
+    }
+
+    fn xattrs(&self) -> &[Xattr] {
+        &self.xattrs
+    }
+
+    fn hardlink_group(&self) -> Option<&Apath> {
+        self.hardlink_group.as_ref()
+    }
+
+    fn rdev(&self) -> Option<u64> {
+        self.rdev
+    }
+
+    fn content_hash(&self) -> Option<&BlockHash> {
+        self.content_hash.as_ref()
+    }
+
+    fn windows_attributes(&self) -> Option<u32> {
+        self.windows_attributes
+    }
+
+    fn creation_time(&self) -> Option<UnixTime> {
+        self.created.map(|secs| UnixTime {
+            secs,
+            nanosecs: self.created_nanos,
+        })
+    }
 }
 
 impl IndexEntry {
@@ -114,6 +264,8 @@ impl IndexEntry {
             source.symlink_target().is_some(),
             source.kind() == Kind::Symlink
         );
+        let owner = source.owner();
+        let created = source.creation_time();
         IndexEntry {
             apath: source.apath().clone(),
             kind: source.kind(),
@@ -121,6 +273,55 @@ impl IndexEntry {
             target: source.symlink_target().clone(),
             mtime: mtime.secs,
             mtime_nanos: mtime.nanosecs,
+            unix_mode: source.unix_mode(),
+            uid: owner.uid,
+            gid: owner.gid,
+            user: owner.user,
+            group: owner.group,
+            xattrs: source.xattrs().to_vec(),
+            windows_attributes: source.windows_attributes(),
+            created: created.map(|t| t.secs),
+            created_nanos: created.map_or(0, |t| t.nanosecs),
+            hardlink_group: source.hardlink_group().cloned(),
+            rdev: source.rdev(),
+            changed_during_read: false,
+            deleted: false,
+            content_hash: None,
+        }
+    }
+
+    /// Make an entry with the given apath and kind, and no content or other
+    /// metadata.
+    pub(crate) fn new(apath: Apath, kind: Kind) -> IndexEntry {
+        IndexEntry {
+            apath,
+            kind,
+            mtime: 0,
+            mtime_nanos: 0,
+            addrs: Vec::new(),
+            target: None,
+            unix_mode: None,
+            uid: None,
+            gid: None,
+            user: None,
+            group: None,
+            xattrs: Vec::new(),
+            windows_attributes: None,
+            created: None,
+            created_nanos: 0,
+            hardlink_group: None,
+            rdev: None,
+            changed_during_read: false,
+            deleted: false,
+            content_hash: None,
+        }
+    }
+
+    /// Make an entry recording, in a delta index, that `apath` was deleted.
+    pub(crate) fn deletion(apath: Apath, kind: Kind) -> IndexEntry {
+        IndexEntry {
+            deleted: true,
+            ..IndexEntry::new(apath, kind)
         }
     }
 }
@@ -144,7 +345,13 @@ pub struct IndexBuilder {
     /// Statistics about work done while writing this index.
     pub stats: IndexBuilderStats,
 
+    /// Bounds of the hunks written by this builder, to be written out as
+    /// the hunk manifest when the index is finished.
+    manifest: Vec<HunkBounds>,
+
     compressor: Compressor,
+    compression: Compression,
+    zstd_level: i32,
 }
 
 /// Accumulate and write out index entries into files in an index directory.
@@ -157,19 +364,57 @@ impl IndexBuilder {
             sequence: 0,
             check_order: apath::CheckOrder::new(),
             stats: IndexBuilderStats::default(),
+            manifest: Vec::new(),
             compressor: Compressor::new(),
+            compression: Compression::Snappy,
+            zstd_level: zstandard::DEFAULT_LEVEL,
+        }
+    }
+
+    /// Compress new hunks with `compression`, using `zstd_level` for Zstandard.
+    ///
+    /// Zstandard should only be used in archives with the `zstd_index` feature.
+    pub(crate) fn with_compression(
+        self,
+        compression: Compression,
+        zstd_level: i32,
+    ) -> IndexBuilder {
+        IndexBuilder {
+            compression,
+            zstd_level,
+            ..self
+        }
+    }
+
+    /// Make a builder that continues writing an existing, incomplete, index.
+    ///
+    /// `next_hunk` is the number of hunks already present, and `last_apath` is the
+    /// last apath already written, if any. New entries must sort after it.
+    pub fn resume(
+        transport: Box<dyn Transport>,
+        next_hunk: u32,
+        last_apath: Option<&Apath>,
+    ) -> IndexBuilder {
+        IndexBuilder {
+            sequence: next_hunk,
+            check_order: last_apath.map_or_else(apath::CheckOrder::new, |a| {
+                apath::CheckOrder::starting_after(a)
+            }),
+            ..IndexBuilder::new(transport)
         }
     }
 
+    /// Write out any queued entries, and then the hunk manifest.
     pub fn finish(mut self) -> Result<IndexBuilderStats> {
         self.finish_hunk()?;
+        self.write_manifest()?;
         Ok(self.stats)
     }
 
     /// Append an entry to the index.
     ///
     /// The new entry must sort after everything already written to the index.
-    pub(crate) fn push_entry(&mut self, entry: IndexEntry) -> Result<()> {
+    pub fn push_entry(&mut self, entry: IndexEntry) -> Result<()> {
         // We do this check here rather than the Index constructor so that we
         // can still read invalid apaths...
         self.check_order.check(&entry.apath);
@@ -194,6 +439,7 @@ impl IndexBuilder {
         if self.entries.is_empty() {
             return Ok(());
         }
+        let start = Instant::now();
 
         let relpath = hunk_relpath(self.sequence);
         let write_error = |source| Error::WriteIndex {
@@ -208,19 +454,49 @@ impl IndexBuilder {
                 .create_dir(&subdir_relpath(self.sequence))
                 .map_err(write_error)?;
         }
-        let compressed_bytes = self.compressor.compress(&json)?;
+        let zstd_compressed;
+        let compressed_bytes: &[u8] = match self.compression {
+            Compression::Snappy => self.compressor.compress(&json)?,
+            Compression::Zstd => {
+                zstd_compressed = zstandard::compress(&json, self.zstd_level)?;
+                &zstd_compressed
+            }
+        };
         let compressed_len = compressed_bytes.len();
         self.transport
             .write_file(&relpath, compressed_bytes)
             .map_err(write_error)?;
+        self.manifest
+            .extend(HunkBounds::of(self.sequence, &self.entries));
 
         self.stats.index_hunks += 1;
         self.stats.compressed_index_bytes += compressed_len as u64;
         self.stats.uncompressed_index_bytes += uncompressed_len as u64;
+        self.stats.write_time += start.elapsed();
         self.entries.clear(); // Ready for the next hunk.
         self.sequence += 1;
         Ok(())
     }
+
+    /// Write the manifest of hunk bounds, which lets readers find the hunk
+    /// holding an apath without reading the others.
+    fn write_manifest(&mut self) -> Result<()> {
+        // A resumed builder didn't see the hunks written before it started,
+        // so read their bounds back from the index.
+        let resumed_at = self.manifest.first().map_or(self.sequence, |b| b.hunk);
+        if resumed_at > 0 {
+            let index = IndexRead::open(self.transport.box_clone());
+            let mut manifest = Vec::new();
+            for hunk in 0..resumed_at {
+                if let Some(entries) = index.read_hunk(hunk)? {
+                    manifest.extend(HunkBounds::of(hunk, &entries));
+                }
+            }
+            manifest.append(&mut self.manifest);
+            self.manifest = manifest;
+        }
+        write_json(&self.transport, HUNK_MANIFEST_FILENAME, &self.manifest)
+    }
 }
 
 /// Return the transport-relative path for a subdirectory.
@@ -233,6 +509,71 @@ fn hunk_relpath(hunk_number: u32) -> String {
     format!("{:05}/{:09}", hunk_number / HUNKS_PER_SUBDIR, hunk_number)
 }
 
+/// Read the hunk manifest, or return None if the index doesn't have one,
+/// because it was written by an older version or was never finished.
+fn read_manifest(transport: &dyn Transport) -> Result<Option<Vec<HunkBounds>>> {
+    let path = HUNK_MANIFEST_FILENAME;
+    let mut buf = Vec::new();
+    match transport.read_file(path, &mut buf) {
+        Ok(()) => (),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(source) => {
+            return Err(Error::ReadIndex {
+                path: path.to_owned(),
+                source,
+            })
+        }
+    }
+    serde_json::from_slice(&buf)
+        .map(Some)
+        .map_err(|source| Error::DeserializeIndex {
+            path: path.to_owned(),
+            source,
+        })
+}
+
+/// Return the number of the first hunk, at or after `start`, containing an entry
+/// for which `before` is false, according to the manifest.
+///
+/// Hunks missing from the manifest are empty, so it's safe to skip over them.
+fn seek_in_manifest<F: Fn(&Apath) -> bool>(manifest: &[HunkBounds], start: u32, before: F) -> u32 {
+    let mut lo = 0;
+    let mut hi = manifest.len();
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        let bounds = &manifest[mid];
+        if bounds.hunk < start || before(&bounds.last) {
+            lo = mid + 1;
+        } else {
+            hi = mid;
+        }
+    }
+    match manifest.get(lo) {
+        Some(bounds) => bounds.hunk,
+        None => manifest
+            .last()
+            .map_or(start, |bounds| std::cmp::max(start, bounds.hunk + 1)),
+    }
+}
+
+/// Return the (1-based) number of index hunks in the index directory at `transport`.
+fn count_hunks(transport: &dyn Transport) -> Result<u32> {
+    // TODO: Might be faster to list the directory than to probe for all of them.
+    // TODO: Perhaps, list the directories and cope cleanly with
+    // one hunk being missing.
+    for i in 0.. {
+        let path = hunk_relpath(i);
+        if !transport
+            .exists(&path)
+            .map_err(|source| Error::ReadIndex { source, path })?
+        {
+            // If hunk 1 is missing, 1 hunks exists.
+            return Ok(i);
+        }
+    }
+    unreachable!();
+}
+
 #[derive(Debug, Clone)]
 pub struct IndexRead {
     /// Transport pointing to this index directory.
@@ -251,27 +592,54 @@ impl IndexRead {
 
     /// Return the (1-based) number of index hunks in an index directory.
     pub fn count_hunks(&self) -> Result<u32> {
-        // TODO: Might be faster to list the directory than to probe for all of them.
-        // TODO: Perhaps, list the directories and cope cleanly with
-        // one hunk being missing.
-        for i in 0.. {
-            let path = hunk_relpath(i);
-            if !self
-                .transport
-                .exists(&path)
-                .map_err(|source| Error::ReadIndex { source, path })?
-            {
-                // If hunk 1 is missing, 1 hunks exists.
-                return Ok(i);
-            }
-        }
-        unreachable!();
+        count_hunks(self.transport.as_ref())
     }
 
+    /// Estimate the number of entries in the index.
+    ///
+    /// This is exact if the index has a hunk manifest.
     pub fn estimate_entry_count(&self) -> Result<u64> {
+        if let Ok(Some(manifest)) = self.hunk_manifest() {
+            return Ok(manifest.iter().map(|b| u64::from(b.entry_count)).sum());
+        }
         Ok(u64::from(self.count_hunks()?) * (MAX_ENTRIES_PER_HUNK as u64))
     }
 
+    /// Return the first and last apath of each non-empty hunk, or None if
+    /// this index has no hunk manifest.
+    ///
+    /// Indexes have a manifest if they were finished by Conserve 0.6.9 or later.
+    pub fn hunk_manifest(&self) -> Result<Option<Vec<HunkBounds>>> {
+        read_manifest(self.transport.as_ref())
+    }
+
+    /// Return the last entry in the index, if there are any entries.
+    ///
+    /// This reads only the last non-empty hunk, not the whole index.
+    pub fn last_entry(&self) -> Result<Option<IndexEntry>> {
+        let mut hunk_iter = self.iter_hunks();
+        for hunk_number in (0..self.count_hunks()?).rev() {
+            hunk_iter.next_hunk_number = hunk_number;
+            if let Some(mut entries) = hunk_iter.read_next_hunk()? {
+                if let Some(last) = entries.pop() {
+                    return Ok(Some(last));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Read the entries from one hunk, or return None if there is no such hunk.
+    ///
+    /// Hunks are numbered from 0 and each holds up to [MAX_ENTRIES_PER_HUNK]
+    /// entries, in apath order, so this can be used to look at part of a
+    /// large index without reading the hunks before it.
+    pub fn read_hunk(&self, hunk_number: u32) -> Result<Option<Vec<IndexEntry>>> {
+        self.iter_hunks()
+            .start_at_hunk(hunk_number)
+            .read_next_hunk()
+    }
+
     /// Make an iterator that will return all entries in this band.
     pub fn iter_entries(&self) -> Result<IndexEntryIter> {
         Ok(IndexEntryIter {
@@ -290,6 +658,7 @@ impl IndexRead {
             compressed_buf: Vec::new(),
             stats: IndexReadStats::default(),
             after: None,
+            manifest: None,
         }
     }
 }
@@ -306,6 +675,9 @@ pub struct IndexHunkIter {
     pub stats: IndexReadStats,
     /// If set, yield only entries ordered after this apath.
     after: Option<Apath>,
+    /// The hunk manifest, if it's been read: the inner value is None if the
+    /// index has no manifest.
+    manifest: Option<Option<Vec<HunkBounds>>>,
 }
 
 impl Iterator for IndexHunkIter {
@@ -319,10 +691,7 @@ impl Iterator for IndexHunkIter {
                 Ok(Some(entries)) => entries,
                 Err(err) => {
                     self.stats.errors += 1;
-                    ui::problem(&format!(
-                        "Error reading index hunk {:?}: {:?} ",
-                        hunk_number, err
-                    ));
+                    log::error!("Error reading index hunk {:?}: {:?}", hunk_number, err);
                     continue;
                 }
             };
@@ -352,37 +721,133 @@ impl Iterator for IndexHunkIter {
 }
 
 impl IndexHunkIter {
+    /// Return the number of the hunk that will be read next.
+    ///
+    /// After a hunk is returned from `next`, this is one more than that hunk's
+    /// number, unless hunks were skipped because they couldn't be read.
+    pub fn next_hunk_number(&self) -> u32 {
+        self.next_hunk_number
+    }
+
+    /// Skip directly to hunk `hunk_number`, without reading the hunks before it.
+    pub fn start_at_hunk(self, hunk_number: u32) -> Self {
+        IndexHunkIter {
+            next_hunk_number: hunk_number,
+            ..self
+        }
+    }
+
     /// Advance self so that it returns only entries with apaths ordered after `apath`.
-    pub fn advance_to_after(self, apath: &Apath) -> Self {
+    ///
+    /// If the index has a hunk manifest, hunks entirely before `apath` are
+    /// skipped without reading them.
+    pub fn advance_to_after(mut self, apath: &Apath) -> Self {
+        let start = self.next_hunk_number;
+        if let Some(next) = self
+            .manifest()
+            .map(|manifest| seek_in_manifest(manifest, start, |a| a <= apath))
+        {
+            self.next_hunk_number = next;
+        }
         IndexHunkIter {
             after: Some(apath.clone()),
             ..self
         }
     }
 
+    /// Skip over whole hunks in which every entry is before the region the caller
+    /// wants, as determined by `before`.
+    ///
+    /// `before` must be true for some prefix of the entries in apath order,
+    /// and then false for all later entries. The hunks are binary searched, so
+    /// only a few need to be read, or none if the index has a hunk manifest.
+    ///
+    /// Entries before the region may still be returned from the first hunk
+    /// that's not skipped.
+    pub fn skip_hunks_while<F: Fn(&Apath) -> bool>(mut self, before: F) -> Self {
+        let start = self.next_hunk_number;
+        if let Some(next) = self
+            .manifest()
+            .map(|manifest| seek_in_manifest(manifest, start, &before))
+        {
+            self.next_hunk_number = next;
+            return self;
+        }
+        let hunk_count = match count_hunks(self.transport.as_ref()) {
+            Ok(hunk_count) => hunk_count,
+            Err(_) => return self, // Read them all, and report errors then.
+        };
+        // All hunks before `lo` end with an entry that's before the region;
+        // hunk `hi`, if it exists, does not.
+        let mut lo = self.next_hunk_number;
+        let mut hi = hunk_count;
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            self.next_hunk_number = mid;
+            match self.read_next_hunk() {
+                Ok(Some(entries)) => match entries.last() {
+                    Some(last) if before(&last.apath) => lo = mid + 1,
+                    Some(_) => hi = mid,
+                    // Can't tell where an empty hunk is; stop here and scan forward.
+                    None => break,
+                },
+                _ => break,
+            }
+        }
+        self.next_hunk_number = lo;
+        self
+    }
+
+    /// Return the hunk manifest, reading it the first time it's needed.
+    ///
+    /// Returns None if there's no manifest, or if it can't be read, in which
+    /// case callers fall back to reading the hunks.
+    fn manifest(&mut self) -> Option<&[HunkBounds]> {
+        if self.manifest.is_none() {
+            self.manifest = Some(
+                read_manifest(self.transport.as_ref()).unwrap_or_else(|err| {
+                    log::error!("Error reading index hunk manifest: {:?}", err);
+                    None
+                }),
+            );
+        }
+        self.manifest.as_ref().unwrap().as_deref()
+    }
+
     fn read_next_hunk(&mut self) -> Result<Option<Vec<IndexEntry>>> {
         let path = &hunk_relpath(self.next_hunk_number);
-        // Whether we succeed or fail, don't try to read this hunk again.
-        self.next_hunk_number += 1;
-        if let Err(err) = self.transport.read_file(&path, &mut self.compressed_buf) {
-            if err.kind() == io::ErrorKind::NotFound {
+        match self.transport.read_file(path, &mut self.compressed_buf) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                // The end of the index, so this stays the next hunk number.
+                //
                 // TODO: Cope with one hunk being missing, while there are still
                 // later-numbered hunks. This would require reading the whole
                 // list of hunks first.
                 return Ok(None);
-            } else {
+            }
+            Err(err) => {
+                // Don't try to read this hunk again.
+                self.next_hunk_number += 1;
                 return Err(Error::ReadIndex {
                     path: path.clone(),
                     source: err,
                 });
             }
+            // Even if it can't be decompressed or parsed, don't read it again.
+            Ok(()) => self.next_hunk_number += 1,
         }
         self.stats.index_hunks += 1;
         self.stats.compressed_index_bytes += self.compressed_buf.len() as u64;
-        let index_bytes = self.decompressor.decompress(&self.compressed_buf)?;
+        let zstd_decompressed;
+        let index_bytes: &[u8] = if zstandard::is_zstd(&self.compressed_buf) {
+            zstd_decompressed = zstandard::decompress(&self.compressed_buf)?;
+            &zstd_decompressed
+        } else {
+            self.decompressor.decompress(&self.compressed_buf)?
+        };
         self.stats.uncompressed_index_bytes += index_bytes.len() as u64;
         let entries: Vec<IndexEntry> =
-            serde_json::from_slice(&index_bytes).map_err(|source| Error::DeserializeIndex {
+            serde_json::from_slice(index_bytes).map_err(|source| Error::DeserializeIndex {
                 path: path.clone(),
                 source,
             })?;
@@ -487,12 +952,8 @@ mod tests {
 
     fn add_an_entry(ib: &mut IndexBuilder, apath: &str) {
         ib.push_entry(IndexEntry {
-            apath: apath.into(),
             mtime: 1_461_736_377,
-            mtime_nanos: 0,
-            kind: Kind::File,
-            addrs: vec![],
-            target: None,
+            ..IndexEntry::new(apath.into(), Kind::File)
         })
         .unwrap();
     }
@@ -500,12 +961,8 @@ mod tests {
     #[test]
     fn serialize_index() {
         let entries = [IndexEntry {
-            apath: "/a/b".into(),
             mtime: 1_461_736_377,
-            mtime_nanos: 0,
-            kind: Kind::File,
-            addrs: vec![],
-            target: None,
+            ..IndexEntry::new("/a/b".into(), Kind::File)
         }];
         let index_json = serde_json::to_string(&entries).unwrap();
         println!("{}", index_json);
@@ -522,22 +979,13 @@ mod tests {
     fn index_builder_checks_order() {
         let (_testdir, mut ib) = scratch_indexbuilder();
         ib.push_entry(IndexEntry {
-            apath: "/zzz".into(),
             mtime: 1_461_736_377,
-            mtime_nanos: 0,
-
-            kind: Kind::File,
-            addrs: vec![],
-            target: None,
+            ..IndexEntry::new("/zzz".into(), Kind::File)
         })
         .unwrap();
         ib.push_entry(IndexEntry {
-            apath: "aaa".into(),
             mtime: 1_461_736_377,
-            mtime_nanos: 0,
-            kind: Kind::File,
-            addrs: vec![],
-            target: None,
+            ..IndexEntry::new("aaa".into(), Kind::File)
         })
         .unwrap();
     }
@@ -547,12 +995,8 @@ mod tests {
     fn index_builder_checks_names() {
         let (_testdir, mut ib) = scratch_indexbuilder();
         ib.push_entry(IndexEntry {
-            apath: "../escapecat".into(),
             mtime: 1_461_736_377,
-            kind: Kind::File,
-            addrs: vec![],
-            mtime_nanos: 0,
-            target: None,
+            ..IndexEntry::new("../escapecat".into(), Kind::File)
         })
         .unwrap();
     }
@@ -622,6 +1066,34 @@ mod tests {
         );
     }
 
+    #[test]
+    fn hunks_are_bounded() -> Result<()> {
+        let (testdir, mut ib) = scratch_indexbuilder();
+        let n_entries = MAX_ENTRIES_PER_HUNK * 2 + 10;
+        for i in 0..n_entries {
+            add_an_entry(&mut ib, &format!("/{:06}", i));
+        }
+        let stats = ib.finish()?;
+        assert_eq!(stats.index_hunks, 3);
+
+        let index_read = IndexRead::open_path(&testdir.path());
+        let mut hunks = index_read.iter_hunks();
+        let lens: Vec<usize> = hunks.by_ref().map(|hunk| hunk.len()).collect();
+        assert_eq!(lens, [MAX_ENTRIES_PER_HUNK, MAX_ENTRIES_PER_HUNK, 10]);
+        assert_eq!(hunks.next_hunk_number(), 3);
+
+        let last_hunk = index_read.read_hunk(2)?.unwrap();
+        assert_eq!(
+            last_hunk[0].apath,
+            format!("/{:06}", MAX_ENTRIES_PER_HUNK * 2).as_str()
+        );
+        let mut hunks = index_read.iter_hunks().start_at_hunk(1);
+        assert_eq!(hunks.next().unwrap()[0].apath, "/001000");
+        assert_eq!(hunks.next_hunk_number(), 2);
+        assert!(index_read.read_hunk(3)?.is_none());
+        Ok(())
+    }
+
     #[test]
     fn iter_hunks_advance_to_after() {
         let (testdir, mut ib) = scratch_indexbuilder();
@@ -707,6 +1179,91 @@ mod tests {
         assert_eq!(names, [] as [&str; 0]);
     }
 
+    #[test]
+    fn iter_hunks_skip_hunks_while() {
+        let (testdir, mut ib) = scratch_indexbuilder();
+        for hunk in 0..10 {
+            for i in 0..3 {
+                add_an_entry(&mut ib, &format!("/{}.{}", hunk, i));
+            }
+            ib.finish_hunk().unwrap();
+        }
+        let index_read = IndexRead::open_path(&testdir.path());
+
+        let mut hunks = index_read
+            .iter_hunks()
+            .skip_hunks_while(|apath| *apath < "/6.1".into());
+        // Only a few hunks need to be read to find it.
+        assert!(hunks.stats.index_hunks <= 4);
+        let first_hunk = hunks.next().unwrap();
+        assert_eq!(first_hunk[0].apath, "/6.0");
+        assert_eq!(hunks.count(), 3);
+
+        let names: Vec<String> = index_read
+            .iter_hunks()
+            .skip_hunks_while(|apath| *apath < "/0.0".into())
+            .take(1)
+            .flatten()
+            .map(|entry| entry.apath.into())
+            .collect();
+        assert_eq!(names, ["/0.0", "/0.1", "/0.2"]);
+
+        assert_eq!(
+            index_read
+                .iter_hunks()
+                .skip_hunks_while(|apath| *apath < "/9.9".into())
+                .count(),
+            0
+        );
+    }
+
+    #[test]
+    fn skip_hunks_using_manifest() -> Result<()> {
+        let (testdir, mut ib) = scratch_indexbuilder();
+        for hunk in 0..10 {
+            for i in 0..3 {
+                add_an_entry(&mut ib, &format!("/{}.{}", hunk, i));
+            }
+            ib.finish_hunk()?;
+        }
+        ib.finish()?;
+        let index_read = IndexRead::open_path(&testdir.path());
+        let manifest = index_read.hunk_manifest()?.unwrap();
+        assert_eq!(manifest.len(), 10);
+        assert_eq!(
+            manifest[6],
+            HunkBounds {
+                hunk: 6,
+                first: "/6.0".into(),
+                last: "/6.2".into(),
+                entry_count: 3,
+            }
+        );
+        assert_eq!(index_read.estimate_entry_count()?, 30);
+
+        // No hunks are read to find the right one.
+        let mut hunks = index_read
+            .iter_hunks()
+            .skip_hunks_while(|apath| *apath < "/6.1".into());
+        assert_eq!(hunks.stats.index_hunks, 0);
+        assert_eq!(hunks.next().unwrap()[0].apath, "/6.0");
+        assert_eq!(hunks.stats.index_hunks, 1);
+        assert_eq!(hunks.count(), 3);
+
+        let mut hunks = index_read.iter_hunks().advance_to_after(&"/4.2".into());
+        assert_eq!(hunks.next().unwrap()[0].apath, "/5.0");
+        assert_eq!(hunks.stats.index_hunks, 1);
+
+        assert_eq!(
+            index_read
+                .iter_hunks()
+                .skip_hunks_while(|apath| *apath < "/9.9".into())
+                .count(),
+            0
+        );
+        Ok(())
+    }
+
     #[test]
     #[should_panic]
     fn no_duplicate_paths() {
@@ -791,6 +1348,63 @@ mod tests {
         assert_eq!(it.next(), None);
     }
 
+    #[test]
+    fn resume_index_builder() -> Result<()> {
+        let (testdir, mut ib) = scratch_indexbuilder();
+        add_an_entry(&mut ib, "/1.1");
+        add_an_entry(&mut ib, "/1.2");
+        ib.finish()?;
+
+        let index_read = IndexRead::open_path(&testdir.path());
+        assert_eq!(index_read.count_hunks()?, 1);
+        let last_apath = index_read.last_entry()?.unwrap().apath;
+        assert_eq!(last_apath, "/1.2");
+
+        let mut ib = IndexBuilder::resume(
+            Box::new(LocalTransport::new(testdir.path())),
+            index_read.count_hunks()?,
+            Some(&last_apath),
+        );
+        add_an_entry(&mut ib, "/2.1");
+        ib.finish()?;
+
+        assert_eq!(index_read.count_hunks()?, 2);
+        let names: Vec<String> = index_read
+            .iter_entries()?
+            .map(|entry| entry.apath.into())
+            .collect();
+        assert_eq!(names, ["/1.1", "/1.2", "/2.1"]);
+        assert_eq!(index_read.last_entry()?.unwrap().apath, "/2.1");
+
+        // The manifest includes the hunks written before resuming.
+        let manifest = index_read.hunk_manifest()?.unwrap();
+        assert_eq!(manifest.len(), 2);
+        assert_eq!(manifest[0].first, "/1.1");
+        assert_eq!(manifest[1].last, "/2.1");
+        Ok(())
+    }
+
+    #[test]
+    #[should_panic]
+    fn resumed_index_builder_checks_order() {
+        let (testdir, mut ib) = scratch_indexbuilder();
+        add_an_entry(&mut ib, "/b");
+        ib.finish().unwrap();
+        let mut ib = IndexBuilder::resume(
+            Box::new(LocalTransport::new(testdir.path())),
+            1,
+            Some(&"/b".into()),
+        );
+        add_an_entry(&mut ib, "/a");
+    }
+
+    #[test]
+    fn last_entry_of_empty_index() -> Result<()> {
+        let (testdir, _ib) = scratch_indexbuilder();
+        assert_eq!(IndexRead::open_path(&testdir.path()).last_entry()?, None);
+        Ok(())
+    }
+
     /// Exactly fill the first hunk: there shouldn't be an empty second hunk.
     ///
     /// https://github.com/sourcefrog/conserve/issues/95
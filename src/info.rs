@@ -0,0 +1,128 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! A summary of the contents of an archive.
+
+use std::io;
+
+use chrono::{DateTime, Local, Utc};
+use thousands::Separable;
+
+use crate::stats::mb_string;
+use crate::*;
+
+/// A summary of an archive's versions, blocks, and format, from
+/// [Archive::info].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ArchiveInfo {
+    /// The archive format version from the header.
+    pub archive_version: String,
+    /// Optional format features used by the archive.
+    pub features: Vec<String>,
+    /// True if the archive is configured to be append-only.
+    pub append_only: bool,
+    /// Number of complete versions.
+    pub complete_versions: usize,
+    /// Number of versions that were never finished, including those sealed
+    /// as partial.
+    pub incomplete_versions: usize,
+    /// Start time of the oldest version, if there are any.
+    pub oldest_backup: Option<DateTime<Utc>>,
+    /// Start time of the newest version, if there are any.
+    pub newest_backup: Option<DateTime<Utc>>,
+    /// Number of blocks present in the archive.
+    pub block_count: usize,
+    /// Total size of the blocks, as compressed on disk.
+    pub compressed_bytes: u64,
+    /// Total size of the distinct content referenced by all versions, before
+    /// compression: roughly the space the archive would need without
+    /// compression.
+    pub deduplicated_bytes: u64,
+}
+
+impl ArchiveInfo {
+    pub fn summarize(&self, w: &mut dyn io::Write) -> Result<()> {
+        writeln!(w, "{:>12}      archive version", self.archive_version)?;
+        writeln!(
+            w,
+            "{:>12}      features",
+            if self.features.is_empty() {
+                "none".to_owned()
+            } else {
+                self.features.join(", ")
+            }
+        )?;
+        if self.append_only {
+            writeln!(w, "{:>12}      append-only", "yes")?;
+        }
+        writeln!(
+            w,
+            "{:>12}      complete versions",
+            self.complete_versions.separate_with_commas()
+        )?;
+        writeln!(
+            w,
+            "{:>12}      incomplete versions",
+            self.incomplete_versions.separate_with_commas()
+        )?;
+        if let Some(oldest) = self.oldest_backup {
+            writeln!(
+                w,
+                "{}  oldest backup",
+                oldest.with_timezone(&Local).format(crate::TIMESTAMP_FORMAT)
+            )?;
+        }
+        if let Some(newest) = self.newest_backup {
+            writeln!(
+                w,
+                "{}  newest backup",
+                newest.with_timezone(&Local).format(crate::TIMESTAMP_FORMAT)
+            )?;
+        }
+        writeln!(
+            w,
+            "{:>12}      blocks",
+            self.block_count.separate_with_commas()
+        )?;
+        writeln!(
+            w,
+            "{:>12} MB     in blocks, compressed",
+            mb_string(self.compressed_bytes)
+        )?;
+        writeln!(
+            w,
+            "{:>12} MB     deduplicated content, uncompressed",
+            mb_string(self.deduplicated_bytes)
+        )?;
+        Ok(())
+    }
+
+    pub fn summarize_json(&self, w: &mut dyn io::Write) -> Result<()> {
+        let json = serde_json::json!({
+            "archive_version": self.archive_version,
+            "features": self.features,
+            "append_only": self.append_only,
+            "complete_versions": self.complete_versions,
+            "incomplete_versions": self.incomplete_versions,
+            "oldest_backup": self.oldest_backup.map(|t| t.to_rfc3339()),
+            "newest_backup": self.newest_backup.map(|t| t.to_rfc3339()),
+            "block_count": self.block_count,
+            "compressed_bytes": self.compressed_bytes,
+            "deduplicated_bytes": self.deduplicated_bytes,
+        });
+        serde_json::to_writer_pretty(&mut *w, &json)
+            .map_err(|source| Error::SerializeStats { source })?;
+        writeln!(w)?;
+        Ok(())
+    }
+}
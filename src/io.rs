@@ -15,7 +15,7 @@
 
 use std::fs;
 use std::io;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 pub(crate) fn ensure_dir_exists(path: &Path) -> std::io::Result<()> {
     fs::create_dir(path).or_else(|e| {
@@ -31,3 +31,110 @@ pub(crate) fn ensure_dir_exists(path: &Path) -> std::io::Result<()> {
 pub(crate) fn directory_is_empty(path: &Path) -> std::io::Result<bool> {
     Ok(std::fs::read_dir(path)?.next().is_none())
 }
+
+/// Join a `/`-separated relative path on to `root`, one component at a time,
+/// so that the result is valid even when `root` is a [long_path].
+pub(crate) fn join_relative(root: &Path, relative: &str) -> PathBuf {
+    let mut path = root.to_owned();
+    for part in relative.split('/').filter(|part| !part.is_empty()) {
+        path.push(part);
+    }
+    path
+}
+
+/// Return a form of `path` that can be longer than the usual Windows limit of
+/// 260 characters: an absolute path with the `\\?\` prefix.
+///
+/// Windows doesn't normalize these paths, so relative paths are made
+/// absolute from the current directory, and `.` and `..` are resolved here.
+/// Components must be joined on to the result with `push`, rather than as
+/// strings with `/`.
+///
+/// On other platforms, the path is returned unchanged.
+#[cfg(windows)]
+pub(crate) fn long_path(path: &Path) -> PathBuf {
+    use std::ffi::OsString;
+    use std::path::{Component, Prefix};
+
+    let absolute = if path.is_absolute() {
+        path.to_owned()
+    } else {
+        match std::env::current_dir() {
+            Ok(cwd) => cwd.join(path),
+            Err(_) => return path.to_owned(),
+        }
+    };
+    let mut components = absolute.components();
+    let mut result = match components.next() {
+        Some(Component::Prefix(prefix)) => match prefix.kind() {
+            Prefix::Disk(_) => {
+                let mut s = OsString::from(r"\\?\");
+                s.push(prefix.as_os_str());
+                PathBuf::from(s)
+            }
+            Prefix::UNC(server, share) => {
+                let mut s = OsString::from(r"\\?\UNC\");
+                s.push(server);
+                s.push(r"\");
+                s.push(share);
+                PathBuf::from(s)
+            }
+            // Already verbatim, or a device path.
+            _ => return absolute,
+        },
+        _ => return absolute,
+    };
+    for component in components {
+        match component {
+            Component::RootDir => result.push(r"\"),
+            Component::CurDir => (),
+            Component::ParentDir => {
+                result.pop();
+            }
+            Component::Normal(name) => result.push(name),
+            Component::Prefix(_) => unreachable!("prefix after the start of a path"),
+        }
+    }
+    result
+}
+
+#[cfg(not(windows))]
+pub(crate) fn long_path(path: &Path) -> PathBuf {
+    path.to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    #[test]
+    fn join_relative_paths() {
+        let root = Path::new("root");
+        assert_eq!(join_relative(root, ""), root);
+        assert_eq!(join_relative(root, "/"), root);
+        assert_eq!(
+            join_relative(root, "/a/b//c"),
+            root.join("a").join("b").join("c")
+        );
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn long_paths() {
+        assert_eq!(
+            long_path(Path::new(r"C:\backup\.\archive\..\home.cons")),
+            Path::new(r"\\?\C:\backup\home.cons")
+        );
+        assert_eq!(
+            long_path(Path::new(r"\\server\share\home.cons")),
+            Path::new(r"\\?\UNC\server\share\home.cons")
+        );
+        assert_eq!(
+            long_path(Path::new(r"\\?\C:\already")),
+            Path::new(r"\\?\C:\already")
+        );
+        assert!(long_path(Path::new("relative")).is_absolute());
+    }
+}
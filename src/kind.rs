@@ -26,10 +26,28 @@ pub enum Kind {
     File,
     Dir,
     Symlink,
+    /// Named pipe.
+    Fifo,
+    /// Unix domain socket.
+    Socket,
+    /// Character device node.
+    CharDevice,
+    /// Block device node.
+    BlockDevice,
     /// Unknown file observed in local tree. Shouldn't be stored.
     Unknown,
 }
 
+impl Kind {
+    /// True for fifos, sockets, and devices, which are stored as metadata only.
+    pub fn is_special(self) -> bool {
+        match self {
+            Kind::Fifo | Kind::Socket | Kind::CharDevice | Kind::BlockDevice => true,
+            Kind::File | Kind::Dir | Kind::Symlink | Kind::Unknown => false,
+        }
+    }
+}
+
 impl From<FileType> for Kind {
     fn from(ft: FileType) -> Kind {
         if ft.is_file() {
@@ -39,7 +57,28 @@ impl From<FileType> for Kind {
         } else if ft.is_symlink() {
             Kind::Symlink
         } else {
-            Kind::Unknown
+            special_kind(ft)
         }
     }
 }
+
+#[cfg(unix)]
+fn special_kind(ft: FileType) -> Kind {
+    use std::os::unix::fs::FileTypeExt;
+    if ft.is_fifo() {
+        Kind::Fifo
+    } else if ft.is_socket() {
+        Kind::Socket
+    } else if ft.is_char_device() {
+        Kind::CharDevice
+    } else if ft.is_block_device() {
+        Kind::BlockDevice
+    } else {
+        Kind::Unknown
+    }
+}
+
+#[cfg(not(unix))]
+fn special_kind(_ft: FileType) -> Kind {
+    Kind::Unknown
+}
@@ -11,65 +11,147 @@
 // GNU General Public License for more details.
 
 //! Conserve backup system.
+//!
+//! The library API is centered on [Archive], which is opened or created
+//! with [Archive::open] or [Archive::create], and then backed up into and
+//! restored from using [BackupOptions] and [RestoreOptions]:
+//!
+//! ```no_run
+//! use std::path::Path;
+//!
+//! use conserve::*;
+//!
+//! let archive = Archive::open_path(Path::new("/backup/archive"))?;
+//! let stats = archive.backup(
+//!     Path::new("/home/me"),
+//!     &BackupOptions::default()
+//!         .with_excludes(excludes::from_strings(&["/.cache"])?)
+//!         .with_threads(4),
+//! )?;
+//! println!("{} new files", stats.new_files);
+//! archive.restore(
+//!     Path::new("/tmp/restored"),
+//!     &RestoreOptions::default().with_only_subtree(Some("/src".into())),
+//! )?;
+//! # Ok::<(), conserve::Error>(())
+//! ```
+//!
+//! Types used in the API are exported from the crate root. The modules that
+//! are public hold helpers for the command line tool. Fixtures for tests are
+//! in `test_fixtures`, when the `test-fixtures` feature is enabled.
 
 // Conserve implementation modules.
-pub mod apath;
-pub mod archive;
-pub mod backup;
+mod apath;
+mod archive;
+mod backup;
 mod band;
-pub mod bandid;
+mod bandid;
 mod blockdir;
-pub mod blockhash;
-pub mod compress;
-pub mod copy_tree;
+mod blockhash;
+mod compress;
+mod config;
+mod copy_tree;
+mod delta;
+mod diff;
+mod du;
 mod entry;
-pub mod errors;
+mod errors;
 pub mod excludes;
 mod gc_lock;
-pub mod index;
+mod history;
+mod hooks;
+mod index;
+mod info;
 mod io;
 mod jsonio;
 pub mod kind;
-pub mod live_tree;
+mod live_tree;
 mod merge;
+mod migrate;
 pub(crate) mod misc;
+#[cfg(all(unix, feature = "fuse"))]
+pub mod mount;
+mod multi_root_tree;
 pub mod output;
+mod owner;
 mod progress;
-pub mod restore;
-pub mod stats;
+mod prune;
+mod restore;
+mod stats;
 mod stitch;
 mod stored_file;
 mod stored_tree;
+mod sync;
+mod tarball;
+#[cfg(any(test, feature = "test-fixtures"))]
 pub mod test_fixtures;
-pub mod transport;
+mod transport;
 mod tree;
+// Terminal output shared with the command line tool; not part of the library API.
+#[doc(hidden)]
 pub mod ui;
-pub mod unix_time;
-
-pub use crate::apath::Apath;
+mod unix_time;
+mod verify;
+mod watch;
+mod windows_metadata;
+mod workers;
+mod write_lock;
+mod xattrs;
+
+pub use crate::apath::{Apath, ApathParseError};
 pub use crate::archive::Archive;
 pub use crate::archive::DeleteOptions;
-pub use crate::backup::BackupOptions;
-pub use crate::backup::BackupWriter;
+pub use crate::archive::ValidateOptions;
+pub use crate::archive::{IncompleteBand, IncompleteBandAction};
+pub use crate::backup::{BackupOptions, DEFAULT_CHANGED_FILE_RETRIES};
 pub use crate::band::Band;
 pub use crate::band::BandSelectionPolicy;
 pub use crate::bandid::BandId;
 pub use crate::blockdir::BlockDir;
-pub use crate::blockhash::BlockHash;
-pub use crate::copy_tree::copy_tree;
+pub use crate::blockhash::{BlockHash, BlockHashParseError};
+pub use crate::compress::Compression;
+pub use crate::config::ArchiveConfig;
+pub use crate::copy_tree::{copy_tree, CopyOptions};
+pub use crate::diff::{diff_live_tree, diff_stored_trees, DiffEntry, DiffKind, DiffOptions};
+pub use crate::du::{dir_usage, DirUsage};
 pub use crate::entry::Entry;
-pub use crate::errors::Error;
-pub use crate::gc_lock::GarbageCollectionLock;
-pub use crate::index::{IndexBuilder, IndexEntry, IndexRead};
+pub use crate::errors::{Error, ErrorCategory};
+pub use crate::history::{file_history, FileVersion, HistoryChange};
+pub use crate::hooks::HookFailurePolicy;
+pub use crate::index::{HunkBounds, IndexEntry, IndexEntryIter, IndexHunkIter, IndexRead};
+pub use crate::info::ArchiveInfo;
 pub use crate::kind::Kind;
 pub use crate::live_tree::{LiveEntry, LiveTree};
 pub use crate::merge::{iter_merged_entries, MergedEntryKind};
+pub use crate::migrate::{migrate_archive, MigrateOptions};
 pub use crate::misc::bytes_to_human_mb;
-pub use crate::progress::ProgressBar;
-pub use crate::restore::{RestoreOptions, RestoreTree};
-pub use crate::stats::{DeleteStats, ValidateStats};
+pub use crate::multi_root_tree::MultiRootTree;
+pub use crate::output::ListFormat;
+pub use crate::owner::{Owner, OwnershipPolicy};
+pub use crate::progress::{NoProgress, Progress, ProgressSink};
+pub use crate::prune::{BandRetention, RetentionPolicy};
+pub use crate::restore::{OverwritePolicy, RestoreOptions, RestoreTree};
+pub use crate::stats::{
+    BackupStats, CopyStats, DeleteStats, DiffStats, ExportTarStats, IndexBuilderStats,
+    IndexReadStats, LiveTreeIterStats, MigrateStats, PhaseTimes, RestoreStats, Sizes, Stats,
+    SyncStats, ValidateStats, VerifyStats, WatchStats,
+};
+pub use crate::stored_file::StoredFile;
 pub use crate::stored_tree::StoredTree;
-pub use crate::tree::{ReadBlocks, ReadTree, TreeSize, WriteTree};
+pub use crate::sync::{sync_archive, SyncOptions};
+pub use crate::tarball::{export_tar, ExportTarOptions, TarCompression, TarTree};
+pub use crate::transport::{local::LocalTransport, Location, Transport};
+pub use crate::tree::{ReadBlocks, ReadContent, ReadTree, TreeSize, WriteTree};
+pub use crate::unix_time::UnixTime;
+pub use crate::verify::{verify_tree, Change, VerifyOptions};
+pub use crate::watch::{parse_duration, watch, WatchOptions};
+pub use crate::xattrs::Xattr;
+
+// Internal types used throughout the crate.
+pub(crate) use crate::backup::BackupWriter;
+pub(crate) use crate::index::IndexBuilder;
+pub(crate) use crate::progress::ProgressBar;
+pub(crate) use crate::write_lock::WriteLock;
 
 // Commonly-used external types.
 pub use globset::GlobSet;
@@ -87,6 +169,23 @@ pub fn version() -> &'static str {
 /// (This might be older than the program version.)
 pub const ARCHIVE_VERSION: &str = "0.6";
 
+/// Optional archive format features understood by this version.
+///
+/// Features used by an archive are listed in its header, and archives using
+/// features not in this list can't be opened.
+pub const ARCHIVE_FEATURES: &[&str] =
+    &[ZSTD_BLOCKS_FEATURE, ZSTD_INDEX_FEATURE, DELTA_INDEX_FEATURE];
+
+/// Archive feature: blocks may be compressed with Zstandard.
+pub const ZSTD_BLOCKS_FEATURE: &str = "zstd_blocks";
+
+/// Archive feature: index hunks may be compressed with Zstandard.
+pub const ZSTD_INDEX_FEATURE: &str = "zstd_index";
+
+/// Archive feature: bands may have delta indexes holding only the entries
+/// changed from a parent band.
+pub const DELTA_INDEX_FEATURE: &str = "delta_index";
+
 pub const SYMLINKS_SUPPORTED: bool = cfg!(target_family = "unix");
 
 /// Break blocks at this many uncompressed bytes.
@@ -14,15 +14,20 @@
 //! Find source files within a source directory, in apath order.
 
 use std::collections::vec_deque::VecDeque;
+use std::collections::HashMap;
 use std::fs;
 use std::io::ErrorKind;
+use std::ops::Range;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
 use globset::GlobSet;
 
+use crate::io::{join_relative, long_path};
 use crate::kind::Kind;
 use crate::stats::LiveTreeIterStats;
 use crate::unix_time::UnixTime;
+use crate::windows_metadata;
 use crate::Result;
 use crate::*;
 
@@ -30,15 +35,29 @@ use crate::*;
 #[derive(Clone)]
 pub struct LiveTree {
     path: PathBuf,
+    /// Apath of the root of the tree, which is normally `/`.
+    apath_prefix: Apath,
     excludes: GlobSet,
+    one_file_system: bool,
+    /// Skip directories containing a valid `CACHEDIR.TAG`.
+    exclude_caches: bool,
+    /// Skip directories containing a file with any of these names.
+    exclude_if_present: Vec<String>,
+    /// Stats from all iterators over this tree, added in as each is dropped.
+    iter_stats: Arc<Mutex<LiveTreeIterStats>>,
 }
 
 impl LiveTree {
     pub fn open<P: AsRef<Path>>(path: P) -> Result<LiveTree> {
         // TODO: Maybe fail here if the root doesn't exist or isn't a directory?
         Ok(LiveTree {
-            path: path.as_ref().to_path_buf(),
+            path: long_path(path.as_ref()),
+            apath_prefix: Apath::from("/"),
             excludes: excludes::excludes_nothing(),
+            one_file_system: false,
+            exclude_caches: false,
+            exclude_if_present: Vec::new(),
+            iter_stats: Arc::new(Mutex::new(LiveTreeIterStats::default())),
         })
     }
 
@@ -49,8 +68,58 @@ impl LiveTree {
         LiveTree { excludes, ..self }
     }
 
+    /// Return a new LiveTree which, if `one_file_system` is true, doesn't descend
+    /// into directories on a different filesystem from the root.
+    ///
+    /// The mount point directories themselves are still listed. This has no effect
+    /// on platforms other than Unix.
+    pub fn with_one_file_system(self, one_file_system: bool) -> LiveTree {
+        LiveTree {
+            one_file_system,
+            ..self
+        }
+    }
+
+    /// Return a new LiveTree which, if `exclude_caches` is true, skips directories
+    /// marked as caches by a `CACHEDIR.TAG` file.
+    ///
+    /// See <https://bford.info/cachedir/>.
+    pub fn with_exclude_caches(self, exclude_caches: bool) -> LiveTree {
+        LiveTree {
+            exclude_caches,
+            ..self
+        }
+    }
+
+    /// Return a new LiveTree which skips directories containing a file or directory
+    /// with any of these names, such as `.nobackup`.
+    ///
+    /// This replaces any previous markers.
+    pub fn with_exclude_if_present(self, exclude_if_present: Vec<String>) -> LiveTree {
+        LiveTree {
+            exclude_if_present,
+            ..self
+        }
+    }
+
+    /// Return a new LiveTree whose entries are named under `apath_prefix`, rather
+    /// than from `/`, so that several trees can be stored in one band.
+    ///
+    /// Excludes are matched against the prefixed apaths.
+    pub fn with_apath_prefix(self, apath_prefix: Apath) -> LiveTree {
+        LiveTree {
+            apath_prefix,
+            ..self
+        }
+    }
+
+    /// Return the stats accumulated by all finished iterations over this tree.
+    pub fn iter_stats(&self) -> LiveTreeIterStats {
+        self.iter_stats.lock().unwrap().clone()
+    }
+
     fn relative_path(&self, apath: &Apath) -> PathBuf {
-        relative_path(&self.path, apath)
+        relative_path(&self.path, &self.apath_prefix, apath)
     }
 }
 
@@ -62,12 +131,25 @@ pub struct LiveEntry {
     mtime: UnixTime,
     size: Option<u64>,
     symlink_target: Option<String>,
+    unix_mode: Option<u32>,
+    owner: Owner,
+    xattrs: Vec<Xattr>,
+    hardlink_group: Option<Apath>,
+    rdev: Option<u64>,
+    windows_attributes: Option<u32>,
+    creation_time: Option<UnixTime>,
+    /// For files with more than one link, the device and inode number.
+    inode: Option<(u64, u64)>,
+    /// True if the file has fewer blocks allocated than its length, so may
+    /// have holes.
+    maybe_sparse: bool,
 }
 
-fn relative_path(root: &PathBuf, apath: &Apath) -> PathBuf {
-    let mut path = root.clone();
-    path.push(&apath[1..]);
-    path
+/// Return the filesystem path for `apath` in a tree whose root is at `root`
+/// and named `prefix`.
+fn relative_path(root: &Path, prefix: &Apath, apath: &Apath) -> PathBuf {
+    debug_assert!(prefix.is_prefix_of(apath));
+    join_relative(root, &apath[prefix.len()..])
 }
 
 impl tree::ReadTree for LiveTree {
@@ -81,7 +163,7 @@ impl tree::ReadTree for LiveTree {
     /// child directories, visit them according to a sorted comparison by their UTF-8
     /// name.
     fn iter_entries(&self) -> Result<Box<dyn Iterator<Item = Self::Entry>>> {
-        Ok(Box::new(Iter::new(&self.path, &self.excludes)?))
+        Ok(Box::new(Iter::new(self)?))
     }
 
     fn iter_subtree_entries(&self, subtree: &Apath) -> Result<Box<dyn Iterator<Item = LiveEntry>>> {
@@ -99,6 +181,17 @@ impl tree::ReadTree for LiveTree {
         fs::File::open(&path).map_err(|source| Error::ReadSourceFile { path, source })
     }
 
+    /// Find holes in sparse files, using `SEEK_HOLE` and `SEEK_DATA`.
+    fn file_holes(&self, entry: &LiveEntry) -> Result<Vec<Range<u64>>> {
+        if !entry.maybe_sparse {
+            return Ok(Vec::new());
+        }
+        let path = self.relative_path(&entry.apath);
+        let file =
+            fs::File::open(&path).map_err(|source| Error::ReadSourceFile { path, source })?;
+        Ok(find_holes(&file, entry.size.unwrap_or_default()))
+    }
+
     fn estimate_count(&self) -> Result<u64> {
         // TODO: This stats the file and builds an entry about them, just to
         // throw it away. We could perhaps change the iter to optionally do
@@ -107,6 +200,18 @@ impl tree::ReadTree for LiveTree {
     }
 }
 
+impl ReadContent for fs::File {
+    fn mtime_and_size(&self) -> std::io::Result<Option<(UnixTime, u64)>> {
+        let metadata = self.metadata()?;
+        Ok(Some((metadata.modified()?.into(), metadata.len())))
+    }
+
+    fn rewind(&mut self) -> std::io::Result<()> {
+        use std::io::{Seek, SeekFrom};
+        self.seek(SeekFrom::Start(0)).map(|_| ())
+    }
+}
+
 impl Entry for LiveEntry {
     fn apath(&self) -> &Apath {
         &self.apath
@@ -127,13 +232,54 @@ impl Entry for LiveEntry {
     fn symlink_target(&self) -> &Option<String> {
         &self.symlink_target
     }
+
+    fn unix_mode(&self) -> Option<u32> {
+        self.unix_mode
+    }
+
+    fn owner(&self) -> Owner {
+        self.owner.clone()
+    }
+
+    fn xattrs(&self) -> &[Xattr] {
+        &self.xattrs
+    }
+
+    fn hardlink_group(&self) -> Option<&Apath> {
+        self.hardlink_group.as_ref()
+    }
+
+    fn rdev(&self) -> Option<u64> {
+        self.rdev
+    }
+
+    fn windows_attributes(&self) -> Option<u32> {
+        self.windows_attributes
+    }
+
+    fn creation_time(&self) -> Option<UnixTime> {
+        self.creation_time
+    }
 }
 
 impl LiveEntry {
+    /// Describe the directory at `path` as an entry named `apath`, without
+    /// reading its contents.
+    pub(crate) fn from_dir_path(apath: Apath, path: &Path) -> Result<LiveEntry> {
+        let metadata = fs::symlink_metadata(path).map_err(Error::from)?;
+        Ok(LiveEntry::from_fs_metadata(
+            apath,
+            &metadata,
+            None,
+            read_xattrs_or_warn(path),
+        ))
+    }
+
     fn from_fs_metadata(
         apath: Apath,
         metadata: &fs::Metadata,
         symlink_target: Option<String>,
+        xattrs: Vec<Xattr>,
     ) -> LiveEntry {
         // TODO: Could we read the symlink target here, rather than in the caller?
         let mtime = metadata
@@ -145,22 +291,168 @@ impl LiveEntry {
         } else {
             None
         };
+        let kind: Kind = metadata.file_type().into();
+        let rdev = match kind {
+            Kind::CharDevice | Kind::BlockDevice => device_number(metadata),
+            _ => None,
+        };
         LiveEntry {
             apath,
-            kind: metadata.file_type().into(),
+            kind,
             mtime,
             symlink_target,
             size,
+            unix_mode: unix_mode(metadata),
+            owner: Owner::from_metadata(metadata),
+            xattrs,
+            hardlink_group: None,
+            rdev,
+            windows_attributes: windows_metadata::windows_attributes(metadata),
+            creation_time: windows_metadata::creation_time(metadata),
+            inode: linked_inode(metadata),
+            maybe_sparse: maybe_sparse(metadata),
         }
     }
 }
 
+/// The device and inode of a file that has more than one link, so might be
+/// linked to others in the tree.
+#[cfg(unix)]
+fn linked_inode(metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    if metadata.is_file() && metadata.nlink() > 1 {
+        Some((metadata.dev(), metadata.ino()))
+    } else {
+        None
+    }
+}
+
+#[cfg(not(unix))]
+fn linked_inode(_metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// The device holding a file or directory.
+#[cfg(unix)]
+fn device_of(metadata: &fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(metadata.dev())
+}
+
+#[cfg(not(unix))]
+fn device_of(_metadata: &fs::Metadata) -> Option<u64> {
+    None
+}
+
+#[cfg(unix)]
+fn device_number(metadata: &fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(metadata.rdev())
+}
+
+#[cfg(not(unix))]
+fn device_number(_metadata: &fs::Metadata) -> Option<u64> {
+    None
+}
+
+#[cfg(unix)]
+fn maybe_sparse(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    // st_blocks is always in 512-byte units.
+    metadata.is_file() && metadata.blocks() * 512 < metadata.len()
+}
+
+#[cfg(not(unix))]
+fn maybe_sparse(_metadata: &fs::Metadata) -> bool {
+    false
+}
+
+/// Find the holes in a file of length `len`.
+///
+/// If holes can't be found, because the platform or filesystem doesn't support
+/// it, or the file changes while it's being examined, return none, and the
+/// holes will be read as data.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn find_holes(file: &fs::File, len: u64) -> Vec<Range<u64>> {
+    use std::os::unix::io::AsRawFd;
+    let fd = file.as_raw_fd();
+    let seek = |pos: u64, whence| {
+        let r = unsafe { libc::lseek(fd, pos as libc::off_t, whence) };
+        if r < 0 {
+            None
+        } else {
+            Some(r as u64)
+        }
+    };
+    let mut holes = Vec::new();
+    let mut pos = 0;
+    while pos < len {
+        let hole_start = match seek(pos, libc::SEEK_HOLE) {
+            Some(p) if p < len => p,
+            Some(_) => break,
+            None => return Vec::new(),
+        };
+        let hole_end = match seek(hole_start, libc::SEEK_DATA) {
+            Some(p) => p.min(len),
+            // ENXIO means there's no more data: the hole runs to the end.
+            None if std::io::Error::last_os_error().raw_os_error() == Some(libc::ENXIO) => len,
+            None => return Vec::new(),
+        };
+        if hole_end <= hole_start {
+            return Vec::new();
+        }
+        holes.push(hole_start..hole_end);
+        pos = hole_end;
+    }
+    holes
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+fn find_holes(_file: &fs::File, _len: u64) -> Vec<Range<u64>> {
+    Vec::new()
+}
+
+/// The start of every valid `CACHEDIR.TAG` file.
+const CACHEDIR_TAG_SIGNATURE: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
+
+/// True if `dir` contains a `CACHEDIR.TAG` file starting with the standard signature.
+fn is_cache_dir(dir: &Path) -> bool {
+    use std::io::Read;
+    let mut buf = [0u8; CACHEDIR_TAG_SIGNATURE.len()];
+    match fs::File::open(dir.join("CACHEDIR.TAG")) {
+        Ok(mut f) => f.read_exact(&mut buf).is_ok() && buf[..] == *CACHEDIR_TAG_SIGNATURE,
+        Err(_) => false,
+    }
+}
+
+/// Read the xattrs of a file, or warn and return none if they can't be read.
+fn read_xattrs_or_warn(path: &Path) -> Vec<Xattr> {
+    xattrs::read_xattrs(path).unwrap_or_else(|e| {
+        log::error!("Failed to read xattrs of {:?}: {}", path, e);
+        Vec::new()
+    })
+}
+
+#[cfg(unix)]
+fn unix_mode(metadata: &fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn unix_mode(_metadata: &fs::Metadata) -> Option<u32> {
+    None
+}
+
 /// Recursive iterator of the contents of a live tree.
 #[derive(Debug)]
 pub struct Iter {
     /// Root of the source tree.
     root_path: PathBuf,
 
+    /// Apath of the root of the tree.
+    apath_prefix: Apath,
+
     /// Directories yet to be visited.
     dir_deque: VecDeque<Apath>,
 
@@ -174,35 +466,73 @@ pub struct Iter {
     /// glob pattern to skip in iterator
     excludes: GlobSet,
 
+    /// Skip directories containing a valid `CACHEDIR.TAG`.
+    exclude_caches: bool,
+
+    /// Skip directories containing any of these files.
+    exclude_if_present: Vec<String>,
+
     stats: LiveTreeIterStats,
+
+    /// For files with several links, the first apath seen for each (device, inode).
+    hardlink_groups: HashMap<(u64, u64), Apath>,
+
+    /// If only one filesystem is to be visited, the device of the root.
+    root_device: Option<u64>,
+
+    /// Stats shared with the LiveTree, which receive `stats` when this is dropped.
+    tree_stats: Arc<Mutex<LiveTreeIterStats>>,
 }
 
 impl Iter {
-    /// Construct a new iter that will visit everything below this root path,
-    /// subject to some exclusions
-    fn new(root_path: &Path, excludes: &GlobSet) -> Result<Iter> {
+    /// Construct a new iter that will visit everything below the root of a tree,
+    /// subject to its exclusions.
+    fn new(tree: &LiveTree) -> Result<Iter> {
+        let root_path = &tree.path;
         let root_metadata = fs::symlink_metadata(&root_path).map_err(Error::from)?;
+        let root_device = if tree.one_file_system {
+            device_of(&root_metadata)
+        } else {
+            None
+        };
         // Preload iter to return the root and then recurse into it.
         let mut entry_deque = VecDeque::<LiveEntry>::new();
         entry_deque.push_back(LiveEntry::from_fs_metadata(
-            Apath::from("/"),
+            tree.apath_prefix.clone(),
             &root_metadata,
             None,
+            read_xattrs_or_warn(root_path),
         ));
         // TODO: Consider the case where the root is not actually a directory?
         // Should that be supported?
         let mut dir_deque = VecDeque::<Apath>::new();
-        dir_deque.push_back("/".into());
+        dir_deque.push_back(tree.apath_prefix.clone());
         Ok(Iter {
-            root_path: root_path.to_path_buf(),
+            root_path: root_path.clone(),
+            apath_prefix: tree.apath_prefix.clone(),
             entry_deque,
             dir_deque,
             check_order: apath::CheckOrder::new(),
-            excludes: excludes.clone(),
+            excludes: tree.excludes.clone(),
+            exclude_caches: tree.exclude_caches,
+            exclude_if_present: tree.exclude_if_present.clone(),
             stats: LiveTreeIterStats::default(),
+            hardlink_groups: HashMap::new(),
+            root_device,
+            tree_stats: tree.iter_stats.clone(),
         })
     }
 
+    /// True if the directory at `path` contains a marker saying it shouldn't be
+    /// backed up.
+    fn is_marked_excluded(&self, path: &Path) -> bool {
+        (self.exclude_caches && is_cache_dir(path))
+            || self
+                .exclude_if_present
+                .iter()
+                .any(|name| fs::symlink_metadata(path.join(name)).is_ok())
+    }
+
     /// Visit the next directory.
     ///
     /// Any errors occurring are logged but not returned; we'll continue to
@@ -216,11 +546,12 @@ impl Iter {
         // reverse order from which we pop would work well.
         self.stats.directories_visited += 1;
         let mut children = Vec::<(String, LiveEntry)>::new();
-        let dir_path = relative_path(&self.root_path, parent_apath);
+        let mut mount_points = Vec::<Apath>::new();
+        let dir_path = relative_path(&self.root_path, &self.apath_prefix, parent_apath);
         let dir_iter = match fs::read_dir(&dir_path) {
             Ok(i) => i,
             Err(e) => {
-                ui::problem(&format!("Error reading directory {:?}: {}", &dir_path, e));
+                log::error!("Error reading directory {:?}: {}", &dir_path, e);
                 return;
             }
         };
@@ -228,10 +559,11 @@ impl Iter {
             let dir_entry = match dir_entry {
                 Ok(dir_entry) => dir_entry,
                 Err(e) => {
-                    ui::problem(&format!(
+                    log::error!(
                         "Error reading next entry from directory {:?}: {}",
-                        &dir_path, e
-                    ));
+                        &dir_path,
+                        e
+                    );
                     continue;
                 }
             };
@@ -244,10 +576,7 @@ impl Iter {
             let child_name = match child_osstr.to_str() {
                 Some(c) => c,
                 None => {
-                    ui::problem(&format!(
-                        "Can't decode filename {:?} in {:?}",
-                        child_osstr, dir_path,
-                    ));
+                    log::error!("Can't decode filename {:?} in {:?}", child_osstr, dir_path);
                     continue;
                 }
             };
@@ -255,10 +584,11 @@ impl Iter {
             let ft = match dir_entry.file_type() {
                 Ok(ft) => ft,
                 Err(e) => {
-                    ui::problem(&format!(
+                    log::error!(
                         "Error getting type of {:?} during iteration: {}",
-                        child_apath_str, e
-                    ));
+                        child_apath_str,
+                        e
+                    );
                     continue;
                 }
             };
@@ -267,6 +597,10 @@ impl Iter {
                 self.stats.exclusions += 1;
                 continue;
             }
+            if ft.is_dir() && self.is_marked_excluded(&dir_path.join(child_osstr)) {
+                self.stats.exclusions += 1;
+                continue;
+            }
             let metadata = match dir_entry.metadata() {
                 Ok(metadata) => metadata,
                 Err(e) => {
@@ -274,16 +608,18 @@ impl Iter {
                         ErrorKind::NotFound => {
                             // Fairly harmless, and maybe not even worth logging. Just a race
                             // between listing the directory and looking at the contents.
-                            ui::problem(&format!(
+                            log::error!(
                                 "File disappeared during iteration: {:?}: {}",
-                                child_apath_str, e
-                            ));
+                                child_apath_str,
+                                e
+                            );
                         }
                         _ => {
-                            ui::problem(&format!(
+                            log::error!(
                                 "Failed to read source metadata from {:?}: {}",
-                                child_apath_str, e
-                            ));
+                                child_apath_str,
+                                e
+                            );
                             self.stats.metadata_error += 1;
                         }
                     };
@@ -291,26 +627,35 @@ impl Iter {
                 }
             };
 
+            if ft.is_dir() && self.root_device.is_some() && device_of(&metadata) != self.root_device
+            {
+                // List the mount point, but not its contents.
+                self.stats.mount_points_skipped += 1;
+                mount_points.push(Apath::from(child_apath_str.as_str()));
+            }
+
             // TODO: Move this into LiveEntry::from_fs_metadata, once there's a
             // global way for it to complain about errors.
             let target: Option<String> = if ft.is_symlink() {
                 let t = match dir_path.join(dir_entry.file_name()).read_link() {
                     Ok(t) => t,
                     Err(e) => {
-                        ui::problem(&format!(
+                        log::error!(
                             "Failed to read target of symlink {:?}: {}",
-                            child_apath_str, e
-                        ));
+                            child_apath_str,
+                            e
+                        );
                         continue;
                     }
                 };
                 match t.into_os_string().into_string() {
                     Ok(t) => Some(t),
                     Err(e) => {
-                        ui::problem(&format!(
+                        log::error!(
                             "Failed to decode target of symlink {:?}: {:?}",
-                            child_apath_str, e
-                        ));
+                            child_apath_str,
+                            e
+                        );
                         continue;
                     }
                 }
@@ -319,15 +664,35 @@ impl Iter {
             };
             children.push((
                 child_name.to_string(),
-                LiveEntry::from_fs_metadata(child_apath_str.into(), &metadata, target),
+                LiveEntry::from_fs_metadata(
+                    child_apath_str.into(),
+                    &metadata,
+                    target,
+                    read_xattrs_or_warn(&dir_path.join(dir_entry.file_name())),
+                ),
             ));
         }
         children.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        // Directories are visited, and their children sorted, in apath order,
+        // so the first file seen for each inode is the first in the tree.
+        for (_, child) in children.iter_mut() {
+            if let Some(inode) = child.inode {
+                let group = self
+                    .hardlink_groups
+                    .entry(inode)
+                    .or_insert_with(|| child.apath.clone());
+                child.hardlink_group = Some(group.clone());
+            }
+        }
         // To get the right overall tree ordering, any new subdirectories
         // discovered here should be visited together in apath order, but before
         // any previously pending directories. In other words, in reverse order
         // push them onto the front of the dir deque.
-        for idir in children.iter().filter(|x| x.1.kind == Kind::Dir).rev() {
+        for idir in children
+            .iter()
+            .filter(|x| x.1.kind == Kind::Dir && !mount_points.contains(&x.1.apath))
+            .rev()
+        {
             self.dir_deque.push_front(idir.1.apath().clone())
         }
         self.entry_deque.reserve(children.len());
@@ -335,6 +700,14 @@ impl Iter {
     }
 }
 
+impl Drop for Iter {
+    fn drop(&mut self) {
+        if let Ok(mut tree_stats) = self.tree_stats.lock() {
+            *tree_stats += std::mem::take(&mut self.stats);
+        }
+    }
+}
+
 // The source iterator yields one path at a time as it walks through the source directories.
 //
 // It has to read each directory entirely so that it can sort the entries.
@@ -402,12 +775,13 @@ mod tests {
         assert_eq!(result.len(), 7);
 
         let repr = format!("{:?}", &result[6]);
-        let re = Regex::new(r#"LiveEntry \{ apath: Apath\("/jam/apricot"\), kind: File, mtime: UnixTime \{ [^)]* \}, size: Some\(8\), symlink_target: None \}"#).unwrap();
+        let re = Regex::new(r#"LiveEntry \{ apath: Apath\("/jam/apricot"\), kind: File, mtime: UnixTime \{ [^)]* \}, size: Some\(8\), symlink_target: None, unix_mode: (Some\(\d+\)|None), owner: Owner \{ [^}]* \}, xattrs: \[[^\]]*\], hardlink_group: None, rdev: None, windows_attributes: None, creation_time: None, inode: None, maybe_sparse: false \}"#).unwrap();
         assert!(re.is_match(&repr), repr);
 
-        // TODO: Somehow get the stats out of the iterator.
-        // assert_eq!(source_iter.stats.directories_visited, 4);
-        // assert_eq!(source_iter.stats.entries_returned, 7);
+        drop(source_iter);
+        let stats = lt.iter_stats();
+        assert_eq!(stats.directories_visited, 4);
+        assert_eq!(stats.entries_returned, 7);
     }
 
     #[test]
@@ -433,10 +807,51 @@ mod tests {
         assert_eq!(&result[2].apath, "/baz/test");
         assert_eq!(result.len(), 3);
 
-        // TODO: Get stats back from the iterator
-        // assert_eq!(source_iter.stats.directories_visited, 2);
-        // assert_eq!(source_iter.stats.entries_returned, 3);
-        // assert_eq!(source_iter.stats.exclusions, 5);
+        drop(source_iter);
+        let stats = lt.iter_stats();
+        assert_eq!(stats.directories_visited, 2);
+        assert_eq!(stats.entries_returned, 3);
+        assert_eq!(stats.exclusions, 5);
+    }
+
+    #[test]
+    fn one_file_system_on_a_single_filesystem() {
+        let tf = TreeFixture::new();
+        tf.create_dir("sub");
+        tf.create_file("sub/a");
+
+        let lt = LiveTree::open(tf.path())
+            .unwrap()
+            .with_one_file_system(true);
+        let names: Vec<String> = lt
+            .iter_entries()
+            .unwrap()
+            .map(|entry| entry.apath.into())
+            .collect();
+        assert_eq!(names.as_slice(), ["/", "/sub", "/sub/a"]);
+        assert_eq!(lt.iter_stats().mount_points_skipped, 0);
+    }
+
+    /// /dev usually has other filesystems such as /dev/pts mounted within it.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn one_file_system_stays_on_root_device() {
+        use std::fs;
+        use std::os::unix::fs::MetadataExt;
+        use std::path::Path;
+
+        let root = Path::new("/dev");
+        let root_dev = fs::symlink_metadata(root).unwrap().dev();
+        let lt = LiveTree::open(root).unwrap().with_one_file_system(true);
+        for entry in lt.iter_entries().unwrap().skip(1) {
+            let parent = root.join(&entry.apath[1..]).parent().unwrap().to_owned();
+            assert_eq!(
+                fs::symlink_metadata(&parent).unwrap().dev(),
+                root_dev,
+                "{:?} is within another filesystem",
+                entry.apath
+            );
+        }
     }
 
     #[cfg(unix)]
@@ -0,0 +1,98 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Upgrade an archive in place to use all the format features of this
+//! version.
+//!
+//! The archive stays readable and restorable throughout: the feature is
+//! recorded in the header before any data uses it, and each block file is
+//! atomically replaced by one with the same content. An interrupted
+//! migration can be finished by running it again.
+
+use std::sync::{Arc, Mutex};
+
+use rayon::prelude::*;
+
+use crate::stats::MigrateStats;
+use crate::*;
+
+/// Options for [migrate_archive].
+#[derive(Debug, Default, Clone)]
+pub struct MigrateOptions {
+    /// Count what would be changed, but don't change the archive.
+    pub dry_run: bool,
+    /// Receives progress of the operation, if set.
+    pub progress: Option<Arc<dyn ProgressSink>>,
+}
+
+/// Recompress all the blocks in `archive` with Zstandard, enabling the
+/// `zstd_blocks` feature.
+///
+/// Other features, such as compressed or delta indexes, aren't enabled, since
+/// nothing is converted to use them, and they'd only stop older versions
+/// reading the archive.
+///
+/// Every block's content is checked against its hash before it's rewritten.
+pub fn migrate_archive(archive: &Archive, options: &MigrateOptions) -> Result<MigrateStats> {
+    if !options.dry_run {
+        archive.check_append_only()?;
+    }
+    let _lock = if options.dry_run {
+        None
+    } else {
+        Some(WriteLock::acquire(archive, "migrate")?)
+    };
+    if !options.dry_run {
+        archive.enable_features(&[ZSTD_BLOCKS_FEATURE])?;
+    }
+    let block_dir = archive.block_dir();
+    let hashes: Vec<BlockHash> = block_dir.block_names()?.collect();
+    let mut progress_bar = ProgressBar::new(options.progress.as_ref());
+    progress_bar.set_phase("Recompress blocks".to_owned());
+    progress_bar.set_total_work(hashes.len());
+    let progress_bar_mutex = Mutex::new(progress_bar);
+    hashes
+        .par_iter()
+        .map(|hash| {
+            let r = migrate_block(block_dir, hash, options);
+            progress_bar_mutex.lock().unwrap().increment_work_done(1);
+            r
+        })
+        .try_reduce(MigrateStats::default, |a, b| Ok(a + b))
+}
+
+fn migrate_block(
+    block_dir: &BlockDir,
+    hash: &BlockHash,
+    options: &MigrateOptions,
+) -> Result<MigrateStats> {
+    let mut stats = MigrateStats::default();
+    if options.dry_run {
+        if block_dir.is_zstd_compressed(hash)? {
+            stats.blocks_already_migrated = 1;
+        } else {
+            stats.blocks_recompressed = 1;
+            stats.compressed_bytes_before = block_dir.compressed_size(hash)?;
+        }
+    } else {
+        match block_dir.recompress_block_zstd(hash)? {
+            Some((before, after)) => {
+                stats.blocks_recompressed = 1;
+                stats.compressed_bytes_before = before;
+                stats.compressed_bytes_after = after;
+            }
+            None => stats.blocks_already_migrated = 1,
+        }
+    }
+    Ok(stats)
+}
@@ -0,0 +1,583 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Mount the versions in an archive as a read-only filesystem, through FUSE.
+//!
+//! The root of the filesystem has a directory for each band, named by its id,
+//! plus `latest` for the most recent band. Directories are listed by seeking to
+//! their entries through the index hunk manifest, and only a bounded number of
+//! listings are cached, so memory use doesn't grow with the size of the index.
+//! File content is read from blocks only as it's requested.
+//!
+//! This requires the `fuse` cargo feature, and is only supported on Unix.
+
+use std::collections::{HashMap, VecDeque};
+use std::ffi::OsStr;
+use std::path::Path;
+use std::rc::Rc;
+
+use fuse_crate::{
+    FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, Request,
+};
+use time::Timespec;
+
+use crate::blockdir::Address;
+use crate::errors::ErrorChain;
+use crate::kind::Kind;
+use crate::*;
+
+/// How long the kernel may cache attributes and lookups: stored trees never change.
+const TTL: Timespec = Timespec { sec: 3600, nsec: 0 };
+
+const ROOT_INO: u64 = 1;
+
+/// Inode number of the first band directory; the others follow in order.
+const FIRST_BAND_INO: u64 = 2;
+
+/// The number of directory listings kept in memory.
+const MAX_CACHED_DIRS: usize = 64;
+
+/// Mount the archive at `mountpoint`, and serve requests until it's unmounted.
+pub fn mount(archive: &Archive, mountpoint: &Path) -> Result<()> {
+    let fs = ArchiveFs::new(archive)?;
+    let options: Vec<&OsStr> = ["-o", "ro", "-o", "fsname=conserve"]
+        .iter()
+        .map(OsStr::new)
+        .collect();
+    fuse_crate::mount(fs, &mountpoint, &options).map_err(|source| Error::Mount {
+        path: mountpoint.to_owned(),
+        source,
+    })
+}
+
+/// The filesystem, with a directory for each band.
+struct ArchiveFs {
+    archive: Archive,
+    bands: Vec<BandDir>,
+    /// The band and apath of each entry given an inode number, starting from
+    /// the inode after the last band directory.
+    inodes: Vec<(usize, Apath)>,
+    /// The inode number of each entry in `inodes`.
+    ino_by_path: HashMap<(usize, Apath), u64>,
+    dirs: DirCache,
+    /// Owner for the root directory, and for entries with no stored owner.
+    uid: u32,
+    gid: u32,
+    /// Time shown on the root directory.
+    mount_time: Timespec,
+}
+
+/// A directory at the root of the filesystem, showing one band.
+struct BandDir {
+    name: String,
+    band_id: BandId,
+    /// The band's tree, opened when the directory is first used.
+    open: Option<OpenBand>,
+}
+
+struct OpenBand {
+    tree: StoredTree,
+    /// The entry for the band's root directory, if it has one.
+    root: Option<IndexEntry>,
+}
+
+/// What an inode number refers to.
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum Node {
+    Root,
+    /// A band directory, by position in `ArchiveFs::bands`.
+    Band(usize),
+    /// An entry within a band: the band's position, and the entry's apath.
+    Entry(usize, Apath),
+}
+
+/// The children of recently used directories, most recently used last.
+#[derive(Default)]
+struct DirCache {
+    dirs: VecDeque<((usize, Apath), Rc<Vec<IndexEntry>>)>,
+}
+
+impl DirCache {
+    fn get(&mut self, band: usize, dir: &Apath) -> Option<Rc<Vec<IndexEntry>>> {
+        let pos = self
+            .dirs
+            .iter()
+            .position(|((b, d), _)| *b == band && d == dir)?;
+        let cached = self.dirs.remove(pos)?;
+        let children = cached.1.clone();
+        self.dirs.push_back(cached);
+        Some(children)
+    }
+
+    fn insert(&mut self, band: usize, dir: Apath, children: Rc<Vec<IndexEntry>>) {
+        if self.dirs.len() >= MAX_CACHED_DIRS {
+            self.dirs.pop_front();
+        }
+        self.dirs.push_back(((band, dir), children));
+    }
+}
+
+impl ArchiveFs {
+    fn new(archive: &Archive) -> Result<ArchiveFs> {
+        let mut bands: Vec<BandDir> = archive
+            .list_band_ids()?
+            .into_iter()
+            .map(|band_id| BandDir {
+                name: band_id.to_string(),
+                band_id,
+                open: None,
+            })
+            .collect();
+        if let Some(band_id) = archive.last_band_id()? {
+            bands.push(BandDir {
+                name: "latest".to_owned(),
+                band_id,
+                open: None,
+            });
+        }
+        Ok(ArchiveFs {
+            archive: archive.clone(),
+            bands,
+            inodes: Vec::new(),
+            ino_by_path: HashMap::new(),
+            dirs: DirCache::default(),
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+            mount_time: time::get_time(),
+        })
+    }
+
+    /// Open the tree of a band, if it's not already open.
+    fn open_band(&mut self, band: usize) -> Result<&OpenBand> {
+        let band_dir = &mut self.bands[band];
+        let open = match band_dir.open {
+            Some(ref open) => open,
+            None => {
+                let tree = self
+                    .archive
+                    .open_stored_tree(BandSelectionPolicy::Specified(band_dir.band_id.clone()))?;
+                let root = tree.get_entry(&Apath::from("/"))?;
+                &*band_dir.open.insert(OpenBand { tree, root })
+            }
+        };
+        Ok(open)
+    }
+
+    /// Return the children of directory `dir` in a band, reading them from
+    /// the index if they're not cached.
+    fn children(&mut self, band: usize, dir: &Apath) -> Result<Rc<Vec<IndexEntry>>> {
+        if let Some(children) = self.dirs.get(band, dir) {
+            return Ok(children);
+        }
+        let children: Rc<Vec<IndexEntry>> =
+            Rc::new(self.open_band(band)?.tree.iter_children(dir).collect());
+        self.dirs.insert(band, dir.clone(), children.clone());
+        Ok(children)
+    }
+
+    /// Return the entry for `apath` in a band, by looking in its parent's children.
+    fn entry(&mut self, band: usize, apath: &Apath) -> Result<Option<IndexEntry>> {
+        let parent = match apath.parent() {
+            Some(parent) => parent,
+            None => return Ok(self.open_band(band)?.root.clone()),
+        };
+        Ok(self
+            .children(band, &parent)?
+            .iter()
+            .find(|entry| entry.apath == *apath)
+            .cloned())
+    }
+
+    fn first_entry_ino(&self) -> u64 {
+        FIRST_BAND_INO + self.bands.len() as u64
+    }
+
+    /// Return the inode number of an entry, giving it one if it has none yet.
+    fn ino(&mut self, band: usize, apath: &Apath) -> u64 {
+        if let Some(ino) = self.ino_by_path.get(&(band, apath.clone())) {
+            return *ino;
+        }
+        let ino = self.first_entry_ino() + self.inodes.len() as u64;
+        self.inodes.push((band, apath.clone()));
+        self.ino_by_path.insert((band, apath.clone()), ino);
+        ino
+    }
+
+    fn resolve(&self, ino: u64) -> Option<Node> {
+        if ino == ROOT_INO {
+            return Some(Node::Root);
+        }
+        let band = ino.checked_sub(FIRST_BAND_INO)? as usize;
+        if band < self.bands.len() {
+            return Some(Node::Band(band));
+        }
+        let i = ino.checked_sub(self.first_entry_ino())? as usize;
+        let (band, apath) = self.inodes.get(i)?;
+        Some(Node::Entry(*band, apath.clone()))
+    }
+
+    /// Return the entry for inode `ino`, or an errno if it's not an entry
+    /// within a band.
+    fn resolve_entry(&mut self, ino: u64) -> std::result::Result<IndexEntry, libc::c_int> {
+        match self.resolve(ino) {
+            Some(Node::Entry(band, apath)) => match self.entry(band, &apath) {
+                Ok(Some(entry)) => Ok(entry),
+                Ok(None) => Err(libc::ENOENT),
+                Err(err) => Err(io_error(err)),
+            },
+            Some(Node::Root) | Some(Node::Band(_)) => Err(libc::EISDIR),
+            None => Err(libc::ENOENT),
+        }
+    }
+
+    /// Return the band and the apath of the directory `ino`, or an errno if
+    /// it's not a directory within a band.
+    fn band_dir(&mut self, ino: u64) -> std::result::Result<(usize, Apath), libc::c_int> {
+        match self.resolve(ino) {
+            Some(Node::Band(band)) => {
+                self.open_band(band).map_err(io_error)?;
+                Ok((band, Apath::from("/")))
+            }
+            Some(Node::Entry(band, _)) => {
+                let entry = self.resolve_entry(ino)?;
+                if entry.kind == Kind::Dir {
+                    Ok((band, entry.apath))
+                } else {
+                    Err(libc::ENOTDIR)
+                }
+            }
+            Some(Node::Root) | None => Err(libc::ENOENT),
+        }
+    }
+
+    fn root_attr(&self) -> FileAttr {
+        FileAttr {
+            ino: ROOT_INO,
+            size: 0,
+            blocks: 0,
+            atime: self.mount_time,
+            mtime: self.mount_time,
+            ctime: self.mount_time,
+            crtime: self.mount_time,
+            kind: FileType::Directory,
+            perm: 0o555,
+            nlink: 2,
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            flags: 0,
+        }
+    }
+
+    fn band_attr(&mut self, band: usize) -> Result<FileAttr> {
+        let ino = FIRST_BAND_INO + band as u64;
+        let root = self.open_band(band)?.root.clone();
+        Ok(match root {
+            Some(root) => self.entry_attr(ino, &root),
+            None => FileAttr {
+                ino,
+                ..self.root_attr()
+            },
+        })
+    }
+
+    fn entry_attr(&self, ino: u64, entry: &IndexEntry) -> FileAttr {
+        let kind = file_type(entry.kind);
+        let mtime = Timespec::new(entry.mtime, entry.mtime_nanos as i32);
+        let size = entry.size().unwrap_or_default();
+        let is_dir = kind == FileType::Directory;
+        FileAttr {
+            ino,
+            size,
+            blocks: (size + 511) / 512,
+            atime: mtime,
+            mtime,
+            ctime: mtime,
+            crtime: mtime,
+            kind,
+            perm: match entry.unix_mode {
+                Some(mode) => (mode & 0o7777) as u16,
+                None if is_dir => 0o555,
+                None => 0o444,
+            },
+            nlink: if is_dir { 2 } else { 1 },
+            uid: entry.uid.unwrap_or(self.uid),
+            gid: entry.gid.unwrap_or(self.gid),
+            rdev: entry.rdev.unwrap_or_default() as u32,
+            flags: 0,
+        }
+    }
+}
+
+impl Filesystem for ArchiveFs {
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        if parent == ROOT_INO {
+            let band = match self.bands.iter().position(|b| OsStr::new(&b.name) == name) {
+                Some(band) => band,
+                None => return reply.error(libc::ENOENT),
+            };
+            return match self.band_attr(band) {
+                Ok(attr) => reply.entry(&TTL, &attr, 0),
+                Err(err) => reply.error(io_error(err)),
+            };
+        }
+        let (band, dir) = match self.band_dir(parent) {
+            Ok(found) => found,
+            Err(errno) => return reply.error(errno),
+        };
+        let children = match self.children(band, &dir) {
+            Ok(children) => children,
+            Err(err) => return reply.error(io_error(err)),
+        };
+        match children
+            .iter()
+            .find(|entry| OsStr::new(file_name(&entry.apath)) == name)
+        {
+            Some(entry) => {
+                let ino = self.ino(band, &entry.apath);
+                reply.entry(&TTL, &self.entry_attr(ino, entry), 0)
+            }
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        match self.resolve(ino) {
+            Some(Node::Root) => reply.attr(&TTL, &self.root_attr()),
+            Some(Node::Band(band)) => match self.band_attr(band) {
+                Ok(attr) => reply.attr(&TTL, &attr),
+                Err(err) => reply.error(io_error(err)),
+            },
+            Some(Node::Entry(..)) => match self.resolve_entry(ino) {
+                Ok(entry) => reply.attr(&TTL, &self.entry_attr(ino, &entry)),
+                Err(errno) => reply.error(errno),
+            },
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        match self.resolve_entry(ino) {
+            Ok(IndexEntry {
+                target: Some(target),
+                ..
+            }) => reply.data(target.as_bytes()),
+            Ok(_) | Err(libc::EISDIR) => reply.error(libc::EINVAL),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        reply: ReplyData,
+    ) {
+        let entry = match self.resolve_entry(ino) {
+            Ok(entry) => entry,
+            Err(errno) => return reply.error(errno),
+        };
+        if entry.kind != Kind::File {
+            return reply.error(libc::EINVAL);
+        }
+        match read_range(
+            self.archive.block_dir(),
+            &entry.addrs,
+            offset as u64,
+            size as usize,
+        ) {
+            Ok(data) => reply.data(&data),
+            Err(err) => reply.error(io_error(err)),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let mut listing: Vec<(u64, FileType, String)> = vec![
+            (ino, FileType::Directory, ".".to_owned()),
+            (ROOT_INO, FileType::Directory, "..".to_owned()),
+        ];
+        if ino == ROOT_INO {
+            for (band, band_dir) in self.bands.iter().enumerate() {
+                listing.push((
+                    FIRST_BAND_INO + band as u64,
+                    FileType::Directory,
+                    band_dir.name.clone(),
+                ));
+            }
+        } else {
+            let (band, dir) = match self.band_dir(ino) {
+                Ok(found) => found,
+                Err(errno) => return reply.error(errno),
+            };
+            let children = match self.children(band, &dir) {
+                Ok(children) => children,
+                Err(err) => return reply.error(io_error(err)),
+            };
+            for entry in children.iter() {
+                listing.push((
+                    self.ino(band, &entry.apath),
+                    file_type(entry.kind),
+                    file_name(&entry.apath).to_owned(),
+                ));
+            }
+        }
+        for (i, (ino, kind, name)) in listing.into_iter().enumerate().skip(offset as usize) {
+            // The offset passed back to us is that of the next entry.
+            if reply.add(ino, (i + 1) as i64, kind, name) {
+                break; // The reply buffer is full.
+            }
+        }
+        reply.ok();
+    }
+}
+
+/// Read up to `size` bytes of a stored file, starting at `offset`, reading only
+/// the blocks that overlap that range.
+fn read_range(
+    block_dir: &BlockDir,
+    addrs: &[Address],
+    offset: u64,
+    size: usize,
+) -> Result<Vec<u8>> {
+    let end = offset + size as u64;
+    let mut buf = Vec::with_capacity(size);
+    let mut addr_start = 0u64;
+    for addr in addrs {
+        if addr_start >= end {
+            break;
+        }
+        let addr_end = addr_start + addr.len;
+        if addr_end > offset {
+            let (bytes, _sizes) = block_dir.get(addr)?;
+            let from = offset.saturating_sub(addr_start) as usize;
+            let to = (std::cmp::min(end, addr_end) - addr_start) as usize;
+            buf.extend_from_slice(&bytes[from..to]);
+        }
+        addr_start = addr_end;
+    }
+    Ok(buf)
+}
+
+/// The last component of an apath.
+fn file_name(apath: &Apath) -> &str {
+    &apath[apath.rfind('/').unwrap() + 1..]
+}
+
+fn file_type(kind: Kind) -> FileType {
+    match kind {
+        Kind::Dir => FileType::Directory,
+        Kind::Symlink => FileType::Symlink,
+        Kind::Fifo => FileType::NamedPipe,
+        Kind::Socket => FileType::Socket,
+        Kind::CharDevice => FileType::CharDevice,
+        Kind::BlockDevice => FileType::BlockDevice,
+        Kind::File | Kind::Unknown => FileType::RegularFile,
+    }
+}
+
+/// Report an error reading the archive, and return the errno for the kernel.
+fn io_error(err: Error) -> libc::c_int {
+    log::error!("{}", ErrorChain(&err));
+    libc::EIO
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::ScratchArchive;
+
+    #[test]
+    fn inodes_and_children() {
+        let af = ScratchArchive::new();
+        af.store_two_versions();
+        let mut fs = ArchiveFs::new(&af).unwrap();
+        let names: Vec<&str> = fs.bands.iter().map(|b| b.name.as_str()).collect();
+        assert_eq!(names, ["b0000", "b0001", "latest"]);
+        assert_eq!(fs.resolve(ROOT_INO), Some(Node::Root));
+        assert_eq!(fs.resolve(FIRST_BAND_INO + 2), Some(Node::Band(2)));
+        // Entries have no inodes until they're looked up.
+        assert_eq!(fs.resolve(FIRST_BAND_INO + 3), None);
+        assert!(fs.bands[2].open.is_none());
+
+        let (band, dir) = fs.band_dir(FIRST_BAND_INO + 2).unwrap();
+        assert_eq!(band, 2);
+        assert_eq!(dir, "/");
+        assert!(fs.bands[2].open.is_some());
+        let children = fs.children(band, &dir).unwrap();
+        assert!(children.iter().any(|entry| entry.apath == "/subdir"));
+        assert!(!children
+            .iter()
+            .any(|entry| entry.apath == "/subdir/subfile"));
+        let subdir_ino = fs.ino(band, &"/subdir".into());
+        assert_eq!(subdir_ino, FIRST_BAND_INO + 3);
+        assert_eq!(fs.ino(band, &"/subdir".into()), subdir_ino);
+        assert_eq!(
+            fs.resolve(subdir_ino),
+            Some(Node::Entry(2, "/subdir".into()))
+        );
+
+        let (_, dir) = fs.band_dir(subdir_ino).unwrap();
+        assert_eq!(dir, "/subdir");
+        let subfile_ino = fs.ino(band, &"/subdir/subfile".into());
+        assert_eq!(fs.band_dir(subfile_ino), Err(libc::ENOTDIR));
+        let subfile = fs.resolve_entry(subfile_ino).unwrap();
+        let attr = fs.entry_attr(subfile_ino, &subfile);
+        assert_eq!(attr.kind, FileType::RegularFile);
+        assert_eq!(attr.size, b"contents".len() as u64);
+        assert_eq!(fs.resolve_entry(FIRST_BAND_INO + 2), Err(libc::EISDIR));
+    }
+
+    #[test]
+    fn dir_cache_is_bounded() {
+        let mut cache = DirCache::default();
+        for i in 0..(MAX_CACHED_DIRS + 1) {
+            cache.insert(0, Apath::from(format!("/{}", i)), Rc::new(Vec::new()));
+        }
+        assert_eq!(cache.dirs.len(), MAX_CACHED_DIRS);
+        // The least recently used listing was dropped.
+        assert!(cache.get(0, &"/0".into()).is_none());
+        assert!(cache.get(0, &"/1".into()).is_some());
+        assert!(cache.get(1, &"/1".into()).is_none());
+        cache.insert(0, "/new".into(), Rc::new(Vec::new()));
+        // "/1" was used most recently, so "/2" is dropped instead.
+        assert!(cache.get(0, &"/1".into()).is_some());
+        assert!(cache.get(0, &"/2".into()).is_none());
+    }
+
+    #[test]
+    fn read_part_of_file() {
+        let af = ScratchArchive::new();
+        af.store_two_versions();
+        let st = af.open_stored_tree(BandSelectionPolicy::Latest).unwrap();
+        let entry = st.get_entry(&"/hello".into()).unwrap().unwrap();
+        let block_dir = af.block_dir();
+        assert_eq!(
+            read_range(block_dir, &entry.addrs, 0, 1000).unwrap(),
+            b"contents"
+        );
+        assert_eq!(read_range(block_dir, &entry.addrs, 2, 3).unwrap(), b"nte");
+        assert!(read_range(block_dir, &entry.addrs, 100, 3)
+            .unwrap()
+            .is_empty());
+    }
+}
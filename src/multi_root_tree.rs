@@ -0,0 +1,295 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Several source directories presented as one tree, each named by its
+//! absolute path, so that they can be stored in a single band.
+//!
+//! For example backing up `/etc` and `/home/me` gives a tree containing
+//! `/etc/...` and `/home/me/...`, plus entries for the directories `/` and
+//! `/home` that contain them.
+
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+
+use globset::GlobSet;
+
+use crate::stats::LiveTreeIterStats;
+use crate::*;
+
+/// Several live trees, each named under the absolute path of its root.
+#[derive(Clone)]
+pub struct MultiRootTree {
+    /// Trees and their prefixes, in apath order.
+    roots: Vec<LiveTree>,
+    prefixes: Vec<Apath>,
+    /// Entries for the directories containing the roots, which are not
+    /// themselves backed up.
+    ancestors: Vec<LiveEntry>,
+}
+
+impl MultiRootTree {
+    /// Open a tree containing each of `paths`, which must be directories that
+    /// don't overlap.
+    pub fn open<P: AsRef<Path>>(paths: &[P]) -> Result<MultiRootTree> {
+        let mut by_prefix = BTreeMap::<Apath, PathBuf>::new();
+        for path in paths {
+            let path = path.as_ref();
+            let path = path
+                .canonicalize()
+                .map_err(|source| Error::ReadSourceFile {
+                    path: path.to_owned(),
+                    source,
+                })?;
+            let prefix = apath_for_root(&path)?;
+            by_prefix.insert(prefix, path);
+        }
+        let prefixes: Vec<Apath> = by_prefix.keys().cloned().collect();
+        for a in &prefixes {
+            for b in &prefixes {
+                if a != b && a.is_prefix_of(b) {
+                    return Err(Error::OverlappingSourceRoots {
+                        a: by_prefix[a].clone(),
+                        b: by_prefix[b].clone(),
+                    });
+                }
+            }
+        }
+        let mut ancestors = BTreeMap::<Apath, LiveEntry>::new();
+        for (prefix, path) in &by_prefix {
+            let mut apath = prefix.parent();
+            let mut dir = path.parent();
+            while let (Some(a), Some(d)) = (apath, dir) {
+                if !ancestors.contains_key(&a) {
+                    ancestors.insert(a.clone(), LiveEntry::from_dir_path(a.clone(), d)?);
+                }
+                apath = a.parent();
+                dir = d.parent();
+            }
+        }
+        let roots = by_prefix
+            .into_iter()
+            .map(|(prefix, path)| LiveTree::open(path).map(|tree| tree.with_apath_prefix(prefix)))
+            .collect::<Result<Vec<LiveTree>>>()?;
+        Ok(MultiRootTree {
+            roots,
+            prefixes,
+            ancestors: ancestors.into_values().collect(),
+        })
+    }
+
+    /// Return a new tree which when listed will ignore certain files.
+    ///
+    /// Exclusions are matched against the full apath, including the root's prefix.
+    pub fn with_excludes(self, excludes: GlobSet) -> MultiRootTree {
+        self.map_roots(|tree| tree.with_excludes(excludes.clone()))
+    }
+
+    /// Return a new tree which doesn't descend into other filesystems from
+    /// each root. See `LiveTree::with_one_file_system`.
+    pub fn with_one_file_system(self, one_file_system: bool) -> MultiRootTree {
+        self.map_roots(|tree| tree.with_one_file_system(one_file_system))
+    }
+
+    /// Return a new tree which skips directories containing a `CACHEDIR.TAG`.
+    pub fn with_exclude_caches(self, exclude_caches: bool) -> MultiRootTree {
+        self.map_roots(|tree| tree.with_exclude_caches(exclude_caches))
+    }
+
+    /// Return a new tree which skips directories containing any of these names.
+    pub fn with_exclude_if_present(self, exclude_if_present: Vec<String>) -> MultiRootTree {
+        self.map_roots(|tree| tree.with_exclude_if_present(exclude_if_present.clone()))
+    }
+
+    /// Return the apaths under which each root is stored, in order.
+    pub fn prefixes(&self) -> &[Apath] {
+        &self.prefixes
+    }
+
+    /// Return the stats accumulated by all finished iterations over all roots.
+    pub fn iter_stats(&self) -> LiveTreeIterStats {
+        self.roots
+            .iter()
+            .map(LiveTree::iter_stats)
+            .fold(LiveTreeIterStats::default(), |a, b| a + b)
+    }
+
+    fn map_roots<F: FnMut(LiveTree) -> LiveTree>(self, f: F) -> MultiRootTree {
+        MultiRootTree {
+            roots: self.roots.into_iter().map(f).collect(),
+            ..self
+        }
+    }
+
+    /// Find the tree holding `apath`.
+    fn root_for(&self, apath: &Apath) -> Option<&LiveTree> {
+        self.prefixes
+            .iter()
+            .position(|prefix| prefix.is_prefix_of(apath))
+            .map(|i| &self.roots[i])
+    }
+}
+
+/// Return the apath under which an absolute, canonical, directory is stored.
+///
+/// On Windows the drive is included as the first component, as in `/C:/Users`.
+fn apath_for_root(path: &Path) -> Result<Apath> {
+    let mut apath = String::new();
+    for component in path.components() {
+        match component {
+            Component::Prefix(prefix) => {
+                apath.push('/');
+                apath.push_str(
+                    prefix
+                        .as_os_str()
+                        .to_string_lossy()
+                        .trim_start_matches(['\\', '?'])
+                        .trim_end_matches('\\'),
+                );
+            }
+            Component::Normal(name) => match name.to_str() {
+                Some(name) => {
+                    apath.push('/');
+                    apath.push_str(name);
+                }
+                None => {
+                    return Err(Error::InvalidSourceRoot {
+                        path: path.to_owned(),
+                    })
+                }
+            },
+            _ => (),
+        }
+    }
+    if apath.is_empty() {
+        // Backing up the whole filesystem is better done as a single root.
+        return Err(Error::InvalidSourceRoot {
+            path: path.to_owned(),
+        });
+    }
+    Ok(Apath::from(apath.as_str()))
+}
+
+impl ReadTree for MultiRootTree {
+    type Entry = LiveEntry;
+    type R = std::fs::File;
+
+    /// Iterate the containing directories and the entries of every root,
+    /// merged into apath order.
+    fn iter_entries(&self) -> Result<Box<dyn Iterator<Item = LiveEntry>>> {
+        let mut iters: Vec<std::iter::Peekable<Box<dyn Iterator<Item = LiveEntry>>>> = Vec::new();
+        let ancestors: Box<dyn Iterator<Item = LiveEntry>> =
+            Box::new(self.ancestors.clone().into_iter());
+        iters.push(ancestors.peekable());
+        for tree in &self.roots {
+            iters.push(tree.iter_entries()?.peekable());
+        }
+        Ok(Box::new(std::iter::from_fn(move || {
+            let mut first: Option<(usize, Apath)> = None;
+            for (i, it) in iters.iter_mut().enumerate() {
+                if let Some(entry) = it.peek() {
+                    if first.as_ref().map_or(true, |(_, a)| entry.apath() < a) {
+                        first = Some((i, entry.apath().clone()));
+                    }
+                }
+            }
+            first.and_then(|(i, _)| iters[i].next())
+        })))
+    }
+
+    fn iter_subtree_entries(&self, subtree: &Apath) -> Result<Box<dyn Iterator<Item = LiveEntry>>> {
+        let subtree = subtree.to_owned();
+        Ok(Box::new(
+            self.iter_entries()?
+                .filter(move |entry| subtree.is_prefix_of(entry.apath())),
+        ))
+    }
+
+    fn file_contents(&self, entry: &LiveEntry) -> Result<Self::R> {
+        self.root_for(entry.apath())
+            .expect("file is within a root")
+            .file_contents(entry)
+    }
+
+    fn file_holes(&self, entry: &LiveEntry) -> Result<Vec<Range<u64>>> {
+        self.root_for(entry.apath())
+            .expect("file is within a root")
+            .file_holes(entry)
+    }
+
+    fn estimate_count(&self) -> Result<u64> {
+        let mut count = self.ancestors.len() as u64;
+        for tree in &self.roots {
+            count += tree.estimate_count()?;
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_fixtures::TreeFixture;
+
+    #[test]
+    fn apath_for_unix_root() {
+        if cfg!(unix) {
+            assert_eq!(
+                apath_for_root(Path::new("/home/me")).unwrap(),
+                Apath::from("/home/me")
+            );
+            assert!(apath_for_root(Path::new("/")).is_err());
+        }
+    }
+
+    #[test]
+    fn iter_two_roots() {
+        let tf = TreeFixture::new();
+        tf.create_dir("a");
+        tf.create_file("a/hello");
+        tf.create_dir("b");
+        tf.create_dir("b/sub");
+        tf.create_file("b/sub/world");
+        let tree = MultiRootTree::open(&[tf.path().join("b"), tf.path().join("a")]).unwrap();
+        let base = apath_for_root(&tf.path().canonicalize().unwrap()).unwrap();
+        let apaths: Vec<String> = tree
+            .iter_entries()
+            .unwrap()
+            .map(|e| e.apath().to_string())
+            .collect();
+        let mut expected: Vec<String> = vec!["/".to_owned()];
+        let mut dir = String::new();
+        for name in base.split('/').skip(1) {
+            dir.push('/');
+            dir.push_str(name);
+            expected.push(dir.clone());
+        }
+        for suffix in &["/a", "/b", "/a/hello", "/b/sub", "/b/sub/world"] {
+            expected.push(format!("{}{}", base, suffix));
+        }
+        assert_eq!(apaths, expected);
+        assert_eq!(tree.estimate_count().unwrap(), expected.len() as u64);
+    }
+
+    #[test]
+    fn overlapping_roots_are_refused() {
+        let tf = TreeFixture::new();
+        tf.create_dir("a");
+        tf.create_dir("a/b");
+        match MultiRootTree::open(&[tf.path().join("a"), tf.path().join("a/b")]) {
+            Err(Error::OverlappingSourceRoots { .. }) => (),
+            Err(other) => panic!("unexpected error {:?}", other),
+            Ok(_) => panic!("overlapping roots were accepted"),
+        }
+    }
+}
@@ -18,8 +18,11 @@
 
 use std::io::{BufWriter, Write};
 
-use chrono::Local;
+use chrono::{Local, TimeZone};
+use thousands::Separable;
 
+use crate::stats::mb_string;
+use crate::unix_time::UnixTime;
 use crate::*;
 
 pub fn show_brief_version_list(archive: &Archive, w: &mut dyn Write) -> Result<()> {
@@ -35,85 +38,421 @@ pub fn show_verbose_version_list(
     w: &mut dyn Write,
 ) -> Result<()> {
     for band_id in archive.list_band_ids()? {
-        let band = match Band::open(&archive, &band_id) {
-            Ok(band) => band,
-            Err(e) => {
-                ui::problem(&format!("Failed to open band {:?}: {:?}", band_id, e));
-                continue;
-            }
-        };
-        let info = match band.get_info() {
-            Ok(info) => info,
-            Err(e) => {
-                ui::problem(&format!("Failed to read band tail {:?}: {:?}", band_id, e));
-                continue;
-            }
-        };
-        let is_complete_str = if info.is_closed {
-            "complete"
-        } else {
-            "incomplete"
+        let info = match read_band_info(archive, &band_id) {
+            Some(info) => info,
+            None => continue,
         };
         let start_time_str = info
             .start_time
             .with_timezone(&Local)
             .format(crate::TIMESTAMP_FORMAT);
-        let duration_str = info
-            .end_time
-            .and_then(|et| (et - info.start_time).to_std().ok())
+        let duration_str = band_duration(&info)
             .map(crate::ui::duration_to_hms)
             .unwrap_or_default();
+        let mut line = format!(
+            "{:<20} {:<10} {} {:>8}",
+            band_id,
+            band_status(&info),
+            start_time_str,
+            duration_str,
+        );
+        if show_sizes {
+            let tree_mb = crate::misc::bytes_to_human_mb(stored_tree_bytes(archive, &info)?);
+            line += &format!(" {:>14}", tree_mb);
+        }
+        if !info.tags.is_empty() {
+            line += &format!(" {}", info.tags.join(","));
+        }
+        writeln!(w, "{}", line)?;
+    }
+    Ok(())
+}
+
+/// Show each band as a json object on its own line, with its id, status,
+/// times, tags, and optionally the size of its files.
+///
+/// `status` is `"complete"`, `"partial"`, or `"incomplete"`; times are in
+/// RFC 3339 format and the duration is in seconds.
+pub fn show_json_version_list(
+    archive: &Archive,
+    show_sizes: bool,
+    w: &mut dyn Write,
+) -> Result<()> {
+    for band_id in archive.list_band_ids()? {
+        let info = match read_band_info(archive, &band_id) {
+            Some(info) => info,
+            None => continue,
+        };
+        let mut json = serde_json::json!({
+            "id": band_id.to_string(),
+            "status": band_status(&info),
+            "is_complete": info.is_closed && !info.is_partial,
+            "start_time": info.start_time.to_rfc3339(),
+            "end_time": info.end_time.map(|t| t.to_rfc3339()),
+            "duration_secs": band_duration(&info).map(|d| d.as_secs()),
+            "tags": info.tags,
+        });
         if show_sizes {
-            let tree_mb = crate::misc::bytes_to_human_mb(
-                archive
-                    .open_stored_tree(BandSelectionPolicy::Specified(band_id.clone()))?
-                    .size()?
-                    .file_bytes,
+            json["size_bytes"] = stored_tree_bytes(archive, &info)?.into();
+        }
+        writeln!(w, "{}", json)?;
+    }
+    Ok(())
+}
+
+/// Show one line for each version of a file: the backup id and start time,
+/// how the file changed, and if it's present, its size and modification
+/// time.
+pub fn show_file_history(history: &[FileVersion], w: &mut dyn Write) -> Result<()> {
+    for version in history {
+        let status = if version.is_complete {
+            "complete"
+        } else {
+            "incomplete"
+        };
+        let mut line = format!(
+            "{:<20} {:<10} {} {:<9}",
+            version.band_id,
+            status,
+            version
+                .start_time
+                .with_timezone(&Local)
+                .format(crate::TIMESTAMP_FORMAT),
+            version.change,
+        );
+        if let Some(entry) = &version.entry {
+            line += &format!(
+                " {:>12} {}",
+                entry
+                    .size()
+                    .map_or_else(|| "-".to_owned(), |s| s.to_string()),
+                local_time(entry.mtime()).format(crate::TIMESTAMP_FORMAT),
             );
+        }
+        writeln!(w, "{}", line.trim_end())?;
+    }
+    Ok(())
+}
+
+/// Show each version of a file as a json object on its own line.
+///
+/// `change` is one of the words shown by [show_file_history]; `size` and
+/// `mtime` are null where the file is absent.
+pub fn show_json_file_history(history: &[FileVersion], w: &mut dyn Write) -> Result<()> {
+    for version in history {
+        let entry = version.entry.as_ref();
+        let json = serde_json::json!({
+            "id": version.band_id.to_string(),
+            "is_complete": version.is_complete,
+            "start_time": version.start_time.to_rfc3339(),
+            "change": version.change.to_string(),
+            "kind": entry.map(|e| e.kind()),
+            "size": entry.and_then(|e| e.size()),
+            "mtime": entry.map(|e| local_time(e.mtime()).to_rfc3339()),
+        });
+        writeln!(w, "{}", json)?;
+    }
+    Ok(())
+}
+
+/// Read a band's info, or report a problem and return None if it can't be read.
+fn read_band_info(archive: &Archive, band_id: &BandId) -> Option<crate::band::Info> {
+    let band = match Band::open(archive, band_id) {
+        Ok(band) => band,
+        Err(e) => {
+            log::error!("Failed to open band {:?}: {:?}", band_id, e);
+            return None;
+        }
+    };
+    match band.get_info() {
+        Ok(info) => Some(info),
+        Err(e) => {
+            log::error!("Failed to read band tail {:?}: {:?}", band_id, e);
+            None
+        }
+    }
+}
+
+fn band_status(info: &crate::band::Info) -> &'static str {
+    if info.is_partial {
+        "partial"
+    } else if info.is_closed {
+        "complete"
+    } else {
+        "incomplete"
+    }
+}
+
+fn band_duration(info: &crate::band::Info) -> Option<std::time::Duration> {
+    info.end_time
+        .and_then(|et| (et - info.start_time).to_std().ok())
+}
+
+/// Total bytes of file content in a band, from the band tail if it was
+/// recorded there, or otherwise by reading the index.
+fn stored_tree_bytes(archive: &Archive, info: &crate::band::Info) -> Result<u64> {
+    if let Some(tree_bytes) = info.tree_bytes {
+        return Ok(tree_bytes);
+    }
+    Ok(archive
+        .open_stored_tree(BandSelectionPolicy::Specified(info.id.clone()))?
+        .size(None)?
+        .file_bytes)
+}
+
+/// Show the apparent and deduplicated size of each directory.
+pub fn show_dir_usage(dirs: &[DirUsage], show_bytes: bool, w: &mut dyn Write) -> Result<()> {
+    let mut bw = BufWriter::new(w);
+    for dir in dirs {
+        if show_bytes {
             writeln!(
-                w,
-                "{:<20} {:<10} {} {:>8} {:>14}",
-                band_id, is_complete_str, start_time_str, duration_str, tree_mb,
+                bw,
+                "{:>15} {:>15}  {}",
+                dir.apparent_bytes, dir.unique_bytes, dir.apath
             )?;
         } else {
             writeln!(
-                w,
-                "{:<20} {:<10} {} {:>8}",
-                band_id, is_complete_str, start_time_str, duration_str,
+                bw,
+                "{:>12} MB {:>12} MB  {}",
+                mb_string(dir.apparent_bytes),
+                mb_string(dir.unique_bytes),
+                dir.apath
             )?;
         }
     }
     Ok(())
 }
 
-pub fn show_index_json(band: &Band, w: &mut dyn Write) -> Result<()> {
-    // TODO: Maybe use https://docs.serde.rs/serde/ser/trait.Serializer.html#method.collect_seq.
-    let bw = BufWriter::new(w);
-    let index_entries: Vec<IndexEntry> = band.iter_entries()?.collect();
-    serde_json::ser::to_writer_pretty(bw, &index_entries)
-        .map_err(|source| Error::SerializeIndex { source })
+/// Show whether each backup is kept or removed by a retention policy, and why.
+pub fn show_retention(retention: &[BandRetention], w: &mut dyn Write) -> Result<()> {
+    for band in retention {
+        writeln!(
+            w,
+            "{:<20} {:<8} {} {}",
+            band.band_id,
+            if band.is_kept() { "keep" } else { "remove" },
+            band.start_time.format(crate::TIMESTAMP_FORMAT),
+            band.keep_reasons.join(", "),
+        )?;
+    }
+    Ok(())
+}
+
+/// Show each incomplete band and the range of apaths it covers.
+pub fn show_incomplete_bands(bands: &[IncompleteBand], w: &mut dyn Write) -> Result<()> {
+    for band in bands {
+        match (&band.first_apath, &band.last_apath) {
+            (Some(first), Some(last)) => writeln!(
+                w,
+                "{:<20} {:>12} entries   {} .. {}",
+                band.band_id,
+                band.entry_count.separate_with_commas(),
+                first,
+                last
+            )?,
+            _ => writeln!(w, "{:<20} {:>12} entries", band.band_id, 0)?,
+        }
+    }
+    Ok(())
 }
 
-pub fn show_tree_names<T: ReadTree>(tree: &T, w: &mut dyn Write) -> Result<()> {
+/// Show the entries of a band's index as json.
+///
+/// By default each entry is written as a json object on its own line, as it
+/// is read from the index. If `pretty` is set, the entries are instead written
+/// as one indented json array.
+pub fn show_index_json(
+    entries: impl Iterator<Item = IndexEntry>,
+    pretty: bool,
+    w: &mut dyn Write,
+) -> Result<()> {
+    use serde::Serializer;
+
     let mut bw = BufWriter::new(w);
-    for entry in tree.iter_entries()? {
-        writeln!(bw, "{}", entry.apath())?;
+    if pretty {
+        let mut ser = serde_json::Serializer::pretty(&mut bw);
+        (&mut ser)
+            .collect_seq(entries)
+            .map_err(|source| Error::SerializeIndex { source })?;
+        writeln!(bw)?;
+    } else {
+        for entry in entries {
+            serde_json::to_writer(&mut bw, &entry)
+                .map_err(|source| Error::SerializeIndex { source })?;
+            writeln!(bw)?;
+        }
     }
+    bw.flush()?;
     Ok(())
 }
 
-pub fn show_tree_diff(
-    iter: &mut dyn Iterator<Item = crate::merge::MergedEntry>,
+/// Show an archive's configuration as json, including its features.
+pub fn show_config(archive: &Archive, w: &mut dyn Write) -> Result<()> {
+    let mut json =
+        serde_json::to_value(archive.config()).map_err(|source| Error::SerializeJson {
+            path: crate::config::CONFIG_FILENAME.to_owned(),
+            source,
+        })?;
+    json["features"] = archive.features().into();
+    serde_json::to_writer_pretty(&mut *w, &json).map_err(|source| Error::SerializeJson {
+        path: crate::config::CONFIG_FILENAME.to_owned(),
+        source,
+    })?;
+    writeln!(w)?;
+    Ok(())
+}
+
+/// How to show each entry when listing a tree.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ListFormat {
+    /// Just the apath, one per line.
+    Names,
+    /// Permissions, size, modification time, apath, and symlink target.
+    Long,
+    /// A json object per line.
+    Json,
+    /// Apaths terminated by a zero byte, for `xargs -0`.
+    Null,
+}
+
+pub fn show_entry_list<E: Entry>(
+    entries: &mut dyn Iterator<Item = E>,
+    format: ListFormat,
     w: &mut dyn Write,
 ) -> Result<()> {
     let mut bw = BufWriter::new(w);
-    for e in iter {
-        let ks = match e.kind {
-            MergedEntryKind::LeftOnly => "left",
-            MergedEntryKind::RightOnly => "right",
-            MergedEntryKind::Both => "both",
-        };
-        writeln!(bw, "{:<8} {}", ks, e.apath)?;
+    for entry in entries {
+        // Stored directories and symlinks have a size of zero, but only files
+        // have a meaningful size.
+        let size = entry.size().filter(|_| entry.kind() == Kind::File);
+        match format {
+            ListFormat::Names => writeln!(bw, "{}", entry.apath())?,
+            ListFormat::Null => write!(bw, "{}\0", entry.apath())?,
+            ListFormat::Long => {
+                write!(
+                    bw,
+                    "{} {:>12} {} {}",
+                    mode_string(entry.kind(), entry.unix_mode()),
+                    size.map_or_else(|| "-".to_owned(), |s| s.to_string()),
+                    local_time(entry.mtime()).format("%Y-%m-%d %H:%M:%S"),
+                    entry.apath()
+                )?;
+                if let Some(target) = entry.symlink_target() {
+                    write!(bw, " -> {}", target)?;
+                }
+                writeln!(bw)?;
+            }
+            ListFormat::Json => {
+                let json = serde_json::json!({
+                    "apath": entry.apath().to_string(),
+                    "kind": entry.kind(),
+                    "size": size,
+                    "mtime": local_time(entry.mtime()).to_rfc3339(),
+                    "unix_mode": entry.unix_mode(),
+                    "target": entry.symlink_target(),
+                });
+                writeln!(bw, "{}", json)?;
+            }
+        }
     }
     Ok(())
 }
+
+fn local_time(t: UnixTime) -> chrono::DateTime<Local> {
+    Local.timestamp(t.secs, t.nanosecs)
+}
+
+/// Describe the kind and permissions of an entry in the style of `ls -l`,
+/// such as `drwxr-xr-x`.
+fn mode_string(kind: Kind, unix_mode: Option<u32>) -> String {
+    let mut s = String::with_capacity(10);
+    s.push(match kind {
+        Kind::File => '-',
+        Kind::Dir => 'd',
+        Kind::Symlink => 'l',
+        Kind::Fifo => 'p',
+        Kind::Socket => 's',
+        Kind::CharDevice => 'c',
+        Kind::BlockDevice => 'b',
+        Kind::Unknown => '?',
+    });
+    let mode = match unix_mode {
+        Some(mode) => mode,
+        None => {
+            s.push_str("?????????");
+            return s;
+        }
+    };
+    for (shift, special, set, unset) in &[
+        (6, 0o4000, 's', 'S'),
+        (3, 0o2000, 's', 'S'),
+        (0, 0o1000, 't', 'T'),
+    ] {
+        let bits = mode >> *shift;
+        s.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        s.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        s.push(match (bits & 0o1 != 0, mode & *special != 0) {
+            (true, true) => *set,
+            (false, true) => *unset,
+            (true, false) => 'x',
+            (false, false) => '-',
+        });
+    }
+    s
+}
+
+/// Show one line for each entry in a diff, and return the accumulated stats.
+pub fn show_diff(
+    diff: &mut dyn Iterator<Item = DiffEntry>,
+    w: &mut dyn Write,
+) -> Result<DiffStats> {
+    let mut bw = BufWriter::new(w);
+    let mut stats = DiffStats::default();
+    for entry in diff {
+        entry.add_to_stats(&mut stats);
+        writeln!(bw, "{:<9} {}", entry.kind, entry.apath)?;
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_fixtures::ScratchArchive;
+
+    #[test]
+    fn mode_strings() {
+        assert_eq!(mode_string(Kind::Dir, Some(0o755)), "drwxr-xr-x");
+        assert_eq!(mode_string(Kind::File, Some(0o4640)), "-rwSr-----");
+        assert_eq!(mode_string(Kind::Dir, Some(0o1777)), "drwxrwxrwt");
+        assert_eq!(mode_string(Kind::Symlink, None), "l?????????");
+    }
+
+    #[test]
+    fn index_json_has_one_entry_per_line() {
+        let af = ScratchArchive::new();
+        let band = Band::create(&af).unwrap();
+        let mut ib = band.index_builder();
+        for hunk in 0..3 {
+            for i in 0..2 {
+                let apath = Apath::from(format!("/{}{}", hunk, i));
+                ib.push_entry(IndexEntry::new(apath, Kind::File)).unwrap();
+            }
+            ib.flush().unwrap();
+        }
+        assert_eq!(ib.finish().unwrap().index_hunks, 3);
+        band.close(3).unwrap();
+
+        let mut out = Vec::new();
+        show_index_json(&band, false, &mut out).unwrap();
+        let apaths: Vec<String> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                let entry: IndexEntry = serde_json::from_str(line).unwrap();
+                entry.apath.into()
+            })
+            .collect();
+        assert_eq!(apaths, ["/00", "/01", "/10", "/11", "/20", "/21"]);
+    }
+}
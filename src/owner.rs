@@ -0,0 +1,324 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! File ownership: Unix user and group ids, and their names.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// The owner and group of a file, as stored in the index.
+///
+/// Each field is None if it isn't known, for example on platforms without
+/// Unix ownership, or in indexes written before ownership was recorded.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Owner {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// The name of the user `uid` on the machine where the backup was made.
+    pub user: Option<String>,
+    /// The name of the group `gid` on the machine where the backup was made.
+    pub group: Option<String>,
+}
+
+/// How to set ownership of restored files.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum OwnershipPolicy {
+    /// Don't set ownership: restored files belong to the user running the restore.
+    Skip,
+    /// Set the stored numeric uid and gid.
+    Numeric,
+    /// Look up the stored user and group names on this machine, falling back to
+    /// the stored numeric ids if the names are unknown.
+    #[default]
+    ByName,
+}
+
+#[cfg(unix)]
+impl Owner {
+    /// Read the ownership of a file from its metadata, looking up the names of
+    /// the user and group.
+    pub(crate) fn from_metadata(metadata: &fs::Metadata) -> Owner {
+        use std::os::unix::fs::MetadataExt;
+        let uid = metadata.uid();
+        let gid = metadata.gid();
+        Owner {
+            uid: Some(uid),
+            gid: Some(gid),
+            user: names::user_name(uid),
+            group: names::group_name(gid),
+        }
+    }
+
+    /// Set the ownership of `path`, without following symlinks.
+    ///
+    /// Ids that are not known are left unchanged.
+    pub(crate) fn apply(&self, path: &Path, policy: OwnershipPolicy) -> io::Result<()> {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        let (uid, gid) = match policy {
+            OwnershipPolicy::Skip => return Ok(()),
+            OwnershipPolicy::Numeric => (self.uid, self.gid),
+            OwnershipPolicy::ByName => (
+                self.user.as_deref().and_then(names::user_id).or(self.uid),
+                self.group.as_deref().and_then(names::group_id).or(self.gid),
+            ),
+        };
+        if uid.is_none() && gid.is_none() {
+            return Ok(());
+        }
+        let c_path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        // -1 (as unsigned) means to leave the id unchanged.
+        let uid = uid.unwrap_or(u32::MAX) as libc::uid_t;
+        let gid = gid.unwrap_or(u32::MAX) as libc::gid_t;
+        if unsafe { libc::lchown(c_path.as_ptr(), uid, gid) } == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+}
+
+#[cfg(not(unix))]
+impl Owner {
+    pub(crate) fn from_metadata(_metadata: &fs::Metadata) -> Owner {
+        Owner::default()
+    }
+
+    pub(crate) fn apply(&self, _path: &Path, _policy: OwnershipPolicy) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// True if this process can set the ownership of files to other users.
+#[cfg(unix)]
+pub(crate) fn can_set_ownership() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn can_set_ownership() -> bool {
+    false
+}
+
+/// Cached lookups between user and group names and ids.
+#[cfg(unix)]
+mod names {
+    use std::collections::HashMap;
+    use std::ffi::{CStr, CString};
+    use std::sync::Mutex;
+
+    use lazy_static::lazy_static;
+
+    /// Buffer size for the string fields of passwd and group entries.
+    const BUF_LEN: usize = 16 << 10;
+
+    lazy_static! {
+        static ref USER_NAMES: Mutex<HashMap<u32, Option<String>>> = Mutex::new(HashMap::new());
+        static ref GROUP_NAMES: Mutex<HashMap<u32, Option<String>>> = Mutex::new(HashMap::new());
+    }
+
+    pub(super) fn user_name(uid: u32) -> Option<String> {
+        USER_NAMES
+            .lock()
+            .unwrap()
+            .entry(uid)
+            .or_insert_with(|| lookup_user_name(uid))
+            .clone()
+    }
+
+    pub(super) fn group_name(gid: u32) -> Option<String> {
+        GROUP_NAMES
+            .lock()
+            .unwrap()
+            .entry(gid)
+            .or_insert_with(|| lookup_group_name(gid))
+            .clone()
+    }
+
+    fn lookup_user_name(uid: u32) -> Option<String> {
+        let mut buf = vec![0 as libc::c_char; BUF_LEN];
+        let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut result: *mut libc::passwd = std::ptr::null_mut();
+        let ret =
+            unsafe { libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result) };
+        if ret != 0 || result.is_null() {
+            return None;
+        }
+        unsafe { CStr::from_ptr(pwd.pw_name) }
+            .to_str()
+            .ok()
+            .map(str::to_owned)
+    }
+
+    fn lookup_group_name(gid: u32) -> Option<String> {
+        let mut buf = vec![0 as libc::c_char; BUF_LEN];
+        let mut grp: libc::group = unsafe { std::mem::zeroed() };
+        let mut result: *mut libc::group = std::ptr::null_mut();
+        let ret =
+            unsafe { libc::getgrgid_r(gid, &mut grp, buf.as_mut_ptr(), buf.len(), &mut result) };
+        if ret != 0 || result.is_null() {
+            return None;
+        }
+        unsafe { CStr::from_ptr(grp.gr_name) }
+            .to_str()
+            .ok()
+            .map(str::to_owned)
+    }
+
+    pub(super) fn user_id(name: &str) -> Option<u32> {
+        let c_name = CString::new(name).ok()?;
+        let mut buf = vec![0 as libc::c_char; BUF_LEN];
+        let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut result: *mut libc::passwd = std::ptr::null_mut();
+        let ret = unsafe {
+            libc::getpwnam_r(
+                c_name.as_ptr(),
+                &mut pwd,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            )
+        };
+        if ret != 0 || result.is_null() {
+            None
+        } else {
+            Some(pwd.pw_uid)
+        }
+    }
+
+    pub(super) fn group_id(name: &str) -> Option<u32> {
+        let c_name = CString::new(name).ok()?;
+        let mut buf = vec![0 as libc::c_char; BUF_LEN];
+        let mut grp: libc::group = unsafe { std::mem::zeroed() };
+        let mut result: *mut libc::group = std::ptr::null_mut();
+        let ret = unsafe {
+            libc::getgrnam_r(
+                c_name.as_ptr(),
+                &mut grp,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            )
+        };
+        if ret != 0 || result.is_null() {
+            None
+        } else {
+            Some(grp.gr_gid)
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::fs::MetadataExt;
+
+    use super::*;
+
+    #[test]
+    fn owner_of_new_file_is_current_user() {
+        let tf = tempfile::NamedTempFile::new().unwrap();
+        let owner = Owner::from_metadata(&tf.as_file().metadata().unwrap());
+        assert_eq!(owner.uid, Some(unsafe { libc::geteuid() }));
+        assert!(owner.gid.is_some());
+    }
+
+    #[test]
+    fn names_round_trip() {
+        // Root always exists, and is always uid 0.
+        assert_eq!(names::user_name(0).as_deref(), Some("root"));
+        assert_eq!(names::user_id("root"), Some(0));
+        assert_eq!(names::user_id("no such user, surely"), None);
+    }
+
+    #[test]
+    fn skip_policy_does_nothing() {
+        let tf = tempfile::NamedTempFile::new().unwrap();
+        let before = tf.as_file().metadata().unwrap();
+        let owner = Owner {
+            uid: Some(12345),
+            gid: Some(12345),
+            ..Owner::default()
+        };
+        owner.apply(tf.path(), OwnershipPolicy::Skip).unwrap();
+        let after = tf.as_file().metadata().unwrap();
+        assert_eq!(after.uid(), before.uid());
+        assert_eq!(after.gid(), before.gid());
+    }
+
+    /// Names are looked up on this machine, and take precedence over the
+    /// stored ids, which may belong to someone else here.
+    #[test]
+    fn by_name_policy_maps_current_user() {
+        let uid = unsafe { libc::geteuid() };
+        let gid = unsafe { libc::getegid() };
+        let user = match names::user_name(uid) {
+            Some(user) => user,
+            // Some containers run as a user with no name.
+            None => return,
+        };
+        assert_eq!(names::user_id(&user), Some(uid));
+        let group = names::group_name(gid);
+        let has_group = group.is_some();
+        if let Some(group) = &group {
+            assert_eq!(names::group_id(group), Some(gid));
+        }
+        // Anyone can set a file's owner to themselves, and its group to their
+        // own group, so this works without root.
+        let tf = tempfile::NamedTempFile::new().unwrap();
+        let owner = Owner {
+            uid: Some(uid.wrapping_add(54321)),
+            gid: group.as_ref().map(|_| gid.wrapping_add(54321)),
+            user: Some(user),
+            group,
+        };
+        owner.apply(tf.path(), OwnershipPolicy::ByName).unwrap();
+        let metadata = tf.as_file().metadata().unwrap();
+        assert_eq!(metadata.uid(), uid);
+        if has_group {
+            assert_eq!(metadata.gid(), gid);
+        }
+    }
+
+    #[test]
+    fn set_stored_ids_as_root() {
+        // Only root can give files away.
+        if !can_set_ownership() {
+            return;
+        }
+        let tf = tempfile::NamedTempFile::new().unwrap();
+        let owner = Owner {
+            uid: Some(54321),
+            gid: Some(54322),
+            user: Some("root".to_owned()),
+            group: None,
+        };
+        owner.apply(tf.path(), OwnershipPolicy::Numeric).unwrap();
+        let metadata = tf.as_file().metadata().unwrap();
+        assert_eq!(metadata.uid(), 54321);
+        assert_eq!(metadata.gid(), 54322);
+
+        // By name, the user is found on this machine, and the group falls back
+        // to the stored id.
+        let owner = Owner {
+            gid: Some(54323),
+            ..owner
+        };
+        owner.apply(tf.path(), OwnershipPolicy::ByName).unwrap();
+        let metadata = tf.as_file().metadata().unwrap();
+        assert_eq!(metadata.uid(), 0);
+        assert_eq!(metadata.gid(), 54323);
+    }
+}
@@ -12,19 +12,74 @@
 // GNU General Public License for more details.
 
 //! Progress bars.
+//!
+//! Long-running operations report their progress through a [ProgressBar],
+//! which periodically sends a [Progress] snapshot to the installed
+//! [ProgressSink]. Operations take their sink from the `progress` field of
+//! their options, and discard progress if it's not set; the command line tool
+//! passes [crate::ui::TerminalProgress] to draw it.
 
 use std::fmt::Write;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use crossterm::{cursor, queue, style, terminal};
 use thousands::Separable;
 use unicode_segmentation::UnicodeSegmentation;
 
-use crate::ui::with_locked_ui;
-
 const PROGRESS_RATE_LIMIT: Duration = Duration::from_millis(200);
 
-/// A progress bar, created from the UI.
+/// Minimum interval between samples of the rate of progress.
+const RATE_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Weight of each new sample in the moving average of the rate of progress.
+const RATE_SMOOTHING: f64 = 0.3;
+
+/// Receives progress updates from long-running operations.
+pub trait ProgressSink: Send + Sync + std::fmt::Debug {
+    /// Show the current progress of an operation.
+    ///
+    /// This is called at most a few times a second.
+    fn update(&self, progress: &Progress);
+
+    /// The operation has finished, so any progress display can be removed.
+    fn finish(&self) {}
+}
+
+/// A [ProgressSink] that ignores all progress.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoProgress;
+
+impl ProgressSink for NoProgress {
+    fn update(&self, _progress: &Progress) {}
+}
+
+/// A snapshot of the progress of an operation, passed to a [ProgressSink].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Progress {
+    /// Description of what the operation is doing now.
+    pub phase: String,
+    /// The filename currently being processed.
+    pub filename: String,
+    /// Total units of work, if known, or zero.
+    pub total_work: usize,
+    pub work_done: usize,
+    pub bytes_done: u64,
+    /// Total bytes to be processed, if known, or zero.
+    pub bytes_total: u64,
+    /// Percentage complete, if the operation knows it directly.
+    pub percent: Option<f64>,
+    /// Time since the operation started.
+    pub elapsed: Duration,
+    /// Moving average of bytes processed per second in this phase, once
+    /// it's been measured.
+    pub bytes_per_sec: Option<f64>,
+    /// Moving average of units of work, such as files, done per second in
+    /// this phase, once it's been measured.
+    pub work_per_sec: Option<f64>,
+}
+
+/// Tracks the progress of one operation, and sends it to a [ProgressSink].
 pub struct ProgressBar {
     phase: String,
     /// The filename currently being processed.
@@ -38,10 +93,24 @@ pub struct ProgressBar {
 
     /// The time this bar was last drawn on the screen, if it ever was.
     last_drawn: Option<Instant>,
+
+    /// Time, bytes done, and work done when the rate was last sampled.
+    last_sample: Option<(Instant, u64, usize)>,
+    bytes_per_sec: Option<f64>,
+    work_per_sec: Option<f64>,
+
+    sink: Arc<dyn ProgressSink>,
 }
 
 impl ProgressBar {
-    pub fn new() -> ProgressBar {
+    /// Make a progress bar reporting to `sink`, or discarding progress if
+    /// there is none.
+    pub fn new(sink: Option<&Arc<dyn ProgressSink>>) -> ProgressBar {
+        ProgressBar::with_sink(sink.cloned().unwrap_or_else(|| Arc::new(NoProgress)))
+    }
+
+    /// Make a progress bar reporting to `sink`.
+    pub fn with_sink(sink: Arc<dyn ProgressSink>) -> ProgressBar {
         ProgressBar {
             phase: String::new(),
             filename: String::new(),
@@ -52,11 +121,19 @@ impl ProgressBar {
             percent: None,
             start: Instant::now(),
             last_drawn: None,
+            last_sample: None,
+            bytes_per_sec: None,
+            work_per_sec: None,
+            sink,
         }
     }
 
+    /// Start a new phase, which has its own rate of progress.
     pub fn set_phase(&mut self, phase: String) {
         self.phase = phase;
+        self.last_sample = None;
+        self.bytes_per_sec = None;
+        self.work_per_sec = None;
         self.maybe_redraw();
     }
 
@@ -110,31 +187,134 @@ impl ProgressBar {
             }
         }
         self.last_drawn = Some(Instant::now());
-        with_locked_ui(|ui| ui.draw_progress_bar(self));
+        self.sample_rate();
+        self.sink.update(&self.progress());
     }
 
-    fn estimate_remaining(&self, percent_done: f64) -> Option<Duration> {
+    /// Update the moving averages of the rate of progress, if it's been long
+    /// enough since the last sample.
+    fn sample_rate(&mut self) {
+        let now = Instant::now();
+        if let Some((last_time, last_bytes, last_work)) = self.last_sample {
+            let secs = (now - last_time).as_secs_f64();
+            if secs < RATE_SAMPLE_INTERVAL.as_secs_f64() {
+                return;
+            }
+            let bytes_rate = self.bytes_done.saturating_sub(last_bytes) as f64 / secs;
+            let work_rate = self.work_done.saturating_sub(last_work) as f64 / secs;
+            self.bytes_per_sec = Some(smooth(self.bytes_per_sec, bytes_rate));
+            self.work_per_sec = Some(smooth(self.work_per_sec, work_rate));
+        }
+        self.last_sample = Some((now, self.bytes_done, self.work_done));
+    }
+
+    /// Return a snapshot of the current progress.
+    pub fn progress(&self) -> Progress {
+        Progress {
+            phase: self.phase.clone(),
+            filename: self.filename.clone(),
+            total_work: self.total_work,
+            work_done: self.work_done,
+            bytes_done: self.bytes_done,
+            bytes_total: self.bytes_total,
+            percent: self.percent,
+            elapsed: self.start.elapsed(),
+            bytes_per_sec: self.bytes_per_sec,
+            work_per_sec: self.work_per_sec,
+        }
+    }
+}
+
+/// Add a new sample to a moving average.
+fn smooth(average: Option<f64>, sample: f64) -> f64 {
+    match average {
+        None => sample,
+        Some(average) => average * (1f64 - RATE_SMOOTHING) + sample * RATE_SMOOTHING,
+    }
+}
+
+impl Progress {
+    /// Estimate the time remaining, from the recent rate if the total amount of
+    /// work is known, or otherwise from the overall percentage done.
+    pub fn estimate_remaining(&self) -> Option<Duration> {
+        if self.bytes_total > 0 {
+            if let Some(rate) = self.bytes_per_sec.filter(|r| *r > 0f64) {
+                let remaining = self.bytes_total.saturating_sub(self.bytes_done);
+                return Some(Duration::from_secs_f64(remaining as f64 / rate));
+            }
+        }
+        if self.total_work > 0 {
+            if let Some(rate) = self.work_per_sec.filter(|r| *r > 0f64) {
+                let remaining = self.total_work.saturating_sub(self.work_done);
+                return Some(Duration::from_secs_f64(remaining as f64 / rate));
+            }
+        }
+        self.percent_done()
+            .and_then(|percent| self.estimate_remaining_from_percent(percent))
+    }
+
+    /// The percentage done, from whichever measure of progress is available.
+    pub fn percent_done(&self) -> Option<f64> {
+        self.percent
+            .or_else(|| self.work_percent())
+            .or_else(|| self.bytes_percent())
+    }
+
+    fn estimate_remaining_from_percent(&self, percent_done: f64) -> Option<Duration> {
         const MIN_ESTIMATE_WINDOW: Duration = Duration::from_millis(500);
         const MIN_ESTIMATE_PERCENT: f64 = 1f64;
         if percent_done < MIN_ESTIMATE_PERCENT {
             return None;
         }
-        let elapsed = Instant::now() - self.start;
+        let elapsed = self.elapsed;
         if elapsed < MIN_ESTIMATE_WINDOW {
             return None;
         }
         Some(elapsed.mul_f64((100f64 - percent_done) / percent_done))
     }
 
+    /// Describe the current progress as a json object.
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        let elapsed = self.elapsed;
+        serde_json::json!({
+            "phase": self.phase,
+            "filename": self.filename,
+            "work_done": self.work_done,
+            "total_work": self.total_work,
+            "bytes_done": self.bytes_done,
+            "bytes_total": self.bytes_total,
+            "percent": self.percent_done(),
+            "elapsed_secs": elapsed.as_secs_f64(),
+            "mb_per_sec": crate::ui::mbps_rate(self.bytes_done, elapsed),
+            "recent_mb_per_sec": self.bytes_per_sec.map(|rate| rate / 1e6),
+            "work_per_sec": self.work_per_sec,
+            "remaining_secs": self.estimate_remaining().map(|d| d.as_secs_f64()),
+        })
+    }
+
+    fn work_percent(&self) -> Option<f64> {
+        if self.total_work > 0 {
+            Some(100f64 * self.work_done as f64 / self.total_work as f64)
+        } else {
+            None
+        }
+    }
+
+    fn bytes_percent(&self) -> Option<f64> {
+        if self.bytes_total > 0 {
+            Some(100f64 * self.bytes_done as f64 / self.bytes_total as f64)
+        } else {
+            None
+        }
+    }
+
     pub(crate) fn draw(&self, out: &mut dyn std::io::Write, width: usize) {
         let mut prefix = String::with_capacity(50);
         if !self.phase.is_empty() {
             write!(prefix, "{} ", self.phase).unwrap();
         }
-        let mut work_percent = None;
         if self.total_work > 0 {
             if self.work_done > 0 {
-                work_percent = Some(100f64 * self.work_done as f64 / self.total_work as f64);
                 write!(
                     prefix,
                     "{}/{} ",
@@ -148,6 +328,16 @@ impl ProgressBar {
         } else if self.work_done > 0 {
             write!(prefix, "{} ", self.work_done.separate_with_commas()).unwrap();
         }
+        if self.work_done > 0 {
+            if let Some(rate) = self.work_per_sec {
+                write!(
+                    prefix,
+                    "({}/s) ",
+                    (rate.round() as u64).separate_with_commas()
+                )
+                .unwrap();
+            }
+        }
 
         if self.bytes_done > 0 {
             write!(
@@ -156,25 +346,33 @@ impl ProgressBar {
                 crate::misc::bytes_to_human_mb(self.bytes_done)
             )
             .unwrap();
+            if let Some(rate) = self.bytes_per_sec {
+                write!(prefix, "{:>6.1} MB/s ", rate / 1e6).unwrap();
+            }
         }
 
-        let percent = self.percent.or(work_percent);
-        let percent_str = if let Some(percent) = percent {
+        let percent_str = if let Some(percent) = self.percent_done() {
             format!("{:>4.1}% ", percent)
         } else {
             String::new()
         };
-        let remaining_str = percent
-            .and_then(|p| self.estimate_remaining(p))
-            .map(|dur| format!("{} remaining ", duration_brief(dur)))
-            .unwrap_or_default();
+        let remaining_str = if percent_str.is_empty() {
+            String::new()
+        } else {
+            self.estimate_remaining()
+                .map(|dur| format!("{} remaining ", duration_brief(dur)))
+                .unwrap_or_default()
+        };
 
         let mut message = String::with_capacity(200);
         if !self.filename.is_empty() {
             write!(message, "{}", self.filename).unwrap();
         }
 
-        let message_limit = width - prefix.len() - percent_str.len() - remaining_str.len();
+        let message_limit = width
+            .saturating_sub(prefix.len())
+            .saturating_sub(percent_str.len())
+            .saturating_sub(remaining_str.len());
         let truncated_message = if message.len() < message_limit {
             message
         } else {
@@ -215,7 +413,7 @@ impl ProgressBar {
 
 impl Drop for ProgressBar {
     fn drop(&mut self) {
-        with_locked_ui(|ui| ui.clear_progress())
+        self.sink.finish()
     }
 }
 
@@ -227,3 +425,69 @@ fn duration_brief(d: Duration) -> String {
         format!("{:4} sec", secs)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    #[derive(Debug, Default)]
+    struct RecordingSink {
+        updates: Mutex<Vec<Progress>>,
+        finished: Mutex<bool>,
+    }
+
+    impl ProgressSink for RecordingSink {
+        fn update(&self, progress: &Progress) {
+            self.updates.lock().unwrap().push(progress.clone());
+        }
+
+        fn finish(&self) {
+            *self.finished.lock().unwrap() = true;
+        }
+    }
+
+    #[test]
+    fn progress_goes_to_sink() {
+        let sink = Arc::new(RecordingSink::default());
+        let mut bar = ProgressBar::with_sink(sink.clone());
+        bar.set_phase("Copying".to_owned());
+        bar.increment_bytes_done(100);
+        assert_eq!(bar.progress().bytes_done, 100);
+        drop(bar);
+
+        let updates = sink.updates.lock().unwrap();
+        assert_eq!(updates[0].phase, "Copying");
+        assert_eq!(updates[0].bytes_done, 0);
+        assert!(*sink.finished.lock().unwrap());
+    }
+
+    #[test]
+    fn moving_average() {
+        assert_eq!(smooth(None, 10f64), 10f64);
+        assert!((smooth(Some(10f64), 20f64) - 13f64).abs() < 1e-9);
+    }
+
+    #[test]
+    fn estimate_remaining_from_rate() {
+        let progress = Progress {
+            bytes_done: 1_000,
+            bytes_total: 11_000,
+            bytes_per_sec: Some(1_000f64),
+            elapsed: Duration::from_secs(1),
+            ..Progress::default()
+        };
+        assert_eq!(progress.percent_done().unwrap().round(), 9f64);
+        assert_eq!(progress.estimate_remaining(), Some(Duration::from_secs(10)));
+
+        // Without a measured rate, it's estimated from the overall percentage.
+        let progress = Progress {
+            work_done: 25,
+            total_work: 100,
+            elapsed: Duration::from_secs(10),
+            ..Progress::default()
+        };
+        assert_eq!(progress.estimate_remaining(), Some(Duration::from_secs(30)));
+    }
+}
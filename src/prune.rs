@@ -0,0 +1,213 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Choose which backups to keep under a retention policy.
+//!
+//! Each rule keeps the most recent backup in each of the last N periods
+//! (days, weeks, or months) that have any backups. A backup is kept if any
+//! rule keeps it. Periods are in local time.
+
+use chrono::{Datelike, NaiveDateTime};
+
+use crate::*;
+
+/// How many backups to keep, from [Archive::prune].
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct RetentionPolicy {
+    /// Keep this many of the most recent backups.
+    pub keep_last: usize,
+    /// Keep the last backup of each of this many days.
+    pub keep_daily: usize,
+    /// Keep the last backup of each of this many ISO weeks.
+    pub keep_weekly: usize,
+    /// Keep the last backup of each of this many months.
+    pub keep_monthly: usize,
+}
+
+impl RetentionPolicy {
+    /// True if this policy would keep no backups at all.
+    pub fn keeps_nothing(&self) -> bool {
+        self.keep_last == 0
+            && self.keep_daily == 0
+            && self.keep_weekly == 0
+            && self.keep_monthly == 0
+    }
+}
+
+/// Whether one backup is kept by a retention policy, and why.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct BandRetention {
+    pub band_id: BandId,
+    /// When the backup started, in local time.
+    pub start_time: NaiveDateTime,
+    /// The rules that keep this backup, such as "daily". If this is empty,
+    /// the backup is removed.
+    pub keep_reasons: Vec<&'static str>,
+}
+
+impl BandRetention {
+    pub fn is_kept(&self) -> bool {
+        !self.keep_reasons.is_empty()
+    }
+}
+
+/// Decide which of `bands` to keep under `policy`.
+///
+/// `bands` are the band ids and start times of complete backups. The result
+/// is in the same order.
+pub fn apply_retention_policy(
+    bands: &[(BandId, NaiveDateTime)],
+    policy: &RetentionPolicy,
+) -> Vec<BandRetention> {
+    let mut result: Vec<BandRetention> = bands
+        .iter()
+        .map(|(band_id, start_time)| BandRetention {
+            band_id: band_id.clone(),
+            start_time: *start_time,
+            keep_reasons: Vec::new(),
+        })
+        .collect();
+    // Visit the newest backups first.
+    let mut newest_first: Vec<usize> = (0..result.len()).collect();
+    newest_first.sort_by(|&a, &b| {
+        (result[b].start_time, &result[b].band_id).cmp(&(result[a].start_time, &result[a].band_id))
+    });
+    for &i in newest_first.iter().take(policy.keep_last) {
+        result[i].keep_reasons.push("last");
+    }
+    keep_by_period(
+        &mut result,
+        &newest_first,
+        policy.keep_daily,
+        "daily",
+        |t| t.num_days_from_ce().into(),
+    );
+    keep_by_period(
+        &mut result,
+        &newest_first,
+        policy.keep_weekly,
+        "weekly",
+        |t| {
+            let week = t.iso_week();
+            i64::from(week.year()) * 100 + i64::from(week.week())
+        },
+    );
+    keep_by_period(
+        &mut result,
+        &newest_first,
+        policy.keep_monthly,
+        "monthly",
+        |t| i64::from(t.year()) * 100 + i64::from(t.month()),
+    );
+    result
+}
+
+/// Keep the newest backup in each of the `count` most recent periods, where
+/// `period` maps a start time to a key identifying its period.
+fn keep_by_period(
+    bands: &mut [BandRetention],
+    newest_first: &[usize],
+    count: usize,
+    reason: &'static str,
+    period: impl Fn(&NaiveDateTime) -> i64,
+) {
+    let mut last_period = None;
+    let mut kept = 0;
+    for &i in newest_first {
+        if kept == count {
+            break;
+        }
+        let this_period = period(&bands[i].start_time);
+        if last_period != Some(this_period) {
+            last_period = Some(this_period);
+            bands[i].keep_reasons.push(reason);
+            kept += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::*;
+
+    fn band(id: u32, y: i32, m: u32, d: u32, h: u32) -> (BandId, NaiveDateTime) {
+        (
+            BandId::new(&[id]),
+            NaiveDate::from_ymd(y, m, d).and_hms(h, 0, 0),
+        )
+    }
+
+    fn kept(result: &[BandRetention]) -> Vec<String> {
+        result
+            .iter()
+            .filter(|r| r.is_kept())
+            .map(|r| format!("{} {}", r.band_id, r.keep_reasons.join(",")))
+            .collect()
+    }
+
+    #[test]
+    fn keep_last() {
+        let bands = [
+            band(0, 2020, 1, 1, 1),
+            band(1, 2020, 1, 1, 2),
+            band(2, 2020, 1, 1, 3),
+        ];
+        let policy = RetentionPolicy {
+            keep_last: 2,
+            ..RetentionPolicy::default()
+        };
+        let result = apply_retention_policy(&bands, &policy);
+        assert_eq!(kept(&result), ["b0001 last", "b0002 last"]);
+        assert!(!result[0].is_kept());
+    }
+
+    #[test]
+    fn keep_last_of_each_period() {
+        let bands = [
+            band(0, 2020, 4, 30, 9),
+            band(1, 2020, 5, 29, 9),
+            band(2, 2020, 6, 1, 9), // Monday
+            band(3, 2020, 6, 2, 9),
+            band(4, 2020, 6, 2, 21),
+            band(5, 2020, 6, 8, 9), // The next Monday
+            band(6, 2020, 6, 8, 10),
+        ];
+        let policy = RetentionPolicy {
+            keep_daily: 3,
+            keep_weekly: 2,
+            keep_monthly: 3,
+            ..RetentionPolicy::default()
+        };
+        let result = apply_retention_policy(&bands, &policy);
+        assert_eq!(
+            kept(&result),
+            [
+                "b0000 monthly",
+                "b0001 monthly",
+                "b0002 daily",
+                "b0004 daily,weekly",
+                "b0006 daily,weekly,monthly",
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_policy_keeps_nothing() {
+        let policy = RetentionPolicy::default();
+        assert!(policy.keeps_nothing());
+        let result = apply_retention_policy(&[band(0, 2020, 1, 1, 1)], &policy);
+        assert!(!result[0].is_kept());
+    }
+}
@@ -12,21 +12,59 @@
 
 //! Restore from the archive to the filesystem.
 
+use std::collections::HashMap;
 use std::fs;
 use std::fs::File;
 use std::io;
-use std::io::Write;
+use std::io::{Read, Write};
+use std::ops::Range;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use globset::GlobSet;
+use serde::{Deserialize, Serialize};
 
 use crate::band::BandSelectionPolicy;
+use crate::blockdir::HashingReader;
 use crate::entry::Entry;
+use crate::errors::ErrorChain;
 use crate::excludes;
-use crate::io::{directory_is_empty, ensure_dir_exists};
+use crate::io::{directory_is_empty, ensure_dir_exists, join_relative, long_path};
+use crate::jsonio::{read_json, write_json};
 use crate::stats::CopyStats;
+use crate::transport::local::LocalTransport;
+use crate::transport::Transport;
+use crate::unix_time::UnixTime;
+use crate::windows_metadata;
+use crate::workers::WorkerPool;
 use crate::*;
 
+/// File in the root of the restore destination that records how far the
+/// restore has got, until it's finished.
+static RESTORE_STATE_FILENAME: &str = ".conserve-restore";
+
+/// How often to record restore progress.
+const RESTORE_STATE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// What to do about entries that already exist in the restore destination.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum OverwritePolicy {
+    /// Only restore into an empty or new destination directory.
+    #[default]
+    Refuse,
+    /// Replace existing files, symlinks, and special files.
+    Force,
+    /// Leave existing files untouched and don't restore over them.
+    SkipExisting,
+    /// Replace existing files only if the stored version has a later mtime.
+    OnlyNewer,
+    /// Replace existing files whose content differs from the stored file,
+    /// and only set the metadata of files that already have the stored
+    /// content.
+    Update,
+}
+
 /// Description of how to restore a tree.
 #[derive(Debug)]
 pub struct RestoreOptions {
@@ -34,19 +72,106 @@ pub struct RestoreOptions {
     pub excludes: GlobSet,
     /// Restore only this subdirectory.
     pub only_subtree: Option<Apath>,
-    pub overwrite: bool,
+    /// What to do about existing files in the destination.
+    pub overwrite: OverwritePolicy,
+    /// Delete entries in the destination that aren't in the stored tree.
+    pub delete: bool,
     // The band to select, or by default the last complete one.
     pub band_selection: BandSelectionPolicy,
+    /// Only list what would be restored, without changing the destination.
+    pub dry_run: bool,
+    /// Allow `Archive::restore_band` to restore an incomplete band, filling in
+    /// the rest of the tree from earlier bands.
+    pub allow_incomplete: bool,
+    /// Set Unix permissions on restored files and directories, if they're
+    /// stored in the archive.
+    pub restore_permissions: bool,
+    /// How to set the owner and group of restored entries.
+    ///
+    /// Ownership is only set when running as root.
+    pub ownership: OwnershipPolicy,
+    /// Number of files to write concurrently.
+    pub threads: usize,
+    /// Continue an interrupted restore into the same destination, skipping
+    /// entries it already restored.
+    pub resume: bool,
+    /// Receives progress of the operation, if set.
+    pub progress: Option<Arc<dyn ProgressSink>>,
 }
 
 impl Default for RestoreOptions {
     fn default() -> Self {
         RestoreOptions {
             print_filenames: false,
-            overwrite: false,
+            overwrite: OverwritePolicy::default(),
+            delete: false,
             band_selection: BandSelectionPolicy::LatestClosed,
+            dry_run: false,
+            allow_incomplete: false,
             excludes: excludes::excludes_nothing(),
             only_subtree: None,
+            restore_permissions: true,
+            ownership: OwnershipPolicy::default(),
+            threads: 1,
+            resume: false,
+            progress: None,
+        }
+    }
+}
+
+impl RestoreOptions {
+    /// Return options that skip entries matching `excludes`.
+    ///
+    /// This replaces any previous exclusions.
+    pub fn with_excludes(self, excludes: GlobSet) -> RestoreOptions {
+        RestoreOptions { excludes, ..self }
+    }
+
+    /// Return options that restore only `subtree`, or the whole tree if it's
+    /// None.
+    pub fn with_only_subtree(self, only_subtree: Option<Apath>) -> RestoreOptions {
+        RestoreOptions {
+            only_subtree,
+            ..self
+        }
+    }
+
+    /// Return options that restore the band selected by `band_selection`.
+    pub fn with_band_selection(self, band_selection: BandSelectionPolicy) -> RestoreOptions {
+        RestoreOptions {
+            band_selection,
+            ..self
+        }
+    }
+
+    /// Return options that treat existing files in the destination according
+    /// to `overwrite`.
+    pub fn with_overwrite(self, overwrite: OverwritePolicy) -> RestoreOptions {
+        RestoreOptions { overwrite, ..self }
+    }
+
+    /// Return options that, if `delete` is true, delete entries in the
+    /// destination that aren't in the stored tree.
+    pub fn with_delete(self, delete: bool) -> RestoreOptions {
+        RestoreOptions { delete, ..self }
+    }
+
+    /// Return options that, if `dry_run` is true, only list what would be
+    /// restored, without changing the destination.
+    pub fn with_dry_run(self, dry_run: bool) -> RestoreOptions {
+        RestoreOptions { dry_run, ..self }
+    }
+
+    /// Return options that write up to `threads` files concurrently.
+    pub fn with_threads(self, threads: usize) -> RestoreOptions {
+        RestoreOptions { threads, ..self }
+    }
+
+    /// Return options that send progress to `progress`.
+    pub fn with_progress(self, progress: Arc<dyn ProgressSink>) -> RestoreOptions {
+        RestoreOptions {
+            progress: Some(progress),
+            ..self
         }
     }
 }
@@ -55,6 +180,61 @@ impl Default for RestoreOptions {
 #[derive(Debug)]
 pub struct RestoreTree {
     path: PathBuf,
+
+    /// Set stored Unix permissions on restored entries.
+    restore_permissions: bool,
+
+    /// How to set ownership of restored entries.
+    ownership: OwnershipPolicy,
+
+    /// Directories whose metadata should be set after their contents are
+    /// written: writing the contents changes the mtime, and the directory
+    /// might not be writable.
+    deferred_dirs: Vec<DeferredDir>,
+
+    /// The restored path of the first file in each hardlink group, to which
+    /// the other members are linked.
+    hardlinks: HashMap<Apath, PathBuf>,
+
+    /// What to do about existing files in the destination.
+    overwrite: OverwritePolicy,
+
+    /// Counts of entries overwritten or skipped, returned from `finish`.
+    stats: CopyStats,
+
+    /// Only report what would be restored, without changing the destination.
+    dry_run: bool,
+
+    /// Writes files on worker threads, if more than one thread is used.
+    ///
+    /// Unlike backup, results can arrive in any order, because nothing waits
+    /// for a particular file: only the directory metadata, set in `finish`.
+    parallel: Option<WorkerPool<Result<CopyStats>>>,
+
+    /// The band whose restore progress is recorded in the destination, or
+    /// None if progress isn't recorded.
+    state_band_id: Option<BandId>,
+
+    /// When progress was last recorded.
+    state_written: Instant,
+
+    /// The last entry whose restore was started: everything before it is
+    /// finished.
+    last_started: Option<Apath>,
+
+    /// When resuming, the last entry already restored by the interrupted
+    /// restore.
+    resume_after: Option<Apath>,
+}
+
+/// Metadata to set on a restored directory after its contents are written.
+#[derive(Debug)]
+struct DeferredDir {
+    path: PathBuf,
+    mtime: UnixTime,
+    unix_mode: Option<u32>,
+    windows_attributes: Option<u32>,
+    creation_time: Option<UnixTime>,
 }
 
 impl RestoreTree {
@@ -65,42 +245,447 @@ impl RestoreTree {
         let path = path.into();
         match ensure_dir_exists(&path).and_then(|()| directory_is_empty(&path)) {
             Err(source) => Err(Error::Restore { path, source }),
-            Ok(true) => Ok(RestoreTree { path }),
+            Ok(true) => Ok(RestoreTree::new(path, OverwritePolicy::Refuse)),
             Ok(false) => Err(Error::DestinationNotEmpty { path }),
         }
     }
 
     /// Create a RestoreTree, even if the destination directory is not empty.
+    ///
+    /// Existing files are replaced, unless another policy is set by `with_overwrite`.
     pub fn create_overwrite(path: &Path) -> Result<RestoreTree> {
+        Ok(RestoreTree::new(path.to_path_buf(), OverwritePolicy::Force))
+    }
+
+    fn new(path: PathBuf, overwrite: OverwritePolicy) -> RestoreTree {
+        RestoreTree {
+            path: long_path(&path),
+            restore_permissions: true,
+            ownership: OwnershipPolicy::Skip,
+            deferred_dirs: Vec::new(),
+            hardlinks: HashMap::new(),
+            overwrite,
+            stats: CopyStats::default(),
+            dry_run: false,
+            parallel: None,
+            state_band_id: None,
+            state_written: Instant::now(),
+            last_started: None,
+            resume_after: None,
+        }
+    }
+
+    /// Create a RestoreTree that lists what would be created, overwritten, or
+    /// skipped, and counts the bytes that would be restored, without changing
+    /// the destination or reading file contents.
+    ///
+    /// As for `create`, with `OverwritePolicy::Refuse` the destination must not
+    /// exist or must be empty.
+    pub fn create_dry_run(path: &Path, overwrite: OverwritePolicy) -> Result<RestoreTree> {
+        let path = path.to_path_buf();
+        if overwrite == OverwritePolicy::Refuse {
+            match directory_is_empty(&path) {
+                Ok(true) => (),
+                Ok(false) => return Err(Error::DestinationNotEmpty { path }),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+                Err(source) => return Err(Error::Restore { path, source }),
+            }
+        }
+        Ok(RestoreTree {
+            dry_run: true,
+            ..RestoreTree::new(path, overwrite)
+        })
+    }
+
+    /// Return a RestoreTree that treats existing files according to `overwrite`.
+    pub fn with_overwrite(self, overwrite: OverwritePolicy) -> RestoreTree {
+        RestoreTree { overwrite, ..self }
+    }
+
+    /// Return a RestoreTree that writes up to `threads` files concurrently.
+    ///
+    /// Directories, symlinks, and special files are still created on the
+    /// calling thread, in order, so each file's directory exists before it's
+    /// written.
+    ///
+    /// This has no effect on a dry run, where nothing is written.
+    pub fn with_threads(self, threads: usize) -> Result<RestoreTree> {
+        let parallel = if threads > 1 && !self.dry_run {
+            Some(WorkerPool::new(threads)?)
+        } else {
+            None
+        };
+        Ok(RestoreTree { parallel, ..self })
+    }
+
+    /// Return a RestoreTree that resumes an interrupted restore, which had
+    /// finished every entry up to and including `resume_after`.
+    ///
+    /// Those entries are skipped if they still exist with the stored size,
+    /// and restored again otherwise.
+    pub fn with_resume_after(self, resume_after: Option<Apath>) -> RestoreTree {
+        RestoreTree {
+            resume_after,
+            ..self
+        }
+    }
+
+    /// Return a RestoreTree that records its progress in restoring `band_id`
+    /// in the destination, so that it can be resumed if it's interrupted.
+    ///
+    /// The record is removed when the restore finishes. This should be called
+    /// after `with_resume_after`, so that progress isn't lost if a resumed
+    /// restore is interrupted again straight away. It has no effect on a dry
+    /// run.
+    pub fn with_restore_state(self, band_id: &BandId) -> Result<RestoreTree> {
+        if self.dry_run {
+            return Ok(self);
+        }
+        ensure_dir_exists(&self.path).map_err(|source| Error::Restore {
+            path: self.path.clone(),
+            source,
+        })?;
+        RestoreState {
+            band_id: band_id.to_string(),
+            last_apath: self.resume_after.clone(),
+        }
+        .write(&self.path)?;
         Ok(RestoreTree {
-            path: path.to_path_buf(),
+            state_band_id: Some(band_id.clone()),
+            state_written: Instant::now(),
+            ..self
         })
     }
 
+    /// Return a RestoreTree that does, or does not, set stored Unix permissions.
+    pub fn with_permissions(self, restore_permissions: bool) -> RestoreTree {
+        RestoreTree {
+            restore_permissions,
+            ..self
+        }
+    }
+
+    /// Return a RestoreTree that sets ownership according to `ownership`.
+    ///
+    /// Unless this process is running as root, ownership is not set regardless
+    /// of the policy.
+    pub fn with_ownership(self, ownership: OwnershipPolicy) -> RestoreTree {
+        let ownership = if owner::can_set_ownership() {
+            ownership
+        } else {
+            OwnershipPolicy::Skip
+        };
+        RestoreTree { ownership, ..self }
+    }
+
     fn rooted_path(&self, apath: &Apath) -> PathBuf {
-        // Remove initial slash so that the apath is relative to the destination.
-        self.path.join(&apath[1..])
+        join_relative(&self.path, apath)
+    }
+
+    /// True if the policy is to update files, and a regular file at `path`
+    /// already has the stored content of `entry`.
+    ///
+    /// A dry run doesn't read blocks from the archive: it compares the file to
+    /// the stored hash of its content if there is one, and otherwise only
+    /// compares its size and mtime.
+    fn is_unchanged<R: ReadTree>(
+        &self,
+        path: &Path,
+        entry: &R::Entry,
+        from_tree: &R,
+    ) -> Result<bool> {
+        if self.overwrite != OverwritePolicy::Update {
+            return Ok(false);
+        }
+        let restore_err = |source| Error::Restore {
+            path: path.to_owned(),
+            source,
+        };
+        match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.is_file() && Some(metadata.len()) == entry.size() => {
+                if !self.dry_run {
+                    from_tree.content_matches(entry, &mut File::open(path).map_err(restore_err)?)
+                } else if let Some(expected) = entry.content_hash() {
+                    Ok(file_has_hash(path, expected))
+                } else {
+                    Ok(metadata.modified().ok().map(UnixTime::from) == Some(entry.mtime()))
+                }
+            }
+            Ok(_) => Ok(false),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(restore_err(err)),
+        }
+    }
+
+    /// Note that restoring `apath` is starting, and every entry before it has
+    /// been started; every so often, record in the destination that they're
+    /// finished.
+    fn record_progress(&mut self, apath: &Apath) -> Result<()> {
+        if let Some(band_id) = &self.state_band_id {
+            if self.state_written.elapsed() >= RESTORE_STATE_INTERVAL {
+                let band_id = band_id.to_string();
+                self.receive_written_files(true);
+                RestoreState {
+                    band_id,
+                    last_apath: self.last_started.clone(),
+                }
+                .write(&self.path)?;
+                self.state_written = Instant::now();
+            }
+        }
+        self.last_started = Some(apath.clone());
+        Ok(())
+    }
+
+    /// True if `entry` was restored before this restore was resumed, and
+    /// it still exists with the stored kind and size, and, for files whose
+    /// content hash was stored, with the stored content.
+    ///
+    /// Directories are always restored again, so that their metadata is set.
+    fn was_restored<E: Entry>(&self, path: &Path, entry: &E) -> bool {
+        match &self.resume_after {
+            Some(after) if entry.apath() <= after => (),
+            _ => return false,
+        }
+        match fs::symlink_metadata(path) {
+            Ok(metadata) => match entry.kind() {
+                Kind::Dir => false,
+                Kind::File => {
+                    metadata.is_file()
+                        && Some(metadata.len()) == entry.size()
+                        && entry
+                            .content_hash()
+                            .map_or(true, |expected| file_has_hash(path, expected))
+                }
+                Kind::Symlink => metadata.file_type().is_symlink(),
+                _ => !metadata.is_dir() && !metadata.is_file(),
+            },
+            Err(_) => false,
+        }
+    }
+
+    /// Add up the results of files written on worker threads.
+    ///
+    /// If `wait_for_all` is true, this waits until every file is written.
+    /// Otherwise, it waits only while the maximum number of files are in
+    /// flight.
+    fn receive_written_files(&mut self, wait_for_all: bool) {
+        while let Some(parallel) = self.parallel.as_mut() {
+            if parallel.in_flight() == 0 || (!wait_for_all && !parallel.is_full()) {
+                return;
+            }
+            match parallel.receive() {
+                Ok(Ok(file_stats)) => self.stats += file_stats,
+                Ok(Err(err)) | Err(err) => {
+                    log::error!("{}", ErrorChain(&err));
+                    self.stats.errors += 1;
+                }
+            }
+        }
+    }
+
+    /// Decide what to do about `entry` at `path`, according to the overwrite
+    /// policy and whatever already exists there.
+    fn plan_write<E: Entry>(&self, path: &Path, entry: &E) -> Result<WriteAction> {
+        let restore_err = |source| Error::Restore {
+            path: path.to_owned(),
+            source,
+        };
+        let existing = match fs::symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(WriteAction::Create),
+            Err(err) => return Err(restore_err(err)),
+        };
+        let keep = match self.overwrite {
+            OverwritePolicy::SkipExisting => Some(WriteAction::SkipExisting),
+            OverwritePolicy::OnlyNewer
+                if UnixTime::from(existing.modified().map_err(restore_err)?) >= entry.mtime() =>
+            {
+                Some(WriteAction::SkipNotNewer)
+            }
+            _ => None,
+        };
+        let is_dir = entry.kind() == Kind::Dir;
+        if let Some(keep) = keep {
+            if is_dir && existing.is_dir() {
+                Ok(WriteAction::KeepDir)
+            } else {
+                Ok(keep)
+            }
+        } else if existing.is_dir() {
+            if is_dir {
+                Ok(WriteAction::Merge)
+            } else {
+                Err(restore_err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    "A directory is in the way",
+                )))
+            }
+        } else {
+            Ok(WriteAction::Overwrite)
+        }
+    }
+
+    /// Decide whether to write `entry` at `path` according to the overwrite
+    /// policy, and if so, remove any existing non-directory in the way.
+    ///
+    /// Directories are always merged into an existing directory, but their
+    /// metadata is only set if the policy would replace a file.
+    ///
+    /// In a dry run, this reports what would be done and always returns false.
+    fn should_write<E: Entry>(&mut self, path: &Path, entry: &E) -> Result<bool> {
+        let action = self.plan_write(path, entry)?;
+        match action {
+            WriteAction::Overwrite => self.stats.overwritten += 1,
+            WriteAction::SkipExisting => self.stats.skipped_existing += 1,
+            WriteAction::SkipNotNewer => self.stats.skipped_not_newer += 1,
+            WriteAction::Create | WriteAction::Merge | WriteAction::KeepDir => (),
+        }
+        if self.dry_run {
+            if let Some(description) = action.describe() {
+                log::info!("{} {}", description, entry.apath());
+            }
+            // Count the bytes that would be read from the archive, once per
+            // group of hardlinks.
+            if action.writes()
+                && entry.kind() == Kind::File
+                && entry.hardlink_group().map_or(true, |group| {
+                    self.hardlinks
+                        .insert(group.clone(), path.to_owned())
+                        .is_none()
+                })
+            {
+                self.stats.uncompressed_bytes += entry.size().unwrap_or_default();
+            }
+            return Ok(false);
+        }
+        if action == WriteAction::Overwrite {
+            windows_metadata::clear_readonly(path)
+                .and_then(|()| fs::remove_file(path))
+                .map_err(|source| Error::Restore {
+                    path: path.to_owned(),
+                    source,
+                })?;
+        }
+        Ok(action.writes())
+    }
+}
+
+/// What restore does at one destination path.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum WriteAction {
+    /// Nothing exists there yet.
+    Create,
+    /// Replace an existing non-directory.
+    Overwrite,
+    /// Restore into an existing directory, and set its metadata.
+    Merge,
+    /// Restore into an existing directory, but leave its metadata alone.
+    KeepDir,
+    /// Something already exists, and it's kept.
+    SkipExisting,
+    /// The existing file is at least as new as the stored one, and it's kept.
+    SkipNotNewer,
+}
+
+impl WriteAction {
+    /// True if the entry is written, or for directories if its metadata is set.
+    fn writes(self) -> bool {
+        match self {
+            WriteAction::Create | WriteAction::Overwrite | WriteAction::Merge => true,
+            WriteAction::KeepDir | WriteAction::SkipExisting | WriteAction::SkipNotNewer => false,
+        }
+    }
+
+    /// Describe the action in a dry run listing, if it makes any visible change
+    /// or skips an entry.
+    fn describe(self) -> Option<&'static str> {
+        match self {
+            WriteAction::Create => Some("create"),
+            WriteAction::Overwrite => Some("overwrite"),
+            WriteAction::SkipExisting => Some("skip existing"),
+            WriteAction::SkipNotNewer => Some("skip newer"),
+            WriteAction::Merge | WriteAction::KeepDir => None,
+        }
     }
 }
 
 impl tree::WriteTree for RestoreTree {
-    fn finish(self) -> Result<CopyStats> {
-        // Live tree doesn't need to be finished.
-        Ok(CopyStats::default())
+    fn finish(mut self) -> Result<CopyStats> {
+        self.receive_written_files(true);
+        let mut stats = self.stats;
+        let path = self.path;
+        // Every entry is restored, so the record of progress is removed before
+        // setting directory metadata, which might make the destination
+        // unwritable.
+        if self.state_band_id.is_some() {
+            RestoreState::remove(&path)?;
+        }
+        // Children sort after their parents, so setting metadata in reverse
+        // order reaches each directory before its parent might become
+        // unsearchable.
+        for dir in self.deferred_dirs.into_iter().rev() {
+            let result = set_mtime(&dir.path, dir.mtime)
+                .and_then(|()| match dir.creation_time {
+                    Some(time) => windows_metadata::set_creation_time(&dir.path, time),
+                    None => Ok(()),
+                })
+                .and_then(|()| match dir.unix_mode {
+                    Some(mode) => set_unix_mode(&dir.path, mode),
+                    None => Ok(()),
+                })
+                .and_then(|()| match dir.windows_attributes {
+                    Some(attributes) => {
+                        windows_metadata::set_windows_attributes(&dir.path, attributes)
+                    }
+                    None => Ok(()),
+                });
+            if let Err(source) = result {
+                log::error!(
+                    "{}",
+                    ErrorChain(&Error::Restore {
+                        path: dir.path,
+                        source,
+                    })
+                );
+                stats.errors += 1;
+            }
+        }
+        Ok(stats)
     }
 
     fn copy_dir<E: Entry>(&mut self, entry: &E) -> Result<()> {
+        self.record_progress(entry.apath())?;
         let path = self.rooted_path(entry.apath());
-        match fs::create_dir_all(&path) {
-            Ok(()) => Ok(()),
-            Err(source) => {
-                if source.kind() == io::ErrorKind::AlreadyExists {
-                    Ok(())
-                } else {
-                    Err(Error::Restore { path, source })
-                }
+        if !self.should_write(&path, entry)? {
+            return Ok(());
+        }
+        if let Err(source) = fs::create_dir_all(&path) {
+            if source.kind() != io::ErrorKind::AlreadyExists {
+                return Err(Error::Restore { path, source });
             }
         }
+        entry
+            .owner()
+            .apply(&path, self.ownership)
+            .map_err(|source| Error::Restore {
+                path: path.clone(),
+                source,
+            })?;
+        restore_xattrs(&path, entry.xattrs());
+        let (unix_mode, windows_attributes) = if self.restore_permissions {
+            (entry.unix_mode(), entry.windows_attributes())
+        } else {
+            (None, None)
+        };
+        self.deferred_dirs.push(DeferredDir {
+            path,
+            mtime: entry.mtime(),
+            unix_mode,
+            windows_attributes,
+            creation_time: entry.creation_time(),
+        });
+        Ok(())
     }
 
     /// Copy in the contents of a file from another tree.
@@ -109,21 +694,83 @@ impl tree::WriteTree for RestoreTree {
         source_entry: &R::Entry,
         from_tree: &R,
     ) -> Result<CopyStats> {
-        // TODO: Restore permissions.
-        // TODO: Reset mtime: can probably use https://docs.rs/utime/0.2.2/utime/
+        self.record_progress(source_entry.apath())?;
         let path = self.rooted_path(source_entry.apath());
         let restore_err = |source| Error::Restore {
             path: path.clone(),
             source,
         };
-        let mut restore_file = File::create(&path).map_err(restore_err)?;
-        // TODO: Read one block at a time: don't pull all the contents into memory.
-        let content = &mut from_tree.file_contents(&source_entry)?;
-        let bytes_copied = std::io::copy(content, &mut restore_file).map_err(restore_err)?;
-        restore_file.flush().map_err(restore_err)?;
-        // TODO: Accumulate more stats.
+        if self.was_restored(&path, source_entry) {
+            self.stats.skipped_restored += 1;
+            if let Some(group) = source_entry.hardlink_group() {
+                self.hardlinks
+                    .entry(group.clone())
+                    .or_insert_with(|| path.clone());
+            }
+            return Ok(CopyStats::default());
+        }
+        if self.is_unchanged(&path, source_entry, from_tree)? {
+            self.stats.unmodified_files += 1;
+            if let Some(group) = source_entry.hardlink_group() {
+                self.hardlinks
+                    .entry(group.clone())
+                    .or_insert_with(|| path.clone());
+            }
+            if !self.dry_run {
+                FileMetadata::from_entry(source_entry, self.restore_permissions)
+                    .apply(&path, self.ownership)
+                    .map_err(restore_err)?;
+            }
+            return Ok(CopyStats::default());
+        }
+        if !self.should_write(&path, source_entry)? {
+            return Ok(CopyStats::default());
+        }
+        if let Some(group) = source_entry.hardlink_group() {
+            if let Some(first_path) = self.hardlinks.get(group) {
+                fs::hard_link(first_path, &path).map_err(restore_err)?;
+                return Ok(CopyStats {
+                    hardlinked_files: 1,
+                    ..CopyStats::default()
+                });
+            }
+            self.hardlinks.insert(group.clone(), path.clone());
+        }
+        // The file is created here, rather than on a worker, so that later
+        // hardlinks to it can be made straight away.
+        let restore_file = File::create(&path).map_err(restore_err)?;
+        let content = from_tree.file_contents(source_entry)?;
+        let holes = from_tree.file_holes(source_entry)?;
+        let metadata = FileMetadata::from_entry(source_entry, self.restore_permissions);
+        if self.parallel.is_some() {
+            // Content from an archive reads its following blocks ahead on the
+            // prefetch threads, so a large file isn't fetched one block at a
+            // time while the worker writes it.
+            self.receive_written_files(false);
+            let ownership = self.ownership;
+            self.parallel.as_mut().unwrap().spawn(move || {
+                write_file(&path, restore_file, content, &holes, &metadata, ownership)
+            });
+            // Stats are collected from the worker when it's done.
+            return Ok(CopyStats::default());
+        }
+        write_file(
+            &path,
+            restore_file,
+            content,
+            &holes,
+            &metadata,
+            self.ownership,
+        )
+    }
+
+    fn measure_file<R: ReadTree>(
+        &mut self,
+        source_entry: &R::Entry,
+        _from_tree: &R,
+    ) -> Result<CopyStats> {
         Ok(CopyStats {
-            uncompressed_bytes: bytes_copied,
+            uncompressed_bytes: source_entry.size().unwrap_or_default(),
             ..CopyStats::default()
         })
     }
@@ -131,12 +778,30 @@ impl tree::WriteTree for RestoreTree {
     #[cfg(unix)]
     fn copy_symlink<E: Entry>(&mut self, entry: &E) -> Result<()> {
         use std::os::unix::fs as unix_fs;
+        self.record_progress(entry.apath())?;
         if let Some(ref target) = entry.symlink_target() {
             let path = self.rooted_path(entry.apath());
-            unix_fs::symlink(target, &path).map_err(|source| Error::Restore { path, source })?;
+            if self.was_restored(&path, entry) {
+                self.stats.skipped_restored += 1;
+                return Ok(());
+            }
+            if !self.should_write(&path, entry)? {
+                return Ok(());
+            }
+            let restore_err = |source| Error::Restore {
+                path: path.clone(),
+                source,
+            };
+            unix_fs::symlink(target, &path).map_err(restore_err)?;
+            set_mtime(&path, entry.mtime()).map_err(restore_err)?;
+            entry
+                .owner()
+                .apply(&path, self.ownership)
+                .map_err(restore_err)?;
+            restore_xattrs(&path, entry.xattrs());
         } else {
             // TODO: Treat as an error.
-            ui::problem(&format!("No target in symlink entry {}", entry.apath()));
+            log::error!("No target in symlink entry {}", entry.apath());
         }
         Ok(())
     }
@@ -145,10 +810,392 @@ impl tree::WriteTree for RestoreTree {
     fn copy_symlink<E: Entry>(&mut self, entry: &E) -> Result<()> {
         // TODO: Add a test with a canned index containing a symlink, and expect
         // it cannot be restored on Windows and can be on Unix.
-        ui::problem(&format!(
-            "Can't restore symlinks on non-Unix: {}",
-            entry.apath()
-        ));
+        log::error!("Can't restore symlinks on non-Unix: {}", entry.apath());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn copy_special<E: Entry>(&mut self, entry: &E) -> Result<()> {
+        self.record_progress(entry.apath())?;
+        let kind = entry.kind();
+        if (kind == Kind::CharDevice || kind == Kind::BlockDevice) && !owner::can_set_ownership() {
+            log::error!(
+                "Skipping device {} because devices can only be restored by root",
+                entry.apath()
+            );
+            return Ok(());
+        }
+        let path = self.rooted_path(entry.apath());
+        if self.was_restored(&path, entry) {
+            self.stats.skipped_restored += 1;
+            return Ok(());
+        }
+        if !self.should_write(&path, entry)? {
+            return Ok(());
+        }
+        let restore_err = |source| Error::Restore {
+            path: path.clone(),
+            source,
+        };
+        make_special(&path, kind, entry.rdev().unwrap_or_default()).map_err(restore_err)?;
+        set_mtime(&path, entry.mtime()).map_err(restore_err)?;
+        entry
+            .owner()
+            .apply(&path, self.ownership)
+            .map_err(restore_err)?;
+        restore_xattrs(&path, entry.xattrs());
+        if self.restore_permissions {
+            if let Some(mode) = entry.unix_mode() {
+                set_unix_mode(&path, mode).map_err(restore_err)?;
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn copy_special<E: Entry>(&mut self, entry: &E) -> Result<()> {
+        log::error!("Can't restore special files on non-Unix: {}", entry.apath());
         Ok(())
     }
 }
+
+/// Progress of a restore, recorded in the destination so that it can be
+/// resumed if it's interrupted.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+struct RestoreState {
+    /// The band being restored.
+    band_id: String,
+    /// The last entry that was completely restored, if any.
+    last_apath: Option<Apath>,
+}
+
+impl RestoreState {
+    fn transport(destination: &Path) -> Box<dyn Transport> {
+        Box::new(LocalTransport::new(destination))
+    }
+
+    /// Read the state of an interrupted restore, if there is one.
+    fn read(destination: &Path) -> Result<Option<RestoreState>> {
+        let transport = RestoreState::transport(destination);
+        if transport.exists(RESTORE_STATE_FILENAME)? {
+            read_json(&transport, RESTORE_STATE_FILENAME).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn write(&self, destination: &Path) -> Result<()> {
+        write_json(
+            &RestoreState::transport(destination),
+            RESTORE_STATE_FILENAME,
+            self,
+        )
+    }
+
+    fn remove(destination: &Path) -> Result<()> {
+        RestoreState::transport(destination)
+            .remove_file(RESTORE_STATE_FILENAME)
+            .map_err(|source| Error::Restore {
+                path: destination.join(RESTORE_STATE_FILENAME),
+                source,
+            })
+    }
+}
+
+/// Find where to resume an interrupted restore of `band_id` into `destination`.
+///
+/// Returns the last apath that was completely restored, or None if nothing
+/// was.
+pub(crate) fn resume_after(destination: &Path, band_id: &BandId) -> Result<Option<Apath>> {
+    let state = RestoreState::read(destination)?.ok_or_else(|| Error::NoRestoreToResume {
+        path: destination.to_owned(),
+    })?;
+    if state.band_id != band_id.to_string() {
+        return Err(Error::ResumeRestoreOfOtherBand {
+            path: destination.to_owned(),
+            band_id: state.band_id,
+        });
+    }
+    Ok(state.last_apath)
+}
+
+/// Metadata set on a restored file after its content is written, which can
+/// be sent to a worker thread.
+#[derive(Debug)]
+struct FileMetadata {
+    mtime: UnixTime,
+    owner: Owner,
+    xattrs: Vec<Xattr>,
+    /// Permissions to set, if they're stored and should be restored.
+    unix_mode: Option<u32>,
+    /// Windows attributes to set, if they're stored and permissions should
+    /// be restored.
+    windows_attributes: Option<u32>,
+    creation_time: Option<UnixTime>,
+    /// The hash of the whole file content, if it was stored, to check the
+    /// restored content against.
+    content_hash: Option<BlockHash>,
+}
+
+impl FileMetadata {
+    fn from_entry<E: Entry>(entry: &E, restore_permissions: bool) -> FileMetadata {
+        FileMetadata {
+            mtime: entry.mtime(),
+            owner: entry.owner(),
+            xattrs: entry.xattrs().to_vec(),
+            unix_mode: if restore_permissions {
+                entry.unix_mode()
+            } else {
+                None
+            },
+            windows_attributes: if restore_permissions {
+                entry.windows_attributes()
+            } else {
+                None
+            },
+            creation_time: entry.creation_time(),
+            content_hash: entry.content_hash().cloned(),
+        }
+    }
+
+    /// Set the metadata on a restored file.
+    fn apply(&self, path: &Path, ownership: OwnershipPolicy) -> io::Result<()> {
+        set_mtime(path, self.mtime)?;
+        if let Some(time) = self.creation_time {
+            windows_metadata::set_creation_time(path, time)?;
+        }
+        // Change ownership before permissions, because chown can clear the
+        // setuid and setgid bits.
+        self.owner.apply(path, ownership)?;
+        restore_xattrs(path, &self.xattrs);
+        if let Some(mode) = self.unix_mode {
+            set_unix_mode(path, mode)?;
+        }
+        // Last, because the file might become read-only.
+        if let Some(attributes) = self.windows_attributes {
+            windows_metadata::set_windows_attributes(path, attributes)?;
+        }
+        Ok(())
+    }
+}
+
+/// True if the file at `path` can be read, and its content has the hash
+/// `expected`.
+fn file_has_hash(path: &Path, expected: &BlockHash) -> bool {
+    let mut content = match File::open(path) {
+        Ok(file) => HashingReader::new(file),
+        Err(_) => return false,
+    };
+    io::copy(&mut content, &mut io::sink()).is_ok() && content.finish() == *expected
+}
+
+/// Write the content of a newly created file, leaving `holes` unwritten, and
+/// then set its metadata.
+fn write_file<R: io::Read>(
+    path: &Path,
+    mut file: File,
+    content: R,
+    holes: &[Range<u64>],
+    metadata: &FileMetadata,
+    ownership: OwnershipPolicy,
+) -> Result<CopyStats> {
+    let restore_err = |source| Error::Restore {
+        path: path.to_owned(),
+        source,
+    };
+    let mut content = HashingReader::new(content);
+    let (bytes_copied, sparse_bytes) =
+        copy_sparse(&mut content, &mut file, holes).map_err(restore_err)?;
+    file.flush().map_err(restore_err)?;
+    drop(file);
+    if let Some(expected) = &metadata.content_hash {
+        if content.finish() != *expected {
+            // Don't leave content that's known to be wrong where it could be
+            // mistaken for a good restore.
+            if let Err(err) = fs::remove_file(path) {
+                log::warn!("Failed to remove mismatched file {:?}: {}", path, err);
+            }
+            return Err(Error::RestoredContentMismatch {
+                path: path.to_owned(),
+            });
+        }
+    }
+    metadata.apply(path, ownership).map_err(restore_err)?;
+    // TODO: Accumulate more stats.
+    Ok(CopyStats {
+        uncompressed_bytes: bytes_copied,
+        sparse_bytes,
+        ..CopyStats::default()
+    })
+}
+
+/// Delete entries in `destination` that aren't in `stored`, so that restoring
+/// `stored` into it leaves the same tree as was backed up.
+///
+/// Excluded entries, and entries outside `options.only_subtree`, are kept,
+/// unless they're inside a directory that's deleted. In a dry run, this lists
+/// what would be deleted.
+///
+/// Returns the number of entries deleted, counting each deleted directory once.
+pub(crate) fn delete_extraneous(
+    stored: &StoredTree,
+    destination: &Path,
+    options: &RestoreOptions,
+) -> Result<usize> {
+    if !destination.is_dir() {
+        return Ok(0);
+    }
+    let live = LiveTree::open(destination)?.with_excludes(options.excludes.clone());
+    let subtree = options
+        .only_subtree
+        .clone()
+        .unwrap_or_else(|| Apath::from("/"));
+    let mut deleted_dirs: Vec<Apath> = Vec::new();
+    let mut deleted = 0;
+    for entry in iter_merged_entries(stored, &live)? {
+        if entry.kind != MergedEntryKind::RightOnly
+            || entry.apath[1..] == *RESTORE_STATE_FILENAME
+            || !subtree.is_prefix_of(&entry.apath)
+            || deleted_dirs
+                .iter()
+                .any(|dir| dir.is_prefix_of(&entry.apath))
+        {
+            continue;
+        }
+        let path = join_relative(destination, &entry.apath);
+        let restore_err = |source| Error::Restore {
+            path: path.clone(),
+            source,
+        };
+        let is_dir = fs::symlink_metadata(&path).map_err(restore_err)?.is_dir();
+        if options.dry_run {
+            log::info!("delete {}", entry.apath);
+        } else if is_dir {
+            fs::remove_dir_all(&path).map_err(restore_err)?;
+        } else {
+            windows_metadata::clear_readonly(&path)
+                .and_then(|()| fs::remove_file(&path))
+                .map_err(restore_err)?;
+        }
+        if is_dir {
+            deleted_dirs.push(entry.apath);
+        }
+        deleted += 1;
+    }
+    Ok(deleted)
+}
+
+/// Copy file content, seeking over the ranges in `holes` rather than writing
+/// them, so that the destination is sparse where the filesystem supports it.
+///
+/// The content of the holes is still read, so that it's included in any hash
+/// of `from`. Other runs of zeros are written, so files that weren't sparse
+/// when they were backed up aren't made sparse.
+///
+/// Returns the total number of bytes copied, and the number that were skipped
+/// as holes.
+fn copy_sparse(
+    from: &mut dyn io::Read,
+    to: &mut File,
+    holes: &[Range<u64>],
+) -> io::Result<(u64, u64)> {
+    use std::io::{Seek, SeekFrom};
+
+    let mut total = 0u64;
+    let mut sparse = 0u64;
+    for hole in holes {
+        total += io::copy(&mut (&mut *from).take(hole.start - total), to)?;
+        let skipped = io::copy(
+            &mut (&mut *from).take(hole.end - hole.start),
+            &mut io::sink(),
+        )?;
+        to.seek(SeekFrom::Current(skipped as i64))?;
+        total += skipped;
+        sparse += skipped;
+    }
+    total += io::copy(from, to)?;
+    if sparse > 0 {
+        // Extend the file over any trailing hole.
+        to.set_len(total)?;
+    }
+    Ok((total, sparse))
+}
+
+/// Set xattrs on a restored entry, warning if they can't be set.
+///
+/// This isn't fatal, because the contents were restored, and many xattrs can
+/// only be set by root or on some filesystems.
+fn restore_xattrs(path: &Path, xattrs: &[Xattr]) {
+    if let Err(e) = xattrs::write_xattrs(path, xattrs) {
+        log::error!("Failed to restore xattrs on {:?}: {}", path, e);
+    }
+}
+
+#[cfg(unix)]
+fn set_unix_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn set_unix_mode(_path: &Path, _mode: u32) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn c_path(path: &Path) -> io::Result<std::ffi::CString> {
+    use std::os::unix::ffi::OsStrExt;
+    std::ffi::CString::new(path.as_os_str().as_bytes())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+}
+
+/// Create a fifo, socket, or device node, with owner-only permissions until
+/// its stored permissions are set.
+#[cfg(unix)]
+fn make_special(path: &Path, kind: Kind, rdev: u64) -> io::Result<()> {
+    let file_type = match kind {
+        Kind::Fifo => libc::S_IFIFO,
+        Kind::Socket => libc::S_IFSOCK,
+        Kind::CharDevice => libc::S_IFCHR,
+        Kind::BlockDevice => libc::S_IFBLK,
+        _ => panic!("Not a special file kind: {:?}", kind),
+    };
+    let c_path = c_path(path)?;
+    if unsafe { libc::mknod(c_path.as_ptr(), file_type | 0o600, rdev as libc::dev_t) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Set the modification time of `path`, without following symlinks.
+///
+/// The access time is set to the same value.
+#[cfg(unix)]
+fn set_mtime(path: &Path, mtime: UnixTime) -> io::Result<()> {
+    let c_path = c_path(path)?;
+    let timespec = libc::timespec {
+        tv_sec: mtime.secs as libc::time_t,
+        tv_nsec: mtime.nanosecs as libc::c_long,
+    };
+    let times = [timespec, timespec];
+    if unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            c_path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    } == 0
+    {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Set the modification time of `path`, to whole-second precision.
+#[cfg(not(unix))]
+fn set_mtime(path: &Path, mtime: UnixTime) -> io::Result<()> {
+    utime::set_file_times(path, mtime.secs, mtime.secs)
+}
@@ -12,8 +12,10 @@
 // GNU General Public License for more details.
 
 use std::io;
+use std::time::Duration;
 
 use derive_more::{Add, AddAssign};
+use serde::{Serialize, Serializer};
 use thousands::Separable;
 
 use crate::*;
@@ -31,6 +33,33 @@ fn ratio(uncompressed: u64, compressed: u64) -> f64 {
     }
 }
 
+/// Serialize a duration as a number of seconds.
+fn serialize_secs<S: Serializer>(
+    duration: &Duration,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
+/// Describe a duration in seconds, to the millisecond.
+fn secs_string(duration: Duration) -> String {
+    format!("{:.3}", duration.as_secs_f64())
+}
+
+/// Common behavior of the stats returned by each operation.
+pub trait Stats: Serialize {
+    /// Write a human-readable summary, one count per line.
+    fn summarize(&self, w: &mut dyn io::Write) -> Result<()>;
+
+    /// Write the stats as a JSON object.
+    fn summarize_json(&self, w: &mut dyn io::Write) -> Result<()> {
+        serde_json::to_writer_pretty(&mut *w, self)
+            .map_err(|source| Error::SerializeStats { source })?;
+        writeln!(w)?;
+        Ok(())
+    }
+}
+
 /// Describes sizes of data read or written, with both the
 /// compressed and uncompressed size.
 #[derive(Add, AddAssign, Clone, Copy, Debug, Default, Eq, PartialEq)]
@@ -39,7 +68,7 @@ pub struct Sizes {
     pub uncompressed: u64,
 }
 
-#[derive(Debug, Default, Clone, PartialEq, Eq, Add, AddAssign)]
+#[derive(Debug, Default, Clone, PartialEq, Eq, Add, AddAssign, Serialize)]
 pub struct ValidateStats {
     /// Count of files in the wrong place.
     pub structure_problems: usize,
@@ -60,32 +89,75 @@ pub struct ValidateStats {
 
     /// Number of blocks read.
     pub block_read_count: u64,
-    /// Number of blocks that failed to read back.
+    /// Number of blocks that failed to read back, for any reason.
     pub block_error_count: usize,
+    /// Blocks that could be decompressed, but whose content doesn't match
+    /// their name.
+    pub block_wrong_hash_count: usize,
+    /// Blocks that couldn't be decompressed.
+    pub block_decompress_error_count: usize,
+    /// Blocks stored in the wrong subdirectory.
+    pub block_misplaced_count: usize,
+    /// Blocks with more uncompressed content than Conserve writes in one block.
+    pub block_oversized_count: usize,
+    /// References from an index to blocks that are missing, or too short.
     pub block_missing_count: usize,
 }
 
-impl ValidateStats {
-    pub fn summarize(&self, write: &mut dyn io::Write) -> Result<()> {
-        // format!(
-        //     "{:>12} MB   in {} blocks.\n\
-        //      {:>12} MB/s block validation rate.\n\
-        //      {:>12}      elapsed.\n",
-        //     (self.get_size("block").uncompressed / M).separate_with_commas(),
-        //     self.get_count("block.read").separate_with_commas(),
-        //     (mbps_rate(self.get_size("block").uncompressed, self.elapsed_time()) as u64)
-        //         .separate_with_commas(),
-        //     duration_to_hms(self.elapsed_time()),
-        // )
-        writeln!(write, "{:#?}", self).map_err(Error::from)
+impl Stats for ValidateStats {
+    fn summarize(&self, w: &mut dyn io::Write) -> Result<()> {
+        let lines = [
+            (self.block_read_count as usize, "blocks read"),
+            (self.block_error_count, "blocks that failed to read"),
+            (self.block_wrong_hash_count, "blocks with the wrong hash"),
+            (
+                self.block_decompress_error_count,
+                "blocks that failed to decompress",
+            ),
+            (
+                self.block_misplaced_count,
+                "blocks in the wrong subdirectory",
+            ),
+            (self.block_oversized_count, "blocks larger than expected"),
+            (self.block_missing_count, "references to missing blocks"),
+            (self.structure_problems, "structure problems"),
+            (self.unexpected_files, "unexpected files"),
+            (self.missing_band_heads, "missing band heads"),
+            (self.band_open_errors, "bands that failed to open"),
+            (self.band_metadata_problems, "band metadata problems"),
+            (self.tree_open_errors, "trees that failed to open"),
+            (self.tree_validate_errors, "trees that failed to validate"),
+            (self.io_errors, "IO errors"),
+        ];
+        for (count, description) in lines.iter() {
+            writeln!(
+                w,
+                "{:>12}      {}",
+                count.separate_with_commas(),
+                description
+            )?;
+        }
+        Ok(())
     }
+}
 
+impl ValidateStats {
     pub fn has_problems(&self) -> bool {
-        self.block_error_count > 0 || self.io_errors > 0 || self.block_missing_count > 0
+        self.block_error_count > 0
+            || self.io_errors > 0
+            || self.block_missing_count > 0
+            || self.block_misplaced_count > 0
+            || self.block_oversized_count > 0
+            || self.structure_problems > 0
+            || self.missing_band_heads > 0
+            || self.band_open_errors > 0
+            || self.band_metadata_problems > 0
+            || self.tree_open_errors > 0
+            || self.tree_validate_errors > 0
     }
 }
 
-#[derive(Default, Debug, Clone, Eq, PartialEq)]
+#[derive(Default, Debug, Clone, Eq, PartialEq, Serialize)]
 pub struct IndexReadStats {
     pub index_hunks: usize,
     pub uncompressed_index_bytes: u64,
@@ -93,35 +165,94 @@ pub struct IndexReadStats {
     pub errors: usize,
 }
 
-#[derive(Add, AddAssign, Clone, Debug, Default, Eq, PartialEq)]
+#[derive(Add, AddAssign, Clone, Debug, Default, Eq, PartialEq, Serialize)]
 pub struct IndexBuilderStats {
     pub index_hunks: u64,
     pub uncompressed_index_bytes: u64,
     pub compressed_index_bytes: u64,
+    /// Time spent serializing, compressing, and writing index hunks.
+    #[serde(serialize_with = "serialize_secs")]
+    pub write_time: Duration,
 }
 
-#[derive(Debug, Default, Clone, Eq, PartialEq)]
+/// Time spent in each phase of copying a tree.
+///
+/// Work done in parallel threads is added up, so the phases can sum to more
+/// than the elapsed time.
+#[derive(Add, AddAssign, Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct PhaseTimes {
+    /// Time reading entries from the source tree, not including file content.
+    #[serde(serialize_with = "serialize_secs")]
+    pub source_walk: Duration,
+    /// Time hashing file content.
+    #[serde(serialize_with = "serialize_secs")]
+    pub hashing: Duration,
+    /// Time compressing new blocks.
+    #[serde(serialize_with = "serialize_secs")]
+    pub compression: Duration,
+    /// Time writing new blocks to the archive's transport.
+    #[serde(serialize_with = "serialize_secs")]
+    pub block_writes: Duration,
+    /// Wall-clock time for the whole operation.
+    #[serde(serialize_with = "serialize_secs")]
+    pub elapsed: Duration,
+}
+
+impl PhaseTimes {
+    fn summarize(&self, w: &mut dyn io::Write) -> Result<()> {
+        writeln!(
+            w,
+            "{:>12} s      source walk",
+            secs_string(self.source_walk)
+        )?;
+        writeln!(w, "{:>12} s      hashing", secs_string(self.hashing))?;
+        writeln!(
+            w,
+            "{:>12} s      compression",
+            secs_string(self.compression)
+        )?;
+        writeln!(
+            w,
+            "{:>12} s      block writes",
+            secs_string(self.block_writes)
+        )?;
+        Ok(())
+    }
+}
+
+#[derive(Add, AddAssign, Debug, Default, Clone, Eq, PartialEq, Serialize)]
 pub struct LiveTreeIterStats {
     pub directories_visited: usize,
     pub exclusions: usize,
     pub metadata_error: usize,
     pub entries_returned: usize,
+    /// Directories on other filesystems that were not descended into.
+    pub mount_points_skipped: usize,
 }
 
-#[derive(Add, AddAssign, Debug, Default, Eq, PartialEq, Clone)]
+#[derive(Add, AddAssign, Debug, Default, Eq, PartialEq, Clone, Serialize)]
 pub struct CopyStats {
-    // TODO: Have separate more-specific stats for backup and restore, and then
-    // each can have a single Display method.
-    // TODO: Include source file bytes, including unmodified files.
     pub files: usize,
     pub symlinks: usize,
     pub directories: usize,
+    /// Fifos, sockets, and device nodes.
+    pub special_files: usize,
     pub unknown_kind: usize,
+    /// Directories on other filesystems whose contents were not copied.
+    pub mount_points_skipped: usize,
 
     pub unmodified_files: usize,
     pub modified_files: usize,
     pub new_files: usize,
+    /// Files hard-linked to another file that was already copied.
+    pub hardlinked_files: usize,
+    /// Files that kept changing while they were being read, and so might have
+    /// been stored inconsistently.
+    pub changed_during_read: usize,
 
+    /// Total length of the source files, including unmodified files whose
+    /// content wasn't read.
+    pub source_bytes: u64,
     /// Bytes that matched an existing block.
     pub deduplicated_bytes: u64,
     /// Bytes that were stored as new blocks, before compression.
@@ -135,163 +266,260 @@ pub struct CopyStats {
     pub single_block_files: usize,
     pub multi_block_files: usize,
 
+    /// Bytes in holes of sparse files, which were skipped rather than copied.
+    pub sparse_bytes: u64,
+
+    /// Existing files in the restore destination that were replaced.
+    pub overwritten: usize,
+    /// Entries not restored because something already existed at that path.
+    pub skipped_existing: usize,
+    /// Entries not restored because the existing file was at least as new
+    /// as the stored one.
+    pub skipped_not_newer: usize,
+    /// Entries deleted from the restore destination because they're not in
+    /// the stored tree.
+    pub deleted: usize,
+    /// Entries skipped because the interrupted restore that was resumed had
+    /// already restored them.
+    pub skipped_restored: usize,
+
     pub errors: usize,
 
     pub index_builder_stats: IndexBuilderStats,
-    // TODO: Include elapsed time.
+
+    /// Time spent in each phase.
+    pub times: PhaseTimes,
 }
 
+/// Stats from a backup, summarizing the [CopyStats] it collected.
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(transparent)]
+pub struct BackupStats<'a>(pub &'a CopyStats);
+
+/// Stats from a restore, summarizing the [CopyStats] it collected.
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(transparent)]
+pub struct RestoreStats<'a>(pub &'a CopyStats);
+
 impl CopyStats {
-    pub fn summarize_restore(&self, _to_stream: &mut dyn io::Write) -> Result<()> {
-        // format!(
-        //     "{:>12} MB   in {} files, {} directories, {} symlinks.\n\
-        //      {:>12} MB/s output rate.\n\
-        //      {:>12} MB   after deduplication.\n\
-        //      {:>12} MB   in {} blocks after {:.1}x compression.\n\
-        //      {:>12} MB   in {} compressed index hunks.\n\
-        //      {:>12}      elapsed.\n",
-        //     (self.get_size("file.bytes").uncompressed / M).separate_with_commas(),
-        //     self.get_count("file").separate_with_commas(),
-        //     self.get_count("dir").separate_with_commas(),
-        //     self.get_count("symlink").separate_with_commas(),
-        //     (mbps_rate(
-        //         self.get_size("file.bytes").uncompressed,
-        //         self.elapsed_time()
-        //     ) as u64)
-        //         .separate_with_commas(),
-        //     (self.get_size("block").uncompressed / M).separate_with_commas(),
-        //     (self.get_size("block").compressed / M).separate_with_commas(),
-        //     self.get_count("block.read").separate_with_commas(),
-        //     compression_ratio(&self.get_size("block")),
-        //     (self.get_size("index").compressed / M).separate_with_commas(),
-        //     self.get_count("index.hunk").separate_with_commas(),
-        //     duration_to_hms(self.elapsed_time()),
+    /// Ratio of the source file content to the new data stored for it, before
+    /// compression: higher means more was unchanged or deduplicated.
+    pub fn deduplication_ratio(&self) -> f64 {
+        ratio(self.source_bytes, self.uncompressed_bytes)
+    }
+
+    /// Ratio of the source file content to the compressed size of the new
+    /// blocks written for it.
+    pub fn overall_ratio(&self) -> f64 {
+        ratio(self.source_bytes, self.compressed_bytes)
+    }
+
+    /// Describe these stats as coming from a backup.
+    pub fn backup(&self) -> BackupStats<'_> {
+        BackupStats(self)
+    }
+
+    /// Describe these stats as coming from a restore.
+    pub fn restore(&self) -> RestoreStats<'_> {
+        RestoreStats(self)
+    }
+}
+
+impl Stats for RestoreStats<'_> {
+    fn summarize(&self, w: &mut dyn io::Write) -> Result<()> {
+        let stats = self.0;
+        writeln!(w, "{:>12}      files", stats.files.separate_with_commas())?;
+        writeln!(
+            w,
+            "{:>12}      symlinks",
+            stats.symlinks.separate_with_commas()
+        )?;
+        writeln!(
+            w,
+            "{:>12}      directories",
+            stats.directories.separate_with_commas()
+        )?;
+        writeln!(
+            w,
+            "{:>12}      special files",
+            stats.special_files.separate_with_commas()
+        )?;
+        writeln!(
+            w,
+            "{:>12} MB     file content",
+            mb_string(stats.uncompressed_bytes)
+        )?;
+        writeln!(
+            w,
+            "{:>12}      existing files overwritten",
+            stats.overwritten.separate_with_commas()
+        )?;
+        writeln!(
+            w,
+            "{:>12}      skipped because they already exist",
+            stats.skipped_existing.separate_with_commas()
+        )?;
+        writeln!(
+            w,
+            "{:>12}      skipped because the existing file is newer",
+            stats.skipped_not_newer.separate_with_commas()
+        )?;
+        writeln!(
+            w,
+            "{:>12}      existing files already up to date",
+            stats.unmodified_files.separate_with_commas()
+        )?;
+        writeln!(
+            w,
+            "{:>12}      extraneous entries deleted",
+            stats.deleted.separate_with_commas()
+        )?;
+        writeln!(
+            w,
+            "{:>12}      already restored before resuming",
+            stats.skipped_restored.separate_with_commas()
+        )?;
+        writeln!(w, "{:>12}      errors", stats.errors.separate_with_commas())?;
+        writeln!(w, "{:>12} s      elapsed", secs_string(stats.times.elapsed))?;
         Ok(())
     }
+}
 
-    pub fn summarize_backup(&self, w: &mut dyn io::Write) {
-        // TODO: Perhaps summarize to a string, or make this the Display impl.
-        writeln!(w, "{:>12}      files:", self.files.separate_with_commas()).unwrap();
+impl Stats for BackupStats<'_> {
+    fn summarize(&self, w: &mut dyn io::Write) -> Result<()> {
+        let stats = self.0;
+        writeln!(w, "{:>12}      files:", stats.files.separate_with_commas())?;
         writeln!(
             w,
             "{:>12}        unmodified files",
-            self.unmodified_files.separate_with_commas()
-        )
-        .unwrap();
+            stats.unmodified_files.separate_with_commas()
+        )?;
         writeln!(
             w,
             "{:>12}        modified files",
-            self.modified_files.separate_with_commas()
-        )
-        .unwrap();
+            stats.modified_files.separate_with_commas()
+        )?;
         writeln!(
             w,
             "{:>12}        new files",
-            self.new_files.separate_with_commas()
-        )
-        .unwrap();
+            stats.new_files.separate_with_commas()
+        )?;
+        writeln!(
+            w,
+            "{:>12}        hardlinked files",
+            stats.hardlinked_files.separate_with_commas()
+        )?;
+        writeln!(
+            w,
+            "{:>12}        files changed while being read",
+            stats.changed_during_read.separate_with_commas()
+        )?;
         writeln!(
             w,
             "{:>12}      symlinks",
-            self.symlinks.separate_with_commas()
-        )
-        .unwrap();
+            stats.symlinks.separate_with_commas()
+        )?;
         writeln!(
             w,
             "{:>12}      directories",
-            self.directories.separate_with_commas()
-        )
-        .unwrap();
+            stats.directories.separate_with_commas()
+        )?;
         writeln!(
             w,
-            "{:>12}      special files skipped",
-            self.unknown_kind.separate_with_commas(),
-        )
-        .unwrap();
-        writeln!(w).unwrap();
+            "{:>12}      special files",
+            stats.special_files.separate_with_commas(),
+        )?;
+        writeln!(
+            w,
+            "{:>12}      unknown files skipped",
+            stats.unknown_kind.separate_with_commas(),
+        )?;
+        writeln!(
+            w,
+            "{:>12}      mount points skipped",
+            stats.mount_points_skipped.separate_with_commas(),
+        )?;
+        writeln!(w)?;
 
+        writeln!(
+            w,
+            "{:>12} MB     file content scanned",
+            mb_string(stats.source_bytes),
+        )?;
         writeln!(
             w,
             "{:>12}      deduplicated data blocks:",
-            self.deduplicated_blocks.separate_with_commas(),
-        )
-        .unwrap();
-        writeln!(w, "{:>12} MB     saved", mb_string(self.deduplicated_bytes),).unwrap();
+            stats.deduplicated_blocks.separate_with_commas(),
+        )?;
+        writeln!(
+            w,
+            "{:>12} MB     saved",
+            mb_string(stats.deduplicated_bytes),
+        )?;
+        writeln!(
+            w,
+            "{:>12} MB     in sparse file holes",
+            mb_string(stats.sparse_bytes),
+        )?;
         writeln!(
             w,
             "{:>12}      new data blocks:",
-            self.written_blocks.separate_with_commas(),
-        )
-        .unwrap();
+            stats.written_blocks.separate_with_commas(),
+        )?;
         writeln!(
             w,
             "{:>12} MB     uncompressed",
-            mb_string(self.uncompressed_bytes),
-        )
-        .unwrap();
+            mb_string(stats.uncompressed_bytes),
+        )?;
         writeln!(
             w,
             "{:>12} MB     after {:.1}x compression",
-            mb_string(self.compressed_bytes),
-            ratio(self.uncompressed_bytes, self.compressed_bytes)
-        )
-        .unwrap();
+            mb_string(stats.compressed_bytes),
+            ratio(stats.uncompressed_bytes, stats.compressed_bytes)
+        )?;
+        if stats.compressed_bytes > 0 {
+            writeln!(
+                w,
+                "{:>12}      deduplication ratio",
+                format!("{:.1}x", stats.deduplication_ratio()),
+            )?;
+            writeln!(
+                w,
+                "{:>12}      overall reduction",
+                format!("{:.1}x", stats.overall_ratio()),
+            )?;
+        }
 
-        writeln!(w).unwrap();
-        let idx = &self.index_builder_stats;
+        writeln!(w)?;
+        let idx = &stats.index_builder_stats;
         writeln!(
             w,
             "{:>12}      new index hunks:",
             idx.index_hunks.separate_with_commas(),
-        )
-        .unwrap();
+        )?;
         writeln!(
             w,
             "{:>12} MB     uncompressed",
             mb_string(idx.uncompressed_index_bytes),
-        )
-        .unwrap();
+        )?;
         writeln!(
             w,
             "{:>12} MB     after {:.1}x compression",
             mb_string(idx.compressed_index_bytes),
             ratio(idx.uncompressed_index_bytes, idx.compressed_index_bytes),
-        )
-        .unwrap();
-        writeln!(w).unwrap();
-        writeln!(w, "{:>12}      errors", self.errors.separate_with_commas()).unwrap();
-
-        // format!(
-        //     "{:>12} MB   in {} files, {} directories, {} symlinks.\n\
-        //      {:>12}      files are unchanged.\n\
-        //      {:>12} MB/s input rate.\n\
-        //      {:>12} MB   after deduplication.\n\
-        //      {:>12} MB   in {} blocks after {:.1}x compression.\n\
-        //      {:>12} MB   in {} index hunks after {:.1}x compression.\n\
-        //      {:>12}      elapsed.\n",
-        //     (self.get_size("file.bytes").uncompressed / M).separate_with_commas(),
-        //     self.get_count("file").separate_with_commas(),
-        //     self.get_count("dir").separate_with_commas(),
-        //     self.get_count("symlink").separate_with_commas(),
-        //     self.get_count("file.unchanged").separate_with_commas(),
-        //     (mbps_rate(
-        //         self.get_size("file.bytes").uncompressed,
-        //         self.elapsed_time()
-        //     ) as u64)
-        //         .separate_with_commas(),
-        //     (self.get_size("block").uncompressed / M).separate_with_commas(),
-        //     (self.get_size("block").compressed / M).separate_with_commas(),
-        //     self.get_count("block.write").separate_with_commas(),
-        //     compression_ratio(&self.get_size("block")),
-        //     (self.get_size("index").compressed / M).separate_with_commas(),
-        //     self.get_count("index.hunk").separate_with_commas(),
-        //     compression_ratio(&self.get_size("index")),
-        //     duration_to_hms(self.elapsed_time()),
-        // )
+        )?;
+        writeln!(w)?;
+        writeln!(w, "{:>12}      errors", stats.errors.separate_with_commas())?;
+        writeln!(w)?;
+        stats.times.summarize(w)?;
+        writeln!(w, "{:>12} s      index writes", secs_string(idx.write_time))?;
+        writeln!(w, "{:>12} s      elapsed", secs_string(stats.times.elapsed))?;
+        Ok(())
     }
 }
 
-#[derive(Add, AddAssign, Clone, Copy, Debug, Default, Eq, PartialEq)]
+/// Counts from deleting backups and garbage-collecting unreferenced blocks.
+#[derive(Add, AddAssign, Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
 pub struct DeleteStats {
     pub deleted_band_count: usize,
     pub unreferenced_block_count: usize,
@@ -299,3 +527,347 @@ pub struct DeleteStats {
     pub deletion_errors: usize,
     pub deleted_block_count: usize,
 }
+
+impl Stats for DeleteStats {
+    fn summarize(&self, w: &mut dyn io::Write) -> Result<()> {
+        writeln!(
+            w,
+            "{:>12}      backups deleted",
+            self.deleted_band_count.separate_with_commas()
+        )?;
+        writeln!(
+            w,
+            "{:>12}      unreferenced blocks",
+            self.unreferenced_block_count.separate_with_commas()
+        )?;
+        writeln!(
+            w,
+            "{:>12} MB     unreferenced block content",
+            mb_string(self.unreferenced_block_bytes)
+        )?;
+        writeln!(
+            w,
+            "{:>12}      blocks deleted",
+            self.deleted_block_count.separate_with_commas()
+        )?;
+        writeln!(
+            w,
+            "{:>12}      deletion errors",
+            self.deletion_errors.separate_with_commas()
+        )?;
+        Ok(())
+    }
+}
+
+/// Counts from watching a source tree and backing it up as it changes, from
+/// [crate::watch::watch].
+#[derive(Add, AddAssign, Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct WatchStats {
+    /// Backups that completed.
+    pub backups: usize,
+    /// Backups that failed, and were tried again later.
+    pub failed_backups: usize,
+    /// Old backups deleted by the retention policy.
+    pub deleted_band_count: usize,
+}
+
+impl Stats for WatchStats {
+    fn summarize(&self, w: &mut dyn io::Write) -> Result<()> {
+        writeln!(
+            w,
+            "{:>12}      backups made",
+            self.backups.separate_with_commas()
+        )?;
+        writeln!(
+            w,
+            "{:>12}      backups failed",
+            self.failed_backups.separate_with_commas()
+        )?;
+        writeln!(
+            w,
+            "{:>12}      old backups deleted",
+            self.deleted_band_count.separate_with_commas()
+        )?;
+        Ok(())
+    }
+}
+
+/// Counts from copying an archive to another location, from [crate::sync::sync_archive].
+#[derive(Add, AddAssign, Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct SyncStats {
+    /// Blocks copied to the destination, or that would be copied in a dry run.
+    pub blocks_copied: usize,
+    /// Compressed size of the copied blocks.
+    pub block_bytes_copied: u64,
+    /// Blocks already present in the destination.
+    pub blocks_present: usize,
+    /// Complete bands copied to the destination.
+    pub bands_copied: usize,
+    /// Complete bands already present in the destination.
+    pub bands_present: usize,
+    /// Incomplete bands in the source, which aren't copied.
+    pub bands_incomplete: usize,
+}
+
+impl Stats for SyncStats {
+    fn summarize(&self, w: &mut dyn io::Write) -> Result<()> {
+        writeln!(
+            w,
+            "{:>12}      backups copied",
+            self.bands_copied.separate_with_commas()
+        )?;
+        writeln!(
+            w,
+            "{:>12}      backups already present",
+            self.bands_present.separate_with_commas()
+        )?;
+        writeln!(
+            w,
+            "{:>12}      incomplete backups not copied",
+            self.bands_incomplete.separate_with_commas()
+        )?;
+        writeln!(
+            w,
+            "{:>12}      blocks copied",
+            self.blocks_copied.separate_with_commas()
+        )?;
+        writeln!(
+            w,
+            "{:>12} MB     compressed block content copied",
+            mb_string(self.block_bytes_copied)
+        )?;
+        writeln!(
+            w,
+            "{:>12}      blocks already present",
+            self.blocks_present.separate_with_commas()
+        )?;
+        Ok(())
+    }
+}
+
+/// Counts from migrating an archive to the current format, from
+/// [crate::migrate::migrate_archive].
+#[derive(Add, AddAssign, Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct MigrateStats {
+    /// Blocks rewritten in the new format, or that would be in a dry run.
+    pub blocks_recompressed: usize,
+    /// Blocks that were already in the new format.
+    pub blocks_already_migrated: usize,
+    /// Compressed size of the recompressed blocks before migration.
+    pub compressed_bytes_before: u64,
+    /// Compressed size of the recompressed blocks after migration. Zero in a
+    /// dry run.
+    pub compressed_bytes_after: u64,
+}
+
+impl Stats for MigrateStats {
+    fn summarize(&self, w: &mut dyn io::Write) -> Result<()> {
+        writeln!(
+            w,
+            "{:>12}      blocks recompressed",
+            self.blocks_recompressed.separate_with_commas()
+        )?;
+        writeln!(
+            w,
+            "{:>12} MB     compressed size before",
+            mb_string(self.compressed_bytes_before)
+        )?;
+        writeln!(
+            w,
+            "{:>12} MB     compressed size after",
+            mb_string(self.compressed_bytes_after)
+        )?;
+        writeln!(
+            w,
+            "{:>12}      blocks already migrated",
+            self.blocks_already_migrated.separate_with_commas()
+        )?;
+        Ok(())
+    }
+}
+
+/// Counts from writing a tree as a tar stream, from
+/// [crate::tarball::export_tar].
+#[derive(Add, AddAssign, Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct ExportTarStats {
+    pub files: usize,
+    pub directories: usize,
+    pub symlinks: usize,
+    /// Files written as hard links to a file earlier in the stream.
+    pub hardlinks: usize,
+    /// Fifos and device nodes.
+    pub special_files: usize,
+    /// Entries that can't be represented in tar, such as sockets.
+    pub skipped: usize,
+    /// Total length of file content.
+    pub file_bytes: u64,
+}
+
+impl Stats for ExportTarStats {
+    fn summarize(&self, w: &mut dyn io::Write) -> Result<()> {
+        writeln!(w, "{:>12}      files", self.files.separate_with_commas())?;
+        writeln!(
+            w,
+            "{:>12}      directories",
+            self.directories.separate_with_commas()
+        )?;
+        writeln!(
+            w,
+            "{:>12}      symlinks",
+            self.symlinks.separate_with_commas()
+        )?;
+        writeln!(
+            w,
+            "{:>12}      hard links",
+            self.hardlinks.separate_with_commas()
+        )?;
+        writeln!(
+            w,
+            "{:>12}      special files",
+            self.special_files.separate_with_commas()
+        )?;
+        writeln!(
+            w,
+            "{:>12}      skipped",
+            self.skipped.separate_with_commas()
+        )?;
+        writeln!(w, "{:>12} MB     file content", mb_string(self.file_bytes))?;
+        Ok(())
+    }
+}
+
+/// Counts from comparing a stored tree to a live tree.
+#[derive(Add, AddAssign, Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct VerifyStats {
+    /// Entries present in both trees.
+    pub entries_compared: usize,
+    /// Entries only in the live tree.
+    pub added: usize,
+    /// Entries only in the stored tree.
+    pub deleted: usize,
+    /// Entries present in both trees, but different.
+    pub modified: usize,
+    /// Live files whose content was read and compared.
+    pub files_read: usize,
+    /// Bytes of live file content compared to stored blocks.
+    pub content_bytes: u64,
+    /// Entries that couldn't be compared because of an error.
+    pub errors: usize,
+}
+
+impl VerifyStats {
+    pub fn has_changes(&self) -> bool {
+        self.added > 0 || self.deleted > 0 || self.modified > 0
+    }
+}
+
+impl Stats for VerifyStats {
+    fn summarize(&self, w: &mut dyn io::Write) -> Result<()> {
+        writeln!(
+            w,
+            "{:>12}      entries compared",
+            self.entries_compared.separate_with_commas()
+        )?;
+        writeln!(
+            w,
+            "{:>12} MB     file content compared",
+            mb_string(self.content_bytes)
+        )?;
+        writeln!(w, "{:>12}      added", self.added.separate_with_commas())?;
+        writeln!(
+            w,
+            "{:>12}      deleted",
+            self.deleted.separate_with_commas()
+        )?;
+        writeln!(
+            w,
+            "{:>12}      modified",
+            self.modified.separate_with_commas()
+        )?;
+        writeln!(w, "{:>12}      errors", self.errors.separate_with_commas())?;
+        Ok(())
+    }
+}
+
+/// Counts and sizes from comparing two trees.
+#[derive(Add, AddAssign, Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct DiffStats {
+    /// Entries present only in the new tree.
+    pub new: usize,
+    /// Entries present only in the old tree.
+    pub deleted: usize,
+    /// Entries present in both trees, but different.
+    pub changed: usize,
+    /// Entries present in both trees and the same, if they were included.
+    pub unchanged: usize,
+    /// Total size of new files.
+    pub new_bytes: u64,
+    /// Total size of deleted files.
+    pub deleted_bytes: u64,
+    /// Total size of changed files in the old tree.
+    pub changed_old_bytes: u64,
+    /// Total size of changed files in the new tree.
+    pub changed_new_bytes: u64,
+}
+
+impl DiffStats {
+    pub fn has_changes(&self) -> bool {
+        self.new > 0 || self.deleted > 0 || self.changed > 0
+    }
+
+    /// Net change in the size of file content from the old tree to the new.
+    pub fn net_bytes(&self) -> i64 {
+        (self.new_bytes + self.changed_new_bytes) as i64
+            - (self.deleted_bytes + self.changed_old_bytes) as i64
+    }
+}
+
+impl Stats for DiffStats {
+    fn summarize(&self, w: &mut dyn io::Write) -> Result<()> {
+        writeln!(w, "{:>12}      new", self.new.separate_with_commas())?;
+        writeln!(w, "{:>12} MB     in new files", mb_string(self.new_bytes))?;
+        writeln!(
+            w,
+            "{:>12}      deleted",
+            self.deleted.separate_with_commas()
+        )?;
+        writeln!(
+            w,
+            "{:>12} MB     in deleted files",
+            mb_string(self.deleted_bytes)
+        )?;
+        writeln!(
+            w,
+            "{:>12}      changed",
+            self.changed.separate_with_commas()
+        )?;
+        writeln!(
+            w,
+            "{:>12} MB     in changed files before",
+            mb_string(self.changed_old_bytes)
+        )?;
+        writeln!(
+            w,
+            "{:>12} MB     in changed files after",
+            mb_string(self.changed_new_bytes)
+        )?;
+        if self.unchanged > 0 {
+            writeln!(
+                w,
+                "{:>12}      unchanged",
+                self.unchanged.separate_with_commas()
+            )?;
+        }
+        let net = self.net_bytes();
+        writeln!(
+            w,
+            "{:>12} MB     net change",
+            format!(
+                "{}{}",
+                if net < 0 { "-" } else { "+" },
+                mb_string(net.unsigned_abs())
+            )
+        )?;
+        Ok(())
+    }
+}
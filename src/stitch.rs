@@ -27,9 +27,18 @@
 //! * The next-older index might end at an earlier apath than we've already
 //!   seen.
 //! * Bands might be deleted, so their numbers are not contiguous.
+//!
+//! Bands with delta indexes are read by merging them with the stitched index of
+//! their parent band: see the `delta` module.
+
+use std::rc::Rc;
 
+use crate::delta::MergeDeltaHunks;
 use crate::*;
 
+/// Decides whether an apath is before the region of interest.
+type ApathPredicate = dyn Fn(&Apath) -> bool;
+
 pub struct IterStitchedIndexHunks {
     /// Current band_id: initially the requested band_id.
     band_id: BandId,
@@ -38,9 +47,15 @@ pub struct IterStitchedIndexHunks {
     last_apath: Option<Apath>,
 
     /// Currently pending index hunks.
-    index_hunks: Option<crate::index::IndexHunkIter>,
+    index_hunks: Option<Box<dyn Iterator<Item = Vec<IndexEntry>>>>,
+
+    /// If set, skip hunks whose entries are all before the region of interest.
+    skip_before: Option<Rc<ApathPredicate>>,
 
     archive: Archive,
+
+    /// Counts bands that couldn't be read, ending the iteration.
+    pub stats: IndexReadStats,
 }
 
 impl IterStitchedIndexHunks {
@@ -50,6 +65,27 @@ impl IterStitchedIndexHunks {
             band_id: band_id.clone(),
             last_apath: None,
             index_hunks: None,
+            skip_before: None,
+            stats: IndexReadStats::default(),
+        }
+    }
+
+    /// Skip hunks in every band in which all the entries are before the region
+    /// of interest, as determined by `before`.
+    ///
+    /// See `IndexHunkIter::skip_hunks_while`.
+    pub fn skip_hunks_while(self, before: impl Fn(&Apath) -> bool + 'static) -> Self {
+        IterStitchedIndexHunks {
+            skip_before: Some(Rc::new(before)),
+            ..self
+        }
+    }
+
+    /// Return only entries ordered after `apath`.
+    pub fn advance_to_after(self, apath: &Apath) -> Self {
+        IterStitchedIndexHunks {
+            last_apath: Some(apath.clone()),
+            ..self
         }
     }
 }
@@ -67,7 +103,12 @@ impl Iterator for IterStitchedIndexHunks {
                         return Some(hunk);
                     } // otherwise, empty, try the next
                 }
-                if self.archive.band_is_closed(&self.band_id).unwrap_or(false) {
+                // Partial bands are filled in from earlier bands, just like
+                // incomplete bands.
+                if Band::open(&self.archive, &self.band_id)
+                    .and_then(|band| band.is_complete())
+                    .unwrap_or(false)
+                {
                     return None;
                 }
                 self.index_hunks = None;
@@ -78,14 +119,37 @@ impl Iterator for IterStitchedIndexHunks {
                 }
             }
             // Start reading this new index and skip forward until after last_apath
-            let mut iter_hunks = Band::open(&self.archive, &self.band_id)
-                .expect("Failed to open band")
-                .index()
-                .iter_hunks();
+            let band = match Band::open(&self.archive, &self.band_id) {
+                Ok(band) => band,
+                Err(err) => {
+                    self.stats.errors += 1;
+                    log::error!("Error opening band {}: {:?}", self.band_id, err);
+                    return None;
+                }
+            };
+            let mut iter_hunks = band.index().iter_hunks();
             if let Some(last) = &self.last_apath {
                 iter_hunks = iter_hunks.advance_to_after(last)
             }
-            self.index_hunks = Some(iter_hunks);
+            if let Some(before) = &self.skip_before {
+                iter_hunks = iter_hunks.skip_hunks_while(before.as_ref());
+            }
+            self.index_hunks = match band.delta_parent() {
+                Err(err) => {
+                    self.stats.errors += 1;
+                    log::error!("Error reading head of band {}: {:?}", self.band_id, err);
+                    return None;
+                }
+                Ok(None) => Some(Box::new(iter_hunks)),
+                Ok(Some(parent_id)) => {
+                    let parent = IterStitchedIndexHunks {
+                        last_apath: self.last_apath.clone(),
+                        skip_before: self.skip_before.clone(),
+                        ..IterStitchedIndexHunks::new(&self.archive, &parent_id)
+                    };
+                    Some(Box::new(MergeDeltaHunks::new(iter_hunks, parent)))
+                }
+            };
         }
     }
 }
@@ -111,12 +175,8 @@ mod test {
 
     fn symlink(name: &str, target: &str) -> IndexEntry {
         IndexEntry {
-            apath: name.into(),
-            kind: Kind::Symlink,
             target: Some(target.to_owned()),
-            mtime: 0,
-            mtime_nanos: 0,
-            addrs: Vec::new(),
+            ..IndexEntry::new(name.into(), Kind::Symlink)
         }
     }
 
@@ -218,4 +278,21 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn unreadable_band_ends_iteration() -> Result<()> {
+        let af = ScratchArchive::new();
+        let band = Band::create(&af)?;
+        let mut ib = band.index_builder();
+        ib.push_entry(symlink("/0", "b0"))?;
+        ib.finish()?;
+        band.close(1)?;
+        std::fs::remove_file(af.path().join("b0000").join("BANDHEAD"))?;
+
+        let archive = Archive::open_path(&af.path())?;
+        let mut hunks = archive.iter_stitched_index_hunks(&BandId::zero());
+        assert!(hunks.next().is_none());
+        assert_eq!(hunks.stats.errors, 1);
+        Ok(())
+    }
 }
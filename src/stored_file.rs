@@ -11,9 +11,34 @@
 // GNU General Public License for more details.
 
 ///! Access a file stored in the archive.
+use std::collections::VecDeque;
+use std::sync::mpsc::{sync_channel, Receiver};
+
+use lazy_static::lazy_static;
+
 use crate::stats::Sizes;
+use crate::unix_time::UnixTime;
 use crate::*;
 
+/// The number of threads reading blocks ahead for readers of stored files.
+const PREFETCH_THREADS: usize = 4;
+
+/// The number of blocks each reader has read ahead of the one it's returning.
+const PREFETCH_DEPTH: usize = 2;
+
+lazy_static! {
+    /// Threads that read blocks ahead for readers of stored files.
+    ///
+    /// This is separate from other pools, so that prefetches can't wait behind
+    /// workers that are themselves waiting for their blocks. If it can't be
+    /// started, blocks are read when they're needed.
+    static ref PREFETCH_POOL: Option<rayon::ThreadPool> = rayon::ThreadPoolBuilder::new()
+        .num_threads(PREFETCH_THREADS)
+        .thread_name(|i| format!("prefetch-{}", i))
+        .build()
+        .ok();
+}
+
 /// Returns the contents of a file stored in the archive, as an iter of byte blocks.
 ///
 /// These can be constructed through `StoredTree::open_stored_file()` or more
@@ -31,13 +56,28 @@ impl StoredFile {
         StoredFile { block_dir, addrs }
     }
 
+    /// Write the whole content of the file to `out`, one block at a time.
+    ///
+    /// Returns the number of bytes written.
+    pub fn write_to(&self, out: &mut dyn std::io::Write) -> Result<u64> {
+        let mut len = 0;
+        for i in self.block_range()? {
+            let (bytes, _sizes) = self.read_block(i)?;
+            out.write_all(&bytes)?;
+            len += bytes.len() as u64;
+        }
+        Ok(len)
+    }
+
     /// Open a cursor on this file that implements `std::io::Read`.
     pub(crate) fn into_read(self) -> ReadStoredFile {
         ReadStoredFile {
-            remaining_addrs: self.addrs.into_iter(),
+            remaining_addrs: self.addrs.clone().into_iter(),
+            addrs: self.addrs,
             buf: Vec::<u8>::new(),
             buf_cursor: 0,
             block_dir: self.block_dir,
+            prefetched: VecDeque::new(),
         }
     }
 }
@@ -54,6 +94,9 @@ impl ReadBlocks for StoredFile {
 
 /// Adapt a StoredFile to `std::io::Read`, which requires keeping a cursor position.
 pub struct ReadStoredFile {
+    /// All the block addresses for the file.
+    addrs: Vec<blockdir::Address>,
+
     /// Block addresses remaining to be read.
     remaining_addrs: std::vec::IntoIter<blockdir::Address>,
 
@@ -66,11 +109,51 @@ pub struct ReadStoredFile {
     buf_cursor: usize,
 
     block_dir: BlockDir,
+
+    /// The content of the first few addresses in `remaining_addrs`, in order,
+    /// being read on the prefetch threads while the current block is consumed.
+    prefetched: VecDeque<Receiver<Result<Vec<u8>>>>,
+}
+
+impl ReadStoredFile {
+    /// Start reading up to `PREFETCH_DEPTH` of the following blocks on the
+    /// prefetch threads.
+    fn fill_prefetch(&mut self) {
+        let pool = match &*PREFETCH_POOL {
+            Some(pool) => pool,
+            None => return,
+        };
+        let upcoming = self.remaining_addrs.as_slice();
+        while self.prefetched.len() < PREFETCH_DEPTH.min(upcoming.len()) {
+            let addr = upcoming[self.prefetched.len()].clone();
+            let block_dir = self.block_dir.clone();
+            let (sender, receiver) = sync_channel(1);
+            pool.spawn(move || {
+                // The receiver is gone if the file was rewound or dropped.
+                let _ = sender.send(block_dir.get(&addr).map(|(bytes, _sizes)| bytes));
+            });
+            self.prefetched.push_back(receiver);
+        }
+    }
+}
+
+impl ReadContent for ReadStoredFile {
+    /// Stored files never change.
+    fn mtime_and_size(&self) -> std::io::Result<Option<(UnixTime, u64)>> {
+        Ok(None)
+    }
+
+    fn rewind(&mut self) -> std::io::Result<()> {
+        self.remaining_addrs = self.addrs.clone().into_iter();
+        self.buf.clear();
+        self.buf_cursor = 0;
+        self.prefetched.clear();
+        Ok(())
+    }
 }
 
 impl std::io::Read for ReadStoredFile {
     fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
-        // TODO: Readahead n_cpus blocks into memory, using futures-cpupool or similar.
         loop {
             // If there's already buffered data, return as much of that as will fit.
             let avail = self.buf.len() - self.buf_cursor;
@@ -81,11 +164,20 @@ impl std::io::Read for ReadStoredFile {
                 self.buf_cursor += s;
                 return Ok(s);
             } else if let Some(addr) = self.remaining_addrs.next() {
-                // TODO: Handle errors nicely, but they need to convert to std::io::Error.
                 // TODO: Remember the sizes somewhere, maybe by changing this not to be
                 // std::io::Read.
-                self.buf = self.block_dir.get(&addr).unwrap().0;
+                let block_dir = &self.block_dir;
+                let read_here = || block_dir.get(&addr).map(|(bytes, _sizes)| bytes);
+                let block = match self.prefetched.pop_front() {
+                    // If the prefetch was lost, read the block here instead.
+                    Some(receiver) => receiver.recv().unwrap_or_else(|_| read_here()),
+                    None => read_here(),
+                };
+                // The error is kept inside the io::Error, so that callers can
+                // find what went wrong.
+                self.buf = block.map_err(std::io::Error::other)?;
                 self.buf_cursor = 0;
+                self.fill_prefetch();
             // TODO: Read directly into the caller's buffer, if it will fit. Requires changing
             // BlockDir::get to take a caller-provided buffer.
             } else {
@@ -95,3 +187,39 @@ impl std::io::Read for ReadStoredFile {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::blockdir::StoreFiles;
+
+    #[test]
+    fn read_blocks_ahead() {
+        let testdir = TempDir::new().unwrap();
+        let block_dir = BlockDir::create_path(testdir.path()).unwrap();
+        let mut store = StoreFiles::new(block_dir.clone());
+        let mut addrs = Vec::new();
+        for part in &["one ", "two ", "three ", "four"] {
+            let (part_addrs, _stats) = store
+                .store_file_content(&Apath::from("/part"), &mut part.as_bytes(), &[])
+                .unwrap();
+            addrs.extend(part_addrs);
+        }
+
+        let mut read = StoredFile::open(block_dir, addrs).into_read();
+        let mut first = [0u8; 4];
+        read.read_exact(&mut first).unwrap();
+        assert_eq!(&first, b"one ");
+        // The following blocks are being read while the first is consumed.
+        assert_eq!(read.prefetched.len(), PREFETCH_DEPTH);
+
+        let mut rest = String::new();
+        read.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "two three four");
+        assert!(read.prefetched.is_empty());
+    }
+}
@@ -19,8 +19,10 @@
 //! multiple index files, bands, and blocks.
 
 use std::collections::HashMap;
+use std::io::{self, Read};
+use std::ops::Range;
 
-use crate::blockdir::BlockDir;
+use crate::blockdir::{hash_bytes, hole_ranges, BlockDir, HashingReader};
 use crate::kind::Kind;
 use crate::stored_file::{ReadStoredFile, StoredFile};
 use crate::*;
@@ -57,7 +59,7 @@ impl StoredTree {
 
     pub fn validate(
         &self,
-        block_lengths: &HashMap<BlockHash, usize>,
+        block_lengths: &HashMap<BlockHash, Option<usize>>,
         stats: &mut ValidateStats,
     ) -> Result<()> {
         let band_id = self.band().id();
@@ -66,27 +68,91 @@ impl StoredTree {
             .filter(|entry| entry.kind() == Kind::File)
         {
             for addr in entry.addrs {
-                if let Some(block_len) = block_lengths.get(&addr.hash) {
+                match block_lengths.get(&addr.hash) {
                     // Present, but the address is out of range.
-                    if (addr.start + addr.len) > (*block_len as u64) {
-                        ui::problem(&format!(
+                    Some(Some(block_len)) if (addr.start + addr.len) > (*block_len as u64) => {
+                        log::error!(
                             "Address {:?} in {:?} in {:?} extends beyond compressed data length {}",
-                            addr, &entry.apath, band_id, block_len
-                        ));
+                            addr,
+                            &entry.apath,
+                            band_id,
+                            block_len
+                        );
+                        stats.block_missing_count += 1;
+                    }
+                    // Present, and either in range or the block wasn't read.
+                    Some(_) => (),
+                    None => {
+                        log::error!(
+                            "Address {:?} in {:?} in {:?} points to missing block",
+                            &entry.apath,
+                            band_id,
+                            addr
+                        );
                         stats.block_missing_count += 1;
                     }
-                } else {
-                    ui::problem(&format!(
-                        "Address {:?} in {:?} in {:?} points to missing block",
-                        &entry.apath, band_id, addr
-                    ));
-                    stats.block_missing_count += 1;
                 }
             }
         }
         Ok(())
     }
 
+    /// Iterate entries, skipping all those at the start of the tree for which
+    /// `before` is true.
+    ///
+    /// Index hunks before the first wanted entry are skipped without reading them all.
+    fn iter_entries_from<F>(&self, before: F) -> impl Iterator<Item = IndexEntry>
+    where
+        F: Fn(&Apath) -> bool + Clone + 'static,
+    {
+        let skip_entries = before.clone();
+        self.archive
+            .iter_stitched_index_hunks(self.band.id())
+            .skip_hunks_while(before)
+            .flatten()
+            .skip_while(move |entry| skip_entries(&entry.apath))
+    }
+
+    /// Return the entry for `apath`, if it's present in this tree.
+    ///
+    /// This seeks in the index rather than reading all of it, and doesn't
+    /// apply excludes.
+    pub fn get_entry(&self, apath: &Apath) -> Result<Option<IndexEntry>> {
+        let seek_to = apath.clone();
+        Ok(self
+            .iter_entries_from(move |a| *a < seek_to)
+            .next()
+            .filter(|entry| entry.apath == *apath))
+    }
+
+    /// Iterate the entries directly within directory `dir`, not including the
+    /// contents of its subdirectories.
+    ///
+    /// This seeks in the index rather than reading all of it, and doesn't
+    /// apply excludes.
+    pub(crate) fn iter_children(&self, dir: &Apath) -> impl Iterator<Item = IndexEntry> {
+        let (seek_to, parent) = (dir.clone(), dir.clone());
+        // A directory's direct children are contiguous, and come before the
+        // contents of its subdirectories.
+        self.iter_entries_from(move |apath| apath.is_before_contents_of(&seek_to))
+            .take_while(move |entry| entry.apath.parent().as_ref() == Some(&parent))
+    }
+
+    /// Open the file stored at `apath`, to read its content.
+    pub fn open_file(&self, apath: &Apath) -> Result<StoredFile> {
+        match self.get_entry(apath)? {
+            None => Err(Error::NotFoundInBand {
+                apath: apath.clone(),
+                band_id: self.band.id().clone(),
+            }),
+            Some(entry) if entry.kind != Kind::File => Err(Error::NotAFile {
+                apath: apath.clone(),
+                kind: entry.kind,
+            }),
+            Some(entry) => self.open_stored_file(&entry),
+        }
+    }
+
     /// Open a file stored within this tree.
     fn open_stored_file(&self, entry: &IndexEntry) -> Result<StoredFile> {
         Ok(StoredFile::open(
@@ -115,19 +181,89 @@ impl ReadTree for StoredTree {
         &self,
         subtree: &Apath,
     ) -> Result<Box<dyn Iterator<Item = IndexEntry>>> {
-        // TODO: Advance in the index to the requested directory, and stop immediately when it's
-        // done.
-        let subtree = subtree.to_owned();
+        if *subtree == "/" {
+            return self.iter_entries();
+        }
+        // The subtree's top directory is ordered among its siblings, and its
+        // contents are contiguous somewhere later in the index: seek to each.
+        let top_entry = {
+            let (subtree, seek_to) = (subtree.clone(), subtree.clone());
+            self.iter_entries_from(move |apath| *apath < seek_to)
+                .take(1)
+                .filter(move |entry| entry.apath == subtree)
+        };
+        let contents = {
+            let (subtree, seek_to) = (subtree.clone(), subtree.clone());
+            self.iter_entries_from(move |apath| apath.is_before_contents_of(&seek_to))
+                .take_while(move |entry| subtree.is_prefix_of(&entry.apath))
+        };
+        let excludes = self.excludes.clone();
         Ok(Box::new(
-            self.iter_entries()?
-                .filter(move |entry| subtree.is_prefix_of(entry.apath())),
+            top_entry
+                .chain(contents)
+                .filter(move |entry| !excludes.is_match(&entry.apath)),
         ))
     }
 
+    /// Find each parent directory by seeking in the index.
+    fn parent_entries(&self, apath: &Apath) -> Result<Vec<IndexEntry>> {
+        let mut parents = Vec::new();
+        let mut parent = apath.parent();
+        while let Some(dir) = parent {
+            if let Some(entry) = self.get_entry(&dir)? {
+                if !self.excludes.is_match(&entry.apath) {
+                    parents.push(entry);
+                }
+            }
+            parent = dir.parent();
+        }
+        parents.reverse();
+        Ok(parents)
+    }
+
     fn file_contents(&self, entry: &Self::Entry) -> Result<Self::R> {
         Ok(self.open_stored_file(entry)?.into_read())
     }
 
+    /// Return the ranges that backup recorded as holes, by addresses of the
+    /// block of zeros.
+    fn file_holes(&self, entry: &IndexEntry) -> Result<Vec<Range<u64>>> {
+        Ok(hole_ranges(&entry.addrs))
+    }
+
+    /// Compare `content` to the stored file.
+    ///
+    /// If the index holds a hash of the whole file, that's compared to the hash
+    /// of `content`, without reading any blocks.
+    ///
+    /// Otherwise, each part of the file is first hashed, which is enough when it filled a
+    /// whole block. A block can also hold more than this part of this file, so
+    /// if the hash doesn't match, the stored block is read and compared.
+    fn content_matches(&self, entry: &IndexEntry, content: &mut dyn Read) -> Result<bool> {
+        if let Some(expected) = &entry.content_hash {
+            let mut hashing = HashingReader::new(content);
+            io::copy(&mut hashing, &mut io::sink())?;
+            return Ok(hashing.finish() == *expected);
+        }
+        let mut buf = Vec::new();
+        for addr in &entry.addrs {
+            buf.resize(addr.len as usize, 0);
+            match content.read_exact(&mut buf) {
+                Ok(()) => (),
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+                Err(err) => return Err(err.into()),
+            }
+            if addr.start == 0 && hash_bytes(&buf)? == addr.hash {
+                continue;
+            }
+            if self.block_dir.get(addr)?.0 != buf {
+                return Ok(false);
+            }
+        }
+        // The content must not be any longer than the stored file.
+        Ok(content.read(&mut [0u8])? == 0)
+    }
+
     fn estimate_count(&self) -> Result<u64> {
         self.band.index().estimate_entry_count()
     }
@@ -176,6 +312,57 @@ mod test {
         }
     }
 
+    #[test]
+    fn open_file() {
+        let af = ScratchArchive::new();
+        af.store_two_versions();
+        let st = af.open_stored_tree(BandSelectionPolicy::Latest).unwrap();
+
+        let mut content = Vec::new();
+        let len = st
+            .open_file(&"/subdir/subfile".into())
+            .unwrap()
+            .write_to(&mut content)
+            .unwrap();
+        assert_eq!(content, b"contents");
+        assert_eq!(len, content.len() as u64);
+
+        match st.open_file(&"/subdir".into()) {
+            Err(Error::NotAFile { kind, .. }) => assert_eq!(kind, Kind::Dir),
+            Err(other) => panic!("unexpected error {:?}", other),
+            Ok(_) => panic!("unexpected success"),
+        }
+        match st.open_file(&"/nonexistent".into()) {
+            Err(Error::NotFoundInBand { apath, .. }) => assert_eq!(apath, "/nonexistent"),
+            Err(other) => panic!("unexpected error {:?}", other),
+            Ok(_) => panic!("unexpected success"),
+        }
+    }
+
+    #[test]
+    fn content_matches() {
+        let af = ScratchArchive::new();
+        af.store_two_versions();
+        let st = af.open_stored_tree(BandSelectionPolicy::Latest).unwrap();
+        let entry = st.get_entry(&"/subdir/subfile".into()).unwrap().unwrap();
+
+        let matches = |content: &[u8]| st.content_matches(&entry, &mut &content[..]).unwrap();
+        assert!(matches(b"contents"));
+        assert!(!matches(b"Contents"));
+        assert!(!matches(b"content"));
+        assert!(!matches(b"contents and more"));
+
+        // Entries from before content hashes were stored are compared block by block.
+        let entry = IndexEntry {
+            content_hash: None,
+            ..entry
+        };
+        let matches = |content: &[u8]| st.content_matches(&entry, &mut &content[..]).unwrap();
+        assert!(matches(b"contents"));
+        assert!(!matches(b"Contents"));
+        assert!(!matches(b"contents and more"));
+    }
+
     #[test]
     fn iter_subtree_entries() {
         let archive = Archive::open_path(Path::new("testdata/archive/v0.6.3/minimal-1/")).unwrap();
@@ -191,4 +378,25 @@ mod test {
 
         assert_eq!(names.as_slice(), ["/subdir", "/subdir/subfile"]);
     }
+
+    #[test]
+    fn iter_children() {
+        let af = ScratchArchive::new();
+        af.store_two_versions();
+        let st = af.open_stored_tree(BandSelectionPolicy::Latest).unwrap();
+        let children = |dir: &str| -> Vec<String> {
+            st.iter_children(&dir.into())
+                .map(|entry| entry.apath.into())
+                .collect()
+        };
+
+        let expected = if SYMLINKS_SUPPORTED {
+            vec!["/hello", "/hello2", "/link", "/subdir"]
+        } else {
+            vec!["/hello", "/hello2", "/subdir"]
+        };
+        assert_eq!(children("/"), expected);
+        assert_eq!(children("/subdir"), ["/subdir/subfile"]);
+        assert!(children("/hello").is_empty());
+    }
 }
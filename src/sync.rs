@@ -0,0 +1,219 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Copy an archive to another location, such as an offsite copy.
+//!
+//! Only blocks and complete bands missing from the destination are copied,
+//! so syncing again is incremental. Blocks are copied before bands, and each
+//! band's tail is copied last, so an interrupted sync leaves the destination
+//! valid, and the next sync finishes the job.
+
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use rayon::prelude::*;
+
+use crate::stats::SyncStats;
+use crate::transport::{ListDirNames, Transport};
+use crate::*;
+
+/// Options for [sync_archive].
+#[derive(Debug, Default, Clone)]
+pub struct SyncOptions {
+    /// Count what would be copied, but don't change the destination.
+    pub dry_run: bool,
+    /// Receives progress of the operation, if set.
+    pub progress: Option<Arc<dyn ProgressSink>>,
+}
+
+/// Copy blocks and complete bands from `source` that are missing in the
+/// archive at `dest`.
+///
+/// If there's no archive at `dest` one is created, except in a dry run.
+/// Format features used by the source are enabled in the destination.
+/// Every block's content is checked against its hash before it's written.
+/// Incomplete bands in the source are not copied.
+///
+/// Returns `Error::GarbageCollectionLockHeld` if the destination is being
+/// garbage collected, and `Error::SyncBandMismatch` if the destination has a band with the
+/// same id as the source but different content, which means it's a copy of
+/// a different archive. An incomplete band in the destination is replaced
+/// only if it was partly copied from the source by an earlier sync, and
+/// otherwise `Error::SyncWouldReplaceBand` is returned.
+pub fn sync_archive(
+    source: &Archive,
+    dest: Box<dyn Transport>,
+    options: &SyncOptions,
+) -> Result<SyncStats> {
+    let dest = if dest.exists(archive::HEADER_FILENAME)? {
+        Some(Archive::open(dest)?)
+    } else if options.dry_run {
+        None
+    } else {
+        Some(Archive::create(dest)?)
+    };
+    let writable_dest = if options.dry_run { None } else { dest.as_ref() };
+    let _lock = writable_dest
+        .map(|dest| WriteLock::acquire(dest, "sync"))
+        .transpose()?;
+    if let Some(dest) = writable_dest {
+        // Garbage collection in the destination might delete blocks as
+        // they're copied.
+        if gc_lock::GarbageCollectionLock::is_locked(dest)? {
+            return Err(Error::GarbageCollectionLockHeld);
+        }
+        dest.enable_features(source.features())?;
+    }
+    let mut stats = SyncStats::default();
+
+    let mut bands_to_copy = Vec::new();
+    for band_id in source.list_band_ids()? {
+        if !Band::open(source, &band_id)?.is_closed()? {
+            stats.bands_incomplete += 1;
+            continue;
+        }
+        match &dest {
+            Some(dest) if dest.band_is_closed(&band_id)? => {
+                check_band_matches(source, dest, &band_id)?;
+                stats.bands_present += 1;
+            }
+            _ => bands_to_copy.push(band_id),
+        }
+    }
+
+    let dest_blocks: HashSet<BlockHash> = match &dest {
+        Some(dest) => dest.block_dir().block_names()?.collect(),
+        None => HashSet::new(),
+    };
+    let (present, missing): (Vec<BlockHash>, Vec<BlockHash>) = source
+        .block_dir()
+        .block_names()?
+        .partition(|hash| dest_blocks.contains(hash));
+    stats.blocks_present = present.len();
+    stats.blocks_copied = missing.len();
+    if let Some(dest) = writable_dest {
+        let mut progress_bar = ProgressBar::new(options.progress.as_ref());
+        progress_bar.set_phase("Copy blocks".to_owned());
+        progress_bar.set_total_work(missing.len());
+        let progress_bar_mutex = Mutex::new(progress_bar);
+        let sizes = missing
+            .par_iter()
+            .map(|hash| {
+                let r = source.block_dir().copy_block_to(hash, dest.block_dir());
+                progress_bar_mutex.lock().unwrap().increment_work_done(1);
+                r
+            })
+            .collect::<Result<Vec<u64>>>()?;
+        stats.block_bytes_copied = sizes.iter().sum();
+        for band_id in &bands_to_copy {
+            copy_band(source, dest, band_id)?;
+        }
+    } else {
+        stats.block_bytes_copied = missing
+            .iter()
+            .map(|hash| source.block_dir().compressed_size(hash))
+            .sum::<Result<u64>>()?;
+    }
+    stats.bands_copied = bands_to_copy.len();
+    Ok(stats)
+}
+
+/// Check that a band present in both archives has the same head, and so is
+/// the same band.
+fn check_band_matches(source: &Archive, dest: &Archive, band_id: &BandId) -> Result<()> {
+    if heads_match(source, dest, band_id)? {
+        Ok(())
+    } else {
+        Err(Error::SyncBandMismatch {
+            band_id: band_id.clone(),
+        })
+    }
+}
+
+fn heads_match(source: &Archive, dest: &Archive, band_id: &BandId) -> Result<bool> {
+    let head_path = format!("{}/{}", band_id, BAND_HEAD_FILENAME);
+    let mut source_head = Vec::new();
+    source.transport().read_file(&head_path, &mut source_head)?;
+    let mut dest_head = Vec::new();
+    dest.transport().read_file(&head_path, &mut dest_head)?;
+    Ok(source_head == dest_head)
+}
+
+/// True if an incomplete band in the destination was left behind by an
+/// interrupted sync from `source`: either nothing was copied into it yet, or
+/// it has the source band's head, which is copied first.
+fn was_partly_synced(source: &Archive, dest: &Archive, band_id: &BandId) -> Result<bool> {
+    let ListDirNames { files, dirs } = dest.transport().list_dir_names(&band_id.to_string())?;
+    if files.iter().any(|name| name == BAND_HEAD_FILENAME) {
+        heads_match(source, dest, band_id)
+    } else {
+        Ok(files.is_empty() && dirs.is_empty())
+    }
+}
+
+fn copy_band(source: &Archive, dest: &Archive, band_id: &BandId) -> Result<()> {
+    let band_dir = band_id.to_string();
+    let dest_transport = dest.transport();
+    if dest_transport.exists(&band_dir)? {
+        // Maybe left behind by an interrupted sync, but maybe a backup
+        // written directly to the destination, which mustn't be lost.
+        if !was_partly_synced(source, dest, band_id)? {
+            return Err(Error::SyncWouldReplaceBand {
+                band_id: band_id.clone(),
+            });
+        }
+        dest_transport.remove_dir_all(&band_dir)?;
+    }
+    dest_transport.create_dir(&band_dir)?;
+    // The head is copied first so that an interrupted copy can be recognized.
+    copy_file(
+        source.transport(),
+        dest_transport,
+        &format!("{}/{}", band_dir, BAND_HEAD_FILENAME),
+    )?;
+    copy_dir_contents(source.transport(), dest_transport, &band_dir)?;
+    // The band is only complete once the tail is present.
+    copy_file(
+        source.transport(),
+        dest_transport,
+        &format!("{}/{}", band_dir, BAND_TAIL_FILENAME),
+    )
+}
+
+/// Recursively copy all the files in a directory, except for band heads and
+/// tails, and temporary files.
+fn copy_dir_contents(source: &dyn Transport, dest: &dyn Transport, relpath: &str) -> Result<()> {
+    let ListDirNames { files, dirs } = source.list_dir_names(relpath)?;
+    for name in files {
+        if name != BAND_HEAD_FILENAME && name != BAND_TAIL_FILENAME && !name.starts_with(TMP_PREFIX)
+        {
+            copy_file(source, dest, &format!("{}/{}", relpath, name))?;
+        }
+    }
+    for name in dirs {
+        let subdir = format!("{}/{}", relpath, name);
+        dest.create_dir(&subdir)?;
+        copy_dir_contents(source, dest, &subdir)?;
+    }
+    Ok(())
+}
+
+fn copy_file(source: &dyn Transport, dest: &dyn Transport, relpath: &str) -> Result<()> {
+    let mut buf = Vec::new();
+    source.read_file(relpath, &mut buf)?;
+    dest.write_file(relpath, &buf)
+        .map_err(|source| Error::WriteMetadata {
+            path: relpath.to_owned(),
+            source,
+        })
+}
@@ -0,0 +1,633 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Convert trees to and from tar streams, so that backups can be used by
+//! people or systems that don't run Conserve, and existing tarballs can be
+//! brought into an archive.
+//!
+//! Entries are named relative to the top of the tree, so `/etc/hosts` is
+//! stored as `etc/hosts`, and the root directory itself isn't stored.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+use globset::GlobSet;
+use tar::{EntryType, Header};
+
+use crate::compress::zstandard;
+use crate::stats::ExportTarStats;
+use crate::unix_time::UnixTime;
+use crate::*;
+
+/// Compression applied to a whole tar stream.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum TarCompression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl FromStr for TarCompression {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(TarCompression::None),
+            "gzip" => Ok(TarCompression::Gzip),
+            "zstd" => Ok(TarCompression::Zstd),
+            _ => Err(format!(
+                "Unknown tar compression {:?}: use \"none\", \"gzip\", or \"zstd\"",
+                s
+            )),
+        }
+    }
+}
+
+/// Options for [export_tar].
+#[derive(Debug, Default, Clone)]
+pub struct ExportTarOptions {
+    pub compression: TarCompression,
+}
+
+/// Write every entry of `tree`, in apath order, as a tar stream to `out`.
+///
+/// Files, directories, symlinks, hard links, fifos, and device nodes are
+/// written with their permissions, ownership, and mtime. Sockets, and links
+/// whose target is too long for the tar header, can't be represented, and are
+/// reported, skipped, and counted.
+pub fn export_tar<T: ReadTree, W: Write>(
+    tree: &T,
+    out: W,
+    options: &ExportTarOptions,
+) -> Result<ExportTarStats> {
+    match options.compression {
+        TarCompression::None => write_tar(tree, out),
+        TarCompression::Gzip => {
+            let mut encoder = flate2::write::GzEncoder::new(out, flate2::Compression::default());
+            let stats = write_tar(tree, &mut encoder)?;
+            encoder.finish()?.flush()?;
+            Ok(stats)
+        }
+        TarCompression::Zstd => {
+            let mut encoder = zstd::stream::write::Encoder::new(out, zstandard::DEFAULT_LEVEL)?;
+            let stats = write_tar(tree, &mut encoder)?;
+            encoder.finish()?.flush()?;
+            Ok(stats)
+        }
+    }
+}
+
+fn write_tar<T: ReadTree, W: Write>(tree: &T, out: W) -> Result<ExportTarStats> {
+    let mut stats = ExportTarStats::default();
+    let mut builder = tar::Builder::new(out);
+    // Files written so far, which can be the targets of hard links.
+    let mut written_files: BTreeSet<Apath> = BTreeSet::new();
+    for entry in tree.iter_entries()? {
+        let apath = entry.apath();
+        if *apath == "/" {
+            continue;
+        }
+        let name = &apath[1..];
+        let mut header = Header::new_gnu();
+        header.set_mtime(entry.mtime().secs.max(0) as u64);
+        let owner = entry.owner();
+        if let Some(uid) = owner.uid {
+            header.set_uid(uid.into());
+        }
+        if let Some(gid) = owner.gid {
+            header.set_gid(gid.into());
+        }
+        // Names too long for the header are left out: the ids are still there.
+        if let Some(user) = &owner.user {
+            let _ = header.set_username(user);
+        }
+        if let Some(group) = &owner.group {
+            let _ = header.set_groupname(group);
+        }
+        let default_mode = if entry.kind() == Kind::Dir {
+            0o755
+        } else {
+            0o644
+        };
+        header.set_mode(entry.unix_mode().unwrap_or(default_mode));
+        match entry.kind() {
+            Kind::Dir => {
+                header.set_entry_type(EntryType::Directory);
+                header.set_size(0);
+                builder.append_data(&mut header, name, io::empty())?;
+                stats.directories += 1;
+            }
+            Kind::File => {
+                if let Some(group) = entry.hardlink_group().filter(|g| *g != apath) {
+                    if written_files.contains(group) {
+                        let mut link_header = header.clone();
+                        link_header.set_entry_type(EntryType::Link);
+                        link_header.set_size(0);
+                        if link_header.set_link_name(&group[1..]).is_ok() {
+                            builder.append_data(&mut link_header, name, io::empty())?;
+                            stats.hardlinks += 1;
+                            continue;
+                        }
+                        // Write the content again, as a separate file.
+                        log::info!(
+                            "Hard link target of {} is too long for tar; writing a copy",
+                            apath
+                        );
+                    }
+                }
+                let size = entry.size().unwrap_or_default();
+                header.set_entry_type(EntryType::Regular);
+                header.set_size(size);
+                let content = tree.file_contents(&entry)?;
+                builder.append_data(&mut header, name, content.take(size))?;
+                written_files.insert(apath.clone());
+                stats.files += 1;
+                stats.file_bytes += size;
+            }
+            Kind::Symlink => {
+                let target = entry.symlink_target().as_ref().expect("symlink has target");
+                header.set_entry_type(EntryType::Symlink);
+                header.set_size(0);
+                if header.set_link_name(target).is_err() {
+                    log::warn!("Symlink target of {} is too long for tar", apath);
+                    stats.skipped += 1;
+                    continue;
+                }
+                builder.append_data(&mut header, name, io::empty())?;
+                stats.symlinks += 1;
+            }
+            Kind::Fifo | Kind::CharDevice | Kind::BlockDevice => {
+                header.set_entry_type(match entry.kind() {
+                    Kind::Fifo => EntryType::Fifo,
+                    Kind::CharDevice => EntryType::Char,
+                    _ => EntryType::Block,
+                });
+                header.set_size(0);
+                if let Some(rdev) = entry.rdev() {
+                    let (major, minor) = split_device_number(rdev);
+                    header.set_device_major(major)?;
+                    header.set_device_minor(minor)?;
+                }
+                builder.append_data(&mut header, name, io::empty())?;
+                stats.special_files += 1;
+            }
+            Kind::Socket | Kind::Unknown => stats.skipped += 1,
+        }
+    }
+    builder.into_inner()?.flush()?;
+    Ok(stats)
+}
+
+/// A tar file read as a tree, in apath order, so that it can be backed up.
+///
+/// All the entries are read into memory when it's opened, and then file
+/// content is read from the tar file as it's needed. Compressed tar files are
+/// first decompressed to a temporary file.
+pub struct TarTree {
+    /// The uncompressed tar file.
+    path: PathBuf,
+    /// If the tar file was compressed, the temporary uncompressed copy,
+    /// which is deleted when this is dropped.
+    _temp: Option<tempfile::NamedTempFile>,
+    /// All the entries, in apath order.
+    entries: Vec<TarEntry>,
+}
+
+/// An entry read from a tar file.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct TarEntry {
+    apath: Apath,
+    kind: Kind,
+    mtime: UnixTime,
+    size: Option<u64>,
+    symlink_target: Option<String>,
+    unix_mode: Option<u32>,
+    owner: Owner,
+    hardlink_group: Option<Apath>,
+    rdev: Option<u64>,
+    /// Position of the file's content in the uncompressed tar file.
+    data_start: u64,
+}
+
+impl TarTree {
+    /// Read the entries from an uncompressed, gzip, or Zstandard tar file.
+    pub fn open(path: &Path) -> Result<TarTree> {
+        let read_err = |source| Error::ReadTar {
+            path: path.to_owned(),
+            source,
+        };
+        let mut file = fs::File::open(path).map_err(read_err)?;
+        let mut magic = [0u8; 4];
+        let magic_len = file.read(&mut magic).map_err(read_err)?;
+        file.seek(SeekFrom::Start(0)).map_err(read_err)?;
+        let decoder: Option<Box<dyn Read>> = if magic[..magic_len].starts_with(&[0x1f, 0x8b]) {
+            Some(Box::new(flate2::read::GzDecoder::new(file)))
+        } else if zstandard::is_zstd(&magic[..magic_len]) {
+            Some(Box::new(
+                zstd::stream::read::Decoder::new(file).map_err(read_err)?,
+            ))
+        } else {
+            None
+        };
+        let (path, temp) = if let Some(mut decoder) = decoder {
+            let mut temp = tempfile::NamedTempFile::new().map_err(read_err)?;
+            io::copy(&mut decoder, &mut temp).map_err(read_err)?;
+            (temp.path().to_owned(), Some(temp))
+        } else {
+            (path.to_owned(), None)
+        };
+        let entries = read_tar_entries(&path).map_err(read_err)?;
+        Ok(TarTree {
+            path,
+            _temp: temp,
+            entries,
+        })
+    }
+
+    /// Return a new tree that skips entries matching `excludes`, and the
+    /// contents of excluded directories.
+    pub fn with_excludes(self, excludes: GlobSet) -> TarTree {
+        let entries = self
+            .entries
+            .into_iter()
+            .filter(|entry| {
+                let mut apath = Some(entry.apath.clone());
+                while let Some(a) = apath {
+                    if excludes.is_match(&*a) {
+                        return false;
+                    }
+                    apath = a.parent();
+                }
+                true
+            })
+            .collect();
+        TarTree { entries, ..self }
+    }
+}
+
+/// Read all entries from an uncompressed tar file, and return them in apath
+/// order, with containing directories added if they're missing.
+fn read_tar_entries(path: &Path) -> io::Result<Vec<TarEntry>> {
+    let mut by_apath = BTreeMap::<Apath, TarEntry>::new();
+    let mut archive = tar::Archive::new(fs::File::open(path)?);
+    for tar_entry in archive.entries()? {
+        let tar_entry = tar_entry?;
+        let header = tar_entry.header();
+        let name = tar_entry.path()?;
+        let apath = match apath_from_tar_name(&name) {
+            Some(apath) => apath,
+            None => {
+                log::warn!("Skipping tar entry with invalid name {:?}", name);
+                continue;
+            }
+        };
+        let mut entry = TarEntry {
+            apath: apath.clone(),
+            kind: Kind::File,
+            mtime: UnixTime {
+                secs: header.mtime()? as i64,
+                nanosecs: 0,
+            },
+            size: None,
+            symlink_target: None,
+            unix_mode: header.mode().ok().map(|m| m & 0o7777),
+            owner: Owner {
+                uid: header.uid().ok().map(|u| u as u32),
+                gid: header.gid().ok().map(|g| g as u32),
+                user: header.username().ok().flatten().map(str::to_owned),
+                group: header.groupname().ok().flatten().map(str::to_owned),
+            },
+            hardlink_group: None,
+            rdev: None,
+            data_start: tar_entry.raw_file_position(),
+        };
+        match header.entry_type() {
+            EntryType::Regular | EntryType::Continuous => {
+                entry.size = Some(header.size()?);
+            }
+            EntryType::Directory => entry.kind = Kind::Dir,
+            EntryType::Symlink => {
+                entry.kind = Kind::Symlink;
+                entry.symlink_target = tar_entry
+                    .link_name()?
+                    .map(|t| t.to_string_lossy().into_owned());
+            }
+            EntryType::Link => {
+                let target = tar_entry
+                    .link_name()?
+                    .as_deref()
+                    .and_then(apath_from_tar_name)
+                    .and_then(|target| by_apath.get(&target))
+                    .cloned();
+                match target {
+                    Some(target) if target.kind == Kind::File => {
+                        let group = target
+                            .hardlink_group
+                            .clone()
+                            .unwrap_or_else(|| target.apath.clone());
+                        entry.size = target.size;
+                        entry.data_start = target.data_start;
+                        entry.hardlink_group = Some(group.clone());
+                        by_apath.get_mut(&group).unwrap().hardlink_group = Some(group.clone());
+                    }
+                    _ => {
+                        log::warn!("Skipping hard link {:?} to a missing file", name);
+                        continue;
+                    }
+                }
+            }
+            EntryType::Fifo => entry.kind = Kind::Fifo,
+            EntryType::Char | EntryType::Block => {
+                entry.kind = if header.entry_type() == EntryType::Char {
+                    Kind::CharDevice
+                } else {
+                    Kind::BlockDevice
+                };
+                if let (Some(major), Some(minor)) = (header.device_major()?, header.device_minor()?)
+                {
+                    entry.rdev = Some(make_device_number(major, minor));
+                }
+            }
+            // Extension headers are handled by the tar crate; anything else
+            // can't be stored.
+            other => {
+                log::warn!("Skipping tar entry {:?} of type {:?}", name, other);
+                continue;
+            }
+        }
+        by_apath.insert(apath, entry);
+    }
+    fix_hardlink_groups(&mut by_apath);
+    add_missing_dirs(&mut by_apath, fs::metadata(path)?.modified()?.into());
+    Ok(by_apath.into_values().collect())
+}
+
+/// Name each hard link group by its first member in apath order, as the
+/// backup writer expects, rather than by the first in the tar file.
+fn fix_hardlink_groups(by_apath: &mut BTreeMap<Apath, TarEntry>) {
+    let mut first_in_group = BTreeMap::<Apath, Apath>::new();
+    for entry in by_apath.values() {
+        if let Some(group) = &entry.hardlink_group {
+            first_in_group
+                .entry(group.clone())
+                .or_insert_with(|| entry.apath.clone());
+        }
+    }
+    for entry in by_apath.values_mut() {
+        if let Some(group) = &entry.hardlink_group {
+            entry.hardlink_group = Some(first_in_group[group].clone());
+        }
+    }
+}
+
+/// Add entries for the root and any directories that contain entries but
+/// aren't themselves in the tar file.
+fn add_missing_dirs(by_apath: &mut BTreeMap<Apath, TarEntry>, mtime: UnixTime) {
+    let mut missing = BTreeSet::<Apath>::new();
+    missing.insert(Apath::from("/"));
+    for apath in by_apath.keys() {
+        let mut parent = apath.parent();
+        while let Some(p) = parent {
+            if !by_apath.contains_key(&p) {
+                missing.insert(p.clone());
+            }
+            parent = p.parent();
+        }
+    }
+    for apath in missing {
+        if by_apath.contains_key(&apath) {
+            continue;
+        }
+        by_apath.insert(
+            apath.clone(),
+            TarEntry {
+                apath,
+                kind: Kind::Dir,
+                mtime,
+                size: None,
+                symlink_target: None,
+                unix_mode: Some(0o755),
+                owner: Owner::default(),
+                hardlink_group: None,
+                rdev: None,
+                data_start: 0,
+            },
+        );
+    }
+}
+
+/// Convert a name from a tar file to an apath, or None if it's not valid,
+/// for example because it contains `..`.
+fn apath_from_tar_name(name: &Path) -> Option<Apath> {
+    let mut apath = String::new();
+    for component in name.components() {
+        match component {
+            Component::Normal(part) => {
+                apath.push('/');
+                apath.push_str(part.to_str()?);
+            }
+            Component::CurDir | Component::RootDir => (),
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+    if apath.is_empty() {
+        apath.push('/');
+    }
+    Some(Apath::from(apath.as_str()))
+}
+
+impl ReadTree for TarTree {
+    type Entry = TarEntry;
+    type R = TarFileContent;
+
+    fn iter_entries(&self) -> Result<Box<dyn Iterator<Item = TarEntry>>> {
+        Ok(Box::new(self.entries.clone().into_iter()))
+    }
+
+    fn iter_subtree_entries(&self, subtree: &Apath) -> Result<Box<dyn Iterator<Item = TarEntry>>> {
+        let subtree = subtree.to_owned();
+        Ok(Box::new(
+            self.iter_entries()?
+                .filter(move |entry| subtree.is_prefix_of(entry.apath())),
+        ))
+    }
+
+    fn file_contents(&self, entry: &TarEntry) -> Result<TarFileContent> {
+        let read_err = |source| Error::ReadTar {
+            path: self.path.clone(),
+            source,
+        };
+        let mut file = fs::File::open(&self.path).map_err(read_err)?;
+        file.seek(SeekFrom::Start(entry.data_start))
+            .map_err(read_err)?;
+        let len = entry.size.unwrap_or_default();
+        Ok(TarFileContent {
+            content: file.take(len),
+            range: entry.data_start..(entry.data_start + len),
+        })
+    }
+
+    fn estimate_count(&self) -> Result<u64> {
+        Ok(self.entries.len() as u64)
+    }
+}
+
+/// The content of one file in a tar file.
+pub struct TarFileContent {
+    content: io::Take<fs::File>,
+    /// Position of the content in the tar file.
+    range: Range<u64>,
+}
+
+impl Read for TarFileContent {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.content.read(buf)
+    }
+}
+
+impl ReadContent for TarFileContent {
+    fn mtime_and_size(&self) -> io::Result<Option<(UnixTime, u64)>> {
+        Ok(None)
+    }
+
+    fn rewind(&mut self) -> io::Result<()> {
+        self.content
+            .get_mut()
+            .seek(SeekFrom::Start(self.range.start))?;
+        self.content.set_limit(self.range.end - self.range.start);
+        Ok(())
+    }
+}
+
+impl Entry for TarEntry {
+    fn apath(&self) -> &Apath {
+        &self.apath
+    }
+
+    fn kind(&self) -> Kind {
+        self.kind
+    }
+
+    fn mtime(&self) -> UnixTime {
+        self.mtime
+    }
+
+    fn size(&self) -> Option<u64> {
+        self.size
+    }
+
+    fn symlink_target(&self) -> &Option<String> {
+        &self.symlink_target
+    }
+
+    fn unix_mode(&self) -> Option<u32> {
+        self.unix_mode
+    }
+
+    fn owner(&self) -> Owner {
+        self.owner.clone()
+    }
+
+    fn xattrs(&self) -> &[Xattr] {
+        &[]
+    }
+
+    fn hardlink_group(&self) -> Option<&Apath> {
+        self.hardlink_group.as_ref()
+    }
+
+    fn rdev(&self) -> Option<u64> {
+        self.rdev
+    }
+}
+
+/// Split a device number, as stored in the index, into major and minor numbers.
+#[cfg(target_os = "linux")]
+fn split_device_number(rdev: u64) -> (u32, u32) {
+    // The glibc encoding, as in `gnu_dev_major` and `gnu_dev_minor`.
+    let major = ((rdev >> 8) & 0xfff) | ((rdev >> 32) & !0xfff);
+    let minor = (rdev & 0xff) | ((rdev >> 12) & !0xff);
+    (major as u32, minor as u32)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn split_device_number(rdev: u64) -> (u32, u32) {
+    // The BSD and macOS encoding.
+    (((rdev >> 24) & 0xff) as u32, (rdev & 0xff_ffff) as u32)
+}
+
+/// Combine major and minor numbers into a device number, the inverse of
+/// `split_device_number`.
+#[cfg(target_os = "linux")]
+fn make_device_number(major: u32, minor: u32) -> u64 {
+    let (major, minor) = (u64::from(major), u64::from(minor));
+    ((major & 0xffff_f000) << 32)
+        | ((major & 0xfff) << 8)
+        | ((minor & 0xffff_ff00) << 12)
+        | (minor & 0xff)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn make_device_number(major: u32, minor: u32) -> u64 {
+    (u64::from(major & 0xff) << 24) | u64::from(minor & 0xff_ffff)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_compression() {
+        assert_eq!("gzip".parse(), Ok(TarCompression::Gzip));
+        assert_eq!("zstd".parse(), Ok(TarCompression::Zstd));
+        assert_eq!("none".parse(), Ok(TarCompression::None));
+        assert!("bzip2".parse::<TarCompression>().is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn split_linux_device_number() {
+        // /dev/null is 1,3; /dev/sda1 is 8,1.
+        assert_eq!(split_device_number(0x103), (1, 3));
+        assert_eq!(split_device_number(0x801), (8, 1));
+    }
+
+    #[test]
+    fn device_number_round_trip() {
+        for &(major, minor) in &[(1, 3), (8, 1), (259, 65536)] {
+            assert_eq!(
+                split_device_number(make_device_number(major, minor)),
+                (major, minor)
+            );
+        }
+    }
+
+    #[test]
+    fn tar_names() {
+        assert_eq!(
+            apath_from_tar_name(Path::new("./etc/hosts")),
+            Some(Apath::from("/etc/hosts"))
+        );
+        assert_eq!(
+            apath_from_tar_name(Path::new("etc/")),
+            Some(Apath::from("/etc"))
+        );
+        assert_eq!(apath_from_tar_name(Path::new("./")), Some(Apath::from("/")));
+        assert_eq!(apath_from_tar_name(Path::new("a/../../etc")), None);
+    }
+}
@@ -0,0 +1,177 @@
+// Copyright 2020 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! A transport wrapper that refuses to delete or overwrite files, used for
+//! archives configured as append-only.
+//!
+//! The only exceptions are the lock files at the top of the archive, which
+//! are always created and removed by writers, and the record of the state of
+//! the archive's mirror, which is replaced after each mirrored backup.
+
+use std::io;
+
+use crate::archive::MIRROR_STATE_FILENAME;
+use crate::gc_lock::GC_LOCK;
+use crate::transport::{DirEntry, Metadata, Transport};
+use crate::write_lock::{BREAK_LOCK_FILENAME, LOCK_FILENAME};
+
+#[derive(Clone, Debug)]
+pub struct AppendOnlyTransport {
+    inner: Box<dyn Transport>,
+    /// Path of this transport from the top of the archive, empty at the top.
+    relpath: String,
+}
+
+impl AppendOnlyTransport {
+    /// Wrap a transport addressing the top directory of an archive.
+    pub fn new(inner: Box<dyn Transport>) -> AppendOnlyTransport {
+        AppendOnlyTransport {
+            inner,
+            relpath: String::new(),
+        }
+    }
+
+    /// True for the few files at the top of the archive that may be
+    /// replaced or removed.
+    fn is_mutable_file(&self, relpath: &str) -> bool {
+        self.relpath.is_empty()
+            && [
+                LOCK_FILENAME,
+                BREAK_LOCK_FILENAME,
+                GC_LOCK,
+                MIRROR_STATE_FILENAME,
+            ]
+            .contains(&relpath)
+    }
+
+    fn refuse(&self, kind: io::ErrorKind, relpath: &str) -> io::Error {
+        io::Error::new(
+            kind,
+            format!(
+                "Can't delete or overwrite {:?} in an append-only archive",
+                join(&self.relpath, relpath)
+            ),
+        )
+    }
+}
+
+fn join(a: &str, b: &str) -> String {
+    if a.is_empty() {
+        b.to_owned()
+    } else {
+        format!("{}/{}", a, b)
+    }
+}
+
+impl Transport for AppendOnlyTransport {
+    fn iter_dir_entries(
+        &self,
+        relpath: &str,
+    ) -> io::Result<Box<dyn Iterator<Item = io::Result<DirEntry>>>> {
+        self.inner.iter_dir_entries(relpath)
+    }
+
+    fn read_file(&self, relpath: &str, out_buf: &mut Vec<u8>) -> io::Result<()> {
+        self.inner.read_file(relpath, out_buf)
+    }
+
+    fn exists(&self, relpath: &str) -> io::Result<bool> {
+        self.inner.exists(relpath)
+    }
+
+    fn create_dir(&self, relpath: &str) -> io::Result<()> {
+        self.inner.create_dir(relpath)
+    }
+
+    fn write_file(&self, relpath: &str, content: &[u8]) -> io::Result<()> {
+        if !self.is_mutable_file(relpath) && self.inner.exists(relpath)? {
+            // Reported as AlreadyExists so that a block written concurrently
+            // by another writer is not treated as a failure.
+            return Err(self.refuse(io::ErrorKind::AlreadyExists, relpath));
+        }
+        self.inner.write_file(relpath, content)
+    }
+
+    fn create_new_file(&self, relpath: &str, content: &[u8]) -> io::Result<()> {
+        // Never overwrites anything, so it's always allowed.
+        self.inner.create_new_file(relpath, content)
+    }
+
+    fn metadata(&self, relpath: &str) -> io::Result<Metadata> {
+        self.inner.metadata(relpath)
+    }
+
+    fn remove_file(&self, relpath: &str) -> io::Result<()> {
+        if !self.is_mutable_file(relpath) {
+            return Err(self.refuse(io::ErrorKind::PermissionDenied, relpath));
+        }
+        self.inner.remove_file(relpath)
+    }
+
+    fn remove_dir(&self, relpath: &str) -> io::Result<()> {
+        Err(self.refuse(io::ErrorKind::PermissionDenied, relpath))
+    }
+
+    fn remove_dir_all(&self, relpath: &str) -> io::Result<()> {
+        Err(self.refuse(io::ErrorKind::PermissionDenied, relpath))
+    }
+
+    fn sub_transport(&self, relpath: &str) -> Box<dyn Transport> {
+        Box::new(AppendOnlyTransport {
+            inner: self.inner.sub_transport(relpath),
+            relpath: join(&self.relpath, relpath),
+        })
+    }
+
+    fn box_clone(&self) -> Box<dyn Transport> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use assert_fs::prelude::*;
+
+    use super::*;
+    use crate::transport::local::LocalTransport;
+
+    #[test]
+    fn refuses_delete_and_overwrite() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        temp.child("d").create_dir_all().unwrap();
+        temp.child("d/old").write_str("old").unwrap();
+        let transport = AppendOnlyTransport::new(Box::new(LocalTransport::new(temp.path())));
+        let sub = transport.sub_transport("d");
+
+        sub.write_file("new", b"new").unwrap();
+        temp.child("d/new").assert("new");
+
+        let err = sub.write_file("old", b"changed").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert!(err.to_string().contains("\"d/old\""));
+        temp.child("d/old").assert("old");
+
+        let err = sub.remove_file("old").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        transport.remove_dir_all("d").unwrap_err();
+        temp.child("d/old").assert("old");
+
+        // Locks at the top of the archive can still be taken and released.
+        transport.write_file(LOCK_FILENAME, b"{}").unwrap();
+        transport.write_file(LOCK_FILENAME, b"{}").unwrap();
+        transport.remove_file(LOCK_FILENAME).unwrap();
+        sub.write_file(LOCK_FILENAME, b"{}").unwrap();
+        sub.remove_file(LOCK_FILENAME).unwrap_err();
+
+        temp.close().unwrap();
+    }
+}
@@ -18,6 +18,7 @@ use std::io;
 use std::io::prelude::*;
 use std::path::{Path, PathBuf};
 
+use crate::io::{join_relative, long_path};
 use crate::transport::{DirEntry, Metadata, Transport};
 
 #[derive(Clone, Debug)]
@@ -27,15 +28,19 @@ pub struct LocalTransport {
 }
 
 impl LocalTransport {
+    /// Make a transport for the directory at `path`.
+    ///
+    /// On Windows, the path is made absolute and given the `\\?\` prefix, so
+    /// that files deep in the archive can be longer than the usual limit.
     pub fn new(path: &Path) -> Self {
         LocalTransport {
-            root: path.to_owned(),
+            root: long_path(path),
         }
     }
 
     pub fn full_path(&self, relpath: &str) -> PathBuf {
         debug_assert!(!relpath.contains("/../"), "path must not contain /../");
-        self.root.join(relpath)
+        join_relative(&self.root, relpath)
     }
 }
 
@@ -108,6 +113,24 @@ impl Transport for LocalTransport {
         }
     }
 
+    fn create_new_file(&self, relpath: &str, content: &[u8]) -> io::Result<()> {
+        let full_path = self.full_path(relpath);
+        let dir = full_path.parent().unwrap();
+        let mut temp = tempfile::Builder::new()
+            .prefix(crate::TMP_PREFIX)
+            .tempfile_in(dir)?;
+        if let Err(err) = temp.write_all(content) {
+            let _ = temp.close();
+            return Err(err);
+        }
+        if let Err(persist_error) = temp.persist_noclobber(&full_path) {
+            let _ = persist_error.file.close();
+            Err(persist_error.error)
+        } else {
+            Ok(())
+        }
+    }
+
     fn remove_file(&self, relpath: &str) -> io::Result<()> {
         std::fs::remove_file(self.full_path(relpath))
     }
@@ -122,12 +145,12 @@ impl Transport for LocalTransport {
 
     fn sub_transport(&self, relpath: &str) -> Box<dyn Transport> {
         Box::new(LocalTransport {
-            root: self.root.join(relpath),
+            root: self.full_path(relpath),
         })
     }
 
     fn metadata(&self, relpath: &str) -> io::Result<Metadata> {
-        let fsmeta = self.root.join(relpath).metadata()?;
+        let fsmeta = self.full_path(relpath).metadata()?;
         Ok(Metadata { len: fsmeta.len() })
     }
 }
@@ -260,6 +283,19 @@ mod test {
         temp.close().unwrap();
     }
 
+    #[test]
+    fn create_new_file() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let transport = Transport::new(&temp.path().to_string_lossy()).unwrap();
+
+        transport.create_new_file("lock", b"first").unwrap();
+        let err = transport.create_new_file("lock", b"second").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+        temp.child("lock").assert("first");
+
+        temp.close().unwrap();
+    }
+
     #[test]
     fn create_existing_dir() {
         let temp = assert_fs::TempDir::new().unwrap();
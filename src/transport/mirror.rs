@@ -0,0 +1,288 @@
+// Copyright 2020 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! A transport wrapper that copies every write to a second transport, used
+//! to write a backup to an archive and its mirror at once.
+//!
+//! Reads come only from the primary, except that files are only reported to
+//! exist if they're in both, so that a block missing from the secondary is
+//! written again rather than being deduplicated. Writes go to the primary and
+//! then to the secondary. Failures writing to the primary are returned as
+//! usual, but once a write to the secondary fails, the failure is reported
+//! and no more files are written to the secondary, so the backup carries on
+//! into the primary alone, and the secondary can be caught up later.
+//!
+//! Removals are always passed on to the secondary, and failures removing
+//! files from it are ignored.
+//!
+//! The lock files at the top of the archive are the exception: they're only
+//! created, checked, and removed in the primary. The secondary is locked
+//! separately, as its own archive, by the caller, so that a lock held there
+//! by another process is neither ignored nor removed.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::gc_lock::GC_LOCK;
+use crate::transport::{DirEntry, Metadata, Transport};
+use crate::write_lock::{BREAK_LOCK_FILENAME, LOCK_FILENAME};
+
+#[derive(Clone, Debug)]
+pub struct MirrorTransport {
+    primary: Box<dyn Transport>,
+    secondary: Box<dyn Transport>,
+    /// Set when a write to the secondary fails; shared by all the
+    /// sub-transports of one mirror.
+    secondary_failed: Arc<AtomicBool>,
+    /// True if this addresses the top directories of the archives.
+    top: bool,
+}
+
+impl MirrorTransport {
+    /// Wrap transports addressing the top directories of two archives.
+    pub fn new(primary: Box<dyn Transport>, secondary: Box<dyn Transport>) -> MirrorTransport {
+        MirrorTransport {
+            primary,
+            secondary,
+            secondary_failed: Arc::new(AtomicBool::new(false)),
+            top: true,
+        }
+    }
+
+    /// True if any write to the secondary failed, so it's missing some
+    /// files written to the primary.
+    pub fn secondary_failed(&self) -> bool {
+        self.secondary_failed.load(Ordering::SeqCst)
+    }
+
+    /// True for the lock files at the top of the archive, which are only
+    /// written to the primary.
+    fn is_lock_file(&self, relpath: &str) -> bool {
+        self.top && [LOCK_FILENAME, BREAK_LOCK_FILENAME, GC_LOCK].contains(&relpath)
+    }
+
+    /// Apply `write` to the primary, and then, unless it previously failed,
+    /// to the secondary.
+    fn mirror<F>(&self, write: F) -> io::Result<()>
+    where
+        F: Fn(&dyn Transport) -> io::Result<()>,
+    {
+        write(self.primary.as_ref())?;
+        self.write_secondary(write);
+        Ok(())
+    }
+
+    /// Apply `write` to the secondary, unless a write to it previously failed.
+    ///
+    /// A file that's already in the secondary isn't counted as a failure.
+    fn write_secondary<F>(&self, write: F)
+    where
+        F: Fn(&dyn Transport) -> io::Result<()>,
+    {
+        if !self.secondary_failed() {
+            if let Err(err) = write(self.secondary.as_ref()) {
+                if err.kind() != io::ErrorKind::AlreadyExists
+                    && !self.secondary_failed.swap(true, Ordering::SeqCst)
+                {
+                    log::warn!(
+                        "Failed to write to mirror, which will be caught up later: {}",
+                        err
+                    );
+                }
+            }
+        }
+    }
+
+    /// Apply `remove` to the primary, and then, unless `relpath` is a lock
+    /// file, to the secondary, ignoring any failure there.
+    fn remove<F>(&self, relpath: &str, remove: F) -> io::Result<()>
+    where
+        F: Fn(&dyn Transport) -> io::Result<()>,
+    {
+        remove(self.primary.as_ref())?;
+        if !self.is_lock_file(relpath) {
+            let _ = remove(self.secondary.as_ref());
+        }
+        Ok(())
+    }
+}
+
+impl Transport for MirrorTransport {
+    fn iter_dir_entries(
+        &self,
+        relpath: &str,
+    ) -> io::Result<Box<dyn Iterator<Item = io::Result<DirEntry>>>> {
+        self.primary.iter_dir_entries(relpath)
+    }
+
+    fn read_file(&self, relpath: &str, out_buf: &mut Vec<u8>) -> io::Result<()> {
+        self.primary.read_file(relpath, out_buf)
+    }
+
+    fn exists(&self, relpath: &str) -> io::Result<bool> {
+        if !self.primary.exists(relpath)? {
+            Ok(false)
+        } else if self.secondary_failed() || self.is_lock_file(relpath) {
+            Ok(true)
+        } else {
+            Ok(self.secondary.exists(relpath).unwrap_or(false))
+        }
+    }
+
+    fn create_dir(&self, relpath: &str) -> io::Result<()> {
+        self.mirror(|transport| transport.create_dir(relpath))
+    }
+
+    fn write_file(&self, relpath: &str, content: &[u8]) -> io::Result<()> {
+        if self.is_lock_file(relpath) {
+            return self.primary.write_file(relpath, content);
+        }
+        // A file that's already in an append-only primary, such as a block,
+        // might still be missing from the secondary.
+        let result = self.primary.write_file(relpath, content);
+        match &result {
+            Err(err) if err.kind() != io::ErrorKind::AlreadyExists => return result,
+            _ => (),
+        }
+        self.write_secondary(|transport| transport.write_file(relpath, content));
+        result
+    }
+
+    fn create_new_file(&self, relpath: &str, content: &[u8]) -> io::Result<()> {
+        if self.is_lock_file(relpath) {
+            return self.primary.create_new_file(relpath, content);
+        }
+        self.mirror(|transport| transport.create_new_file(relpath, content))
+    }
+
+    fn metadata(&self, relpath: &str) -> io::Result<Metadata> {
+        self.primary.metadata(relpath)
+    }
+
+    fn remove_file(&self, relpath: &str) -> io::Result<()> {
+        self.remove(relpath, |transport| transport.remove_file(relpath))
+    }
+
+    fn remove_dir(&self, relpath: &str) -> io::Result<()> {
+        self.remove(relpath, |transport| transport.remove_dir(relpath))
+    }
+
+    fn remove_dir_all(&self, relpath: &str) -> io::Result<()> {
+        self.remove(relpath, |transport| transport.remove_dir_all(relpath))
+    }
+
+    fn sub_transport(&self, relpath: &str) -> Box<dyn Transport> {
+        Box::new(MirrorTransport {
+            primary: self.primary.sub_transport(relpath),
+            secondary: self.secondary.sub_transport(relpath),
+            secondary_failed: self.secondary_failed.clone(),
+            top: false,
+        })
+    }
+
+    fn box_clone(&self) -> Box<dyn Transport> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use assert_fs::prelude::*;
+    use predicates::prelude::*;
+
+    use super::*;
+    use crate::transport::local::LocalTransport;
+
+    #[test]
+    fn writes_to_both() {
+        let primary = assert_fs::TempDir::new().unwrap();
+        let secondary = assert_fs::TempDir::new().unwrap();
+        let transport = MirrorTransport::new(
+            Box::new(LocalTransport::new(primary.path())),
+            Box::new(LocalTransport::new(secondary.path())),
+        );
+        transport.create_dir("d").unwrap();
+        let sub = transport.sub_transport("d");
+        sub.write_file("f", b"hello").unwrap();
+        primary.child("d/f").assert("hello");
+        secondary.child("d/f").assert("hello");
+
+        // Reads only come from the primary, but a file missing from the
+        // secondary is reported as missing, so that it's written again.
+        std::fs::remove_file(secondary.child("d/f").path()).unwrap();
+        assert!(!sub.exists("f").unwrap());
+        let mut buf = Vec::new();
+        sub.read_file("f", &mut buf).unwrap();
+        assert_eq!(buf, b"hello");
+        sub.write_file("f", b"hello").unwrap();
+        secondary.child("d/f").assert("hello");
+        assert!(sub.exists("f").unwrap());
+
+        sub.remove_file("f").unwrap();
+        primary.child("d/f").assert(predicate::path::missing());
+        assert!(!transport.secondary_failed());
+    }
+
+    #[test]
+    fn stops_writing_to_failed_secondary() {
+        let primary = assert_fs::TempDir::new().unwrap();
+        let secondary = assert_fs::TempDir::new().unwrap();
+        let transport = MirrorTransport::new(
+            Box::new(LocalTransport::new(primary.path())),
+            Box::new(LocalTransport::new(secondary.path())),
+        );
+        // The secondary is missing this directory, so writing into it fails.
+        primary.child("d").create_dir_all().unwrap();
+        let sub = transport.sub_transport("d");
+        sub.write_file("f", b"hello").unwrap();
+        primary.child("d/f").assert("hello");
+        assert!(transport.secondary_failed());
+
+        // Later writes only go to the primary, even where they would succeed.
+        transport.write_file("g", b"world").unwrap();
+        primary.child("g").assert("world");
+        secondary.child("g").assert(predicate::path::missing());
+
+        // Files on the secondary can still be removed.
+        secondary.child("h").write_str("old").unwrap();
+        primary.child("h").write_str("old").unwrap();
+        transport.remove_file("h").unwrap();
+        secondary.child("h").assert(predicate::path::missing());
+    }
+
+    #[test]
+    fn lock_files_are_only_in_primary() {
+        let primary = assert_fs::TempDir::new().unwrap();
+        let secondary = assert_fs::TempDir::new().unwrap();
+        let transport = MirrorTransport::new(
+            Box::new(LocalTransport::new(primary.path())),
+            Box::new(LocalTransport::new(secondary.path())),
+        );
+        // A lock held on the secondary by another process is left alone.
+        secondary.child("LOCK").write_str("other").unwrap();
+        transport.create_new_file("LOCK", b"ours").unwrap();
+        assert!(transport.exists("LOCK").unwrap());
+        transport.remove_file("LOCK").unwrap();
+        primary.child("LOCK").assert(predicate::path::missing());
+        secondary.child("LOCK").assert("other");
+        assert!(!transport.secondary_failed());
+
+        // Files with those names below the top are mirrored as usual.
+        transport.create_dir("d").unwrap();
+        transport
+            .sub_transport("d")
+            .write_file("LOCK", b"x")
+            .unwrap();
+        secondary.child("d/LOCK").assert("x");
+    }
+}
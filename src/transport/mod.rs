@@ -22,7 +22,9 @@ use crate::errors::Error;
 use crate::kind::Kind;
 use crate::Result;
 
+pub mod append_only;
 pub mod local;
+pub mod mirror;
 
 /// Abstracted filesystem IO ta access an archive.
 ///
@@ -91,6 +93,13 @@ pub trait Transport: Send + Sync + std::fmt::Debug {
     /// If a temporary file is used, the name should start with `crate::TMP_PREFIX`.
     fn write_file(&self, relpath: &str, content: &[u8]) -> io::Result<()>;
 
+    /// Write a complete file, only if it doesn't already exist.
+    ///
+    /// If the file exists, this fails with `io::ErrorKind::AlreadyExists`, so
+    /// that only one of several writers racing to create it succeeds. Like
+    /// `write_file`, the file should only be visible with its complete content.
+    fn create_new_file(&self, relpath: &str, content: &[u8]) -> io::Result<()>;
+
     /// Get metadata about a file.
     fn metadata(&self, relpath: &str) -> io::Result<Metadata>;
 
@@ -152,7 +161,7 @@ pub struct ListDirNames {
 /// or relative filename.
 /// ```
 /// use std::str::FromStr;
-/// use conserve::transport::Location;
+/// use conserve::Location;
 ///
 /// let location: Location = Location::from_str("/backup/example").unwrap();
 /// let transport = location.open();
@@ -170,7 +179,7 @@ impl Location {
     ///
     /// ```
     /// use std::path::PathBuf;
-    /// use conserve::transport::Location;
+    /// use conserve::Location;
     ///
     /// let location = Location::Local("/backup".to_owned().into());
     /// let transport = location.open().unwrap();
@@ -13,16 +13,19 @@
 
 //! Abstract Tree trait.
 
+use std::io::{self, Read};
 use std::ops::Range;
+use std::sync::Arc;
 
 use crate::stats::{CopyStats, Sizes};
+use crate::unix_time::UnixTime;
 use crate::*;
 
 /// Abstract Tree that may be either on the real filesystem or stored in an archive.
 pub trait ReadTree {
     // TODO: Perhaps hide these and just return dyn objects?
     type Entry: Entry;
-    type R: std::io::Read;
+    type R: ReadContent;
 
     /// Iterate, in apath order, all the entries in this tree.
     ///
@@ -40,10 +43,55 @@ pub trait ReadTree {
         subtree: &Apath,
     ) -> Result<Box<dyn Iterator<Item = Self::Entry>>>;
 
+    /// Return the entries for all the directories containing `apath`, starting
+    /// from the root, and not including `apath` itself.
+    ///
+    /// The provided implementation scans entries from the start of the tree, but
+    /// implementations may be able to do better.
+    fn parent_entries(&self, apath: &Apath) -> Result<Vec<Self::Entry>> {
+        Ok(self
+            .iter_entries()?
+            .take_while(|entry| entry.apath() < apath)
+            .filter(|entry| entry.apath().is_prefix_of(apath))
+            .collect())
+    }
+
     /// Read file contents as a `std::io::Read`.
     // TODO: Remove this and use ReadBlocks or similar.
     fn file_contents(&self, entry: &Self::Entry) -> Result<Self::R>;
 
+    /// Return true if `content` is exactly the content of the file `entry`.
+    ///
+    /// The provided implementation reads and compares both, but trees that
+    /// know the hashes of their content may be able to read less.
+    fn content_matches(&self, entry: &Self::Entry, content: &mut dyn Read) -> Result<bool> {
+        let mut ours = self.file_contents(entry)?;
+        let mut buf = vec![0; 64 << 10];
+        let mut theirs = vec![0; 64 << 10];
+        loop {
+            let len = ours.read(&mut buf)?;
+            if len == 0 {
+                return Ok(content.read(&mut theirs[..1])? == 0);
+            }
+            match content.read_exact(&mut theirs[..len]) {
+                Ok(()) => (),
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+                Err(err) => return Err(err.into()),
+            }
+            if buf[..len] != theirs[..len] {
+                return Ok(false);
+            }
+        }
+    }
+
+    /// Return the ranges of a file known to be holes, in order.
+    ///
+    /// The provided implementation finds no holes; only trees that can detect
+    /// them need to implement this.
+    fn file_holes(&self, _entry: &Self::Entry) -> Result<Vec<Range<u64>>> {
+        Ok(Vec::new())
+    }
+
     /// Estimate the number of entries in the tree.
     /// This might do somewhat expensive IO, so isn't the Iter's `size_hint`.
     fn estimate_count(&self) -> Result<u64>;
@@ -51,8 +99,9 @@ pub trait ReadTree {
     /// Measure the tree size.
     ///
     /// This typically requires walking all entries, which may take a while.
-    fn size(&self) -> Result<TreeSize> {
-        let mut progress_bar = ProgressBar::new();
+    /// Progress is sent to `progress`, if it's given.
+    fn size(&self, progress: Option<&Arc<dyn ProgressSink>>) -> Result<TreeSize> {
+        let mut progress_bar = ProgressBar::new(progress);
         progress_bar.set_phase("Measuring".to_owned());
         let mut tot = 0u64;
         for e in self.iter_entries()? {
@@ -66,6 +115,18 @@ pub trait ReadTree {
     }
 }
 
+/// Reads the content of a file in a tree.
+///
+/// Readers can be sent to other threads to be read concurrently.
+pub trait ReadContent: std::io::Read + Send + 'static {
+    /// Return the file's current mtime and size, so that changes while it's being
+    /// read can be detected, or None if the file can't change.
+    fn mtime_and_size(&self) -> std::io::Result<Option<(UnixTime, u64)>>;
+
+    /// Go back to the start of the content, to read it again.
+    fn rewind(&mut self) -> std::io::Result<()>;
+}
+
 /// A tree open for writing, either local or an an archive.
 ///
 /// This isn't a sub-trait of ReadTree since a backup band can't be read while writing is
@@ -81,12 +142,18 @@ pub trait WriteTree {
     /// Copy a symlink entry from a source tree to this tree.
     fn copy_symlink<E: Entry>(&mut self, entry: &E) -> Result<()>;
 
+    /// Copy a fifo, socket, or device node from a source tree to this tree.
+    fn copy_special<E: Entry>(&mut self, entry: &E) -> Result<()>;
+
     /// Copy in the contents of a file from another tree.
     ///
     /// Returns Sizes describing the compressed and uncompressed sizes copied.
     // TODO: Use some better interface than IO::Read, that permits getting sizes
     // from the source file when restoring.
     fn copy_file<R: ReadTree>(&mut self, entry: &R::Entry, from_tree: &R) -> Result<CopyStats>;
+
+    /// Count what `copy_file` would do for this file, without changing this tree.
+    fn measure_file<R: ReadTree>(&mut self, entry: &R::Entry, from_tree: &R) -> Result<CopyStats>;
 }
 
 /// Read a file as a series of blocks of bytes.
@@ -12,18 +12,30 @@
 // GNU General Public License for more details.
 
 //! Abstract user interface trait.
+//!
+//! Problems and informational messages from the library are sent through the
+//! `log` crate, so that programs using Conserve as a library can filter or
+//! redirect them. The command line tool installs [install_logger] to show them
+//! on the terminal, interleaved with progress bars, and optionally to write
+//! them to a log file.
 
-use std::fmt::Write;
+use std::fs::{File, OpenOptions};
 use std::io;
 use std::io::Write as IoWrite;
-use std::sync::Mutex;
-use std::time::Duration;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
 
+use chrono::Local;
 use crossterm::{cursor, queue, terminal};
 use lazy_static::lazy_static;
+use log::{Level, LevelFilter, Log, Metadata, Record};
 
+use crate::errors::ErrorChain;
+use crate::progress::{Progress, ProgressSink};
 use crate::stats::Sizes;
-use crate::ProgressBar;
+use crate::{Error, Result};
 
 /// A terminal/text UI.
 ///
@@ -42,20 +54,163 @@ pub(crate) struct UIState {
 
     /// Should a progress bar be drawn?
     progress_enabled: bool,
+
+    /// Should progress be written as json lines to stderr, rather than drawn
+    /// as a bar?
+    progress_json: bool,
+
+    /// The time a json progress line was last written, if ever.
+    last_json_progress: Option<Instant>,
+
+    /// Is stdout reserved for data, such as json or a tar stream, so that
+    /// messages are written to stderr and no progress bar is drawn?
+    stdout_reserved: bool,
+}
+
+/// Minimum interval between json progress lines.
+const JSON_PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How to show the progress of long operations.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ProgressMode {
+    /// Draw a progress bar if stdout is a terminal.
+    Auto,
+    /// Periodically write a json object describing progress to stderr.
+    Json,
+    /// Don't show progress.
+    None,
+}
+
+impl FromStr for ProgressMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<ProgressMode, String> {
+        match s {
+            "auto" => Ok(ProgressMode::Auto),
+            "json" => Ok(ProgressMode::Json),
+            "none" => Ok(ProgressMode::None),
+            _ => Err(format!("Unknown progress mode {:?}", s)),
+        }
+    }
+}
+
+/// Draws progress on the terminal, or writes it to stderr as json, depending
+/// on [set_progress_mode].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TerminalProgress;
+
+impl ProgressSink for TerminalProgress {
+    fn update(&self, progress: &Progress) {
+        with_locked_ui(|ui| ui.draw_progress_bar(progress))
+    }
+
+    fn finish(&self) {
+        with_locked_ui(|ui| ui.clear_progress())
+    }
 }
 
 lazy_static! {
     static ref UI_STATE: Mutex<UIState> = Mutex::new(UIState::default());
 }
 
-// TODO: Rather than a directly-called function, hook this into logging.
+/// Print a line of output, such as a listing requested by the user.
+///
+/// Diagnostic messages should go through `log` instead.
 pub fn println(s: &str) {
     with_locked_ui(|ui| ui.println(s))
 }
 
-// TODO: Rather than a directly-called function, hook this into logging.
+/// Report a problem, logged at the error level.
 pub fn problem(s: &str) {
-    with_locked_ui(|ui| ui.problem(s));
+    log::error!("{}", s);
+}
+
+/// Shows log messages on the terminal, and optionally writes them to a file.
+///
+/// Errors and warnings are always written to stderr, with a prefix; others are
+/// shown as plain lines on stdout, unless it's reserved for data.
+struct UiLogger {
+    /// Most verbose level shown on the terminal.
+    terminal_level: LevelFilter,
+    /// File receiving all messages at debug level and above, with their
+    /// time, level, and origin.
+    file: Option<Mutex<File>>,
+}
+
+impl Log for UiLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.terminal_level
+            || (self.file.is_some() && metadata.level() <= Level::Debug)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let message = record.args().to_string();
+        if record.level() <= self.terminal_level {
+            match record.level() {
+                Level::Error => with_locked_ui(|ui| ui.problem(&message)),
+                Level::Warn => with_locked_ui(|ui| ui.warning(&message)),
+                _ => with_locked_ui(|ui| ui.println(&message)),
+            }
+        }
+        if let Some(file) = &self.file {
+            if record.level() <= Level::Debug {
+                // Failing to write the log is not worth interrupting the operation.
+                let _ = writeln!(
+                    file.lock().unwrap(),
+                    "{} {:<5} {} {}",
+                    Local::now().to_rfc3339(),
+                    record.level(),
+                    record.target(),
+                    message
+                );
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = &self.file {
+            let _ = file.lock().unwrap().flush();
+        }
+    }
+}
+
+/// Show log messages up to `terminal_level` on the terminal, and write
+/// messages at debug level and above to `log_file` if it's given.
+///
+/// If the log file can't be opened, messages are still shown on the terminal
+/// and the error is returned.
+///
+/// This can only be called once in a process.
+pub fn install_logger(terminal_level: LevelFilter, log_file: Option<&Path>) -> Result<()> {
+    let (file, result) = match log_file.map(|path| {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|source| Error::OpenLogFile {
+                path: path.to_owned(),
+                source,
+            })
+    }) {
+        None => (None, Ok(())),
+        Some(Ok(file)) => (Some(file), Ok(())),
+        Some(Err(err)) => (None, Err(err)),
+    };
+    let max_level = if file.is_some() {
+        terminal_level.max(LevelFilter::Debug)
+    } else {
+        terminal_level
+    };
+    log::set_boxed_logger(Box::new(UiLogger {
+        terminal_level,
+        file: file.map(Mutex::new),
+    }))
+    .expect("logger is installed only once");
+    log::set_max_level(max_level);
+    result
 }
 
 pub(crate) fn with_locked_ui<F>(mut cb: F)
@@ -63,21 +218,19 @@ where
     F: FnMut(&mut UIState),
 {
     use std::ops::DerefMut;
-    cb(UI_STATE.lock().unwrap().deref_mut())
+    // Messages are still shown after a panic while the lock was held, such
+    // as those logged while unwinding.
+    cb(UI_STATE
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .deref_mut())
 }
 
 /// Report that a non-fatal error occurred.
 ///
 /// The program will continue.
 pub fn show_error(e: &dyn std::error::Error) {
-    // TODO: Convert to logging.
-    let mut buf = e.to_string();
-    let mut cause = e;
-    while let Some(c) = cause.source() {
-        write!(&mut buf, "\n  caused by: {}", c).expect("Failed to format error cause");
-        cause = c;
-    }
-    problem(&buf);
+    log::error!("{}", ErrorChain(e));
 }
 
 /// Enable drawing progress bars, only if stdout is a tty.
@@ -86,7 +239,19 @@ pub fn show_error(e: &dyn std::error::Error) {
 pub fn enable_progress(enabled: bool) {
     use crossterm::tty::IsTty;
     let mut ui = UI_STATE.lock().unwrap();
-    ui.progress_enabled = io::stdout().is_tty() && enabled;
+    ui.progress_enabled = (ui.progress_json || io::stdout().is_tty()) && enabled;
+}
+
+/// Choose how progress is shown, and enable it unless the mode is `None`.
+pub fn set_progress_mode(mode: ProgressMode) {
+    UI_STATE.lock().unwrap().progress_json = mode == ProgressMode::Json;
+    enable_progress(mode != ProgressMode::None);
+}
+
+/// Keep stdout for data, such as json or a tar stream: write informational
+/// messages to stderr, as problems always are, and don't draw progress bars.
+pub fn reserve_stdout() {
+    UI_STATE.lock().unwrap().stdout_reserved = true;
 }
 
 impl Default for UIState {
@@ -94,6 +259,9 @@ impl Default for UIState {
         UIState {
             progress_present: false,
             progress_enabled: false,
+            progress_json: false,
+            last_json_progress: None,
+            stdout_reserved: false,
         }
     }
 }
@@ -153,10 +321,24 @@ impl UIState {
         }
     }
 
-    pub(crate) fn draw_progress_bar(&mut self, bar: &ProgressBar) {
+    pub(crate) fn draw_progress_bar(&mut self, bar: &Progress) {
         if !self.progress_enabled {
             return;
         }
+        if self.progress_json {
+            if let Some(last) = self.last_json_progress {
+                if last.elapsed() < JSON_PROGRESS_INTERVAL {
+                    return;
+                }
+            }
+            self.last_json_progress = Some(Instant::now());
+            // Progress is only advisory, so failing to write it is ignored.
+            let _ = writeln!(io::stderr(), "{}", bar.to_json());
+            return;
+        }
+        if self.stdout_reserved {
+            return;
+        }
         let width = if let Ok((width, _)) = terminal::size() {
             width as usize
         } else {
@@ -168,12 +350,21 @@ impl UIState {
 
     pub(crate) fn println(&mut self, s: &str) {
         self.clear_progress();
-        println!("{}", s);
+        if self.stdout_reserved {
+            eprintln!("{}", s);
+        } else {
+            println!("{}", s);
+        }
+    }
+
+    fn warning(&mut self, s: &str) {
+        self.clear_progress();
+        eprintln!("conserve warning: {}", s);
     }
 
     fn problem(&mut self, s: &str) {
         self.clear_progress();
-        println!("conserve error: {}", s);
+        eprintln!("conserve error: {}", s);
         // Drawing this way makes messages leak from tests, for unclear reasons.
 
         // queue!(
@@ -194,6 +385,13 @@ impl UIState {
 mod tests {
     use super::*;
 
+    #[test]
+    fn parse_progress_mode() {
+        assert_eq!("json".parse(), Ok(ProgressMode::Json));
+        assert_eq!("none".parse(), Ok(ProgressMode::None));
+        assert!("fancy".parse::<ProgressMode>().is_err());
+    }
+
     #[test]
     pub fn test_compression_ratio() {
         let ratio = compression_ratio(&Sizes {
@@ -17,7 +17,7 @@ use std::convert::From;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 /// A Unix time, as seconds since 1970 UTC, plus fractional nanoseconds.
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd)]
 pub struct UnixTime {
     /// Whole seconds after (or if negative, before) 1 Jan 1970 UTC.
     pub secs: i64,
@@ -0,0 +1,228 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Check that a stored tree matches a live tree, without restoring it.
+//!
+//! Both trees are walked together in apath order. Entries are compared by
+//! kind, size, and symlink target, and optionally by re-reading the live
+//! file and checking it against the stored blocks.
+
+use std::cmp::Ordering;
+use std::fmt;
+
+use crate::errors::ErrorChain;
+use crate::stats::VerifyStats;
+use crate::*;
+
+/// Options for [verify_tree].
+#[derive(Debug, Default, Clone)]
+pub struct VerifyOptions {
+    /// Read every live file that has the same size as the stored file, and
+    /// compare its content to the stored blocks.
+    pub content: bool,
+}
+
+/// How one entry differs between the stored and the live tree.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Change {
+    /// Present in the live tree but not in the stored tree.
+    Added,
+    /// Present in the stored tree but not in the live tree.
+    Deleted,
+    /// The entry is of a different kind, such as a file replaced by a directory.
+    KindChanged,
+    /// A file has a different length.
+    SizeChanged,
+    /// A file has the same length but different content.
+    ///
+    /// This is only detected when checking content.
+    ContentChanged,
+    /// A symlink has a different target.
+    TargetChanged,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            Change::Added => "added",
+            Change::Deleted => "deleted",
+            Change::KindChanged => "kind",
+            Change::SizeChanged => "size",
+            Change::ContentChanged => "content",
+            Change::TargetChanged => "target",
+        })
+    }
+}
+
+/// Compare `stored` to `live`, calling `report` for each entry that differs.
+///
+/// Problems reading individual live files are shown and counted in the
+/// returned stats, and don't stop the comparison.
+pub fn verify_tree(
+    stored: &StoredTree,
+    live: &LiveTree,
+    options: &VerifyOptions,
+    report: &mut dyn FnMut(&Apath, Change),
+) -> Result<VerifyStats> {
+    let mut stats = VerifyStats::default();
+    let mut stored_entries = stored.iter_entries()?.peekable();
+    let mut live_entries = live.iter_entries()?.peekable();
+    loop {
+        let order = match (stored_entries.peek(), live_entries.peek()) {
+            (None, None) => break,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some(s), Some(l)) => s.apath().cmp(l.apath()),
+        };
+        match order {
+            Ordering::Less => {
+                let s = stored_entries.next().unwrap();
+                stats.deleted += 1;
+                report(s.apath(), Change::Deleted);
+            }
+            Ordering::Greater => {
+                let l = live_entries.next().unwrap();
+                stats.added += 1;
+                report(l.apath(), Change::Added);
+            }
+            Ordering::Equal => {
+                let s = stored_entries.next().unwrap();
+                let l = live_entries.next().unwrap();
+                stats.entries_compared += 1;
+                match compare_entries(stored, live, &s, &l, options, &mut stats) {
+                    Ok(None) => (),
+                    Ok(Some(change)) => {
+                        stats.modified += 1;
+                        report(s.apath(), change);
+                    }
+                    Err(err) => {
+                        log::error!("{}", ErrorChain(&err));
+                        stats.errors += 1;
+                    }
+                }
+            }
+        }
+    }
+    Ok(stats)
+}
+
+fn compare_entries(
+    stored: &StoredTree,
+    live: &LiveTree,
+    s: &IndexEntry,
+    l: &LiveEntry,
+    options: &VerifyOptions,
+    stats: &mut VerifyStats,
+) -> Result<Option<Change>> {
+    if s.kind() != l.kind() {
+        return Ok(Some(Change::KindChanged));
+    }
+    match s.kind() {
+        Kind::File => {
+            if s.size() != l.size() {
+                Ok(Some(Change::SizeChanged))
+            } else if options.content {
+                stats.files_read += 1;
+                stats.content_bytes += s.size().unwrap_or_default();
+                if stored.content_matches(s, &mut live.file_contents(l)?)? {
+                    Ok(None)
+                } else {
+                    Ok(Some(Change::ContentChanged))
+                }
+            } else {
+                Ok(None)
+            }
+        }
+        Kind::Symlink if s.symlink_target() != l.symlink_target() => {
+            Ok(Some(Change::TargetChanged))
+        }
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::test_fixtures::{ScratchArchive, TreeFixture};
+
+    fn verify(
+        af: &ScratchArchive,
+        tf: &TreeFixture,
+        content: bool,
+    ) -> (VerifyStats, Vec<(String, Change)>) {
+        let st = af.open_stored_tree(BandSelectionPolicy::Latest).unwrap();
+        let mut changes = Vec::new();
+        let stats = verify_tree(
+            &st,
+            &tf.live_tree(),
+            &VerifyOptions { content },
+            &mut |apath, change| changes.push((apath.to_string(), change)),
+        )
+        .unwrap();
+        (stats, changes)
+    }
+
+    #[test]
+    fn unchanged_tree_matches() {
+        let af = ScratchArchive::new();
+        let tf = TreeFixture::new();
+        tf.create_file_with_contents("hello", b"hello world");
+        tf.create_dir("subdir");
+        tf.create_file_with_contents("subdir/big", &vec![7u8; 3 << 20]);
+        af.backup(tf.path(), &BackupOptions::default()).unwrap();
+
+        let (stats, changes) = verify(&af, &tf, true);
+        assert_eq!(changes, []);
+        assert_eq!(stats.entries_compared, 4);
+        assert_eq!(stats.files_read, 2);
+        assert_eq!(stats.content_bytes, 11 + (3 << 20));
+        assert!(!stats.has_changes());
+    }
+
+    #[test]
+    fn report_changes() {
+        let af = ScratchArchive::new();
+        let tf = TreeFixture::new();
+        tf.create_file_with_contents("same_size", b"hello");
+        tf.create_file_with_contents("grows", b"hello");
+        tf.create_file_with_contents("gone", b"hello");
+        tf.create_dir("becomes_file");
+        af.backup(tf.path(), &BackupOptions::default()).unwrap();
+
+        tf.create_file_with_contents("same_size", b"jello");
+        tf.create_file_with_contents("grows", b"hello world");
+        fs::remove_file(tf.path().join("gone")).unwrap();
+        fs::remove_dir(tf.path().join("becomes_file")).unwrap();
+        tf.create_file("becomes_file");
+        tf.create_file("new");
+
+        let (stats, changes) = verify(&af, &tf, false);
+        assert_eq!(
+            changes,
+            [
+                ("/becomes_file".to_owned(), Change::KindChanged),
+                ("/gone".to_owned(), Change::Deleted),
+                ("/grows".to_owned(), Change::SizeChanged),
+                ("/new".to_owned(), Change::Added),
+            ]
+        );
+        assert_eq!(stats.files_read, 0);
+        assert!(stats.has_changes());
+
+        let (stats, changes) = verify(&af, &tf, true);
+        assert!(changes.contains(&("/same_size".to_owned(), Change::ContentChanged)));
+        assert_eq!(stats.modified, 3);
+    }
+}
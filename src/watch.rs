@@ -0,0 +1,227 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Continuous backup: watch a source tree and back it up again whenever it
+//! changes.
+//!
+//! The source is polled by walking it and looking at the metadata of every
+//! entry, as a backup does to find unmodified files, so this works on any
+//! filesystem without platform-specific change notifications.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crate::errors::ErrorChain;
+use crate::stats::{mb_string, WatchStats};
+use crate::*;
+
+/// Options for [watch].
+#[derive(Debug)]
+pub struct WatchOptions {
+    /// Options for each backup.
+    pub backup: BackupOptions,
+
+    /// How often to look for changes, when there's no quiet period, and
+    /// otherwise the longest time to wait for the source to settle before
+    /// backing up anyway.
+    pub interval: Duration,
+
+    /// Back up once the source has stopped changing for this long.
+    pub quiet_period: Option<Duration>,
+
+    /// Prune old backups under this policy after each backup.
+    pub retention: Option<RetentionPolicy>,
+
+    /// Stop after making this many backups, rather than running until
+    /// there's an error.
+    pub max_backups: Option<usize>,
+}
+
+impl Default for WatchOptions {
+    fn default() -> Self {
+        WatchOptions {
+            backup: BackupOptions::default(),
+            interval: Duration::from_secs(60 * 60),
+            quiet_period: None,
+            retention: None,
+            max_backups: None,
+        }
+    }
+}
+
+/// Back up `source` into `archive` when it starts, and then again each time
+/// it changes.
+///
+/// Without a quiet period, the source is checked every `interval`, and backed
+/// up if anything changed since the last backup. With a quiet period, it's
+/// checked that often, and backed up once it stopped changing between two
+/// checks, or once `interval` has passed since the last backup while it
+/// kept changing.
+///
+/// A summary of each backup is logged. Backups that fail are logged and
+/// counted, and tried again next time.
+///
+/// This only returns after `options.max_backups` backups, or if the source
+/// can't be read at all.
+pub fn watch(archive: &Archive, source: &Path, options: &WatchOptions) -> Result<WatchStats> {
+    let poll_interval = options.quiet_period.unwrap_or(options.interval);
+    let mut stats = WatchStats::default();
+    // The state of the source when it was last backed up and last checked.
+    let mut backed_up: Option<u64> = None;
+    let mut last_seen: Option<u64> = None;
+    let mut last_backup: Option<Instant> = None;
+    loop {
+        let current = fingerprint(source, &options.backup)?;
+        let quiet = options.quiet_period.is_none() || last_seen == Some(current);
+        let overdue = last_backup.map_or(true, |t| t.elapsed() >= options.interval);
+        last_seen = Some(current);
+        if backed_up != Some(current) && (quiet || overdue) {
+            last_backup = Some(Instant::now());
+            if backup_once(archive, source, options, &mut stats) {
+                backed_up = Some(current);
+            }
+            if Some(stats.backups) == options.max_backups {
+                return Ok(stats);
+            }
+        }
+        sleep(poll_interval);
+    }
+}
+
+/// Make one backup, and then prune old backups, logging the results.
+///
+/// Returns true if the backup succeeded.
+fn backup_once(
+    archive: &Archive,
+    source: &Path,
+    options: &WatchOptions,
+    stats: &mut WatchStats,
+) -> bool {
+    let copy_stats = match archive.backup(source, &options.backup) {
+        Ok(copy_stats) => copy_stats,
+        Err(err) => {
+            log::error!("{}", ErrorChain(&err));
+            stats.failed_backups += 1;
+            return false;
+        }
+    };
+    stats.backups += 1;
+    log::info!(
+        "Backup complete: {} files, {} new, {} modified, {} MB written",
+        copy_stats.files,
+        copy_stats.new_files,
+        copy_stats.modified_files,
+        mb_string(copy_stats.compressed_bytes),
+    );
+    if let Some(policy) = options.retention.as_ref().filter(|p| !p.keeps_nothing()) {
+        match archive.prune(
+            policy,
+            &DeleteOptions {
+                progress: options.backup.progress.clone(),
+                ..DeleteOptions::default()
+            },
+        ) {
+            Ok((_retention, delete_stats)) => {
+                if delete_stats.deleted_band_count > 0 {
+                    log::info!("Pruned {} old backups", delete_stats.deleted_band_count);
+                }
+                stats.deleted_band_count += delete_stats.deleted_band_count;
+            }
+            Err(err) => log::error!("{}", ErrorChain(&err)),
+        }
+    }
+    true
+}
+
+/// Summarize the metadata of every entry that would be backed up from
+/// `source`, so that changes can be noticed without reading file content.
+fn fingerprint(source: &Path, options: &BackupOptions) -> Result<u64> {
+    let live_tree = LiveTree::open(source)?
+        .with_excludes(options.excludes.clone())
+        .with_exclude_caches(options.exclude_caches)
+        .with_exclude_if_present(options.exclude_if_present.clone())
+        .with_one_file_system(options.one_file_system);
+    let mut hasher = DefaultHasher::new();
+    for entry in live_tree.iter_entries()? {
+        let apath: &str = entry.apath();
+        apath.hash(&mut hasher);
+        (entry.kind() as u8).hash(&mut hasher);
+        let mtime = entry.mtime();
+        (mtime.secs, mtime.nanosecs).hash(&mut hasher);
+        entry.size().hash(&mut hasher);
+        entry.symlink_target().hash(&mut hasher);
+        entry.unix_mode().hash(&mut hasher);
+    }
+    Ok(hasher.finish())
+}
+
+/// Parse a duration such as `90s`, `15m`, `1h`, or `1d`. A number without a
+/// unit is in seconds.
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let invalid = || Error::InvalidDuration {
+        duration: s.to_owned(),
+    };
+    let (number, unit_secs) = match s.char_indices().last() {
+        Some((i, 's')) => (&s[..i], 1),
+        Some((i, 'm')) => (&s[..i], 60),
+        Some((i, 'h')) => (&s[..i], 60 * 60),
+        Some((i, 'd')) => (&s[..i], 24 * 60 * 60),
+        _ => (s, 1),
+    };
+    let number: u64 = number.parse().map_err(|_| invalid())?;
+    number
+        .checked_mul(unit_secs)
+        .map(Duration::from_secs)
+        .ok_or_else(invalid)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_fixtures::TreeFixture;
+
+    #[test]
+    fn parse_durations() {
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("15m").unwrap(), Duration::from_secs(900));
+        assert_eq!(parse_duration("1h").unwrap(), Duration::from_secs(3600));
+        assert_eq!(parse_duration("2d").unwrap(), Duration::from_secs(172_800));
+        for bad in &["", "h", "1.5h", "-1m", "1w", "99999999999999999999d"] {
+            assert!(parse_duration(bad).is_err(), "{:?} should be invalid", bad);
+        }
+    }
+
+    #[test]
+    fn fingerprint_changes_with_metadata() {
+        let tf = TreeFixture::new();
+        tf.create_file("a");
+        let options = BackupOptions::default();
+        let before = fingerprint(tf.path(), &options).unwrap();
+        assert_eq!(fingerprint(tf.path(), &options).unwrap(), before);
+
+        tf.create_file_with_contents("a", b"longer contents");
+        let changed = fingerprint(tf.path(), &options).unwrap();
+        assert_ne!(changed, before);
+
+        // Changes to excluded files aren't noticed.
+        tf.create_file("b");
+        let options = options.with_excludes(excludes::from_strings(&["/b"]).unwrap());
+        let before = fingerprint(tf.path(), &options).unwrap();
+        tf.create_file_with_contents("b", b"longer contents");
+        assert_eq!(fingerprint(tf.path(), &options).unwrap(), before);
+    }
+}
@@ -0,0 +1,210 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Windows file attributes and creation times.
+//!
+//! These are only read and set on Windows. Elsewhere they're never stored,
+//! and stored values are ignored on restore.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::unix_time::UnixTime;
+
+/// Windows attribute bits that are stored and restored: read-only, hidden,
+/// system, and archive. Others, such as compression, describe how the file is
+/// stored rather than the file itself.
+#[cfg(windows)]
+const STORED_ATTRIBUTES: u32 = 0x1 | 0x2 | 0x4 | 0x20;
+
+/// Seconds from the Windows epoch, 1601-01-01, to the Unix epoch.
+#[cfg(any(windows, test))]
+const WINDOWS_EPOCH_OFFSET_SECS: i64 = 11_644_473_600;
+
+/// Return the stored subset of the Windows attributes of a file.
+#[cfg(windows)]
+pub(crate) fn windows_attributes(metadata: &fs::Metadata) -> Option<u32> {
+    use std::os::windows::fs::MetadataExt;
+    Some(metadata.file_attributes() & STORED_ATTRIBUTES)
+}
+
+#[cfg(not(windows))]
+pub(crate) fn windows_attributes(_metadata: &fs::Metadata) -> Option<u32> {
+    None
+}
+
+/// Return the creation time of a file.
+#[cfg(windows)]
+pub(crate) fn creation_time(metadata: &fs::Metadata) -> Option<UnixTime> {
+    metadata.created().ok().map(UnixTime::from)
+}
+
+#[cfg(not(windows))]
+pub(crate) fn creation_time(_metadata: &fs::Metadata) -> Option<UnixTime> {
+    None
+}
+
+/// Set the stored attributes of a restored file, keeping any others it has.
+///
+/// This should be done after everything else about the file is set, since it
+/// might make the file read-only.
+#[cfg(windows)]
+pub(crate) fn set_windows_attributes(path: &Path, attributes: u32) -> io::Result<()> {
+    use std::os::windows::fs::MetadataExt;
+    use winapi::um::fileapi::SetFileAttributesW;
+
+    let existing = fs::symlink_metadata(path)?.file_attributes();
+    let attributes = (existing & !STORED_ATTRIBUTES) | (attributes & STORED_ATTRIBUTES);
+    let wide_path = wide_path(path);
+    if unsafe { SetFileAttributesW(wide_path.as_ptr(), attributes) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(windows))]
+pub(crate) fn set_windows_attributes(_path: &Path, _attributes: u32) -> io::Result<()> {
+    Ok(())
+}
+
+/// Clear the read-only attribute of an existing file, so that it can be
+/// removed and replaced.
+#[cfg(windows)]
+pub(crate) fn clear_readonly(path: &Path) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(path)?;
+    let mut permissions = metadata.permissions();
+    if metadata.is_file() && permissions.readonly() {
+        permissions.set_readonly(false);
+        fs::set_permissions(path, permissions)?;
+    }
+    Ok(())
+}
+
+/// On Unix a read-only file can be removed from a writable directory, so
+/// nothing needs to be done.
+#[cfg(not(windows))]
+pub(crate) fn clear_readonly(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Set the creation time of a restored file or directory.
+#[cfg(windows)]
+pub(crate) fn set_creation_time(path: &Path, time: UnixTime) -> io::Result<()> {
+    use std::os::windows::fs::OpenOptionsExt;
+    use std::os::windows::io::AsRawHandle;
+    use std::ptr::null;
+    use winapi::shared::minwindef::FILETIME;
+    use winapi::um::fileapi::SetFileTime;
+    use winapi::um::winbase::FILE_FLAG_BACKUP_SEMANTICS;
+    use winapi::um::winnt::FILE_WRITE_ATTRIBUTES;
+
+    // Backup semantics are needed to open directories.
+    let file = fs::OpenOptions::new()
+        .access_mode(FILE_WRITE_ATTRIBUTES)
+        .custom_flags(FILE_FLAG_BACKUP_SEMANTICS)
+        .open(path)?;
+    let ticks = filetime_ticks(time);
+    let created = FILETIME {
+        dwLowDateTime: ticks as u32,
+        dwHighDateTime: (ticks >> 32) as u32,
+    };
+    if unsafe { SetFileTime(file.as_raw_handle() as _, &created, null(), null()) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(windows))]
+pub(crate) fn set_creation_time(_path: &Path, _time: UnixTime) -> io::Result<()> {
+    Ok(())
+}
+
+/// Convert a time to a Windows `FILETIME`, in 100ns ticks since 1601.
+///
+/// Times before 1601 are clamped to it.
+#[cfg(any(windows, test))]
+fn filetime_ticks(time: UnixTime) -> u64 {
+    let secs = (time.secs + WINDOWS_EPOCH_OFFSET_SECS).max(0) as u64;
+    secs * 10_000_000 + u64::from(time.nanosecs / 100)
+}
+
+/// Return a nul-terminated UTF-16 form of `path`, for Windows APIs.
+#[cfg(windows)]
+fn wide_path(path: &Path) -> Vec<u16> {
+    use std::os::windows::ffi::OsStrExt;
+    path.as_os_str()
+        .encode_wide()
+        .chain(std::iter::once(0))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filetime_conversion() {
+        let epoch = UnixTime {
+            secs: 0,
+            nanosecs: 0,
+        };
+        assert_eq!(filetime_ticks(epoch), 116_444_736_000_000_000);
+        let time = UnixTime {
+            secs: 1,
+            nanosecs: 250,
+        };
+        assert_eq!(filetime_ticks(time), 116_444_736_010_000_002);
+        let ancient = UnixTime {
+            secs: -WINDOWS_EPOCH_OFFSET_SECS - 10,
+            nanosecs: 0,
+        };
+        assert_eq!(filetime_ticks(ancient), 0);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn restore_attributes_and_creation_time() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("file");
+        fs::write(&path, b"hello").unwrap();
+        let created = UnixTime {
+            secs: 1_000_000_000,
+            nanosecs: 0,
+        };
+        set_creation_time(&path, created).unwrap();
+        // Hidden and read-only.
+        set_windows_attributes(&path, 0x3).unwrap();
+
+        let metadata = fs::metadata(&path).unwrap();
+        assert_eq!(windows_attributes(&metadata), Some(0x3));
+        assert_eq!(creation_time(&metadata), Some(created));
+        assert!(metadata.permissions().readonly());
+
+        set_windows_attributes(&path, 0).unwrap();
+        assert!(!fs::metadata(&path).unwrap().permissions().readonly());
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn read_only_file_can_be_replaced() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("file");
+        fs::write(&path, b"hello").unwrap();
+        set_windows_attributes(&path, 0x1).unwrap();
+        assert!(fs::remove_file(&path).is_err());
+
+        clear_readonly(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,156 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! A bounded pool of worker threads, used to store files during backup and
+//! write them during restore.
+
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::thread;
+
+use crate::*;
+
+/// Runs jobs on a pool of threads, and collects their results in the order
+/// they finish.
+///
+/// The caller decides when to wait, using `is_full`, so that only a bounded
+/// number of jobs, and whatever they hold open, are outstanding at once.
+pub(crate) struct WorkerPool<T> {
+    pool: rayon::ThreadPool,
+
+    /// The maximum number of jobs that should be in flight at once.
+    max_in_flight: usize,
+
+    /// The number of jobs spawned whose results haven't been received.
+    in_flight: usize,
+
+    sender: Sender<thread::Result<T>>,
+    receiver: Receiver<thread::Result<T>>,
+}
+
+impl<T: Send + 'static> WorkerPool<T> {
+    pub fn new(threads: usize) -> Result<WorkerPool<T>> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .map_err(|source| Error::StartThreads { source })?;
+        let (sender, receiver) = channel();
+        Ok(WorkerPool {
+            pool,
+            // Keep a job queued for each thread, so that workers don't wait
+            // while the caller prepares the next one.
+            max_in_flight: threads * 2,
+            in_flight: 0,
+            sender,
+            receiver,
+        })
+    }
+
+    /// Run `job` on a worker thread.
+    pub fn spawn<F>(&mut self, job: F)
+    where
+        F: FnOnce() -> T + Send + 'static,
+    {
+        self.in_flight += 1;
+        let sender = self.sender.clone();
+        self.pool.spawn(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(job));
+            // The receiver is only gone if the pool was dropped, abandoning
+            // the work.
+            let _ = sender.send(result);
+        });
+    }
+
+    /// The number of jobs whose results haven't been received.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight
+    }
+
+    /// True if the caller should wait for a result before spawning more jobs.
+    pub fn is_full(&self) -> bool {
+        self.in_flight >= self.max_in_flight
+    }
+
+    /// Wait for the next job to finish, and return its result.
+    ///
+    /// Returns `Error::WorkerFailed` if the job panicked.
+    pub fn receive(&mut self) -> Result<T> {
+        match self.receiver.recv() {
+            Ok(result) => {
+                self.in_flight -= 1;
+                result.map_err(|_| Error::WorkerFailed)
+            }
+            // Not possible while the pool holds a sender, but don't wait
+            // forever for results that can't arrive.
+            Err(_) => {
+                self.in_flight = 0;
+                Err(Error::WorkerFailed)
+            }
+        }
+    }
+
+    /// Return the result of a job that's already finished, if there is one.
+    pub fn try_receive(&mut self) -> Option<Result<T>> {
+        match self.receiver.try_recv() {
+            Ok(result) => {
+                self.in_flight -= 1;
+                Some(result.map_err(|_| Error::WorkerFailed))
+            }
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => {
+                self.in_flight = 0;
+                Some(Err(Error::WorkerFailed))
+            }
+        }
+    }
+}
+
+impl<T> std::fmt::Debug for WorkerPool<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WorkerPool")
+            .field("max_in_flight", &self.max_in_flight)
+            .field("in_flight", &self.in_flight)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn collect_results() {
+        let mut workers = WorkerPool::new(2).unwrap();
+        for i in 0..4 {
+            workers.spawn(move || i * 10);
+        }
+        assert_eq!(workers.in_flight(), 4);
+        assert!(workers.is_full());
+        let mut results: Vec<i32> = (0..4).map(|_| workers.receive().unwrap()).collect();
+        results.sort_unstable();
+        assert_eq!(results, [0, 10, 20, 30]);
+        assert_eq!(workers.in_flight(), 0);
+        assert!(workers.try_receive().is_none());
+    }
+
+    #[test]
+    fn panicking_job_is_an_error() {
+        let mut workers: WorkerPool<()> = WorkerPool::new(1).unwrap();
+        workers.spawn(|| panic!("job failed"));
+        match workers.receive() {
+            Err(Error::WorkerFailed) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(workers.in_flight(), 0);
+    }
+}
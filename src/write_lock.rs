@@ -0,0 +1,440 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! A `WriteLock` excludes other processes from changing an archive: by
+//! writing a backup, deleting bands, or garbage collection.
+//!
+//! The lock is a `LOCK` file in the archive directory, describing the
+//! process that holds it. Reading from the archive never takes the lock.
+//!
+//! The lock is cooperative: it depends on every writer checking for it. The
+//! lock file is only created if it doesn't already exist, so of two processes
+//! starting at the same moment, only one gets the lock.
+//!
+//! A lock is stale, and is broken automatically, if it was left behind by a
+//! process on this machine that is no longer running, or if it was taken more
+//! than a week ago. Younger locks from other machines can only be
+//! broken explicitly, with `conserve break-lock`.
+//!
+//! Breaking a stale lock is itself guarded by a `LOCK.break` file, created
+//! only if it doesn't exist, so that of two processes that both saw the same
+//! stale lock, only one removes it: otherwise the slower one could remove the
+//! lock that the faster one had just taken.
+
+use std::fmt;
+use std::io;
+
+use chrono::{Duration, Local, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::transport::Transport;
+use crate::*;
+
+pub(crate) const LOCK_FILENAME: &str = "LOCK";
+
+/// Claimed by a process while it breaks a stale lock.
+pub(crate) const BREAK_LOCK_FILENAME: &str = "LOCK.break";
+
+/// Locks older than this are assumed to be abandoned, wherever they were taken.
+///
+/// No operation is expected to hold the lock for this long.
+const MAX_LOCK_AGE_DAYS: i64 = 7;
+
+/// Number of times to try to take the lock, if it's stale but changes while
+/// it's being broken.
+const ACQUIRE_ATTEMPTS: usize = 3;
+
+/// Contents of the lock file, describing who holds the lock.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+struct LockInfo {
+    hostname: String,
+    pid: u32,
+    /// Seconds since the Unix epoch when the lock was taken.
+    start_time: i64,
+    /// The operation holding the lock, such as "backup".
+    operation: String,
+}
+
+/// Lock on an archive that excludes other writers.
+///
+/// The lock is released when the object is dropped.
+#[derive(Debug)]
+pub struct WriteLock {
+    transport: Box<dyn Transport>,
+    info: LockInfo,
+}
+
+impl WriteLock {
+    /// Lock this archive for `operation`, such as "backup" or "gc".
+    ///
+    /// Returns `Err(Error::ArchiveLocked)` if another process holds the lock.
+    pub fn acquire(archive: &Archive, operation: &str) -> Result<WriteLock> {
+        let transport = archive.transport().box_clone();
+        let info = LockInfo {
+            hostname: hostname(),
+            pid: std::process::id(),
+            start_time: Utc::now().timestamp(),
+            operation: operation.to_owned(),
+        };
+        let json = serde_json::to_vec(&info).map_err(|source| Error::SerializeJson {
+            path: LOCK_FILENAME.to_owned(),
+            source,
+        })?;
+        for _ in 0..ACQUIRE_ATTEMPTS {
+            if create_file(transport.as_ref(), LOCK_FILENAME, &json)? {
+                return Ok(WriteLock { transport, info });
+            }
+            match read_lock_info(transport.as_ref(), LOCK_FILENAME) {
+                Ok(holder) if holder.is_stale() => {
+                    log::warn!("Breaking stale lock held by {}", holder);
+                    if break_stale_lock(transport.as_ref(), &holder, &json)? {
+                        return Ok(WriteLock { transport, info });
+                    }
+                    // The lock changed while we were breaking it; look again.
+                }
+                // Released since we tried to create it.
+                Err(Error::IOError { source }) if source.kind() == io::ErrorKind::NotFound => {}
+                _ => break,
+            }
+        }
+        Err(locked_error(transport.as_ref()))
+    }
+
+    /// Take a lock on an archive, breaking any existing lock.
+    ///
+    /// Use this only if you're confident that the process owning the lock
+    /// has terminated and the lock is stale.
+    pub fn break_lock(archive: &Archive, operation: &str) -> Result<WriteLock> {
+        WriteLock::remove(archive)?;
+        WriteLock::acquire(archive, operation)
+    }
+
+    /// Remove any lock on the archive, without taking a new one.
+    pub fn remove(archive: &Archive) -> Result<()> {
+        if WriteLock::is_locked(archive)? {
+            archive.transport().remove_file(LOCK_FILENAME)?;
+        }
+        Ok(())
+    }
+
+    /// Returns true if the archive is currently locked by a writer.
+    pub fn is_locked(archive: &Archive) -> Result<bool> {
+        archive
+            .transport()
+            .exists(LOCK_FILENAME)
+            .map_err(Error::from)
+    }
+}
+
+impl Drop for WriteLock {
+    fn drop(&mut self) {
+        // Don't remove a lock that was broken and taken by someone else.
+        //
+        // This can run while unwinding from a panic, so it mustn't panic
+        // itself: problems are only logged.
+        match read_lock_info(self.transport.as_ref(), LOCK_FILENAME) {
+            Ok(holder) if holder == self.info => {
+                if let Err(err) = self.transport.remove_file(LOCK_FILENAME) {
+                    log::warn!("Failed to delete {}: {}", LOCK_FILENAME, err)
+                }
+            }
+            Ok(holder) => log::warn!("Lock was broken and taken by {}", holder),
+            Err(err) => log::warn!("Failed to read {}: {}", LOCK_FILENAME, err),
+        }
+    }
+}
+
+impl LockInfo {
+    /// True if the lock is held by a process on this machine that's no longer
+    /// running, or was taken more than `MAX_LOCK_AGE_DAYS` ago.
+    fn is_stale(&self) -> bool {
+        (self.hostname == hostname() && !process_exists(self.pid))
+            || Utc::now().timestamp() - self.start_time
+                > Duration::days(MAX_LOCK_AGE_DAYS).num_seconds()
+    }
+}
+
+impl fmt::Display for LockInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} (pid {} on {}) since {}",
+            self.operation,
+            self.pid,
+            self.hostname,
+            Local
+                .timestamp(self.start_time, 0)
+                .format(crate::TIMESTAMP_FORMAT)
+        )
+    }
+}
+
+/// Create `filename` containing `json`, if it doesn't already exist.
+///
+/// Returns false if it exists.
+fn create_file(transport: &dyn Transport, filename: &str, json: &[u8]) -> Result<bool> {
+    match transport.create_new_file(filename, json) {
+        Ok(()) => Ok(true),
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => Ok(false),
+        Err(source) => Err(Error::WriteMetadata {
+            path: filename.to_owned(),
+            source,
+        }),
+    }
+}
+
+/// Replace the lock held by `stale` with a new lock containing `json`.
+///
+/// Returns false, leaving the lock alone, if another process is breaking the
+/// lock, or if the lock is no longer the one held by `stale`.
+fn break_stale_lock(transport: &dyn Transport, stale: &LockInfo, json: &[u8]) -> Result<bool> {
+    if !create_file(transport, BREAK_LOCK_FILENAME, json)? {
+        // A breaker that died part way through would block everyone else, so
+        // its claim can be removed by the same rules as a stale lock.
+        match read_lock_info(transport, BREAK_LOCK_FILENAME) {
+            Ok(breaker) if breaker.is_stale() => {
+                log::warn!("Removing stale claim to break the lock by {}", breaker);
+                transport.remove_file(BREAK_LOCK_FILENAME)?;
+            }
+            _ => {}
+        }
+        return Ok(false);
+    }
+    let result = match read_lock_info(transport, LOCK_FILENAME) {
+        Ok(holder) if holder == *stale => transport
+            .remove_file(LOCK_FILENAME)
+            .map_err(Error::from)
+            .and_then(|()| create_file(transport, LOCK_FILENAME, json)),
+        _ => Ok(false),
+    };
+    if let Err(err) = transport.remove_file(BREAK_LOCK_FILENAME) {
+        log::warn!("Failed to delete {}: {}", BREAK_LOCK_FILENAME, err);
+    }
+    result
+}
+
+/// Describe who holds the existing lock.
+fn locked_error(transport: &dyn Transport) -> Error {
+    Error::ArchiveLocked {
+        holder: match read_lock_info(transport, LOCK_FILENAME) {
+            Ok(holder) => holder.to_string(),
+            Err(_) => "an unknown process".to_owned(),
+        },
+    }
+}
+
+fn read_lock_info(transport: &dyn Transport, filename: &str) -> Result<LockInfo> {
+    let mut buf = Vec::new();
+    transport.read_file(filename, &mut buf)?;
+    serde_json::from_slice(&buf).map_err(|source| Error::DeserializeJson {
+        path: filename.into(),
+        source,
+    })
+}
+
+#[cfg(unix)]
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } != 0 {
+        return String::new();
+    }
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+#[cfg(not(unix))]
+fn hostname() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_default()
+}
+
+#[cfg(unix)]
+fn process_exists(pid: u32) -> bool {
+    // Signal 0 checks whether the process exists without sending anything.
+    // EPERM means it exists but belongs to someone else.
+    let signalled = unsafe { libc::kill(pid as libc::pid_t, 0) } == 0;
+    signalled || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn process_exists(_pid: u32) -> bool {
+    // Can't tell, so assume the lock is still held.
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::test_fixtures::{ScratchArchive, TreeFixture};
+
+    fn write_lock_file(
+        archive: &ScratchArchive,
+        hostname: &str,
+        pid: u32,
+        start_time: i64,
+    ) -> LockInfo {
+        let info = LockInfo {
+            hostname: hostname.to_owned(),
+            pid,
+            start_time,
+            operation: "backup".to_owned(),
+        };
+        fs::write(
+            archive.path().join(LOCK_FILENAME),
+            serde_json::to_vec(&info).unwrap(),
+        )
+        .unwrap();
+        info
+    }
+
+    #[test]
+    fn lock_and_release() {
+        let archive = ScratchArchive::new();
+        let lock = WriteLock::acquire(&archive, "test").unwrap();
+        assert!(WriteLock::is_locked(&archive).unwrap());
+        drop(lock);
+        assert!(!WriteLock::is_locked(&archive).unwrap());
+        let _lock2 = WriteLock::acquire(&archive, "test").unwrap();
+    }
+
+    #[test]
+    fn concurrent_lock_denied() {
+        let archive = ScratchArchive::new();
+        let _lock = WriteLock::acquire(&archive, "backup").unwrap();
+        match WriteLock::acquire(&archive, "gc") {
+            Err(Error::ArchiveLocked { holder }) => {
+                assert!(holder.starts_with("backup (pid "), "{}", holder)
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        // The first lock is still held.
+        assert!(WriteLock::is_locked(&archive).unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stale_lock_from_this_host_is_broken() {
+        let archive = ScratchArchive::new();
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let dead_pid = child.id();
+        child.wait().unwrap();
+        write_lock_file(&archive, &hostname(), dead_pid, Utc::now().timestamp());
+
+        let _lock = WriteLock::acquire(&archive, "test").unwrap();
+    }
+
+    #[test]
+    fn lock_from_other_host_is_not_broken() {
+        let archive = ScratchArchive::new();
+        write_lock_file(
+            &archive,
+            "some-other-host.example",
+            1,
+            Utc::now().timestamp(),
+        );
+
+        assert!(WriteLock::acquire(&archive, "test").is_err());
+        let _lock = WriteLock::break_lock(&archive, "test").unwrap();
+    }
+
+    #[test]
+    fn old_lock_from_other_host_is_broken() {
+        let archive = ScratchArchive::new();
+        write_lock_file(&archive, "some-other-host.example", 1, 0);
+
+        let _lock = WriteLock::acquire(&archive, "test").unwrap();
+        assert!(!archive.path().join(BREAK_LOCK_FILENAME).exists());
+    }
+
+    #[test]
+    fn lock_changed_since_inspected_is_not_broken() {
+        let archive = ScratchArchive::new();
+        let stale = write_lock_file(&archive, "some-other-host.example", 1, 0);
+        // Another process broke the stale lock and took its own.
+        let current = write_lock_file(
+            &archive,
+            "some-other-host.example",
+            2,
+            Utc::now().timestamp(),
+        );
+
+        assert!(!break_stale_lock(archive.transport(), &stale, b"{}").unwrap());
+        assert_eq!(
+            read_lock_info(archive.transport(), LOCK_FILENAME).unwrap(),
+            current
+        );
+        assert!(!archive.path().join(BREAK_LOCK_FILENAME).exists());
+    }
+
+    #[test]
+    fn stale_lock_not_broken_while_another_process_is_breaking_it() {
+        let archive = ScratchArchive::new();
+        write_lock_file(&archive, "some-other-host.example", 1, 0);
+        let breaker = LockInfo {
+            hostname: "some-other-host.example".to_owned(),
+            pid: 2,
+            start_time: Utc::now().timestamp(),
+            operation: "gc".to_owned(),
+        };
+        fs::write(
+            archive.path().join(BREAK_LOCK_FILENAME),
+            serde_json::to_vec(&breaker).unwrap(),
+        )
+        .unwrap();
+
+        assert!(WriteLock::acquire(&archive, "test").is_err());
+    }
+
+    /// Writers exclude each other through the archive lock, but reads don't need it.
+    #[test]
+    fn write_lock_excludes_other_writers() -> Result<()> {
+        let af = ScratchArchive::new();
+        let srcdir = TreeFixture::new();
+        srcdir.create_file("aaa");
+        af.backup(&srcdir.path(), &BackupOptions::default())?;
+
+        let lock = WriteLock::acquire(&af, "test")?;
+        for result in vec![
+            af.backup(&srcdir.path(), &BackupOptions::default())
+                .map(|_| ()),
+            af.delete_unreferenced(&DeleteOptions::default())
+                .map(|_| ()),
+            af.delete_bands(&[BandId::zero()], &DeleteOptions::default())
+                .map(|_| ()),
+        ] {
+            match result {
+                Err(Error::ArchiveLocked { .. }) => (),
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+        assert_eq!(af.list_band_ids()?, [BandId::zero()]);
+        let destdir = TempDir::new().unwrap();
+        af.restore(destdir.path(), &RestoreOptions::default())?;
+        af.validate(&ValidateOptions::default())?;
+
+        // Breaking the lock lets a writer continue.
+        let options = DeleteOptions {
+            break_lock: true,
+            ..DeleteOptions::default()
+        };
+        af.delete_unreferenced(&options)?;
+        drop(lock);
+        assert!(!af.is_locked()?);
+        af.backup(&srcdir.path(), &BackupOptions::default())?;
+        assert!(!af.is_locked()?);
+        Ok(())
+    }
+}
@@ -0,0 +1,202 @@
+// Conserve backup system.
+// Copyright 2020 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Extended attributes, including POSIX ACLs.
+//!
+//! On Linux, ACLs, SELinux contexts, and file capabilities are all stored as
+//! extended attributes (in the `system.` and `security.` namespaces), so
+//! preserving xattrs also preserves them.
+//!
+//! POSIX ACLs are only preserved on Linux, where they're the
+//! `system.posix_acl_access` and `system.posix_acl_default` xattrs; other
+//! platforms don't expose ACLs as xattrs. The stored ACLs name users and groups
+//! by numeric id, so unlike the owner they're not mapped by name on restore,
+//! and they can only be restored onto a filesystem that supports ACLs.
+//!
+//! Reading and writing xattrs requires the `xattr` cargo feature, and is only
+//! supported on Unix. Otherwise, no xattrs are read, and stored xattrs are not
+//! restored.
+
+use std::io;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+/// One extended attribute of a file.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Xattr {
+    /// Name including the namespace prefix, such as `user.mime_type`.
+    pub name: String,
+
+    /// Attribute value, which may be arbitrary bytes; hex-encoded in json.
+    #[serde(with = "hex_bytes")]
+    pub value: Vec<u8>,
+}
+
+/// Read all the xattrs of `path`, without following symlinks, in name order.
+///
+/// Attributes whose names are not UTF-8 are skipped with a warning.
+#[cfg(all(unix, feature = "xattr"))]
+pub(crate) fn read_xattrs(path: &Path) -> io::Result<Vec<Xattr>> {
+    let mut xattrs = Vec::new();
+    for os_name in xattr::list(path)? {
+        let name = match os_name.to_str() {
+            Some(name) => name.to_owned(),
+            None => {
+                log::warn!("Skipping non-UTF-8 xattr {:?} on {:?}", os_name, path);
+                continue;
+            }
+        };
+        // The attribute might have been removed since it was listed.
+        if let Some(value) = xattr::get(path, &os_name)? {
+            xattrs.push(Xattr { name, value });
+        }
+    }
+    xattrs.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+    Ok(xattrs)
+}
+
+#[cfg(not(all(unix, feature = "xattr")))]
+pub(crate) fn read_xattrs(_path: &Path) -> io::Result<Vec<Xattr>> {
+    Ok(Vec::new())
+}
+
+/// Set xattrs on `path`, without following symlinks.
+#[cfg(all(unix, feature = "xattr"))]
+pub(crate) fn write_xattrs(path: &Path, xattrs: &[Xattr]) -> io::Result<()> {
+    for x in xattrs {
+        xattr::set(path, &x.name, &x.value)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(all(unix, feature = "xattr")))]
+pub(crate) fn write_xattrs(_path: &Path, _xattrs: &[Xattr]) -> io::Result<()> {
+    Ok(())
+}
+
+mod hex_bytes {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        hex::decode(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn xattr_json_is_hex() {
+        let x = Xattr {
+            name: "user.test".to_owned(),
+            value: b"\x00hi".to_vec(),
+        };
+        let json = serde_json::to_string(&x).unwrap();
+        assert_eq!(json, r#"{"name":"user.test","value":"006869"}"#);
+        assert_eq!(serde_json::from_str::<Xattr>(&json).unwrap(), x);
+    }
+
+    #[test]
+    fn index_entry_round_trip() {
+        let mut entry = crate::IndexEntry::new("/file".into(), crate::Kind::File);
+        entry.xattrs = vec![
+            Xattr {
+                name: "system.posix_acl_access".to_owned(),
+                value: vec![2, 0, 0, 0, 1, 0, 6, 0, 0xff, 0xff, 0xff, 0xff],
+            },
+            Xattr {
+                name: "user.empty".to_owned(),
+                value: Vec::new(),
+            },
+        ];
+        let json = serde_json::to_string(&entry).unwrap();
+        assert!(json.contains(r#""value":"0200000001000600ffffffff""#));
+        assert_eq!(
+            serde_json::from_str::<crate::IndexEntry>(&json).unwrap(),
+            entry
+        );
+
+        // Entries with no xattrs don't mention them.
+        entry.xattrs.clear();
+        let json = serde_json::to_string(&entry).unwrap();
+        assert!(!json.contains("xattrs"));
+        assert_eq!(
+            serde_json::from_str::<crate::IndexEntry>(&json).unwrap(),
+            entry
+        );
+    }
+
+    #[cfg(all(unix, feature = "xattr"))]
+    #[test]
+    fn read_and_write_user_xattrs() {
+        let tf = tempfile::NamedTempFile::new().unwrap();
+        let xattrs = vec![Xattr {
+            name: "user.conserve_test".to_owned(),
+            value: b"value".to_vec(),
+        }];
+        if write_xattrs(tf.path(), &xattrs).is_err() {
+            // The temporary filesystem might not support user xattrs.
+            return;
+        }
+        let back = read_xattrs(tf.path()).unwrap();
+        assert!(back.contains(&xattrs[0]));
+    }
+
+    /// Copy a POSIX ACL from one file to another, where the filesystem supports them.
+    #[cfg(all(target_os = "linux", feature = "xattr"))]
+    #[test]
+    fn copy_posix_acl() {
+        let uid = unsafe { libc::geteuid() };
+        // An ACL giving one named user read access, beyond the mode bits: each
+        // entry is a tag, permissions, and an id, after a version header.
+        let mut acl = vec![2, 0, 0, 0];
+        for (tag, perm, id) in &[
+            (0x01u16, 6u16, u32::MAX),
+            (0x02, 4, uid),
+            (0x04, 4, u32::MAX),
+            (0x10, 4, u32::MAX),
+            (0x20, 4, u32::MAX),
+        ] {
+            acl.extend_from_slice(&tag.to_le_bytes());
+            acl.extend_from_slice(&perm.to_le_bytes());
+            acl.extend_from_slice(&id.to_le_bytes());
+        }
+        let acl = Xattr {
+            name: "system.posix_acl_access".to_owned(),
+            value: acl,
+        };
+        let from = tempfile::NamedTempFile::new().unwrap();
+        if write_xattrs(from.path(), &[acl]).is_err() {
+            // The temporary filesystem might not support ACLs.
+            return;
+        }
+        let find_acl = |path: &Path| {
+            read_xattrs(path)
+                .unwrap()
+                .into_iter()
+                .find(|x| x.name == "system.posix_acl_access")
+        };
+        let stored = find_acl(from.path()).expect("ACL was set");
+
+        let to = tempfile::NamedTempFile::new().unwrap();
+        write_xattrs(to.path(), &[stored.clone()]).unwrap();
+        assert_eq!(find_acl(to.path()), Some(stored));
+    }
+}
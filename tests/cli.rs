@@ -24,6 +24,7 @@ use lazy_static::lazy_static;
 use predicates::prelude::*;
 
 use conserve::test_fixtures::{ScratchArchive, TreeFixture};
+use conserve::Band;
 
 lazy_static! {
     // This doesn's pass `.current_target()` because it doesn't seem
@@ -73,7 +74,7 @@ fn clean_error_on_non_archive() {
         .arg(".")
         .assert()
         .failure()
-        .stdout(predicate::str::contains("Not a Conserve archive"));
+        .stderr(predicate::str::contains("Not a Conserve archive"));
 }
 
 #[test]
@@ -149,12 +150,21 @@ fn basic_backup() {
         .assert()
         .success()
         .stderr(predicate::str::is_empty())
+        .stdout("");
+
+    run_conserve()
+        .args(&["diff", "--include-unchanged"])
+        .arg(&arch_dir)
+        .arg(&src)
+        .assert()
+        .success()
+        .stderr(predicate::str::is_empty())
         .stdout(
             "\
-both     /
-both     /hello
-both     /subdir
-both     /subdir/subfile
+unchanged /
+unchanged /hello
+unchanged /subdir
+unchanged /subdir/subfile
 ",
         );
 
@@ -201,13 +211,46 @@ both     /subdir/subfile
         .stderr("")
         .stdout("");
 
-    run_conserve()
+    let output = run_conserve()
         .args(&["debug", "index"])
         .arg(&arch_dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(output.stderr.is_empty());
+    let apaths: Vec<String> = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| {
+            let entry: serde_json::Value = serde_json::from_str(line).unwrap();
+            entry["apath"].as_str().unwrap().to_owned()
+        })
+        .collect();
+    assert_eq!(apaths, ["/", "/hello", "/subdir", "/subdir/subfile"]);
+
+    let output = run_conserve()
+        .args(&["debug", "index", "--pretty"])
+        .arg(&arch_dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let entries: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(entries.as_array().unwrap().len(), 4);
+
+    // The whole small index is in hunk 0; there's no hunk 1.
+    let output = run_conserve()
+        .args(&["debug", "index", "--hunk", "0"])
+        .arg(&arch_dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap().lines().count(), 4);
+    run_conserve()
+        .args(&["debug", "index", "--hunk", "1"])
+        .arg(&arch_dir)
         .assert()
         .success()
-        .stderr(predicate::str::is_empty());
-    // TODO: Deserialize index json, or somehow check it.
+        .stdout("");
 
     // gc: should find no garbage.
     run_conserve().arg("gc").arg(&arch_dir).assert().success();
@@ -293,7 +336,7 @@ both     /subdir/subfile
         .arg(restore_dir.path())
         .assert()
         .failure()
-        .stdout(predicate::str::contains("Destination directory not empty"));
+        .stderr(predicate::str::contains("Destination directory not empty"));
 
     // Restore with specified band id / backup version.
     {
@@ -317,6 +360,14 @@ both     /subdir/subfile
         .stderr(predicate::str::is_empty())
         .stdout(predicate::str::contains("Archive is OK.\n"));
 
+    run_conserve()
+        .args(&["validate", "--quick"])
+        .arg(&arch_dir)
+        .assert()
+        .success()
+        .stderr(predicate::str::is_empty())
+        .stdout(predicate::str::contains("Archive is OK.\n"));
+
     // TODO: Compare vs source tree.
 }
 
@@ -334,14 +385,14 @@ fn empty_archive() {
         .arg(restore_dir.path())
         .assert()
         .failure()
-        .stdout(predicate::str::contains("Archive has no bands"));
+        .stderr(predicate::str::contains("Archive has no bands"));
 
     run_conserve()
         .arg("ls")
         .arg(&adir)
         .assert()
         .failure()
-        .stdout(predicate::str::contains("Archive has no bands"));
+        .stderr(predicate::str::contains("Archive has no bands"));
 
     run_conserve()
         .arg("versions")
@@ -379,7 +430,7 @@ fn incomplete_version() {
         .arg(af.path())
         .assert()
         .failure()
-        .stdout(predicate::str::contains("incomplete and may be in use"));
+        .stderr(predicate::str::contains("incomplete and may be in use"));
 }
 
 #[test]
@@ -404,15 +455,151 @@ fn exclude_option_ordering() {
         .success();
 }
 
+#[test]
+fn backup_dry_run() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+
+    run_conserve()
+        .args(&["backup", "--dry-run"])
+        .arg(af.path())
+        .arg(&src.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "Backup dry run complete; nothing was written.",
+        ));
+
+    assert!(af.list_band_ids().unwrap().is_empty());
+}
+
+#[test]
+fn backup_stdin() {
+    let af = ScratchArchive::new();
+
+    // std::process::Command can't take stdin from a string, so use assert_cmd's
+    // own Command for this one.
+    assert_cmd::Command::cargo_bin("conserve")
+        .unwrap()
+        .args(&["backup-stdin", "--name", "/db/dump.sql"])
+        .arg(af.path())
+        .write_stdin("some data\n")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Backup complete."));
+
+    run_conserve()
+        .args(&["ls"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout("/\n/db\n/db/dump.sql\n");
+}
+
+#[test]
+fn watch_makes_a_backup() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+
+    run_conserve()
+        .args(&["watch", "--interval", "1s", "--max-backups", "1"])
+        .arg(af.path())
+        .arg(&src.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("1      backups made"));
+    assert_eq!(af.list_band_ids().unwrap().len(), 1);
+
+    run_conserve()
+        .args(&["watch", "--interval", "soon"])
+        .arg(af.path())
+        .arg(&src.path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Invalid duration \"soon\""));
+}
+
+#[test]
+fn exit_codes() {
+    run_conserve()
+        .arg("--help")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("EXIT CODES:"));
+
+    // Missing arguments.
+    run_conserve().arg("backup").assert().code(5);
+
+    let tempdir = TempDir::new().unwrap();
+    run_conserve()
+        .arg("ls")
+        .arg(tempdir.path())
+        .assert()
+        .code(5)
+        .stderr(predicate::str::contains("Not a Conserve archive"));
+
+    // A damaged archive.
+    run_conserve()
+        .args(&["validate", "testdata/damaged/missing-block/"])
+        .assert()
+        .code(2);
+
+    // A file whose block is missing can't be restored, but the rest can.
+    run_conserve()
+        .args(&["restore", "testdata/damaged/missing-block/"])
+        .arg(tempdir.path().join("restore"))
+        .assert()
+        .code(3);
+
+    // The stats can't be written.
+    run_conserve()
+        .args(&[
+            "validate",
+            "testdata/archive/v0.6.3/minimal-1/",
+            "--stats-json",
+        ])
+        .arg(tempdir.path().join("nonexistent/stats.json"))
+        .assert()
+        .code(4);
+
+    // Restoring a band that doesn't exist.
+    run_conserve()
+        .args(&[
+            "restore",
+            "-b",
+            "b9999",
+            "testdata/archive/v0.6.3/minimal-1/",
+        ])
+        .arg(tempdir.path().join("missing-band"))
+        .assert()
+        .code(5);
+}
+
 #[test]
 fn validate_non_fatal_problems_nonzero_result() {
     run_conserve()
         .args(&["validate", "testdata/damaged/missing-block/"])
         .assert()
-        .stdout(predicate::str::contains("Archive has some problems."))
+        .stderr(predicate::str::contains("Archive has some problems."))
         .code(2);
 }
 
+#[test]
+fn validate_json_stats() {
+    // Problems go to stderr, leaving only the json on stdout.
+    let output = run_conserve()
+        .args(&["validate", "--json", "testdata/damaged/missing-block/"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    let stats: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(stats["block_missing_count"], 1);
+    assert_eq!(stats["block_wrong_hash_count"], 0);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("conserve error: "));
+}
+
 #[test]
 fn restore_only_subtree() {
     let dest = TempDir::new().unwrap();
@@ -436,6 +623,157 @@ fn restore_only_subtree() {
     dest.close().unwrap();
 }
 
+#[test]
+fn restore_incomplete_band() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    Band::create(&af).unwrap();
+    let dest = TempDir::new().unwrap();
+
+    run_conserve()
+        .args(&["restore", "--backup", "b0002"])
+        .arg(af.path())
+        .arg(dest.path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Band b0002 is incomplete"));
+
+    run_conserve()
+        .args(&["restore", "--backup", "b0002", "--incomplete"])
+        .arg(af.path())
+        .arg(dest.path())
+        .assert()
+        .success();
+    dest.child("hello2").assert(predicate::path::is_file());
+}
+
+#[test]
+fn restore_skip_existing() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    let dest = TempDir::new().unwrap();
+    dest.child("hello").write_str("local changes").unwrap();
+
+    run_conserve()
+        .args(&["restore", "--skip-existing"])
+        .arg(af.path())
+        .arg(dest.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "1      skipped because they already exist",
+        ));
+    dest.child("hello").assert("local changes");
+    dest.child("hello2").assert(predicate::path::is_file());
+
+    run_conserve()
+        .args(&["restore", "--skip-existing", "--force"])
+        .arg(af.path())
+        .arg(dest.path())
+        .assert()
+        .failure();
+}
+
+#[test]
+fn restore_dry_run() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    let dest = TempDir::new().unwrap();
+    dest.child("hello").write_str("local changes").unwrap();
+
+    run_conserve()
+        .args(&["restore", "--dry-run", "--force"])
+        .arg(af.path())
+        .arg(dest.path())
+        .assert()
+        .success()
+        .stdout(
+            predicate::str::contains("overwrite /hello\n")
+                .and(predicate::str::contains("create /hello2\n"))
+                .and(predicate::str::contains("create /subdir/subfile\n"))
+                .and(predicate::str::contains(
+                    "Restore dry run complete; nothing was written.\n",
+                )),
+        );
+    dest.child("hello").assert("local changes");
+    dest.child("subdir").assert(predicate::path::missing());
+}
+
+#[test]
+fn cat_file() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+
+    run_conserve()
+        .arg("cat")
+        .arg(af.path())
+        .arg("/subdir/subfile")
+        .assert()
+        .success()
+        .stdout("contents");
+
+    run_conserve()
+        .arg("cat")
+        .arg(af.path())
+        .arg("/subdir")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("/subdir is a Dir, not a file"));
+
+    // Messages are kept out of the file content on stdout.
+    std::fs::write(af.path().join("CONFIG"), r#"{"compression_level":99}"#).unwrap();
+    run_conserve()
+        .arg("cat")
+        .arg(af.path())
+        .arg("/subdir/subfile")
+        .assert()
+        .success()
+        .stdout("contents")
+        .stderr(predicate::str::contains("compression_level 99"));
+}
+
+#[test]
+fn verify_against_source() {
+    let af = ScratchArchive::new();
+    let tf = TreeFixture::new();
+    tf.create_file_with_contents("hello", b"hello");
+    tf.create_dir("subdir");
+    run_conserve()
+        .arg("backup")
+        .arg(af.path())
+        .arg(tf.path())
+        .assert()
+        .success();
+
+    run_conserve()
+        .args(&["verify", "--content"])
+        .arg(af.path())
+        .arg(tf.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Source matches the backup."));
+
+    tf.create_file_with_contents("hello", b"jello");
+    tf.create_file("new");
+    run_conserve()
+        .arg("verify")
+        .arg(af.path())
+        .arg(tf.path())
+        .assert()
+        .code(2)
+        .stdout(predicate::str::starts_with("added    /new\n"));
+
+    run_conserve()
+        .args(&["verify", "--content"])
+        .arg(af.path())
+        .arg(tf.path())
+        .assert()
+        .code(2)
+        .stdout(predicate::str::starts_with(
+            "content  /hello\nadded    /new\n",
+        ));
+}
+
 #[test]
 fn delete_bands() {
     let af = ScratchArchive::new();
@@ -450,6 +788,21 @@ fn delete_bands() {
         .success();
 }
 
+#[test]
+fn delete_dry_run() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+
+    run_conserve()
+        .args(&["delete", "--dry-run", "-b", "b0000"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("0      backups deleted"))
+        .stdout(predicate::str::contains("Dry run: nothing was deleted."));
+    assert!(af.path().join("b0000").is_dir());
+}
+
 #[test]
 fn delete_nonexistent_band() {
     let af = ScratchArchive::new();
@@ -466,6 +819,596 @@ fn delete_nonexistent_band() {
         .args(&["-b", "b0000"])
         .arg(af.path())
         .assert()
-        .stdout(pred_fn)
+        .stderr(pred_fn)
+        .failure();
+}
+
+#[test]
+fn config_is_used_by_backup() {
+    let testdir = TempDir::new().unwrap();
+    let arch_dir = testdir.path().join("a");
+    run_conserve()
+        .args(&["init", "--exclude", "/*.tmp"])
+        .arg(&arch_dir)
+        .assert()
+        .success();
+    run_conserve()
+        .args(&["config", "--block-size", "65536"])
+        .arg(&arch_dir)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("\"block_size\": 65536"))
+        .stdout(predicate::str::contains("\"/*.tmp\""));
+
+    let src = TreeFixture::new();
+    src.create_file("hello");
+    src.create_file("scratch.tmp");
+    run_conserve()
+        .arg("backup")
+        .arg(&arch_dir)
+        .arg(src.path())
+        .assert()
+        .success();
+    run_conserve()
+        .arg("ls")
+        .arg(&arch_dir)
+        .assert()
+        .success()
+        .stdout("/\n/hello\n");
+
+    run_conserve()
+        .args(&["config", "--block-size", "12"])
+        .arg(&arch_dir)
+        .assert()
+        .stderr(predicate::str::contains("Invalid archive configuration"))
         .failure();
 }
+
+#[test]
+fn relative_mirror_is_stored_as_absolute() {
+    let testdir = TempDir::new().unwrap();
+    run_conserve()
+        .args(&["init", "a"])
+        .current_dir(testdir.path())
+        .assert()
+        .success();
+    run_conserve()
+        .args(&["config", "a", "--mirror", "mirror"])
+        .current_dir(testdir.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            testdir.path().join("mirror").to_str().unwrap(),
+        ));
+}
+
+#[test]
+fn tag_and_restore_by_tag() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+
+    run_conserve()
+        .args(&["tag"])
+        .arg(af.path())
+        .args(&["b0000", "quarterly"])
+        .assert()
+        .success();
+    run_conserve()
+        .args(&["versions"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::is_match(r"(?m)^b0000 +complete .* quarterly$").unwrap());
+
+    let dest = TempDir::new().unwrap();
+    run_conserve()
+        .args(&["restore", "--backup", "quarterly"])
+        .arg(af.path())
+        .arg(dest.path())
+        .assert()
+        .success();
+    // Does not have the 'hello2' file added in the second version.
+    dest.child("hello").assert(predicate::path::is_file());
+    dest.child("hello2").assert(predicate::path::missing());
+
+    run_conserve()
+        .args(&["restore", "--backup", "nonesuch"])
+        .arg(af.path())
+        .arg(dest.path().join("other"))
+        .assert()
+        .stderr(predicate::str::contains(
+            "No backup has id or tag \"nonesuch\"",
+        ))
+        .failure();
+}
+
+#[test]
+fn append_only_requires_allow_delete() {
+    let testdir = TempDir::new().unwrap();
+    let arch_dir = testdir.path().join("a");
+    run_conserve()
+        .args(&["init", "--append-only"])
+        .arg(&arch_dir)
+        .assert()
+        .success();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+    run_conserve()
+        .arg("backup")
+        .arg(&arch_dir)
+        .arg(src.path())
+        .assert()
+        .success();
+
+    run_conserve()
+        .args(&["delete", "-b", "b0000"])
+        .arg(&arch_dir)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Archive is append-only"));
+    run_conserve()
+        .arg("tag")
+        .arg(&arch_dir)
+        .args(&["b0000", "old"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Archive is append-only"));
+    run_conserve()
+        .arg("migrate")
+        .arg(&arch_dir)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Archive is append-only"));
+    run_conserve()
+        .args(&["migrate", "--dry-run"])
+        .arg(&arch_dir)
+        .assert()
+        .success();
+    run_conserve()
+        .args(&["tag", "--allow-delete"])
+        .arg(&arch_dir)
+        .args(&["b0000", "old"])
+        .assert()
+        .success();
+    run_conserve()
+        .args(&["delete", "-b", "b0000", "--allow-delete"])
+        .arg(&arch_dir)
+        .assert()
+        .success();
+    run_conserve()
+        .arg("versions")
+        .arg(&arch_dir)
+        .assert()
+        .success()
+        .stdout("");
+}
+
+#[test]
+fn export_tar_to_stdout() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file_with_contents("hello", b"contents");
+    af.backup(&src.path(), &conserve::BackupOptions::default())
+        .unwrap();
+
+    let output = run_conserve()
+        .arg("export-tar")
+        .arg(af.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let mut tar = tar::Archive::new(output.stdout.as_slice());
+    let names: Vec<String> = tar
+        .entries()
+        .unwrap()
+        .map(|e| e.unwrap().path().unwrap().to_string_lossy().into_owned())
+        .collect();
+    assert_eq!(names, ["hello"]);
+}
+
+#[cfg(unix)]
+#[test]
+fn export_tar_to_stdout_keeps_warnings_out() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file_with_contents("hello", b"contents");
+    src.create_symlink("link", &"long".repeat(50));
+    af.backup(&src.path(), &conserve::BackupOptions::default())
+        .unwrap();
+
+    let output = run_conserve()
+        .arg("export-tar")
+        .arg(af.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("conserve warning: Symlink target of /link is too long for tar"));
+    let mut tar = tar::Archive::new(output.stdout.as_slice());
+    let names: Vec<String> = tar
+        .entries()
+        .unwrap()
+        .map(|e| e.unwrap().path().unwrap().to_string_lossy().into_owned())
+        .collect();
+    assert_eq!(names, ["hello"]);
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("conserve warning"));
+}
+
+#[test]
+fn log_file_history() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+
+    run_conserve()
+        .arg("log")
+        .arg(af.path())
+        .arg("/hello2")
+        .assert()
+        .success()
+        .stdout(
+            predicate::str::is_match(
+                r"^b0000 +complete +\S+ \S+ absent\nb0001 +complete +\S+ \S+ added +\d+ \S+ \S+\n$",
+            )
+            .unwrap(),
+        );
+
+    let output = run_conserve()
+        .args(&["log", "--json"])
+        .arg(af.path())
+        .arg("/hello")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let changes: Vec<String> = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| {
+            let json: serde_json::Value = serde_json::from_str(line).unwrap();
+            json["change"].as_str().unwrap().to_owned()
+        })
+        .collect();
+    assert_eq!(changes, ["added", "unchanged"]);
+
+    // Apaths must be absolute.
+    run_conserve()
+        .arg("log")
+        .arg(af.path())
+        .arg("hello")
+        .assert()
+        .code(5);
+}
+
+#[test]
+fn versions_json() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    af.add_tags(&conserve::BandId::zero(), &["first".to_owned()])
+        .unwrap();
+    af.setup_incomplete_empty_band();
+
+    let output = run_conserve()
+        .args(&["versions", "--json", "--sizes"])
+        .arg(af.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let bands: Vec<serde_json::Value> = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(bands.len(), 3);
+    assert_eq!(bands[0]["id"], "b0000");
+    assert_eq!(bands[0]["status"], "complete");
+    assert_eq!(bands[0]["is_complete"], true);
+    assert_eq!(bands[0]["tags"], serde_json::json!(["first"]));
+    assert!(bands[0]["end_time"].is_string());
+    assert!(bands[0]["size_bytes"].is_u64());
+    assert_eq!(bands[2]["id"], "b0002");
+    assert_eq!(bands[2]["status"], "incomplete");
+    assert_eq!(bands[2]["is_complete"], false);
+    assert!(bands[2]["end_time"].is_null());
+    assert!(bands[2]["duration_secs"].is_null());
+}
+
+#[test]
+fn diff_two_versions() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+
+    run_conserve()
+        .args(&["diff", "-b", "b0000", "-b", "b0001"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout("new       /hello2\n");
+
+    run_conserve()
+        .args(&["diff", "--stat", "-b", "b0001", "-b", "b0000"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("           1      deleted\n"));
+
+    run_conserve()
+        .args(&["diff", "-b", "b0000"])
+        .arg(af.path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Diff needs either two backups"));
+}
+
+#[test]
+fn diff_against_source() {
+    let af = ScratchArchive::new();
+    let tf = TreeFixture::new();
+    tf.create_file_with_contents("hello", b"hello");
+    tf.create_file_with_contents("old", b"old");
+    tf.create_dir("subdir");
+    run_conserve()
+        .arg("backup")
+        .arg(af.path())
+        .arg(tf.path())
+        .assert()
+        .success();
+
+    tf.create_file_with_contents("hello", b"hello, world");
+    tf.create_file("new");
+    tf.create_file("junk.tmp");
+    std::fs::remove_file(tf.path().join("old")).unwrap();
+    run_conserve()
+        .args(&["diff", "-e", "*.tmp"])
+        .arg(af.path())
+        .arg(tf.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::ends_with(
+            "changed   /hello\nnew       /new\ndeleted   /old\n",
+        ));
+
+    run_conserve()
+        .args(&["diff", "--stat", "-b", "b0000", "-e", "*.tmp"])
+        .arg(af.path())
+        .arg(tf.path())
+        .assert()
+        .success()
+        .stdout(
+            predicate::str::contains("           1      new\n")
+                .and(predicate::str::contains("           1      deleted\n")),
+        );
+}
+
+#[test]
+fn ls_formats_and_filters() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+
+    run_conserve()
+        .args(&["ls", "--path", "/subdir"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout("/subdir\n/subdir/subfile\n");
+
+    run_conserve()
+        .args(&["ls", "--null", "--include", "/hello*"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout("/hello\0/hello2\0");
+
+    run_conserve()
+        .args(&["ls", "-l", "--path", "/subdir"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::is_match(r"^drwx.{6}            - \d{4}-\d\d-\d\d \d\d:\d\d:\d\d /subdir\n-rw.{7}            8 .* /subdir/subfile\n$").unwrap());
+
+    let output = run_conserve()
+        .args(&["ls", "--json", "--include", "/subdir/*"])
+        .arg(af.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let entry: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(entry["apath"], "/subdir/subfile");
+    assert_eq!(entry["kind"], "File");
+    assert_eq!(entry["size"], 8);
+    assert!(entry["mtime"].is_string());
+    assert!(entry["target"].is_null());
+}
+
+#[test]
+fn du_in_bytes() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+
+    run_conserve()
+        .args(&["du", "--bytes"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout("             24               8  /\n              8               0  /subdir\n");
+}
+
+#[test]
+fn json_progress_on_stderr() {
+    let af = ScratchArchive::new();
+    let tf = TreeFixture::new();
+    tf.create_file("hello");
+
+    let output = run_conserve()
+        .args(&["backup", "--progress=json"])
+        .arg(af.path())
+        .arg(tf.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .starts_with("Backup complete.\n"));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(!stderr.is_empty());
+    for line in stderr.lines() {
+        let progress: serde_json::Value = serde_json::from_str(line).unwrap();
+        assert!(progress["phase"].is_string());
+        assert!(progress["bytes_done"].is_u64());
+    }
+
+    run_conserve()
+        .args(&["--progress=fancy", "versions"])
+        .arg(af.path())
+        .assert()
+        .failure();
+}
+
+#[test]
+fn info_json() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+
+    run_conserve()
+        .arg("info")
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "           2      complete versions\n",
+        ));
+
+    let output = run_conserve()
+        .args(&["info", "--json"])
+        .arg(af.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let info: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(info["complete_versions"], 2);
+    assert_eq!(info["incomplete_versions"], 0);
+    assert_eq!(info["block_count"], 1);
+    assert_eq!(info["deduplicated_bytes"], 8);
+    assert!(info["newest_backup"].is_string());
+}
+
+#[test]
+fn log_file_and_verbosity() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    let temp = TempDir::new().unwrap();
+    let log_path = temp.path().join("conserve.log");
+
+    run_conserve()
+        .args(&["-q", "validate"])
+        .arg(af.path())
+        .arg("--log-file")
+        .arg(&log_path)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Check blockdir").not());
+    let log = std::fs::read_to_string(&log_path).unwrap();
+    assert!(log.contains(" INFO  conserve::archive Check blockdir...\n"));
+
+    run_conserve()
+        .arg("validate")
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Check blockdir...\n"));
+
+    // Like other global options, --quiet can come after the command.
+    run_conserve()
+        .arg("validate")
+        .arg(af.path())
+        .arg("--quiet")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Check blockdir").not());
+
+    run_conserve()
+        .args(&["ls", "--log-file"])
+        .arg(&log_path)
+        .arg(temp.path().join("nothing"))
+        .assert()
+        .failure()
+        .stderr(predicate::str::starts_with("conserve error: "));
+    let log = std::fs::read_to_string(&log_path).unwrap();
+    assert!(log.contains(" ERROR "));
+}
+
+#[test]
+fn warnings_are_not_shown_as_errors() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+    // A lock taken on another machine more than a week ago is broken, with a warning.
+    std::fs::write(
+        af.path().join("LOCK"),
+        r#"{"hostname":"elsewhere","pid":1,"start_time":0,"operation":"backup"}"#,
+    )
+    .unwrap();
+
+    run_conserve()
+        .arg("backup")
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "conserve warning: Breaking stale lock held by backup (pid 1 on elsewhere)",
+        ))
+        .stderr(predicate::str::contains("conserve error").not());
+}
+
+#[test]
+fn stats_json() {
+    let af = ScratchArchive::new();
+    let tf = TreeFixture::new();
+    tf.create_file("hello");
+    let testdir = TempDir::new().unwrap();
+    let stats_path = testdir.path().join("backup-stats.json");
+
+    run_conserve()
+        .arg("backup")
+        .arg(af.path())
+        .arg(tf.path())
+        .arg("--stats-json")
+        .arg(&stats_path)
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with("Backup complete.\n"));
+    let stats: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&stats_path).unwrap()).unwrap();
+    assert_eq!(stats["files"], 1);
+    assert_eq!(stats["new_files"], 1);
+    assert!(stats["times"]["elapsed"].is_f64());
+
+    let output = run_conserve()
+        .args(&["restore", "--verbose", "--stats-json", "-"])
+        .arg(af.path())
+        .arg(testdir.path().join("restore"))
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stats: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(stats["files"], 1);
+    // Listed filenames are kept off stdout.
+    assert!(String::from_utf8_lossy(&output.stderr).contains("/hello"));
+
+    let output = run_conserve()
+        .args(&["validate", "--stats-json", "-"])
+        .arg(af.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stats: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(stats["block_error_count"], 0);
+
+    let output = run_conserve()
+        .args(&["gc", "--stats-json", "-"])
+        .arg(af.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stats: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(stats["deleted_block_count"], 0);
+}
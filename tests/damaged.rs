@@ -13,6 +13,7 @@
 
 //! Test validation of archives with some problems.
 
+use std::io::Read;
 use std::path::Path;
 
 use conserve::*;
@@ -21,8 +22,44 @@ use conserve::*;
 fn missing_block() -> Result<()> {
     let archive = Archive::open_path(Path::new("testdata/damaged/missing-block"))?;
 
-    let validate_stats = archive.validate()?;
+    let validate_stats = archive.validate(&ValidateOptions::default())?;
     assert_eq!(validate_stats.has_problems(), true);
     assert_eq!(validate_stats.block_missing_count, 1);
     Ok(())
 }
+
+#[test]
+fn missing_block_quick() -> Result<()> {
+    let archive = Archive::open_path(Path::new("testdata/damaged/missing-block"))?;
+
+    let validate_stats = archive.validate(&ValidateOptions {
+        quick: true,
+        ..ValidateOptions::default()
+    })?;
+    assert_eq!(validate_stats.has_problems(), true);
+    assert_eq!(validate_stats.block_missing_count, 1);
+    assert_eq!(validate_stats.block_read_count, 0);
+    Ok(())
+}
+
+#[test]
+fn missing_block_is_read_error() -> Result<()> {
+    let archive = Archive::open_path(Path::new("testdata/damaged/missing-block"))?;
+    let tree = archive.open_stored_tree(BandSelectionPolicy::Latest)?;
+    let mut errors = Vec::new();
+    for entry in tree.iter_entries()? {
+        if entry.kind() == Kind::File {
+            let mut content = Vec::new();
+            if let Err(err) = tree.file_contents(&entry)?.read_to_end(&mut content) {
+                errors.push(err);
+            }
+        }
+    }
+    assert_eq!(errors.len(), 1);
+    // The archive's error is kept inside the io::Error.
+    assert!(errors[0]
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<Error>())
+        .is_some());
+    Ok(())
+}
@@ -42,6 +42,7 @@ fn unreferenced_blocks() {
             dry_run: true,
             break_lock: false,
             no_gc: false,
+            progress: None,
         })
         .unwrap();
     assert_eq!(
@@ -86,32 +87,3 @@ fn unreferenced_blocks() {
         }
     );
 }
-
-#[test]
-fn backup_prevented_by_gc_lock() -> Result<()> {
-    let archive = ScratchArchive::new();
-    let tf = TreeFixture::new();
-    tf.create_file("hello");
-
-    let lock1 = GarbageCollectionLock::new(&archive)?;
-
-    // Backup should fail while gc lock is held.
-    let backup_result = archive.backup(&tf.path(), &BackupOptions::default());
-    match backup_result {
-        Err(Error::GarbageCollectionLockHeld) => (),
-        other => panic!("unexpected result {:?}", other),
-    };
-
-    // Leak the lock, then gc breaking the lock.
-    std::mem::forget(lock1);
-    archive.delete_unreferenced(&DeleteOptions {
-        break_lock: true,
-        ..Default::default()
-    })?;
-
-    // Backup should now succeed.
-    let backup_result = archive.backup(&tf.path(), &BackupOptions::default());
-    assert!(backup_result.is_ok());
-
-    Ok(())
-}
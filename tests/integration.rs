@@ -15,6 +15,7 @@
 use std::fs;
 use std::fs::File;
 use std::io::prelude::*;
+use std::str::FromStr;
 
 use assert_fs::prelude::*;
 use assert_fs::TempDir;
@@ -47,6 +48,8 @@ pub fn simple_backup() {
     assert_eq!(archive.band_exists(&BandId::zero()).unwrap(), true);
     assert_eq!(archive.band_is_closed(&BandId::zero()).unwrap(), true);
     assert_eq!(archive.band_exists(&BandId::new(&[1])).unwrap(), false);
+    let band = Band::open(&archive, &BandId::zero()).unwrap();
+    assert_eq!(band.get_info().unwrap().tree_bytes, Some(8));
     let copy_stats = archive
         .restore(&restore_dir.path(), &RestoreOptions::default())
         .expect("restore");
@@ -54,6 +57,59 @@ pub fn simple_backup() {
     assert_eq!(copy_stats.uncompressed_bytes, 8);
 }
 
+#[test]
+pub fn backup_and_restore_stats_summaries() -> Result<()> {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file("hello");
+    let backup_stats = af.backup(&srcdir.path(), &BackupOptions::default())?;
+
+    let mut text = Vec::new();
+    backup_stats.backup().summarize(&mut text)?;
+    let text = String::from_utf8(text).unwrap();
+    assert!(text.contains("           1        new files\n"), "{}", text);
+
+    let mut json = Vec::new();
+    backup_stats.backup().summarize_json(&mut json)?;
+    let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+    assert_eq!(json["files"], 1);
+    assert_eq!(json["new_files"], 1);
+    assert_eq!(json["index_builder_stats"]["index_hunks"], 1);
+
+    let restore_dir = TempDir::new().unwrap();
+    let restore_stats = af.restore(restore_dir.path(), &RestoreOptions::default())?;
+    let mut text = Vec::new();
+    restore_stats.restore().summarize(&mut text)?;
+    let text = String::from_utf8(text).unwrap();
+    assert!(text.contains("           1      files\n"), "{}", text);
+    Ok(())
+}
+
+#[test]
+pub fn backup_records_phase_times() -> Result<()> {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file("hello");
+    let stats = af.backup(&srcdir.path(), &BackupOptions::default())?;
+    let times = stats.times;
+    assert!(times.elapsed > std::time::Duration::from_secs(0));
+    assert!(times.elapsed >= times.source_walk);
+    assert!(times.elapsed >= stats.index_builder_stats.write_time);
+
+    let mut text = Vec::new();
+    stats.backup().summarize(&mut text)?;
+    let text = String::from_utf8(text).unwrap();
+    assert!(text.contains(" s      hashing\n"), "{}", text);
+    assert!(text.contains(" s      elapsed\n"), "{}", text);
+
+    let mut json = Vec::new();
+    stats.backup().summarize_json(&mut json)?;
+    let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+    assert!(json["times"]["elapsed"].as_f64().unwrap() > 0.0);
+    assert!(json["index_builder_stats"]["write_time"].is_f64());
+    Ok(())
+}
+
 #[test]
 pub fn simple_backup_with_excludes() -> Result<()> {
     let af = ScratchArchive::new();
@@ -100,7 +156,7 @@ pub fn simple_backup_with_excludes() -> Result<()> {
     // TODO: Check index stats.
     // TODO: Check what was restored.
 
-    let validate_stats = af.validate().unwrap();
+    let validate_stats = af.validate(&ValidateOptions::default()).unwrap();
     assert!(!validate_stats.has_problems());
     Ok(())
 }
@@ -123,6 +179,7 @@ pub fn backup_more_excludes() {
     let options = BackupOptions {
         excludes,
         print_filenames: false,
+        ..BackupOptions::default()
     };
     let stats = af.backup(&srcdir.path(), &options).expect("backup");
 
@@ -178,6 +235,335 @@ fn check_backup(af: &ScratchArchive) {
     assert_eq!(af.unreferenced_blocks().unwrap().count(), 0);
 }
 
+/// An interrupted backup can be resumed, continuing in the same band.
+#[test]
+fn resume_interrupted_backup() -> Result<()> {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file("aaa");
+    srcdir.create_file("bbb");
+    af.backup(&srcdir.path(), &BackupOptions::default())?;
+
+    // Simulate an interruption before the band was closed, and then more
+    // files being added after the point where it stopped.
+    fs::remove_file(af.path().join("b0000").join("BANDTAIL"))?;
+    assert_eq!(af.band_is_closed(&BandId::zero())?, false);
+    srcdir.create_file("ccc");
+
+    let options = BackupOptions {
+        resume: true,
+        ..BackupOptions::default()
+    };
+    let stats = af.backup(&srcdir.path(), &options)?;
+    // Only the new file is copied.
+    assert_eq!(stats.files, 1);
+    assert_eq!(stats.directories, 0);
+
+    assert_eq!(af.list_band_ids()?, [BandId::zero()]);
+    let band = Band::open(&af, &BandId::zero())?;
+    assert!(band.is_closed()?);
+    assert_eq!(band.get_info()?.index_hunk_count, Some(2));
+    let names: Vec<String> = band.iter_entries()?.map(|e| e.apath.into()).collect();
+    assert_eq!(names, ["/", "/aaa", "/bbb", "/ccc"]);
+
+    // Now there's nothing left to resume.
+    match af.backup(&srcdir.path(), &options) {
+        Err(Error::NothingToResume { band_id }) => assert_eq!(band_id, BandId::zero()),
+        other => panic!("unexpected result {:?}", other),
+    }
+    Ok(())
+}
+
+/// Bands left by interrupted backups can be found, and then sealed or deleted.
+#[test]
+fn fix_incomplete_bands() -> Result<()> {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file("aaa");
+    srcdir.create_file("bbb");
+    af.backup(&srcdir.path(), &BackupOptions::default())?;
+    af.backup(&srcdir.path(), &BackupOptions::default())?;
+    af.backup(&srcdir.path(), &BackupOptions::default())?;
+    // Simulate two interrupted backups.
+    fs::remove_file(af.path().join("b0001").join("BANDTAIL"))?;
+    fs::remove_file(af.path().join("b0002").join("BANDTAIL"))?;
+
+    let expected = [
+        IncompleteBand {
+            band_id: BandId::new(&[1]),
+            entry_count: 3,
+            first_apath: Some("/".into()),
+            last_apath: Some("/bbb".into()),
+        },
+        IncompleteBand {
+            band_id: BandId::new(&[2]),
+            entry_count: 3,
+            first_apath: Some("/".into()),
+            last_apath: Some("/bbb".into()),
+        },
+    ];
+    assert_eq!(
+        af.fix_incomplete_bands(IncompleteBandAction::Report)?,
+        expected
+    );
+    assert_eq!(af.band_is_closed(&BandId::new(&[1]))?, false);
+
+    // Sealed bands are closed, but aren't the latest complete backup.
+    assert_eq!(
+        af.fix_incomplete_bands(IncompleteBandAction::Seal)?,
+        expected
+    );
+    assert_eq!(af.band_is_closed(&BandId::new(&[2]))?, true);
+    assert!(Band::open(&af, &BandId::new(&[2]))?.get_info()?.is_partial);
+    assert_eq!(
+        af.last_complete_band()?.map(|band| band.id().clone()),
+        Some(BandId::zero())
+    );
+    assert_eq!(af.fix_incomplete_bands(IncompleteBandAction::Seal)?, []);
+
+    // Now b0003 is left incomplete, and deleted.
+    af.backup(&srcdir.path(), &BackupOptions::default())?;
+    fs::remove_file(af.path().join("b0003").join("BANDTAIL"))?;
+    let deleted = af.fix_incomplete_bands(IncompleteBandAction::Delete)?;
+    assert_eq!(deleted.len(), 1);
+    assert_eq!(deleted[0].band_id, BandId::new(&[3]));
+    assert_eq!(
+        af.list_band_ids()?,
+        [BandId::new(&[0]), BandId::new(&[1]), BandId::new(&[2])]
+    );
+    Ok(())
+}
+
+/// A dry run counts what would be stored, but writes neither a band nor blocks.
+#[test]
+fn dry_run_backup() -> Result<()> {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file("aaa");
+    srcdir.create_file_with_contents("bbb", b"some content");
+    srcdir.create_file_with_contents("ccc", b"some content");
+
+    let options = BackupOptions {
+        dry_run: true,
+        ..BackupOptions::default()
+    };
+    let stats = af.backup(&srcdir.path(), &options)?;
+    assert_eq!(stats.files, 3);
+    assert_eq!(stats.new_files, 3);
+    // The second identical file would be deduplicated against the first.
+    assert_eq!(stats.written_blocks, 2);
+    assert_eq!(stats.deduplicated_blocks, 1);
+    assert_eq!(stats.errors, 0);
+    assert!(af.list_band_ids()?.is_empty());
+    assert_eq!(af.block_dir().block_names()?.count(), 0);
+
+    // After a real backup, a dry run finds nothing new to write.
+    af.backup(&srcdir.path(), &BackupOptions::default())?;
+    let stats = af.backup(&srcdir.path(), &options)?;
+    assert_eq!(stats.unmodified_files, 3);
+    assert_eq!(stats.written_blocks, 0);
+    assert_eq!(stats.uncompressed_bytes, 0);
+    assert_eq!(af.list_band_ids()?, [BandId::zero()]);
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn backup_hooks() -> Result<()> {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    let logdir = TempDir::new().unwrap();
+    let log_path = logdir.path().join("status");
+
+    let options = BackupOptions {
+        pre_backup_hook: Some("echo snapshot > \"$CONSERVE_SOURCE/from_hook\"".to_owned()),
+        post_backup_hook: Some(format!("echo $CONSERVE_BACKUP_STATUS > {:?}", log_path)),
+        ..BackupOptions::default()
+    };
+    let stats = af.backup(&srcdir.path(), &options)?;
+    // The file made by the pre-backup hook is included.
+    assert_eq!(stats.files, 1);
+    assert_eq!(fs::read_to_string(&log_path)?, "succeeded\n");
+
+    // A failing pre-backup hook stops the backup, unless it only warns.
+    let failing = BackupOptions {
+        pre_backup_hook: Some("exit 1".to_owned()),
+        ..BackupOptions::default()
+    };
+    assert!(af.backup(&srcdir.path(), &failing).is_err());
+    assert_eq!(af.list_band_ids()?.len(), 1);
+    let warning = BackupOptions {
+        hook_failure: HookFailurePolicy::Warn,
+        ..failing
+    };
+    af.backup(&srcdir.path(), &warning)?;
+    assert_eq!(af.list_band_ids()?.len(), 2);
+    Ok(())
+}
+
+#[test]
+fn backup_stream() -> Result<()> {
+    let af = ScratchArchive::new();
+    let content = b"-- a database dump\n".repeat(100_000);
+    let stats = af.backup_stream(&Apath::from("/db/dump.sql"), &mut content.as_slice())?;
+    assert_eq!(stats.files, 1);
+    assert_eq!(stats.directories, 2);
+
+    let apaths: Vec<String> = af
+        .open_stored_tree(BandSelectionPolicy::Latest)?
+        .iter_entries()?
+        .map(|entry| entry.apath.into())
+        .collect();
+    assert_eq!(apaths, ["/", "/db", "/db/dump.sql"]);
+
+    let destdir = TempDir::new().unwrap();
+    af.restore(destdir.path(), &RestoreOptions::default())?;
+    assert_eq!(fs::read(destdir.path().join("db/dump.sql"))?, content);
+
+    assert!(af
+        .backup_stream(&Apath::from("/"), &mut content.as_slice())
+        .is_err());
+    Ok(())
+}
+
+#[test]
+fn exclude_marked_directories() -> Result<()> {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_dir("cache");
+    srcdir.create_file_with_contents(
+        "cache/CACHEDIR.TAG",
+        b"Signature: 8a477f597d28d172789f06886806bc55\n# A cache.\n",
+    );
+    srcdir.create_file("cache/data");
+    srcdir.create_dir("not_a_cache");
+    srcdir.create_file_with_contents("not_a_cache/CACHEDIR.TAG", b"Signature: wrong");
+    srcdir.create_dir("private");
+    srcdir.create_file(".nobackup");
+    srcdir.create_file("private/.nobackup");
+    srcdir.create_file("private/secret");
+    srcdir.create_file("kept");
+
+    let options = BackupOptions {
+        exclude_caches: true,
+        exclude_if_present: vec![".nobackup".to_owned()],
+        ..BackupOptions::default()
+    };
+    af.backup(&srcdir.path(), &options)?;
+    let apaths: Vec<String> = af
+        .open_stored_tree(BandSelectionPolicy::Latest)?
+        .iter_entries()?
+        .map(|entry| entry.apath.into())
+        .collect();
+    // The source root is always backed up, even if it contains a marker.
+    assert_eq!(
+        apaths,
+        [
+            "/",
+            "/.nobackup",
+            "/kept",
+            "/not_a_cache",
+            "/not_a_cache/CACHEDIR.TAG"
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn parallel_backup() -> Result<()> {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    for d in 0..5 {
+        let dir_name = format!("dir{}", d);
+        srcdir.create_dir(&dir_name);
+        for f in 0..20 {
+            srcdir.create_file_with_contents(
+                &format!("{}/file{:02}", dir_name, f),
+                format!("content of {} {}", d, f).as_bytes(),
+            );
+        }
+    }
+
+    let options = BackupOptions {
+        threads: 4,
+        ..BackupOptions::default()
+    };
+    let stats = af.backup(&srcdir.path(), &options)?;
+    assert_eq!(stats.files, 100);
+    assert_eq!(stats.new_files, 100);
+    assert_eq!(stats.written_blocks, 100);
+    assert_eq!(stats.errors, 0);
+
+    let apaths: Vec<Apath> = af
+        .open_stored_tree(BandSelectionPolicy::Latest)?
+        .iter_entries()?
+        .map(|entry| entry.apath)
+        .collect();
+    assert_eq!(apaths.len(), 106);
+    let mut sorted = apaths.clone();
+    sorted.sort();
+    assert_eq!(apaths, sorted);
+
+    let destdir = TempDir::new().unwrap();
+    af.restore(destdir.path(), &RestoreOptions::default())?;
+    assert_eq!(
+        fs::read_to_string(destdir.path().join("dir3/file17"))?,
+        "content of 3 17"
+    );
+
+    // A second backup finds everything unchanged.
+    let stats = af.backup(&srcdir.path(), &options)?;
+    assert_eq!(stats.unmodified_files, 100);
+    assert_eq!(stats.written_blocks, 0);
+    Ok(())
+}
+
+/// Identical files are stored once, and the summary shows the ratio of the
+/// source size to what was newly stored.
+#[test]
+fn deduplication_stats() -> Result<()> {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    let content = b"compressible content ".repeat(1000);
+    let len = content.len() as u64;
+    srcdir.create_file_with_contents("a", &content);
+    srcdir.create_file_with_contents("b", &content);
+    let summarize = |stats: &CopyStats| {
+        let mut summary = Vec::new();
+        stats.backup().summarize(&mut summary).unwrap();
+        String::from_utf8(summary).unwrap()
+    };
+
+    let stats = af.backup(&srcdir.path(), &BackupOptions::default())?;
+    assert_eq!(stats.source_bytes, 2 * len);
+    assert_eq!(stats.uncompressed_bytes, len);
+    assert_eq!(stats.deduplicated_bytes, len);
+    assert_eq!(stats.deduplicated_blocks, 1);
+    assert_eq!(stats.deduplication_ratio(), 2.0);
+    assert!(stats.compressed_bytes < len);
+    assert_eq!(
+        stats.overall_ratio(),
+        (2 * len) as f64 / stats.compressed_bytes as f64
+    );
+    let summary = summarize(&stats);
+    assert!(summary.contains("        2.0x      deduplication ratio\n"));
+    assert!(summary.contains(&format!(
+        "{:>12}      overall reduction\n",
+        format!("{:.1}x", stats.overall_ratio())
+    )));
+
+    // Backing up the same files again scans them but stores nothing new, so
+    // there's no ratio to show.
+    let stats = af.backup(&srcdir.path(), &BackupOptions::default())?;
+    assert_eq!(stats.source_bytes, 2 * len);
+    assert_eq!(stats.uncompressed_bytes, 0);
+    assert_eq!(stats.compressed_bytes, 0);
+    let summary = summarize(&stats);
+    assert!(summary.contains("file content scanned"));
+    assert!(!summary.contains("deduplication ratio"));
+    Ok(())
+}
+
 /// Store and retrieve large files.
 #[test]
 fn large_file() {
@@ -197,6 +583,13 @@ fn large_file() {
     assert_eq!(copy_stats.deduplicated_blocks, 3);
     assert_eq!(copy_stats.errors, 0);
     assert_eq!(copy_stats.index_builder_stats.index_hunks, 1);
+    assert_eq!(copy_stats.source_bytes, 4 << 20);
+    assert_eq!(copy_stats.deduplication_ratio(), 4.0);
+    let mut summary = Vec::new();
+    copy_stats.backup().summarize(&mut summary).unwrap();
+    let summary = String::from_utf8(summary).unwrap();
+    assert!(summary.contains("           4 MB     file content scanned\n"));
+    assert!(summary.contains("        4.0x      deduplication ratio\n"));
 
     // Try to restore it
     let rd = TempDir::new().unwrap();
@@ -434,6 +827,415 @@ fn restore_specified_band() {
     let stats = archive.restore(&destdir.path(), &options).expect("restore");
     // Does not have the 'hello2' file added in the second version.
     assert_eq!(stats.files, 2);
+
+    let destdir = TreeFixture::new();
+    let stats = archive
+        .restore_band(
+            &BandId::new(&[0]),
+            &destdir.path(),
+            &RestoreOptions::default(),
+        )
+        .expect("restore");
+    assert_eq!(stats.files, 2);
+    assert!(!destdir.path().join("hello2").exists());
+}
+
+#[test]
+fn restore_incomplete_or_missing_band() -> Result<()> {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    let band = Band::create(&af)?;
+    let band_id = band.id().clone();
+    let destdir = TempDir::new().unwrap();
+
+    match af.restore_band(&band_id, destdir.path(), &RestoreOptions::default()) {
+        Err(Error::BandIncomplete {
+            band_id: err_band_id,
+        }) => assert_eq!(err_band_id, band_id),
+        other => panic!("unexpected result {:?}", other),
+    }
+    match af.restore_band(
+        &BandId::new(&[42]),
+        destdir.path(),
+        &RestoreOptions::default(),
+    ) {
+        Err(Error::BandNotFound { band_id }) => assert_eq!(band_id, BandId::new(&[42])),
+        other => panic!("unexpected result {:?}", other),
+    }
+
+    // If allowed, the empty incomplete band is filled in from the previous band.
+    let options = RestoreOptions {
+        allow_incomplete: true,
+        ..RestoreOptions::default()
+    };
+    let stats = af.restore_band(&band_id, destdir.path(), &options)?;
+    assert_eq!(stats.files, 3);
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn restore_unix_permissions() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let set_mode = |path: &std::path::Path, mode: u32| {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap()
+    };
+    let mode_of =
+        |path: &std::path::Path| fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    set_mode(&srcdir.create_file("private"), 0o600);
+    set_mode(&srcdir.create_file("script"), 0o755);
+    srcdir.create_dir("readonly");
+    let ro_dir = srcdir.path().join("readonly");
+    srcdir.create_file("readonly/inside");
+    set_mode(&ro_dir, 0o555);
+    af.backup(&srcdir.path(), &BackupOptions::default())?;
+    set_mode(&ro_dir, 0o755);
+
+    let entries: Vec<IndexEntry> = af
+        .open_stored_tree(BandSelectionPolicy::Latest)?
+        .iter_entries()?
+        .collect();
+    let private = entries.iter().find(|e| e.apath == "/private").unwrap();
+    assert_eq!(private.unix_mode, Some(0o600));
+
+    let destdir = TempDir::new().unwrap();
+    af.restore(destdir.path(), &RestoreOptions::default())?;
+    assert_eq!(mode_of(&destdir.path().join("private")), 0o600);
+    assert_eq!(mode_of(&destdir.path().join("script")), 0o755);
+    // The read-only directory still got its contents.
+    assert!(destdir.path().join("readonly/inside").is_file());
+    assert_eq!(mode_of(&destdir.path().join("readonly")), 0o555);
+    set_mode(&destdir.path().join("readonly"), 0o755);
+
+    let destdir = TempDir::new().unwrap();
+    let options = RestoreOptions {
+        restore_permissions: false,
+        ..RestoreOptions::default()
+    };
+    af.restore(destdir.path(), &options)?;
+    assert_eq!(mode_of(&destdir.path().join("readonly")) & 0o200, 0o200);
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn store_and_restore_ownership() -> Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    let path = srcdir.create_file("file");
+    let running_as_root = unsafe { libc::geteuid() } == 0;
+    // Only root can give files away, and then restore them as they were.
+    let (uid, gid) = if running_as_root {
+        use std::os::unix::ffi::OsStrExt;
+        let c_path = std::ffi::CString::new(path.as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::chown(c_path.as_ptr(), 54321, 54322) }, 0);
+        (54321, 54322)
+    } else {
+        let metadata = fs::metadata(&path)?;
+        (metadata.uid(), metadata.gid())
+    };
+    af.backup(&srcdir.path(), &BackupOptions::default())?;
+
+    let entries: Vec<IndexEntry> = af
+        .open_stored_tree(BandSelectionPolicy::Latest)?
+        .iter_entries()?
+        .collect();
+    let file_entry = entries.iter().find(|e| &e.apath == "/file").unwrap();
+    assert_eq!(file_entry.uid, Some(uid));
+    assert_eq!(file_entry.gid, Some(gid));
+
+    let destdir = TempDir::new().unwrap();
+    let options = RestoreOptions {
+        ownership: OwnershipPolicy::Numeric,
+        ..RestoreOptions::default()
+    };
+    af.restore(destdir.path(), &options)?;
+    let metadata = fs::metadata(destdir.path().join("file"))?;
+    assert_eq!(metadata.uid(), uid);
+    if running_as_root {
+        assert_eq!(metadata.gid(), gid);
+    }
+
+    if running_as_root {
+        let destdir = TempDir::new().unwrap();
+        let options = RestoreOptions {
+            ownership: OwnershipPolicy::Skip,
+            ..RestoreOptions::default()
+        };
+        af.restore(destdir.path(), &options)?;
+        assert_eq!(fs::metadata(destdir.path().join("file"))?.uid(), 0);
+    }
+    Ok(())
+}
+
+/// Restored files and directories get the stored mtimes, including directories
+/// whose contents were restored after them.
+#[test]
+fn restore_mtimes() -> Result<()> {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_dir("subdir");
+    let file_path = srcdir.create_file("subdir/file");
+    let dir_path = srcdir.path().join("subdir");
+    utime::set_file_times(&file_path, 1_500_000_000, 1_500_000_000)?;
+    utime::set_file_times(&dir_path, 1_400_000_000, 1_400_000_000)?;
+    af.backup(&srcdir.path(), &BackupOptions::default())?;
+
+    let destdir = TempDir::new().unwrap();
+    af.restore(destdir.path(), &RestoreOptions::default())?;
+    let mtime = |path: &std::path::Path| fs::metadata(path).unwrap().modified().unwrap();
+    assert_eq!(
+        mtime(&destdir.path().join("subdir/file")),
+        mtime(&file_path)
+    );
+    assert_eq!(mtime(&destdir.path().join("subdir")), mtime(&dir_path));
+    Ok(())
+}
+
+/// Restoring only a subtree also restores its parent directories, with their
+/// metadata, but nothing else.
+#[test]
+fn restore_only_subtree() -> Result<()> {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file("top");
+    srcdir.create_dir("a");
+    srcdir.create_file("a/sibling");
+    srcdir.create_dir("a/b");
+    srcdir.create_file("a/b/file");
+    srcdir.create_file("a/b/other");
+    srcdir.create_dir("a/c");
+    srcdir.create_file("a/c/cousin");
+    let dir_path = srcdir.path().join("a");
+    utime::set_file_times(&dir_path, 1_400_000_000, 1_400_000_000)?;
+    af.backup(&srcdir.path(), &BackupOptions::default())?;
+
+    let destdir = TempDir::new().unwrap();
+    let options = RestoreOptions {
+        only_subtree: Some(Apath::from("/a/b/file")),
+        ..RestoreOptions::default()
+    };
+    let stats = af.restore(destdir.path(), &options)?;
+    assert_eq!(stats.files, 1);
+    let dest = destdir.path();
+    assert!(dest.join("a/b/file").is_file());
+    assert!(!dest.join("a/b/other").exists());
+    assert!(!dest.join("a/sibling").exists());
+    assert!(!dest.join("a/c").exists());
+    assert!(!dest.join("top").exists());
+    assert_eq!(
+        fs::metadata(dest.join("a"))?.modified()?,
+        fs::metadata(&dir_path)?.modified()?
+    );
+
+    let destdir = TempDir::new().unwrap();
+    let options = RestoreOptions {
+        only_subtree: Some(Apath::from("/a")),
+        ..RestoreOptions::default()
+    };
+    let stats = af.restore(destdir.path(), &options)?;
+    assert_eq!(stats.files, 4);
+    assert!(!destdir.path().join("top").exists());
+    assert!(destdir.path().join("a/c/cousin").is_file());
+    Ok(())
+}
+
+/// The library API can be used through the option builders, without naming
+/// any fields or internal modules.
+#[test]
+fn backup_and_restore_with_option_builders() -> Result<()> {
+    let archive_dir = TempDir::new().unwrap();
+    let archive = Archive::create_path(&archive_dir.path().join("archive"))?;
+    let srcdir = TreeFixture::new();
+    srcdir.create_dir("a");
+    srcdir.create_file("a/file");
+    srcdir.create_file("a/junk.tmp");
+    srcdir.create_file("top");
+
+    let excludes = excludes::from_strings(&["/**/*.tmp"])?;
+    let options = |dry_run| {
+        BackupOptions::default()
+            .with_excludes(excludes.clone())
+            .with_threads(2)
+            .with_tags(vec!["daily".to_owned()])
+            .with_dry_run(dry_run)
+    };
+    let stats: CopyStats = archive.backup(&srcdir.path(), &options(true))?;
+    assert_eq!(stats.files, 2);
+    assert_eq!(archive.last_band_id()?, None);
+    let stats = archive.backup(&srcdir.path(), &options(false))?;
+    assert_eq!(stats.files, 2);
+    let band_id = archive.last_band_id()?.unwrap();
+
+    let destdir = TempDir::new().unwrap();
+    let options = RestoreOptions::default()
+        .with_band_selection(BandSelectionPolicy::Specified(band_id))
+        .with_only_subtree(Some("/a".into()))
+        .with_threads(2);
+    let stats = archive.restore(destdir.path(), &options)?;
+    assert_eq!(stats.files, 1);
+    assert!(destdir.path().join("a/file").is_file());
+    assert!(!destdir.path().join("a/junk.tmp").exists());
+    assert!(!destdir.path().join("top").exists());
+    Ok(())
+}
+
+#[cfg(all(unix, feature = "xattr"))]
+#[test]
+fn store_and_restore_xattrs() -> Result<()> {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    let path = srcdir.create_file("file");
+    if xattr::set(&path, "user.conserve_test", b"hello").is_err() {
+        // The filesystem holding temporary files might not support user xattrs.
+        return Ok(());
+    }
+    af.backup(&srcdir.path(), &BackupOptions::default())?;
+
+    let destdir = TempDir::new().unwrap();
+    af.restore(destdir.path(), &RestoreOptions::default())?;
+    assert_eq!(
+        xattr::get(destdir.path().join("file"), "user.conserve_test")?,
+        Some(b"hello".to_vec())
+    );
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn hardlinks() -> Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file_with_contents("a", b"linked content");
+    fs::hard_link(srcdir.path().join("a"), srcdir.path().join("b"))?;
+    srcdir.create_dir("sub");
+    fs::hard_link(srcdir.path().join("a"), srcdir.path().join("sub/c"))?;
+    srcdir.create_file("unlinked");
+
+    let stats = af.backup(&srcdir.path(), &BackupOptions::default())?;
+    assert_eq!(stats.files, 4);
+    assert_eq!(stats.new_files, 2);
+    assert_eq!(stats.hardlinked_files, 2);
+
+    let entries: Vec<IndexEntry> = af
+        .open_stored_tree(BandSelectionPolicy::Latest)?
+        .iter_entries()?
+        .collect();
+    let group_of = |name: &str| {
+        entries
+            .iter()
+            .find(|e| &e.apath == name)
+            .unwrap()
+            .hardlink_group
+            .clone()
+    };
+    assert_eq!(group_of("/a"), Some(Apath::from("/a")));
+    assert_eq!(group_of("/b"), Some(Apath::from("/a")));
+    assert_eq!(group_of("/sub/c"), Some(Apath::from("/a")));
+    assert_eq!(group_of("/unlinked"), None);
+
+    let destdir = TempDir::new().unwrap();
+    let stats = af.restore(destdir.path(), &RestoreOptions::default())?;
+    assert_eq!(stats.hardlinked_files, 2);
+    let a_meta = fs::metadata(destdir.path().join("a"))?;
+    assert_eq!(a_meta.nlink(), 3);
+    assert_eq!(
+        fs::metadata(destdir.path().join("sub/c"))?.ino(),
+        a_meta.ino()
+    );
+    assert_eq!(
+        fs::read(destdir.path().join("b"))?,
+        b"linked content".to_vec()
+    );
+    Ok(())
+}
+
+#[test]
+fn sparse_file() -> Result<()> {
+    use std::io::{Seek, SeekFrom};
+
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    let path = srcdir.path().join("sparse");
+    let len = 8 << 20;
+    {
+        let mut f = File::create(&path)?;
+        f.set_len(len)?;
+        f.seek(SeekFrom::Start(4 << 20))?;
+        f.write_all(b"data in the middle")?;
+    }
+    let backup_stats = af.backup(&srcdir.path(), &BackupOptions::default())?;
+    assert_eq!(backup_stats.errors, 0);
+    // Whether holes are detected depends on the platform and filesystem, but
+    // they're at most the whole file.
+    assert!(backup_stats.sparse_bytes < len);
+
+    let destdir = TempDir::new().unwrap();
+    let restore_stats = af.restore(destdir.path(), &RestoreOptions::default())?;
+    // Holes recorded by the backup are left as holes, and so are any runs of
+    // zeros that happened to fill a whole block.
+    assert!(restore_stats.sparse_bytes >= backup_stats.sparse_bytes);
+    let restored_path = destdir.path().join("sparse");
+    assert_eq!(fs::metadata(&restored_path)?.len(), len);
+    assert_eq!(fs::read(&restored_path)?, fs::read(&path)?);
+    Ok(())
+}
+
+/// Runs of zeros in a file that wasn't sparse are written out on restore,
+/// rather than left as holes.
+#[test]
+fn zeros_in_file_that_is_not_sparse() -> Result<()> {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    let mut content = vec![0u8; 500 << 10];
+    content[..5].copy_from_slice(b"start");
+    srcdir.create_file_with_contents("zeros", &content);
+    let backup_stats = af.backup(&srcdir.path(), &BackupOptions::default())?;
+    assert_eq!(backup_stats.sparse_bytes, 0);
+
+    let destdir = TempDir::new().unwrap();
+    let restore_stats = af.restore(destdir.path(), &RestoreOptions::default())?;
+    assert_eq!(restore_stats.sparse_bytes, 0);
+    assert_eq!(fs::read(destdir.path().join("zeros"))?, content);
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn fifo() -> Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::FileTypeExt;
+
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    let fifo_path = srcdir.path().join("fifo");
+    let c_path = std::ffi::CString::new(fifo_path.as_os_str().as_bytes()).unwrap();
+    assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o640) }, 0);
+
+    let stats = af.backup(&srcdir.path(), &BackupOptions::default())?;
+    assert_eq!(stats.special_files, 1);
+    assert_eq!(stats.unknown_kind, 0);
+    let entries: Vec<IndexEntry> = af
+        .open_stored_tree(BandSelectionPolicy::Latest)?
+        .iter_entries()?
+        .collect();
+    assert_eq!(entries[1].apath, "/fifo");
+    assert_eq!(entries[1].kind, Kind::Fifo);
+
+    let destdir = TempDir::new().unwrap();
+    let stats = af.restore(destdir.path(), &RestoreOptions::default())?;
+    assert_eq!(stats.special_files, 1);
+    let metadata = fs::symlink_metadata(destdir.path().join("fifo"))?;
+    assert!(metadata.file_type().is_fifo());
+    Ok(())
 }
 
 #[test]
@@ -457,7 +1259,7 @@ pub fn forced_overwrite() {
 
     let restore_archive = Archive::open_path(af.path()).unwrap();
     let options = RestoreOptions {
-        overwrite: true,
+        overwrite: OverwritePolicy::Force,
         ..RestoreOptions::default()
     };
     let stats = restore_archive
@@ -470,15 +1272,256 @@ pub fn forced_overwrite() {
 }
 
 #[test]
-fn exclude_files() {
+fn overwrite_policies() -> Result<()> {
     let af = ScratchArchive::new();
-    af.store_two_versions();
-    let destdir = TreeFixture::new();
-    let restore_archive = Archive::open_path(af.path()).unwrap();
-    let options = RestoreOptions {
-        overwrite: true,
-        excludes: excludes::from_strings(&["/**/subfile"]).unwrap(),
-        ..RestoreOptions::default()
+    let srcdir = TreeFixture::new();
+    srcdir.create_file_with_contents("old", b"stored old");
+    srcdir.create_file_with_contents("new", b"stored new");
+    srcdir.create_file_with_contents("absent", b"stored absent");
+    utime::set_file_times(srcdir.path().join("old"), 1_400_000_000, 1_400_000_000)?;
+    utime::set_file_times(srcdir.path().join("new"), 1_600_000_000, 1_600_000_000)?;
+    af.backup(&srcdir.path(), &BackupOptions::default())?;
+
+    let make_dest = || {
+        let destdir = TreeFixture::new();
+        for name in &["old", "new"] {
+            destdir.create_file_with_contents(name, b"existing");
+            utime::set_file_times(destdir.path().join(name), 1_500_000_000, 1_500_000_000).unwrap();
+        }
+        destdir
+    };
+    let restore = |destdir: &TreeFixture, overwrite| {
+        let options = RestoreOptions {
+            overwrite,
+            ..RestoreOptions::default()
+        };
+        af.restore(&destdir.path(), &options).unwrap()
+    };
+    let read = |destdir: &TreeFixture, name: &str| fs::read(destdir.path().join(name)).unwrap();
+
+    let destdir = make_dest();
+    let stats = restore(&destdir, OverwritePolicy::Force);
+    assert_eq!(stats.overwritten, 2);
+    assert_eq!(read(&destdir, "old"), b"stored old");
+    assert_eq!(read(&destdir, "new"), b"stored new");
+    assert_eq!(read(&destdir, "absent"), b"stored absent");
+
+    let destdir = make_dest();
+    let stats = restore(&destdir, OverwritePolicy::SkipExisting);
+    assert_eq!(stats.skipped_existing, 2);
+    assert_eq!(stats.overwritten, 0);
+    assert_eq!(read(&destdir, "old"), b"existing");
+    assert_eq!(read(&destdir, "new"), b"existing");
+    assert_eq!(read(&destdir, "absent"), b"stored absent");
+
+    let destdir = make_dest();
+    let stats = restore(&destdir, OverwritePolicy::OnlyNewer);
+    assert_eq!(stats.skipped_not_newer, 1);
+    assert_eq!(stats.overwritten, 1);
+    assert_eq!(read(&destdir, "old"), b"existing");
+    assert_eq!(read(&destdir, "new"), b"stored new");
+    assert_eq!(read(&destdir, "absent"), b"stored absent");
+
+    // Skipping existing files makes restore idempotent.
+    let stats = restore(&destdir, OverwritePolicy::SkipExisting);
+    assert_eq!(stats.skipped_existing, 3);
+    Ok(())
+}
+
+#[test]
+fn restore_dry_run() -> Result<()> {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    let destdir = TreeFixture::new();
+    destdir.create_file_with_contents("hello", b"existing");
+
+    let options = RestoreOptions {
+        dry_run: true,
+        overwrite: OverwritePolicy::SkipExisting,
+        ..RestoreOptions::default()
+    };
+    let stats = af.restore(&destdir.path(), &options)?;
+    assert_eq!(stats.files, 3);
+    assert_eq!(stats.skipped_existing, 1);
+    let stored_sizes: u64 = af
+        .open_stored_tree(BandSelectionPolicy::Latest)?
+        .iter_entries()?
+        .filter(|entry| entry.apath != "/hello")
+        .filter_map(|entry| entry.size())
+        .sum();
+    assert_eq!(stats.uncompressed_bytes, stored_sizes);
+    // Nothing was written.
+    assert_eq!(fs::read(destdir.path().join("hello"))?, b"existing");
+    assert!(!destdir.path().join("hello2").exists());
+    assert!(!destdir.path().join("subdir").exists());
+
+    // The destination needn't exist, and isn't created.
+    let new_dest = destdir.path().join("new");
+    let options = RestoreOptions {
+        dry_run: true,
+        ..RestoreOptions::default()
+    };
+    let stats = af.restore(&new_dest, &options)?;
+    assert_eq!(stats.overwritten, 0);
+    assert!(stats.uncompressed_bytes > stored_sizes);
+    assert!(!new_dest.exists());
+
+    // Without an overwrite policy, a non-empty destination is still refused.
+    assert!(af.restore(&destdir.path(), &options).is_err());
+    Ok(())
+}
+
+#[test]
+fn update_and_delete_in_place() -> Result<()> {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file_with_contents("same", b"same");
+    srcdir.create_file_with_contents("changed", b"stored");
+    srcdir.create_dir("dir");
+    srcdir.create_file_with_contents("dir/sub", b"sub");
+    utime::set_file_times(srcdir.path().join("same"), 1_400_000_000, 1_400_000_000)?;
+    af.backup(&srcdir.path(), &BackupOptions::default())?;
+
+    let destdir = TreeFixture::new();
+    af.restore(&destdir.path(), &RestoreOptions::default())?;
+    destdir.create_file_with_contents("changed", b"local!");
+    destdir.create_file("extra");
+    destdir.create_file("keep.tmp");
+    destdir.create_dir("extradir");
+    destdir.create_file("extradir/file");
+    utime::set_file_times(destdir.path().join("same"), 1_500_000_000, 1_500_000_000)?;
+    let dest = destdir.path();
+
+    let options = RestoreOptions {
+        overwrite: OverwritePolicy::Update,
+        delete: true,
+        excludes: excludes::from_strings(&["/*.tmp"])?,
+        dry_run: true,
+        ..RestoreOptions::default()
+    };
+    let stats = af.restore(&dest, &options)?;
+    assert_eq!(stats.deleted, 2);
+    assert_eq!(stats.overwritten, 1);
+    assert!(dest.join("extradir/file").exists());
+
+    let stats = af.restore(
+        &dest,
+        &RestoreOptions {
+            dry_run: false,
+            ..options
+        },
+    )?;
+    assert_eq!(stats.unmodified_files, 2);
+    assert_eq!(stats.overwritten, 1);
+    assert_eq!(stats.deleted, 2);
+    assert_eq!(fs::read(dest.join("changed"))?, b"stored");
+    assert!(!dest.join("extra").exists());
+    assert!(!dest.join("extradir").exists());
+    assert!(dest.join("keep.tmp").exists());
+    // Metadata is restored on unchanged files.
+    assert_eq!(
+        fs::metadata(dest.join("same"))?
+            .modified()?
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs(),
+        1_400_000_000
+    );
+    Ok(())
+}
+
+#[test]
+fn parallel_restore() -> Result<()> {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    for i in 0..20 {
+        srcdir.create_dir(&format!("dir{}", i));
+        srcdir.create_file_with_contents(
+            &format!("dir{}/file", i),
+            format!("content of file {}", i).as_bytes(),
+        );
+    }
+    // Big enough to be stored in several blocks, which are read ahead.
+    let big: Vec<u8> = (0..(3 << 20) + 1000).map(|i| (i % 251) as u8).collect();
+    srcdir.create_file_with_contents("big", &big);
+    af.backup(&srcdir.path(), &BackupOptions::default())?;
+
+    let destdir = TreeFixture::new();
+    let options = RestoreOptions {
+        threads: 4,
+        ..RestoreOptions::default()
+    };
+    let stats = af.restore(&destdir.path(), &options)?;
+    assert_eq!(stats.files, 21);
+    assert_eq!(stats.errors, 0);
+    assert_eq!(
+        stats.uncompressed_bytes,
+        srcdir.live_tree().size(None)?.file_bytes
+    );
+    assert_eq!(fs::read(destdir.path().join("big"))?, big);
+    for i in 0..20 {
+        assert_eq!(
+            fs::read_to_string(destdir.path().join(format!("dir{}/file", i)))?,
+            format!("content of file {}", i)
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn resume_restore() -> Result<()> {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    let destdir = TreeFixture::new();
+    let dest = destdir.path();
+    let options = RestoreOptions {
+        resume: true,
+        ..RestoreOptions::default()
+    };
+    match af.restore(&dest, &options) {
+        Err(Error::NoRestoreToResume { .. }) => (),
+        other => panic!("unexpected result {:?}", other),
+    }
+
+    // Simulate a restore interrupted after /hello and /hello2 were recorded
+    // as written. /hello is complete, so it's skipped, but /hello2 has
+    // different content of the stored size, so it's written again.
+    destdir.create_file_with_contents("hello", b"contents");
+    destdir.create_file_with_contents("hello2", b"CONTENTS");
+    fs::write(
+        dest.join(".conserve-restore"),
+        r#"{"band_id":"b0001","last_apath":"/hello2"}"#,
+    )?;
+    let stats = af.restore(&dest, &options)?;
+    assert_eq!(stats.skipped_restored, 1);
+    assert_eq!(fs::read(dest.join("hello"))?, b"contents");
+    assert_eq!(fs::read(dest.join("hello2"))?, b"contents");
+    assert_eq!(fs::read(dest.join("subdir/subfile"))?, b"contents");
+    // The record is removed when the restore is finished.
+    assert!(!dest.join(".conserve-restore").exists());
+
+    // A restore of a different band can't be resumed.
+    fs::write(
+        dest.join(".conserve-restore"),
+        r#"{"band_id":"b0000","last_apath":"/hello"}"#,
+    )?;
+    match af.restore(&dest, &options) {
+        Err(Error::ResumeRestoreOfOtherBand { band_id, .. }) => assert_eq!(band_id, "b0000"),
+        other => panic!("unexpected result {:?}", other),
+    }
+    Ok(())
+}
+
+#[test]
+fn exclude_files() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    let destdir = TreeFixture::new();
+    let restore_archive = Archive::open_path(af.path()).unwrap();
+    let options = RestoreOptions {
+        overwrite: OverwritePolicy::Force,
+        excludes: excludes::from_strings(&["/**/subfile"]).unwrap(),
+        ..RestoreOptions::default()
     };
     let stats = restore_archive
         .restore(&destdir.path(), &options)
@@ -503,3 +1546,1002 @@ fn delete_bands() {
     assert_eq!(stats.deleted_block_count, 1);
     assert_eq!(stats.deleted_band_count, 2);
 }
+
+#[test]
+fn prune_keep_last() -> Result<()> {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    for i in 0..3 {
+        srcdir.create_file_with_contents(&format!("file{}", i), format!("{}", i).as_bytes());
+        af.backup(&srcdir.path(), &BackupOptions::default())?;
+    }
+    // An incomplete backup is neither counted nor deleted.
+    Band::create(&af)?;
+    let policy = RetentionPolicy {
+        keep_last: 2,
+        ..RetentionPolicy::default()
+    };
+
+    // gc can't run while the last band is incomplete.
+    let dry_run = DeleteOptions {
+        dry_run: true,
+        no_gc: true,
+        ..DeleteOptions::default()
+    };
+    let (retention, _stats) = af.prune(&policy, &dry_run)?;
+    let kept: Vec<bool> = retention.iter().map(BandRetention::is_kept).collect();
+    assert_eq!(kept, [false, true, true]);
+    assert_eq!(af.list_band_ids()?.len(), 4);
+
+    // Remove the incomplete band, so that gc can run.
+    fs::remove_dir_all(af.path().join("b0003"))?;
+    let (_retention, stats) = af.prune(&policy, &DeleteOptions::default())?;
+    assert_eq!(stats.deleted_band_count, 1);
+    assert_eq!(af.list_band_ids()?, [BandId::new(&[1]), BandId::new(&[2])]);
+
+    match af.prune(&RetentionPolicy::default(), &DeleteOptions::default()) {
+        Err(Error::RetentionPolicyKeepsNothing) => (),
+        other => panic!("unexpected result {:?}", other),
+    }
+    Ok(())
+}
+
+/// Syncing copies missing bands and blocks, and is incremental.
+#[test]
+fn sync_to_new_archive() -> Result<()> {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    // An incomplete band isn't copied.
+    Band::create(&af)?;
+    let destdir = TempDir::new().unwrap();
+    let dest_path = destdir.path().join("copy");
+    let dest_transport = || Box::new(LocalTransport::new(&dest_path));
+
+    let dry_run = SyncOptions {
+        dry_run: true,
+        ..SyncOptions::default()
+    };
+    let stats = sync_archive(&af, dest_transport(), &dry_run)?;
+    assert_eq!(stats.bands_copied, 2);
+    assert_eq!(stats.blocks_copied, 1);
+    assert!(!dest_path.exists());
+
+    let stats = sync_archive(&af, dest_transport(), &SyncOptions::default())?;
+    assert_eq!(stats.bands_copied, 2);
+    assert_eq!(stats.bands_incomplete, 1);
+    assert_eq!(stats.blocks_copied, 1);
+    assert!(stats.block_bytes_copied > 0);
+
+    let dest = Archive::open_path(&dest_path)?;
+    assert_eq!(
+        dest.list_band_ids()?,
+        [BandId::new(&[0]), BandId::new(&[1])]
+    );
+    assert!(!dest.validate(&ValidateOptions::default())?.has_problems());
+    let restore_dir = TempDir::new().unwrap();
+    dest.restore(restore_dir.path(), &RestoreOptions::default())?;
+    assert!(restore_dir.path().join("hello2").is_file());
+
+    // Running again copies nothing new.
+    let stats = sync_archive(&af, dest_transport(), &SyncOptions::default())?;
+    assert_eq!(stats.bands_copied, 0);
+    assert_eq!(stats.bands_present, 2);
+    assert_eq!(stats.blocks_copied, 0);
+    assert_eq!(stats.blocks_present, 1);
+    Ok(())
+}
+
+/// An incomplete band in the destination is replaced only if it was partly
+/// copied by an earlier sync.
+#[test]
+fn sync_replaces_only_partly_synced_bands() -> Result<()> {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    let destdir = TempDir::new().unwrap();
+    let dest_transport = || Box::new(LocalTransport::new(destdir.path()));
+    sync_archive(&af, dest_transport(), &SyncOptions::default())?;
+    let srcdir = TreeFixture::new();
+    srcdir.create_file("third");
+    af.backup(&srcdir.path(), &BackupOptions::default())?;
+
+    // A backup started in the destination isn't replaced.
+    let band_dir = destdir.path().join("b0002");
+    fs::create_dir(&band_dir)?;
+    fs::write(
+        band_dir.join("BANDHEAD"),
+        r#"{"start_time":0,"band_format_version":"0.6.3"}"#,
+    )?;
+    match sync_archive(&af, dest_transport(), &SyncOptions::default()) {
+        Err(Error::SyncWouldReplaceBand { band_id }) => assert_eq!(band_id, BandId::new(&[2])),
+        other => panic!("unexpected result {:?}", other),
+    }
+    assert!(band_dir.join("BANDHEAD").is_file());
+
+    // A band interrupted while it was being synced is copied again.
+    fs::copy(
+        af.path().join("b0002").join("BANDHEAD"),
+        band_dir.join("BANDHEAD"),
+    )?;
+    let stats = sync_archive(&af, dest_transport(), &SyncOptions::default())?;
+    assert_eq!(stats.bands_copied, 1);
+    assert!(Archive::open_path(destdir.path())?.band_is_closed(&BandId::new(&[2]))?);
+    Ok(())
+}
+
+/// A block whose content doesn't match its hash isn't copied, and neither is
+/// the band that refers to it.
+#[test]
+fn sync_checks_block_hashes() -> Result<()> {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file_with_contents("hello", b"hello");
+    af.backup(&srcdir.path(), &BackupOptions::default())?;
+    let hash = af.block_dir().block_names()?.next().unwrap().to_string();
+    let block_path = af.path().join("d").join(&hash[..3]).join(&hash);
+    let corrupt = snap::raw::Encoder::new().compress_vec(b"jello").unwrap();
+    fs::write(&block_path, corrupt)?;
+
+    let destdir = TempDir::new().unwrap();
+    match sync_archive(
+        &af,
+        Box::new(LocalTransport::new(destdir.path())),
+        &SyncOptions::default(),
+    ) {
+        Err(Error::BlockCorrupt { .. }) => (),
+        other => panic!("unexpected result {:?}", other),
+    }
+    assert_eq!(Archive::open_path(destdir.path())?.list_band_ids()?, []);
+    Ok(())
+}
+
+/// Migrating recompresses every block with zstd, and the archive is still
+/// restorable afterwards.
+#[test]
+fn migrate_to_zstd_blocks() -> Result<()> {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    assert!(!af.has_feature(ZSTD_BLOCKS_FEATURE));
+    let block_count = af.block_dir().block_names()?.count();
+
+    let dry_run = MigrateOptions {
+        dry_run: true,
+        ..MigrateOptions::default()
+    };
+    let stats = migrate_archive(&af, &dry_run)?;
+    assert_eq!(stats.blocks_recompressed, block_count);
+    assert!(!Archive::open_path(af.path())?.has_feature(ZSTD_BLOCKS_FEATURE));
+
+    let stats = migrate_archive(&af, &MigrateOptions::default())?;
+    assert_eq!(stats.blocks_recompressed, block_count);
+    assert_eq!(stats.blocks_already_migrated, 0);
+    let migrated = Archive::open_path(af.path())?;
+    assert_eq!(migrated.features(), [ZSTD_BLOCKS_FEATURE]);
+    let restore_dir = TempDir::new().unwrap();
+    migrated.restore(restore_dir.path(), &RestoreOptions::default())?;
+    assert!(restore_dir.path().join("hello2").is_file());
+
+    // New blocks are written with zstd too.
+    let srcdir = TreeFixture::new();
+    srcdir.create_file_with_contents("new", b"written after migration");
+    migrated.backup(&srcdir.path(), &BackupOptions::default())?;
+    for hash in migrated.block_dir().block_names()? {
+        let hash = hash.to_string();
+        let content = fs::read(af.path().join("d").join(&hash[..3]).join(&hash))?;
+        assert!(content.starts_with(b"\x28\xb5\x2f\xfd"));
+    }
+    assert!(!migrated
+        .validate(&ValidateOptions::default())?
+        .has_problems());
+
+    // Running again finds nothing to do.
+    let stats = migrate_archive(&migrated, &MigrateOptions::default())?;
+    assert_eq!(stats.blocks_recompressed, 0);
+    assert_eq!(stats.blocks_already_migrated, block_count + 1);
+    Ok(())
+}
+
+/// Backups use the block size and compression set in the archive's config.
+#[test]
+fn backup_uses_archive_config() -> Result<()> {
+    let af = ScratchArchive::new();
+    assert_eq!(*af.config(), ArchiveConfig::default());
+    af.set_config(&ArchiveConfig {
+        compression: Some(Compression::Zstd),
+        block_size: Some(4096),
+        ..ArchiveConfig::default()
+    })?;
+    let archive = Archive::open_path(af.path())?;
+    assert_eq!(archive.config().block_size(), 4096);
+    assert!(archive.has_feature(ZSTD_BLOCKS_FEATURE));
+
+    let srcdir = TreeFixture::new();
+    let content: Vec<u8> = (0..10000u32).map(|i| (i % 251) as u8).collect();
+    srcdir.create_file_with_contents("data", &content);
+    let stats = archive.backup(&srcdir.path(), &BackupOptions::default())?;
+    assert_eq!(stats.written_blocks, 3);
+    for hash in archive.block_dir().block_names()? {
+        let hash = hash.to_string();
+        let block = fs::read(af.path().join("d").join(&hash[..3]).join(&hash))?;
+        assert!(block.starts_with(b"\x28\xb5\x2f\xfd"));
+    }
+    let restore_dir = TempDir::new().unwrap();
+    archive.restore(restore_dir.path(), &RestoreOptions::default())?;
+    assert_eq!(fs::read(restore_dir.path().join("data"))?, content);
+    Ok(())
+}
+
+/// Index hunks compressed with zstd are smaller than with snappy, and can be
+/// read back.
+#[test]
+fn zstd_index_is_smaller() -> Result<()> {
+    let srcdir = TreeFixture::new();
+    for i in 0..300 {
+        srcdir.create_file_with_contents(&format!("file{:04}", i), format!("{}", i).as_bytes());
+    }
+
+    let snappy_af = ScratchArchive::new();
+    let snappy_stats = snappy_af.backup(&srcdir.path(), &BackupOptions::default())?;
+
+    let af = ScratchArchive::new();
+    af.set_config(&ArchiveConfig {
+        index_compression: Some(Compression::Zstd),
+        ..ArchiveConfig::default()
+    })?;
+    let archive = Archive::open_path(af.path())?;
+    assert!(archive.has_feature(ZSTD_INDEX_FEATURE));
+    assert!(!archive.has_feature(ZSTD_BLOCKS_FEATURE));
+    let zstd_stats = archive.backup(&srcdir.path(), &BackupOptions::default())?;
+
+    let snappy_bytes = snappy_stats.index_builder_stats.compressed_index_bytes;
+    let zstd_bytes = zstd_stats.index_builder_stats.compressed_index_bytes;
+    assert_eq!(
+        zstd_stats.index_builder_stats.uncompressed_index_bytes,
+        snappy_stats.index_builder_stats.uncompressed_index_bytes
+    );
+    assert!(
+        zstd_bytes * 4 < snappy_bytes * 3,
+        "zstd index {} bytes, snappy {}",
+        zstd_bytes,
+        snappy_bytes
+    );
+
+    let band = Band::open(&archive, &BandId::zero())?;
+    let hunk = band.index().read_hunk(0)?.unwrap();
+    assert!(!hunk.is_empty());
+    let restore_dir = TempDir::new().unwrap();
+    archive.restore(restore_dir.path(), &RestoreOptions::default())?;
+    assert_eq!(fs::read(restore_dir.path().join("file0123"))?, b"123");
+    Ok(())
+}
+
+/// With delta indexes, later bands store only the changed entries, but read
+/// back as the whole tree.
+#[test]
+fn delta_index_backups() -> Result<()> {
+    let af = ScratchArchive::new();
+    af.set_config(&ArchiveConfig {
+        delta_index: true,
+        ..ArchiveConfig::default()
+    })?;
+    let archive = Archive::open_path(af.path())?;
+    assert!(archive.has_feature(DELTA_INDEX_FEATURE));
+
+    let srcdir = TreeFixture::new();
+    for i in 0..100 {
+        srcdir.create_file_with_contents(&format!("file{:03}", i), format!("{}", i).as_bytes());
+    }
+    archive.backup(&srcdir.path(), &BackupOptions::default())?;
+    let b0 = BandId::zero();
+    let b1 = BandId::new(&[1]);
+    assert_eq!(Band::open(&archive, &b0)?.delta_parent()?, None);
+
+    srcdir.create_file_with_contents("file007", b"changed");
+    fs::remove_file(srcdir.path().join("file050"))?;
+    srcdir.create_file_with_contents("new", b"new");
+    archive.backup(&srcdir.path(), &BackupOptions::default())?;
+
+    let band = Band::open(&archive, &b1)?;
+    assert_eq!(band.delta_parent()?, Some(b0.clone()));
+    let changes: Vec<(String, bool)> = band
+        .iter_entries()?
+        .map(|entry| (entry.apath.into(), entry.deleted))
+        .collect();
+    assert_eq!(
+        changes,
+        [
+            // The root directory's mtime changed.
+            ("/".to_owned(), false),
+            ("/file007".to_owned(), false),
+            ("/file050".to_owned(), true),
+            ("/new".to_owned(), false)
+        ]
+    );
+
+    let tree = archive.open_stored_tree(BandSelectionPolicy::Specified(b1.clone()))?;
+    let apaths: Vec<String> = tree
+        .iter_entries()?
+        .map(|entry| entry.apath.into())
+        .collect();
+    // The stitched tree has everything now in the source, and nothing else.
+    let source_apaths: Vec<String> = LiveTree::open(srcdir.path())?
+        .iter_entries()?
+        .map(|entry| entry.apath().to_string())
+        .collect();
+    assert_eq!(apaths, source_apaths);
+    assert!(!apaths.contains(&"/file050".to_owned()));
+
+    let restore_dir = TempDir::new().unwrap();
+    archive.restore(restore_dir.path(), &RestoreOptions::default())?;
+    assert_eq!(fs::read(restore_dir.path().join("file007"))?, b"changed");
+    assert_eq!(fs::read(restore_dir.path().join("file008"))?, b"8");
+    assert!(!restore_dir.path().join("file050").exists());
+    assert!(!archive
+        .validate(&ValidateOptions::default())?
+        .has_problems());
+
+    // The parent can't be deleted while the delta band exists, and prune keeps it.
+    match archive.delete_band(&b0, &DeleteOptions::default()) {
+        Err(Error::DeltaParentInUse { band_id, child }) => {
+            assert_eq!(band_id, b0);
+            assert_eq!(child, b1);
+        }
+        other => panic!("unexpected result {:?}", other),
+    }
+    let policy = RetentionPolicy {
+        keep_last: 1,
+        ..RetentionPolicy::default()
+    };
+    let (retention, stats) = archive.prune(&policy, &DeleteOptions::default())?;
+    assert_eq!(retention[0].keep_reasons, ["delta parent"]);
+    assert_eq!(stats.deleted_band_count, 0);
+    Ok(())
+}
+
+#[test]
+fn whole_file_content_hash() -> Result<()> {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file("hello");
+    af.backup(&srcdir.path(), &BackupOptions::default())?;
+
+    // A file in one block has the same hash as the block.
+    let tree = af.open_stored_tree(BandSelectionPolicy::Latest)?;
+    let entry = tree.get_entry(&"/hello".into())?.unwrap();
+    assert_eq!(entry.content_hash.as_ref().unwrap().to_string(), HELLO_HASH);
+    assert!(tree.get_entry(&"/".into())?.unwrap().content_hash.is_none());
+
+    // An unchanged file keeps its hash in the next backup.
+    af.backup(&srcdir.path(), &BackupOptions::default())?;
+    let tree = af.open_stored_tree(BandSelectionPolicy::Latest)?;
+    let unchanged = tree.get_entry(&"/hello".into())?.unwrap();
+    assert_eq!(unchanged.content_hash, entry.content_hash);
+
+    // A file whose restored content doesn't match its hash is an error.
+    let band = Band::create(&af)?;
+    let mut ib = band.index_builder();
+    ib.push_entry(IndexEntry {
+        content_hash: Some(BlockHash::from_str(&"0".repeat(128)).unwrap()),
+        ..entry
+    })?;
+    let stats = ib.finish()?;
+    band.close(stats.index_hunks)?;
+    let destdir = TempDir::new().unwrap();
+    let stats = af.restore_band(band.id(), destdir.path(), &RestoreOptions::default())?;
+    assert_eq!(stats.errors, 1);
+    // The mismatched file is removed rather than left looking restored.
+    assert!(!destdir.path().join("hello").exists());
+    Ok(())
+}
+
+#[test]
+fn watch_backs_up_changes() -> Result<()> {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file("hello");
+
+    let path = srcdir.path().join("hello");
+    let writer = std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(500));
+        fs::write(path, b"changed contents").unwrap();
+    });
+    let options = WatchOptions {
+        interval: std::time::Duration::from_millis(50),
+        retention: Some(RetentionPolicy {
+            keep_last: 1,
+            ..RetentionPolicy::default()
+        }),
+        max_backups: Some(2),
+        ..WatchOptions::default()
+    };
+    let stats = watch(&af, srcdir.path(), &options)?;
+    writer.join().unwrap();
+    assert_eq!(stats.backups, 2);
+    assert_eq!(stats.failed_backups, 0);
+    assert_eq!(stats.deleted_band_count, 1);
+    assert_eq!(af.list_band_ids()?, [BandId::new(&[1])]);
+
+    let tree = af.open_stored_tree(BandSelectionPolicy::Latest)?;
+    let mut content = String::new();
+    let entry = tree.get_entry(&"/hello".into())?.unwrap();
+    tree.file_contents(&entry)?.read_to_string(&mut content)?;
+    assert_eq!(content, "changed contents");
+    Ok(())
+}
+
+/// Bands can be found by tags given at backup time or added later.
+#[test]
+fn find_band_by_tag() -> Result<()> {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file("hello");
+    let options = BackupOptions {
+        tags: vec!["pre-upgrade".to_owned()],
+        ..BackupOptions::default()
+    };
+    af.backup(&srcdir.path(), &options)?;
+    af.backup(&srcdir.path(), &BackupOptions::default())?;
+
+    assert_eq!(af.find_band("pre-upgrade")?, BandId::zero());
+    assert_eq!(af.find_band("b0001")?, BandId::new(&[1]));
+    match af.find_band("quarterly") {
+        Err(Error::NoSuchBandOrTag { name }) => assert_eq!(name, "quarterly"),
+        other => panic!("unexpected result {:?}", other),
+    }
+
+    // The latest band with a tag is found.
+    af.add_tags(&BandId::new(&[1]), &["pre-upgrade".to_owned()])?;
+    assert_eq!(af.find_band("pre-upgrade")?, BandId::new(&[1]));
+    af.remove_tags(&BandId::new(&[1]), &["pre-upgrade".to_owned()])?;
+    assert_eq!(af.find_band("pre-upgrade")?, BandId::zero());
+
+    let bad_tag = BackupOptions {
+        tags: vec!["b0009".to_owned()],
+        ..BackupOptions::default()
+    };
+    match af.backup(&srcdir.path(), &bad_tag) {
+        Err(Error::InvalidTag { .. }) => (),
+        other => panic!("unexpected result {:?}", other),
+    }
+    assert_eq!(af.list_band_ids()?.len(), 2);
+    Ok(())
+}
+
+/// A dry run of deleting a band counts the blocks that would be reclaimed.
+#[test]
+fn delete_band_dry_run() -> Result<()> {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file_with_contents("aaa", b"kept");
+    af.backup(&srcdir.path(), &BackupOptions::default())?;
+    srcdir.create_file_with_contents("bbb", b"only in the second backup");
+    af.backup(&srcdir.path(), &BackupOptions::default())?;
+
+    let options = DeleteOptions {
+        dry_run: true,
+        ..DeleteOptions::default()
+    };
+    let stats = af.delete_band(&BandId::new(&[1]), &options)?;
+    assert_eq!(stats.deleted_band_count, 0);
+    assert_eq!(stats.unreferenced_block_count, 1);
+    assert_eq!(stats.deleted_block_count, 0);
+    assert_eq!(af.list_band_ids()?.len(), 2);
+    assert_eq!(af.block_dir().block_names()?.count(), 2);
+
+    let stats = af.delete_band(&BandId::new(&[1]), &DeleteOptions::default())?;
+    assert_eq!(stats.deleted_band_count, 1);
+    assert_eq!(stats.unreferenced_block_count, 1);
+    assert_eq!(stats.deleted_block_count, 1);
+    assert_eq!(af.list_band_ids()?, [BandId::zero()]);
+    assert_eq!(af.block_dir().block_names()?.count(), 1);
+    Ok(())
+}
+
+/// Several source directories are stored in one band under their absolute paths.
+#[test]
+fn backup_several_roots() -> Result<()> {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_dir("etc");
+    srcdir.create_file_with_contents("etc/hosts", b"localhost");
+    srcdir.create_dir("home");
+    srcdir.create_file_with_contents("home/notes", b"remember");
+    srcdir.create_file("home/skip.tmp");
+    let base = srcdir.path().canonicalize()?;
+    let base_apath = base.to_str().unwrap().replace('\\', "/");
+
+    let options = BackupOptions {
+        excludes: excludes::from_strings(&["/**/*.tmp"]).unwrap(),
+        ..BackupOptions::default()
+    };
+    let stats = af.backup_roots(&[base.join("home"), base.join("etc")], &options)?;
+    assert_eq!(stats.files, 2);
+
+    let apaths: Vec<String> = af
+        .open_stored_tree(BandSelectionPolicy::Latest)?
+        .iter_entries()?
+        .map(|entry| entry.apath.into())
+        .filter(|apath: &String| apath.starts_with(&base_apath))
+        .collect();
+    assert_eq!(
+        apaths,
+        [
+            base_apath.clone(),
+            format!("{}/etc", base_apath),
+            format!("{}/home", base_apath),
+            format!("{}/etc/hosts", base_apath),
+            format!("{}/home/notes", base_apath),
+        ]
+    );
+
+    let destdir = TempDir::new().unwrap();
+    af.restore(destdir.path(), &RestoreOptions::default())?;
+    let restored = destdir.path().join(&base_apath[1..]);
+    assert_eq!(fs::read(restored.join("etc/hosts"))?, b"localhost");
+    assert_eq!(fs::read(restored.join("home/notes"))?, b"remember");
+    assert!(!restored.join("home/skip.tmp").exists());
+
+    match af.backup_roots(&[base.clone(), base.join("etc")], &options) {
+        Err(Error::OverlappingSourceRoots { .. }) => (),
+        other => panic!("unexpected result {:?}", other),
+    }
+    Ok(())
+}
+
+/// An append-only archive accepts new backups, but refuses to delete or change
+/// existing ones unless opened allowing deletion.
+#[test]
+fn append_only_archive() -> Result<()> {
+    let af = ScratchArchive::new();
+    af.set_config(&ArchiveConfig {
+        append_only: true,
+        ..ArchiveConfig::default()
+    })?;
+    let archive = Archive::open_path(af.path())?;
+    assert!(archive.is_append_only());
+
+    let srcdir = TreeFixture::new();
+    srcdir.create_file_with_contents("aaa", b"first");
+    archive.backup(&srcdir.path(), &BackupOptions::default())?;
+    srcdir.create_file_with_contents("aaa", b"second");
+    archive.backup(&srcdir.path(), &BackupOptions::default())?;
+    assert_eq!(archive.list_band_ids()?.len(), 2);
+
+    let refused = |result: Result<DeleteStats>| match result {
+        Err(Error::ArchiveAppendOnly) => (),
+        other => panic!("unexpected result {:?}", other),
+    };
+    refused(archive.delete_band(&BandId::zero(), &DeleteOptions::default()));
+    refused(archive.delete_unreferenced(&DeleteOptions::default()));
+    match archive.add_tags(&BandId::zero(), &["old".to_owned()]) {
+        Err(Error::ArchiveAppendOnly) => (),
+        other => panic!("unexpected result {:?}", other),
+    }
+    match archive.remove_tags(&BandId::zero(), &["old".to_owned()]) {
+        Err(Error::ArchiveAppendOnly) => (),
+        other => panic!("unexpected result {:?}", other),
+    }
+    match migrate_archive(&archive, &MigrateOptions::default()) {
+        Err(Error::ArchiveAppendOnly) => (),
+        other => panic!("unexpected result {:?}", other),
+    }
+    let dry_run = MigrateOptions {
+        dry_run: true,
+        ..MigrateOptions::default()
+    };
+    assert_eq!(migrate_archive(&archive, &dry_run)?.blocks_recompressed, 2);
+    assert!(!archive.has_feature(ZSTD_BLOCKS_FEATURE));
+    match archive.set_config(&ArchiveConfig::default()) {
+        Err(Error::ArchiveAppendOnly) => (),
+        other => panic!("unexpected result {:?}", other),
+    }
+    let stats = archive.delete_band(
+        &BandId::zero(),
+        &DeleteOptions {
+            dry_run: true,
+            ..DeleteOptions::default()
+        },
+    )?;
+    assert_eq!(stats.unreferenced_block_count, 1);
+    assert_eq!(archive.list_band_ids()?.len(), 2);
+
+    let archive = Archive::open_path_with_allow_delete(af.path(), true)?;
+    assert!(!archive.is_append_only());
+    let stats = archive.delete_band(&BandId::zero(), &DeleteOptions::default())?;
+    assert_eq!(stats.deleted_band_count, 1);
+    assert_eq!(stats.deleted_block_count, 1);
+    assert_eq!(archive.list_band_ids()?, [BandId::new(&[1])]);
+    Ok(())
+}
+
+/// A stored tree can be written as a tar stream, optionally compressed.
+#[test]
+fn export_tar_stream() -> Result<()> {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file_with_contents("hello", b"contents");
+    srcdir.create_dir("subdir");
+    srcdir.create_file_with_contents("subdir/world", b"more contents");
+    srcdir.create_symlink("link", "hello");
+    af.backup(&srcdir.path(), &BackupOptions::default())?;
+    let st = af.open_stored_tree(BandSelectionPolicy::Latest)?;
+
+    for compression in &[
+        TarCompression::None,
+        TarCompression::Gzip,
+        TarCompression::Zstd,
+    ] {
+        let mut buf = Vec::new();
+        let stats = export_tar(
+            &st,
+            &mut buf,
+            &ExportTarOptions {
+                compression: *compression,
+            },
+        )?;
+        assert_eq!(stats.files, 2);
+        assert_eq!(stats.directories, 1);
+        assert_eq!(stats.file_bytes, 21);
+        let reader: Box<dyn Read> = match compression {
+            TarCompression::None => Box::new(buf.as_slice()),
+            TarCompression::Gzip => Box::new(flate2::read::GzDecoder::new(buf.as_slice())),
+            TarCompression::Zstd => Box::new(zstd::stream::read::Decoder::new(buf.as_slice())?),
+        };
+        let mut tar = tar::Archive::new(reader);
+        let mut names = Vec::new();
+        for entry in tar.entries()? {
+            let mut entry = entry?;
+            let name = entry.path()?.to_string_lossy().into_owned();
+            match name.as_str() {
+                "hello" => {
+                    let mut content = String::new();
+                    entry.read_to_string(&mut content)?;
+                    assert_eq!(content, "contents");
+                }
+                "subdir" => assert_eq!(entry.header().entry_type(), tar::EntryType::Directory),
+                "link" => assert_eq!(entry.link_name()?.unwrap().to_str().unwrap(), "hello"),
+                _ => (),
+            }
+            names.push(name);
+        }
+        if cfg!(unix) {
+            assert_eq!(names, ["hello", "link", "subdir", "subdir/world"]);
+        }
+    }
+    Ok(())
+}
+
+/// A hard link whose target is too long for a tar header is written as a
+/// copy of the file.
+#[cfg(unix)]
+#[test]
+fn export_tar_copies_long_hard_link_targets() -> Result<()> {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    let long_name = "a".repeat(120);
+    srcdir.create_file_with_contents(&long_name, b"contents");
+    fs::hard_link(srcdir.path().join(&long_name), srcdir.path().join("b"))?;
+    af.backup(&srcdir.path(), &BackupOptions::default())?;
+    let st = af.open_stored_tree(BandSelectionPolicy::Latest)?;
+
+    let mut buf = Vec::new();
+    let stats = export_tar(&st, &mut buf, &ExportTarOptions::default())?;
+    assert_eq!(stats.files, 2);
+    assert_eq!(stats.hardlinks, 0);
+    assert_eq!(stats.skipped, 0);
+    let mut tar = tar::Archive::new(buf.as_slice());
+    let entries = tar
+        .entries()?
+        .map(|entry| {
+            let mut entry = entry?;
+            let name = entry.path()?.to_string_lossy().into_owned();
+            assert_eq!(entry.header().entry_type(), tar::EntryType::Regular);
+            let mut content = String::new();
+            entry.read_to_string(&mut content)?;
+            Ok((name, content))
+        })
+        .collect::<std::io::Result<Vec<(String, String)>>>()?;
+    assert_eq!(
+        entries,
+        [
+            (long_name, "contents".to_owned()),
+            ("b".to_owned(), "contents".to_owned())
+        ]
+    );
+    Ok(())
+}
+
+/// A tar file can be imported as a new backup, with entries sorted into apath
+/// order and missing directories added.
+#[test]
+fn import_tar_file() -> Result<()> {
+    let tempdir = TempDir::new().unwrap();
+    let tar_path = tempdir.path().join("old.tar.gz");
+    let encoder =
+        flate2::write::GzEncoder::new(File::create(&tar_path)?, flate2::Compression::default());
+    let mut builder = tar::Builder::new(encoder);
+    let mut append_file = |name: &str, content: &[u8]| {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o600);
+        header.set_mtime(1_500_000_000);
+        builder.append_data(&mut header, name, content).unwrap();
+    };
+    append_file("src/zzz", b"last");
+    append_file("src/aaa", b"first");
+    append_file("README", b"read me");
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Link);
+    header.set_size(0);
+    header.set_link_name("src/aaa")?;
+    builder.append_data(&mut header, "src/linked", std::io::empty())?;
+    builder.into_inner()?.finish()?;
+
+    let af = ScratchArchive::new();
+    let stats = af.import_tar(&tar_path, &BackupOptions::default())?;
+    assert_eq!(stats.files, 4);
+    assert_eq!(stats.hardlinked_files, 1);
+
+    let st = af.open_stored_tree(BandSelectionPolicy::Latest)?;
+    let apaths: Vec<String> = st.iter_entries()?.map(|entry| entry.apath.into()).collect();
+    assert_eq!(
+        apaths,
+        [
+            "/",
+            "/README",
+            "/src",
+            "/src/aaa",
+            "/src/linked",
+            "/src/zzz"
+        ]
+    );
+    let destdir = TempDir::new().unwrap();
+    af.restore(destdir.path(), &RestoreOptions::default())?;
+    assert_eq!(fs::read(destdir.path().join("src/zzz"))?, b"last");
+    assert_eq!(fs::read(destdir.path().join("src/linked"))?, b"first");
+    Ok(())
+}
+
+/// Two stored versions are compared by kind, size, and content blocks.
+#[test]
+fn diff_stored_versions() -> Result<()> {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file_with_contents("same", b"same");
+    srcdir.create_file_with_contents("edited", b"before");
+    srcdir.create_file_with_contents("gone", b"deleted later");
+    af.backup(&srcdir.path(), &BackupOptions::default())?;
+    srcdir.create_file_with_contents("edited", b"after!!");
+    fs::remove_file(srcdir.path().join("gone"))?;
+    srcdir.create_dir("new");
+    af.backup(&srcdir.path(), &BackupOptions::default())?;
+
+    let old = af.open_stored_tree(BandSelectionPolicy::Specified(BandId::zero()))?;
+    let new = af.open_stored_tree(BandSelectionPolicy::Latest)?;
+    let diff: Vec<DiffEntry> = diff_stored_trees(&old, &new, &DiffOptions::default())?.collect();
+    let summary: Vec<(String, DiffKind)> =
+        diff.iter().map(|e| (e.apath.to_string(), e.kind)).collect();
+    assert_eq!(
+        summary,
+        [
+            ("/edited".to_owned(), DiffKind::Changed),
+            ("/gone".to_owned(), DiffKind::Deleted),
+            ("/new".to_owned(), DiffKind::New),
+        ]
+    );
+    let mut stats = DiffStats::default();
+    diff.iter().for_each(|e| e.add_to_stats(&mut stats));
+    assert_eq!(stats.changed_old_bytes, 6);
+    assert_eq!(stats.changed_new_bytes, 7);
+    assert_eq!(stats.deleted_bytes, 13);
+    assert_eq!(stats.net_bytes(), -12);
+    Ok(())
+}
+
+/// A live tree that hasn't changed since the backup, including its
+/// directories, has no differences from the stored tree.
+#[test]
+fn diff_unchanged_live_tree() -> Result<()> {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_dir("subdir");
+    srcdir.create_file("subdir/hello");
+    srcdir.create_dir("subdir/deeper");
+    srcdir.create_file("top");
+    af.backup(&srcdir.path(), &BackupOptions::default())?;
+
+    let st = af.open_stored_tree(BandSelectionPolicy::Latest)?;
+    let lt = LiveTree::open(srcdir.path())?;
+    let diff: Vec<DiffEntry> = diff_live_tree(&st, &lt, &DiffOptions::default())?.collect();
+    assert!(diff.is_empty(), "{:?}", diff);
+
+    srcdir.create_file_with_contents("subdir/hello", b"a longer replacement");
+    let diff: Vec<DiffEntry> = diff_live_tree(&st, &lt, &DiffOptions::default())?.collect();
+    let summary: Vec<(String, DiffKind)> =
+        diff.iter().map(|e| (e.apath.to_string(), e.kind)).collect();
+    assert_eq!(summary, [("/subdir/hello".to_owned(), DiffKind::Changed)]);
+    Ok(())
+}
+
+/// Directory sizes count content shared between files only once.
+#[test]
+fn dir_usage_of_stored_tree() -> Result<()> {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_dir("a");
+    srcdir.create_file_with_contents("a/one", b"hello world");
+    srcdir.create_dir("b");
+    srcdir.create_file_with_contents("b/two", b"hello world");
+    srcdir.create_file_with_contents("b/three", b"other");
+    af.backup(&srcdir.path(), &BackupOptions::default())?;
+    let st = af.open_stored_tree(BandSelectionPolicy::Latest)?;
+
+    let usage = dir_usage(&st, None)?;
+    let sizes: Vec<(String, u64, u64, u64)> = usage
+        .into_iter()
+        .map(|d| {
+            (
+                d.apath.to_string(),
+                d.files,
+                d.apparent_bytes,
+                d.unique_bytes,
+            )
+        })
+        .collect();
+    assert_eq!(
+        sizes,
+        [
+            ("/".to_owned(), 3, 27, 16),
+            ("/a".to_owned(), 1, 11, 11),
+            ("/b".to_owned(), 2, 16, 5),
+        ]
+    );
+
+    let usage = dir_usage(&st, Some(0))?;
+    assert_eq!(usage.len(), 1);
+    assert_eq!(usage[0].unique_bytes, 16);
+    Ok(())
+}
+
+#[test]
+fn archive_info() -> Result<()> {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file_with_contents("hello", b"hello");
+    srcdir.create_file_with_contents("copy", b"hello");
+    af.backup(&srcdir.path(), &BackupOptions::default())?;
+    srcdir.create_file_with_contents("world", b"world!");
+    af.backup(&srcdir.path(), &BackupOptions::default())?;
+    af.setup_incomplete_empty_band();
+
+    let info = af.info(None)?;
+    assert_eq!(info.archive_version, ARCHIVE_VERSION);
+    assert_eq!(info.complete_versions, 2);
+    assert_eq!(info.incomplete_versions, 1);
+    assert!(info.oldest_backup.unwrap() <= info.newest_backup.unwrap());
+    assert_eq!(info.block_count, 2);
+    assert!(info.compressed_bytes > 0);
+    assert_eq!(info.deduplicated_bytes, 11);
+    Ok(())
+}
+
+#[test]
+fn mirrored_backup() -> Result<()> {
+    let af = ScratchArchive::new();
+    let mirror_dir = TempDir::new().unwrap();
+    let mirror_path = mirror_dir.path().join("mirror");
+    af.set_config(&ArchiveConfig {
+        compression: Some(Compression::Zstd),
+        mirror: Some(mirror_path.to_str().unwrap().to_owned()),
+        ..ArchiveConfig::default()
+    })?;
+    let archive = Archive::open_path(af.path())?;
+    let srcdir = TreeFixture::new();
+    srcdir.create_file_with_contents("hello", b"hello");
+
+    // The mirror is created, and the backup is written to both archives.
+    archive.backup(&srcdir.path(), &BackupOptions::default())?;
+    let mirror = Archive::open_path(&mirror_path)?;
+    assert!(mirror.has_feature(ZSTD_BLOCKS_FEATURE));
+    assert_eq!(mirror.list_band_ids()?, vec![BandId::zero()]);
+    assert!(mirror.band_is_closed(&BandId::zero())?);
+    assert_eq!(mirror.block_dir().block_names()?.count(), 1);
+
+    // While the mirror is unavailable, backups are still made.
+    let moved_path = mirror_dir.path().join("moved");
+    fs::rename(&mirror_path, &moved_path).unwrap();
+    fs::write(&mirror_path, b"not an archive").unwrap();
+    srcdir.create_file_with_contents("world", b"world");
+    archive.backup(&srcdir.path(), &BackupOptions::default())?;
+    assert_eq!(archive.list_band_ids()?.len(), 2);
+
+    // Once it's back, it's caught up before the next backup is written to it.
+    fs::remove_file(&mirror_path).unwrap();
+    fs::rename(&moved_path, &mirror_path).unwrap();
+    srcdir.create_file_with_contents("again", b"again");
+    archive.backup(&srcdir.path(), &BackupOptions::default())?;
+    let mirror = Archive::open_path(&mirror_path)?;
+    assert_eq!(mirror.list_band_ids()?, archive.list_band_ids()?);
+    assert!(mirror.band_is_closed(&BandId::new(&[2]))?);
+    assert_eq!(mirror.block_dir().block_names()?.count(), 3);
+    assert!(!mirror.validate(&ValidateOptions::default())?.has_problems());
+    assert_eq!(mirror.config().mirror, None);
+
+    // The archive records that the mirror is current, so the next backup
+    // needn't compare them.
+    let state: serde_json::Value =
+        serde_json::from_slice(&fs::read(af.path().join("MIRROR"))?).unwrap();
+    assert_eq!(state["last_band"], "b0002");
+    assert!(!archive
+        .validate(&ValidateOptions::default())?
+        .has_problems());
+
+    // Without that record, the mirror is compared to the archive, and blocks
+    // missing from it are copied again before the next backup.
+    let hash = mirror
+        .block_dir()
+        .block_names()?
+        .next()
+        .unwrap()
+        .to_string();
+    fs::remove_file(mirror_path.join("d").join(&hash[..3]).join(&hash))?;
+    fs::remove_file(af.path().join("MIRROR"))?;
+    archive.backup(&srcdir.path(), &BackupOptions::default())?;
+    let mirror = Archive::open_path(&mirror_path)?;
+    assert_eq!(mirror.block_dir().block_names()?.count(), 3);
+    assert!(!mirror.validate(&ValidateOptions::default())?.has_problems());
+    Ok(())
+}
+
+/// A backup isn't written to a mirror that another process has locked, and
+/// the lock is left alone.
+#[test]
+fn mirror_locked_by_another_process() -> Result<()> {
+    let af = ScratchArchive::new();
+    let mirror_dir = TempDir::new().unwrap();
+    let mirror_path = mirror_dir.path().join("mirror");
+    af.set_config(&ArchiveConfig {
+        mirror: Some(mirror_path.to_str().unwrap().to_owned()),
+        ..ArchiveConfig::default()
+    })?;
+    let archive = Archive::open_path(af.path())?;
+    let srcdir = TreeFixture::new();
+    srcdir.create_file_with_contents("hello", b"hello");
+    archive.backup(&srcdir.path(), &BackupOptions::default())?;
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let lock = format!(
+        r#"{{"hostname":"elsewhere","pid":1,"start_time":{},"operation":"gc"}}"#,
+        now
+    );
+    let lock_path = mirror_path.join("LOCK");
+    fs::write(&lock_path, &lock)?;
+    srcdir.create_file_with_contents("world", b"world");
+    archive.backup(&srcdir.path(), &BackupOptions::default())?;
+    assert_eq!(archive.list_band_ids()?.len(), 2);
+    let mirror = Archive::open_path(&mirror_path)?;
+    assert_eq!(mirror.list_band_ids()?, vec![BandId::zero()]);
+    assert_eq!(mirror.block_dir().block_names()?.count(), 1);
+    assert_eq!(fs::read_to_string(&lock_path)?, lock);
+    fs::remove_file(&lock_path)?;
+
+    // A garbage collection lock also keeps the backup out of the mirror.
+    let gc_lock_path = mirror_path.join("GC_LOCK");
+    fs::write(&gc_lock_path, b"{}\n")?;
+    srcdir.create_file_with_contents("again", b"again");
+    archive.backup(&srcdir.path(), &BackupOptions::default())?;
+    assert_eq!(mirror.list_band_ids()?, vec![BandId::zero()]);
+    assert!(gc_lock_path.exists());
+    assert!(!lock_path.exists());
+    fs::remove_file(&gc_lock_path)?;
+
+    // Once the mirror is unlocked, it's caught up by the next backup.
+    archive.backup(&srcdir.path(), &BackupOptions::default())?;
+    assert_eq!(mirror.list_band_ids()?, archive.list_band_ids()?);
+    assert_eq!(mirror.block_dir().block_names()?.count(), 3);
+    assert!(!mirror.validate(&ValidateOptions::default())?.has_problems());
+    assert!(!lock_path.exists());
+    Ok(())
+}
@@ -55,7 +55,9 @@ fn validate_archive() {
         println!("validate {}", ver);
         let archive = open_old_archive(ver, "minimal-1");
 
-        let stats = archive.validate().expect("validate archive");
+        let stats = archive
+            .validate(&ValidateOptions::default())
+            .expect("validate archive");
         assert_eq!(stats.structure_problems, 0);
         assert_eq!(stats.io_errors, 0);
         assert_eq!(stats.block_error_count, 0);
@@ -122,18 +124,10 @@ fn restore_modify_backup() {
             .expect("Backup modified tree");
 
         assert_eq!(backup_stats.files, 3);
-        // unmodified_files should be 0, but the unchanged file is s not defected as unmodified,
-        // for two reasons.
-        //
-        // 1. We don't restore the mtime, so that doesn't match (#47).
-        //
-        // 2. Files that have a different mtime but the same content aren't seen as
-        //    unmodified.  (And, maybe that's correct: we did have to do work to read and hash all
-        //    the content. Perhaps they should be counted separately: #124.)
-        //
-        // Similarly, modified_files should be 1.
-        assert_eq!(backup_stats.unmodified_files, 0);
-        assert_eq!(backup_stats.modified_files, 2);
+        // The restored mtime matches the stored one, so the file that wasn't
+        // touched is seen as unmodified.
+        assert_eq!(backup_stats.unmodified_files, 1);
+        assert_eq!(backup_stats.modified_files, 1);
         assert_eq!(backup_stats.new_files, 1);
         assert_eq!(backup_stats.empty_files, 1);
 
@@ -146,3 +140,44 @@ fn restore_modify_backup() {
         new_archive_temp.close().expect("Cleanup copied archive");
     }
 }
+
+#[test]
+fn update_dry_run_reads_no_blocks() {
+    for ver in ARCHIVE_VERSIONS {
+        let dest = TempDir::new().unwrap();
+        println!("update {} in {:?}", ver, dest.path());
+        open_old_archive(ver, "minimal-1")
+            .restore(&dest.path(), &RestoreOptions::default())
+            .expect("restore");
+        // Same length, but a new mtime.
+        fs::write(dest.path().join("hello"), "HELLO WORLD\n").expect("overwrite file");
+
+        // These old indexes don't have hashes of whole files, and the copied
+        // archive has no blocks, so a dry run can only compare metadata.
+        let archive_temp = TempDir::new().unwrap();
+        let archive_path = archive_temp.path().join("archive");
+        copy_dir(
+            format!("testdata/archive/v{}/minimal-1", ver),
+            &archive_path,
+        )
+        .expect("copy archive tree");
+        fs::remove_dir_all(archive_path.join("d")).unwrap();
+        fs::create_dir(archive_path.join("d")).unwrap();
+
+        let archive = Archive::open_path(&archive_path).expect("Open archive");
+        let stats = archive
+            .restore(
+                &dest.path(),
+                &RestoreOptions {
+                    overwrite: OverwritePolicy::Update,
+                    dry_run: true,
+                    ..RestoreOptions::default()
+                },
+            )
+            .expect("dry run");
+        assert_eq!(stats.unmodified_files, 1);
+        assert_eq!(stats.overwritten, 1);
+        assert_eq!(stats.errors, 0);
+        dest.child("hello").assert("HELLO WORLD\n");
+    }
+}